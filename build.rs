@@ -0,0 +1,28 @@
+use std::env;
+use std::process::Command;
+
+// records the commit being built, for the /version route. builds made
+// outside of a git checkout can set GIT_SHA instead
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = env::var("GIT_SHA")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| {
+            let out = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+
+            out.status
+                .success()
+                .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        });
+
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=PUBSUB_GIT_SHA={sha}");
+    }
+}
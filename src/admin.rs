@@ -1,76 +1,1653 @@
-use crate::auth::Authorization;
+use crate::audit;
+use crate::auth::{self, AdminScope, Authorization, AuthorizationError, KeyMetadata, TokenGrants};
+use crate::config::{Config, SettingSource};
+use crate::deadline::Deadline;
+use crate::events;
+use crate::kv::{FastlyKv, Kv};
+use crate::meta::MessageMeta;
+use crate::problem::{self, Problem};
+use crate::publickeys;
+use crate::publish::{
+    publish, publish_close, read_body, Batch, BodyError, CONNECTION_CHANNEL_PREFIX,
+    MESSAGE_SIZE_MAX,
+};
+use crate::stats::Counts;
+use crate::storage::{unix_now, RetainedVersion, Storage, StorageError};
+use crate::topic;
+use crate::{log_error, log_warn};
 use fastly::http::StatusCode;
 use fastly::kv_store;
 use fastly::{Request, Response};
 use jwt_simple::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
 use std::fmt::Write;
+use std::time::Instant;
+
+const RETAINED_LIST_LIMIT_DEFAULT: u32 = 100;
+
+// the most the KV store returns per page
+const RETAINED_LIST_LIMIT_MAX: u32 = 1000;
+
+const KEYS_LIST_LIMIT_DEFAULT: u32 = 100;
+
+// keys are looked up individually for their metadata, so pages are kept
+// smaller than the store allows
+const KEYS_LIST_LIMIT_MAX: u32 = 100;
+
+const KEY_LABEL_LENGTH_MAX: usize = 256;
+
+// how long a rotated key's previous value remains valid, in seconds
+const KEY_GRACE_DEFAULT: u64 = 60 * 60;
+const KEY_GRACE_MAX: u64 = 60 * 60 * 24 * 7;
+
+const TOKEN_TTL_DEFAULT: u64 = 60 * 60;
+const TOKEN_TTL_MAX: u64 = 60 * 60 * 24;
+
+// tokens minted for services via /admin/tokens
+const SERVICE_TOKEN_TTL_DEFAULT: u64 = 60 * 60 * 24 * 30;
+const SERVICE_TOKEN_TTL_MAX: u64 = 60 * 60 * 24 * 365;
+
+// topics are listed in small pages, so that a broadcast that runs out of
+// time can be continued without skipping many
+const BROADCAST_PAGE_SIZE: u32 = 100;
+
+// minutes of stats to report. stats are kept in storage for longer
+const STATS_MINUTES_DEFAULT: u64 = 60;
+const STATS_MINUTES_MAX: u64 = 60;
+
+// internal topic used for diagnostics. MQTT clients cannot publish to
+// topics beginning with $
+const SELFTEST_TOPIC: &str = "$selftest";
 
 #[derive(Serialize)]
 struct Key {
     id: String,
     value: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
 }
 
-fn text_response(status: StatusCode, text: &str) -> Response {
-    Response::from_status(status).with_body_text_plain(&format!("{text}\n"))
+#[derive(Deserialize, Default)]
+struct KeyRequest {
+    label: Option<String>,
 }
 
-pub fn post_keys(auth: &Authorization, _req: Request) -> Response {
-    if !auth.fastly {
-        return text_response(
-            StatusCode::UNAUTHORIZED,
-            "Fastly-Key header invalid or not specified",
-        );
+// a key as listed, without its value
+#[derive(Serialize)]
+struct KeyInfo {
+    id: String,
+
+    #[serde(flatten)]
+    meta: KeyMetadata,
+}
+
+#[derive(Serialize)]
+struct KeyListResult {
+    keys: Vec<KeyInfo>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    #[serde(default)]
+    read: Vec<String>,
+
+    #[serde(default)]
+    write: Vec<String>,
+
+    #[serde(default)]
+    subtree: bool,
+
+    sub: Option<String>,
+
+    // seconds
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TokenResult {
+    token: String,
+    expires_at: u64,
+}
+
+#[derive(Deserialize)]
+struct ServiceTokenRequest {
+    #[serde(flatten)]
+    grants: TokenRequest,
+
+    // ID of the key to sign with, instead of the token signing key
+    key: Option<String>,
+
+    // unix timestamp, as an alternative to a ttl
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ServiceTokenResult {
+    token: String,
+
+    // ID of the key the token was signed with
+    key: String,
+
+    expires_at: u64,
+    claims: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    ok: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u128>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SelfTestResult {
+    ok: bool,
+    publish: CheckResult,
+}
+
+#[derive(Serialize)]
+struct ScheduledResult {
+    topics: usize,
+    delivered: usize,
+
+    // true if the time budget ran out before all topics were checked
+    incomplete: bool,
+}
+
+#[derive(Serialize)]
+struct BroadcastResult {
+    topics: usize,
+    published: usize,
+    failed: usize,
+
+    // for continuing with the remaining topics, if the time budget ran out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ConfigResult<'a> {
+    config: &'a Config,
+
+    // where settings came from, by store key. others have their defaults
+    sources: &'a BTreeMap<String, SettingSource>,
+
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct StatsMinute {
+    // unix timestamp of the start of the minute
+    time: u64,
+
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+#[derive(Serialize)]
+struct StatsResult {
+    totals: Counts,
+
+    // oldest first
+    minutes: Vec<StatsMinute>,
+}
+
+#[derive(Serialize)]
+struct RetainedTopicInfo {
+    topic: String,
+    id: String,
+
+    // size of the latest message, if it hasn't expired or been deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+
+    // seconds until the slot is removed from storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+
+    // when the message stops being delivered, as a unix timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+
+    deleted: bool,
+    depth: u32,
+}
+
+#[derive(Serialize)]
+struct RetainedListResult {
+    topics: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Vec<RetainedTopicInfo>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+// who is making an admin request
+enum Admin {
+    // the platform owner, authenticated with a Fastly API token, or a holder
+    // of a token with the needed x-fastly-admin-scopes and no prefix
+    Platform,
+
+    // a holder of a token with the x-fastly-admin-prefix claim, limited to
+    // the topics within that prefix
+    Tenant(String),
+}
+
+fn get_admin(auth: &Authorization, req: &Request, scope: AdminScope) -> Result<Admin, Problem> {
+    if auth.fastly {
+        return Ok(Admin::Platform);
+    }
+
+    let token = match events::get_token(req, false) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Err(Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "Fastly-Key header invalid or not specified",
+            ))
+        }
+        Err(e) => return Err(Problem::new(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    let caps = match auth.validate_token(token) {
+        Ok(caps) => caps,
+        Err(AuthorizationError::Token(_)) => {
+            return Err(
+                Problem::new(StatusCode::FORBIDDEN, "Invalid token").with_code("invalid-token")
+            );
+        }
+        Err(e) => {
+            log_error!("auth failed: {e:?}");
+
+            return Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Auth process failed",
+            ));
+        }
+    };
+
+    if !caps.has_admin_scope(scope) {
+        return Err(Problem::new(
+            StatusCode::FORBIDDEN,
+            &format!("Token does not grant admin scope: {}", scope.as_str()),
+        ));
+    }
+
+    match caps.admin_prefix() {
+        Some(prefix) => Ok(Admin::Tenant(prefix.to_string())),
+        None => Ok(Admin::Platform),
+    }
+}
+
+// for operations that affect the whole app
+fn require_platform(auth: &Authorization, req: &Request, scope: AdminScope) -> Result<(), Problem> {
+    match get_admin(auth, req, scope)? {
+        Admin::Platform => Ok(()),
+        Admin::Tenant(_) => Err(Problem::new(
+            StatusCode::FORBIDDEN,
+            "Operation requires platform admin access",
+        )),
     }
+}
 
-    let store = match kv_store::KVStore::open("keys") {
-        Ok(Some(store)) => store,
+fn open_keys_store() -> Result<kv_store::KVStore, Problem> {
+    match kv_store::KVStore::open("keys") {
+        Ok(Some(store)) => Ok(store),
         Ok(None) => {
-            println!("kv store not found");
+            log_error!("kv store not found");
 
-            return text_response(
+            Err(Problem::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Storage access process failed",
-            );
+            ))
         }
         Err(e) => {
-            println!("failed to open kv store: {e}");
+            log_error!("failed to open kv store: {e}");
 
-            return text_response(
+            Err(Problem::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Storage access process failed",
-            );
+            ))
+        }
+    }
+}
+
+fn generate_key_value() -> String {
+    let random_bytes = HS256Key::generate().to_bytes();
+
+    let mut value = String::new();
+    for &b in Sha1::digest(&random_bytes).as_slice() {
+        value.write_fmt(format_args!("{b:02x}")).unwrap();
+    }
+
+    value
+}
+
+fn write_key(
+    store: &kv_store::KVStore,
+    id: &str,
+    value: &str,
+    meta: &KeyMetadata,
+) -> Result<(), Problem> {
+    let meta_json = serde_json::to_string(meta).expect("metadata should always be serializable");
+
+    if let Err(e) = store
+        .build_insert()
+        .metadata(&meta_json)
+        .execute(id, value.to_string())
+    {
+        log_error!("failed to write to kv store: {e}");
+
+        return Err(Problem::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Storage writing process failed",
+        ));
+    }
+
+    Ok(())
+}
+
+// looks up a key for an admin operation. keys outside a tenant admin's
+// topics are treated as not found
+fn read_key(
+    store: &kv_store::KVStore,
+    id: &str,
+    root: Option<&str>,
+) -> Result<(Vec<u8>, KeyMetadata), Problem> {
+    let not_found = || Problem::new(StatusCode::NOT_FOUND, "Key not found");
+
+    let (value, meta) = match store.lookup(id) {
+        Ok(mut lookup) => {
+            let meta: KeyMetadata = match lookup.metadata() {
+                Some(data) => match serde_json::from_slice(&data) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        log_error!("invalid metadata for key {id}");
+
+                        return Err(Problem::new(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Storage access process failed",
+                        ));
+                    }
+                },
+                None => KeyMetadata::default(),
+            };
+
+            (lookup.take_body_bytes(), meta)
+        }
+        Err(kv_store::KVStoreError::ItemNotFound) => return Err(not_found()),
+        Err(e) => {
+            log_error!("failed to read from kv store: {e}");
+
+            return Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Storage access process failed",
+            ));
         }
     };
 
-    let key = {
-        let random_bytes = HS256Key::generate().to_bytes();
+    if let Some(root) = root {
+        if !meta
+            .prefix
+            .as_ref()
+            .is_some_and(|p| topic::is_within(p, root))
+        {
+            return Err(not_found());
+        }
+    }
+
+    Ok((value, meta))
+}
+
+// a path under /admin/keys/
+pub enum KeyPath {
+    Key(String),
+    Rotate(String),
+}
+
+pub fn parse_key_path(path: &str) -> Option<KeyPath> {
+    let rest = path.strip_prefix("/admin/keys/")?;
+
+    let (id, rotate) = match rest.strip_suffix("/rotate") {
+        Some(id) => (id, true),
+        None => (rest, false),
+    };
+
+    if id.is_empty() || id.contains('/') || !auth::is_signing_key_name(id) {
+        return None;
+    }
+
+    if rotate {
+        Some(KeyPath::Rotate(id.to_string()))
+    } else {
+        Some(KeyPath::Key(id.to_string()))
+    }
+}
 
-        let mut value = String::new();
-        for &b in Sha1::digest(&random_bytes).as_slice() {
-            value.write_fmt(format_args!("{b:02x}")).unwrap();
+// keys minted by tenant admins can only sign tokens for the tenant's topics.
+// the body can optionally give the key a label
+pub fn post_keys(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    mut req: Request,
+) -> Response {
+    let prefix = match get_admin(auth, &req, AdminScope::Keys) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let body = req.take_body_bytes();
+
+    let r: KeyRequest = if body.is_empty() {
+        KeyRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(r) => r,
+            Err(e) => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid request body: {e}"),
+                )
+            }
         }
+    };
+
+    if r.label
+        .as_ref()
+        .is_some_and(|s| s.len() > KEY_LABEL_LENGTH_MAX)
+    {
+        return problem::response(
+            StatusCode::BAD_REQUEST,
+            &format!("Label exceeds {KEY_LABEL_LENGTH_MAX} bytes maximum"),
+        );
+    }
+
+    let store = match open_keys_store() {
+        Ok(store) => store,
+        Err(e) => return e.response(),
+    };
+
+    let key = {
+        let value = generate_key_value();
 
         let mut id = String::new();
         for &b in Sha1::digest(&value).as_slice()[..4].iter() {
             id.write_fmt(format_args!("{b:02x}")).unwrap();
         }
 
-        Key { id, value }
+        Key {
+            id,
+            value,
+            prefix,
+            label: r.label,
+        }
+    };
+
+    let meta = KeyMetadata {
+        prefix: key.prefix.clone(),
+        alg: None,
+        created_at: Some(unix_now()),
+        label: key.label.clone(),
+        ..Default::default()
     };
 
-    if let Err(e) = store.insert(&key.id, key.value.clone()) {
-        println!("failed to write to kv store: {e}");
+    if let Err(e) = write_key(&store, &key.id, &key.value, &meta) {
+        return e.response();
+    }
 
-        return text_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Storage writing process failed",
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::KeyCreate,
+        &key.id,
+        serde_json::json!({"prefix": key.prefix, "label": key.label}),
+    );
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&key)
+        .unwrap()
+}
+
+// also removes the previous value of a rotated key
+pub fn delete_key(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    id: &str,
+    req: Request,
+) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Keys) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let store = match open_keys_store() {
+        Ok(store) => store,
+        Err(e) => return e.response(),
+    };
+
+    if let Err(e) = read_key(&store, id, root.as_deref()) {
+        return e.response();
+    }
+
+    for name in [id.to_string(), auth::previous_key_name(id)] {
+        match store.delete(&name) {
+            Ok(()) | Err(kv_store::KVStoreError::ItemNotFound) => {}
+            Err(e) => {
+                log_error!("failed to delete from kv store: {e}");
+
+                return problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Storage writing process failed",
+                );
+            }
+        }
+    }
+
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::KeyDelete,
+        id,
+        serde_json::Value::Null,
+    );
+
+    Response::from_status(StatusCode::NO_CONTENT)
+}
+
+// gives a key a new value. the previous value remains valid for the number
+// of seconds in the grace param
+pub fn post_key_rotate(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    id: &str,
+    req: Request,
+) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Keys) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let grace = match req.get_query_parameter("grace") {
+        Some(x) => match x.parse::<u64>() {
+            Ok(x) if x <= KEY_GRACE_MAX => x,
+            _ => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("'grace' param must be between 0 and {KEY_GRACE_MAX}"),
+                )
+            }
+        },
+        None => KEY_GRACE_DEFAULT,
+    };
+
+    let store = match open_keys_store() {
+        Ok(store) => store,
+        Err(e) => return e.response(),
+    };
+
+    let (old_value, mut meta) = match read_key(&store, id, root.as_deref()) {
+        Ok(v) => v,
+        Err(e) => return e.response(),
+    };
+
+    // public keys are replaced by their owners
+    if meta.alg.as_deref().is_some_and(|a| a != "HS256") {
+        return problem::response(
+            StatusCode::BAD_REQUEST,
+            "Only keys used with HS256 can be rotated",
         );
     }
 
+    let now = unix_now();
+
+    if grace > 0 {
+        if let Err(e) = store
+            .build_insert()
+            .time_to_live(std::time::Duration::from_secs(grace))
+            .execute(&auth::previous_key_name(id), old_value)
+        {
+            log_error!("failed to write to kv store: {e}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Storage writing process failed",
+            );
+        }
+    }
+
+    meta.rotated_at = Some(now);
+    meta.previous_expires_at = (grace > 0).then_some(now + grace);
+
+    let key = Key {
+        id: id.to_string(),
+        value: generate_key_value(),
+        prefix: meta.prefix.clone(),
+        label: meta.label.clone(),
+    };
+
+    if let Err(e) = write_key(&store, &key.id, &key.value, &meta) {
+        return e.response();
+    }
+
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::KeyRotate,
+        id,
+        serde_json::json!({"grace": grace}),
+    );
+
     Response::from_status(StatusCode::OK)
         .with_body_json(&key)
         .unwrap()
 }
+
+// lists signing keys, a page at a time, without their values. tenant admins
+// only see keys for their own topics
+pub fn get_keys(auth: &Authorization, req: Request) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Keys) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let limit = match req.get_query_parameter("limit") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(x) if x > 0 && x <= KEYS_LIST_LIMIT_MAX => x,
+            _ => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("'limit' param must be between 1 and {KEYS_LIST_LIMIT_MAX}"),
+                )
+            }
+        },
+        None => KEYS_LIST_LIMIT_DEFAULT,
+    };
+
+    let cursor = req.get_query_parameter("cursor");
+
+    let store = FastlyKv::new("keys");
+
+    let page = match store.list("", cursor, Some(limit)) {
+        Ok(page) => page,
+        Err(e) => {
+            log_error!("failed to list keys: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Storage access process failed",
+            );
+        }
+    };
+
+    let ids: Vec<String> = page
+        .keys
+        .into_iter()
+        .filter(|k| auth::is_signing_key_name(k))
+        .collect();
+
+    let items = match store.lookup_many(&ids) {
+        Ok(items) => items,
+        Err(e) => {
+            log_error!("failed to look up keys: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Storage access process failed",
+            );
+        }
+    };
+
+    let mut keys = Vec::new();
+
+    // keys deleted since listing are skipped
+    for (id, item) in ids.into_iter().zip(items) {
+        let Some(item) = item else {
+            continue;
+        };
+
+        let meta: KeyMetadata = match item.metadata.as_deref().map(serde_json::from_slice) {
+            Some(Ok(meta)) => meta,
+            Some(Err(_)) => {
+                log_error!("invalid metadata for key {id}");
+
+                continue;
+            }
+            None => KeyMetadata::default(),
+        };
+
+        if let Some(root) = &root {
+            if !meta
+                .prefix
+                .as_ref()
+                .is_some_and(|p| topic::is_within(p, root))
+            {
+                continue;
+            }
+        }
+
+        keys.push(KeyInfo { id, meta });
+    }
+
+    let result = KeyListResult {
+        keys,
+        cursor: page.cursor,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// checks that the requested grants are valid topics that the admin can
+// grant
+fn check_grants(r: &TokenRequest, root: Option<&str>) -> Result<(), Problem> {
+    for t in r.read.iter().chain(&r.write) {
+        if topic::parse(t).is_err() {
+            return Err(
+                Problem::new(StatusCode::BAD_REQUEST, &format!("Invalid topic: {t}"))
+                    .with_code("invalid-topic"),
+            );
+        }
+
+        if let Some(root) = root {
+            if !topic::is_within(t, root) {
+                return Err(Problem::new(
+                    StatusCode::FORBIDDEN,
+                    &format!("Cannot grant topic: {t}"),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// the value of the configured token signing key. only secret keys can sign
+fn read_token_signing_key(
+    config: &Config,
+    store: &kv_store::KVStore,
+) -> Result<(String, Vec<u8>), Problem> {
+    let Some(key_id) = &config.token_signing_key else {
+        return Err(Problem::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Token signing key not configured",
+        ));
+    };
+
+    match store.lookup(key_id) {
+        Ok(mut lookup) => {
+            let meta: KeyMetadata = lookup
+                .metadata()
+                .and_then(|data| serde_json::from_slice(&data).ok())
+                .unwrap_or_default();
+
+            if meta.alg.as_deref().is_some_and(|alg| alg != "HS256") {
+                log_error!("token signing key {key_id} is not a secret key");
+
+                return Err(Problem::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Token signing key not usable",
+                ));
+            }
+
+            Ok((key_id.clone(), lookup.take_body_bytes()))
+        }
+        Err(e) => {
+            log_error!("failed to read token signing key {key_id}: {e}");
+
+            Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Storage access process failed",
+            ))
+        }
+    }
+}
+
+fn sign_token(key_id: &str, key: &[u8], r: TokenRequest, ttl: u64) -> Result<String, Problem> {
+    let grants = TokenGrants {
+        subject: r.sub,
+        read: r.read,
+        write: r.write,
+        subtree: r.subtree,
+    };
+
+    match auth::create_token(key_id, key, &grants, Duration::from_secs(ttl)) {
+        Ok(token) => Ok(token),
+        Err(e) => {
+            log_error!("failed to create token: {e:?}");
+
+            Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Token creation process failed",
+            ))
+        }
+    }
+}
+
+// mints a client token. tenant admins can only grant their own topics
+pub fn post_tokens(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    mut req: Request,
+) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Keys) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let r: TokenRequest = match serde_json::from_slice(&req.take_body_bytes()) {
+        Ok(r) => r,
+        Err(e) => {
+            return problem::response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid request body: {e}"),
+            )
+        }
+    };
+
+    if let Err(e) = check_grants(&r, root.as_deref()) {
+        return e.response();
+    }
+
+    let ttl = r.ttl.unwrap_or(TOKEN_TTL_DEFAULT);
+
+    if ttl == 0 || ttl > TOKEN_TTL_MAX {
+        return problem::response(
+            StatusCode::BAD_REQUEST,
+            &format!("Invalid ttl, maximum {TOKEN_TTL_MAX}"),
+        );
+    }
+
+    let store = match open_keys_store() {
+        Ok(store) => store,
+        Err(e) => return e.response(),
+    };
+
+    let (key_id, key) = match read_token_signing_key(config, &store) {
+        Ok(v) => v,
+        Err(e) => return e.response(),
+    };
+
+    let token = match sign_token(&key_id, &key, r, ttl) {
+        Ok(token) => token,
+        Err(e) => return e.response(),
+    };
+
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::TokenMint,
+        &key_id,
+        auth::token_claims(&token).unwrap_or_default(),
+    );
+
+    let result = TokenResult {
+        token,
+        expires_at: Clock::now_since_epoch().as_secs() + ttl,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// mints a longer-lived token for a service, optionally signed with a chosen
+// key instead of the token signing key, and returns its claims. tenant
+// admins can only grant their own topics, and only choose their own keys
+pub fn post_admin_tokens(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    mut req: Request,
+) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Keys) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let r: ServiceTokenRequest = match serde_json::from_slice(&req.take_body_bytes()) {
+        Ok(r) => r,
+        Err(e) => {
+            return problem::response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid request body: {e}"),
+            )
+        }
+    };
+
+    if let Err(e) = check_grants(&r.grants, root.as_deref()) {
+        return e.response();
+    }
+
+    let now = Clock::now_since_epoch().as_secs();
+
+    let ttl = match (r.grants.ttl, r.expires_at) {
+        (Some(_), Some(_)) => {
+            return problem::response(
+                StatusCode::BAD_REQUEST,
+                "Only one of 'ttl' and 'expires_at' can be given",
+            );
+        }
+        (Some(ttl), None) => ttl,
+        (None, Some(expires_at)) => expires_at.saturating_sub(now),
+        (None, None) => SERVICE_TOKEN_TTL_DEFAULT,
+    };
+
+    if ttl == 0 || ttl > SERVICE_TOKEN_TTL_MAX {
+        return problem::response(
+            StatusCode::BAD_REQUEST,
+            &format!("Expiry must be in the future and within {SERVICE_TOKEN_TTL_MAX} seconds"),
+        );
+    }
+
+    let store = match open_keys_store() {
+        Ok(store) => store,
+        Err(e) => return e.response(),
+    };
+
+    let (key_id, key) = match &r.key {
+        Some(id) => {
+            if !auth::is_signing_key_name(id) {
+                return problem::response(StatusCode::NOT_FOUND, "Key not found");
+            }
+
+            let (value, meta) = match read_key(&store, id, root.as_deref()) {
+                Ok(v) => v,
+                Err(e) => return e.response(),
+            };
+
+            if meta.alg.as_deref().is_some_and(|alg| alg != "HS256") {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    "Only secret keys can sign tokens",
+                );
+            }
+
+            (id.clone(), value)
+        }
+        None => match read_token_signing_key(config, &store) {
+            Ok(v) => v,
+            Err(e) => return e.response(),
+        },
+    };
+
+    let token = match sign_token(&key_id, &key, r.grants, ttl) {
+        Ok(token) => token,
+        Err(e) => return e.response(),
+    };
+
+    let Some(claims) = auth::token_claims(&token) else {
+        log_error!("failed to decode created token");
+
+        return problem::response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Token creation process failed",
+        );
+    };
+
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::TokenMint,
+        &key_id,
+        claims.clone(),
+    );
+
+    let result = ServiceTokenResult {
+        token,
+        key: key_id,
+        expires_at: now + ttl,
+        claims,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+pub fn post_selftest(
+    config: &Config,
+    auth: &Authorization,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    if let Err(e) = require_platform(auth, &req, AdminScope::Stats) {
+        return e.response();
+    }
+
+    let publish_result = if config.publish_token.is_empty() {
+        CheckResult {
+            ok: false,
+            latency_ms: None,
+            error: Some("Publish token not configured".to_string()),
+        }
+    } else {
+        let start = Instant::now();
+
+        // a successful response from the publish API means the message
+        // was accepted for delivery
+        match publish(
+            config,
+            SELFTEST_TOPIC,
+            None,
+            b"selftest",
+            &MessageMeta::default(),
+            None,
+            None,
+            deadline,
+        ) {
+            Ok(()) => CheckResult {
+                ok: true,
+                latency_ms: Some(start.elapsed().as_millis()),
+                error: None,
+            },
+            Err(e) => {
+                log_warn!("selftest publish failed: {e:?}");
+
+                CheckResult {
+                    ok: false,
+                    latency_ms: Some(start.elapsed().as_millis()),
+                    error: Some(e.to_string()),
+                }
+            }
+        }
+    };
+
+    let result = SelfTestResult {
+        ok: publish_result.ok,
+        publish: publish_result,
+    };
+
+    let status = if result.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::from_status(status)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// delivers scheduled messages that have come due. intended to be called
+// periodically by an external scheduler. tenant admins only deliver
+// messages for their own topics
+pub fn post_scheduled(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    let prefix = match get_admin(auth, &req, AdminScope::Retained) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let topics = match storage.list_scheduled_topics() {
+        Ok(v) => v
+            .into_iter()
+            .filter(|t| match &prefix {
+                Some(prefix) => topic::is_within(t, prefix),
+                None => true,
+            })
+            .collect(),
+        Err(StorageError::StoreNotFound) => Vec::new(),
+        Err(e) => {
+            log_error!("failed to list scheduled topics: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list scheduled messages",
+            );
+        }
+    };
+
+    let mut result = ScheduledResult {
+        topics: topics.len(),
+        delivered: 0,
+        incomplete: false,
+    };
+
+    for topic in &topics {
+        if deadline.expired() {
+            result.incomplete = true;
+            break;
+        }
+
+        match events::deliver_scheduled(config, storage, topic, deadline) {
+            Ok(n) => result.delivered += n,
+            Err(e) => log_error!("failed to deliver scheduled messages to topic {topic}: {e:?}"),
+        }
+    }
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// lists topics that have retained slots, a page at a time. tenant admins
+// can only list topics within their own prefix
+pub fn get_retained(auth: &Authorization, storage: &dyn Storage, req: Request) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Retained) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let prefix = req.get_query_parameter("prefix").unwrap_or_default();
+
+    let prefix = match &root {
+        Some(root) if prefix.is_empty() => root.as_str(),
+        Some(root) if !prefix.starts_with(root.as_str()) => {
+            return problem::response(
+                StatusCode::FORBIDDEN,
+                &format!("Prefix must be within: {root}"),
+            );
+        }
+        _ => prefix,
+    };
+
+    let limit = match req.get_query_parameter("limit") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(x) if x > 0 && x <= RETAINED_LIST_LIMIT_MAX => x,
+            _ => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("'limit' param must be between 1 and {RETAINED_LIST_LIMIT_MAX}"),
+                )
+            }
+        },
+        None => RETAINED_LIST_LIMIT_DEFAULT,
+    };
+
+    let cursor = req.get_query_parameter("cursor");
+
+    let list = match storage.list_retained(prefix, cursor, limit) {
+        Ok(v) => v,
+        Err(StorageError::StoreNotFound) => {
+            return Problem::new(StatusCode::NOT_FOUND, "Storage not configured")
+                .with_code("storage-not-configured")
+                .response();
+        }
+        Err(e) => {
+            log_error!("failed to list retained topics: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list retained topics",
+            );
+        }
+    };
+
+    // a string prefix can match topics outside the tenant's namespace
+    let topics: Vec<String> = list
+        .topics
+        .into_iter()
+        .filter(|t| match &root {
+            Some(root) => topic::is_within(t, root),
+            None => true,
+        })
+        .collect();
+
+    let details = if req.get_query_parameter("details") == Some("true") {
+        let reads: Vec<(&str, Option<RetainedVersion>)> =
+            topics.iter().map(|t| (t.as_str(), None)).collect();
+
+        let slots = match storage.read_retained_many(&reads) {
+            Ok(v) => v,
+            Err(e) => {
+                log_error!("failed to read retained topics: {e:?}");
+
+                return problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to read retained topics",
+                );
+            }
+        };
+
+        // slots removed since listing are skipped
+        let details = topics
+            .iter()
+            .zip(slots)
+            .filter_map(|(topic, slot)| {
+                let slot = slot?;
+
+                Some(RetainedTopicInfo {
+                    topic: topic.clone(),
+                    id: events::version_id(&slot.version),
+                    size: slot.message.as_ref().map(|m| m.data.len()),
+                    ttl: slot
+                        .message
+                        .as_ref()
+                        .and_then(|m| m.ttl)
+                        .map(|ttl| ttl.as_secs()),
+                    expires_at: slot.message.as_ref().and_then(|m| m.meta.expires_at),
+                    deleted: slot.deleted,
+                    depth: slot.depth,
+                })
+            })
+            .collect();
+
+        Some(details)
+    } else {
+        None
+    };
+
+    let result = RetainedListResult {
+        topics,
+        details,
+        cursor: list.cursor,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// returns the topic of a path of the form /admin/retained/{topic}
+pub fn parse_retained_path(path: &str) -> Option<String> {
+    let topic = path.strip_prefix("/admin/retained/")?;
+
+    if topic.is_empty() {
+        return None;
+    }
+
+    publickeys::percent_decode(topic)
+}
+
+// removes a topic's retained message and any history. with the tombstone
+// param, the message is first deleted as usual, so that durable subscribers
+// learn of it before the topic starts over
+pub fn delete_retained(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    topic: &str,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Retained) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    if let Some(root) = &root {
+        if !topic::is_within(topic, root) {
+            return problem::response(
+                StatusCode::FORBIDDEN,
+                &format!("Topic must be within: {root}"),
+            );
+        }
+    }
+
+    let tombstone = req.get_query_parameter("tombstone") == Some("true");
+
+    if tombstone {
+        let v = match storage.delete_retained(topic, config.retained_settings(topic), deadline) {
+            Ok(Some(v)) => v,
+            Ok(None) | Err(StorageError::StoreNotFound) => {
+                return problem::response(StatusCode::NOT_FOUND, "No retained message for topic");
+            }
+            Err(e) => {
+                log_error!("failed to delete message from storage: {e:?}");
+
+                return problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to delete message from storage",
+                );
+            }
+        };
+
+        if let Err(e) = events::publish_tombstone(config, topic, None, &v, deadline) {
+            log_error!("failed to publish: {e:?}");
+
+            return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Publish process failed");
+        }
+    }
+
+    match storage.purge_retained(topic) {
+        Ok(true) => {
+            audit::record(
+                config,
+                auth,
+                storage,
+                &req,
+                audit::Action::RetainedPurge,
+                topic,
+                serde_json::json!({"tombstone": tombstone}),
+            );
+
+            Response::from_status(StatusCode::NO_CONTENT)
+        }
+        Ok(false) | Err(StorageError::StoreNotFound) => {
+            problem::response(StatusCode::NOT_FOUND, "No retained message for topic")
+        }
+        Err(e) => {
+            log_error!("failed to purge retained topic {topic}: {e:?}");
+
+            problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to purge retained topic",
+            )
+        }
+    }
+}
+
+// reports activity counts for the app as a whole, per minute, including
+// the current minute so far
+pub fn get_stats(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    req: Request,
+) -> Response {
+    if let Err(e) = require_platform(auth, &req, AdminScope::Stats) {
+        return e.response();
+    }
+
+    if !config.stats_enabled {
+        return problem::response(StatusCode::NOT_FOUND, "Stats not enabled");
+    }
+
+    let count = match req.get_query_parameter("minutes") {
+        Some(x) => match x.parse::<u64>() {
+            Ok(x) if x > 0 && x <= STATS_MINUTES_MAX => x,
+            _ => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("'minutes' param must be between 1 and {STATS_MINUTES_MAX}"),
+                )
+            }
+        },
+        None => STATS_MINUTES_DEFAULT,
+    };
+
+    let current = unix_now() / 60;
+    let minutes: Vec<u64> = ((current + 1 - count)..=current).collect();
+
+    let stats = match storage.read_stats(&minutes) {
+        Ok(v) => v,
+        Err(StorageError::StoreNotFound) => {
+            return Problem::new(StatusCode::NOT_FOUND, "Storage not configured")
+                .with_code("storage-not-configured")
+                .response();
+        }
+        Err(e) => {
+            log_error!("failed to read stats: {e:?}");
+
+            return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read stats");
+        }
+    };
+
+    let mut result = StatsResult {
+        totals: Counts::default(),
+        minutes: Vec::new(),
+    };
+
+    for (minute, counts) in minutes.into_iter().zip(stats) {
+        result.totals.add(&counts);
+
+        result.minutes.push(StatsMinute {
+            time: minute * 60,
+            counts,
+        });
+    }
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// reports the effective configuration, with secrets redacted
+pub fn get_config(config: &Config, auth: &Authorization, req: Request) -> Response {
+    if let Err(e) = require_platform(auth, &req, AdminScope::Stats) {
+        return e.response();
+    }
+
+    let result = ConfigResult {
+        config,
+        sources: &config.sources,
+        warnings: config.warnings(),
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// publishes the request body to every topic that has a retained slot and
+// begins with the prefix param. the message isn't retained. tenant admins
+// can only broadcast to their own topics
+pub fn post_broadcast(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    mut req: Request,
+) -> Response {
+    let root = match get_admin(auth, &req, AdminScope::Retained) {
+        Ok(Admin::Platform) => None,
+        Ok(Admin::Tenant(prefix)) => Some(prefix),
+        Err(e) => return e.response(),
+    };
+
+    let prefix = req.get_query_parameter("prefix").unwrap_or_default();
+
+    let prefix = match &root {
+        Some(root) if prefix.is_empty() => root.clone(),
+        Some(root) if !prefix.starts_with(root.as_str()) => {
+            return problem::response(
+                StatusCode::FORBIDDEN,
+                &format!("Prefix must be within: {root}"),
+            );
+        }
+        _ => prefix.to_string(),
+    };
+
+    let mut cursor = req.get_query_parameter("cursor").map(|s| s.to_string());
+
+    let mut meta = match MessageMeta::from_request(&req) {
+        Ok(m) => m,
+        Err(e) => return problem::response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    meta.start_span(&prefix);
+
+    let message = match read_body(&mut req, MESSAGE_SIZE_MAX) {
+        Ok(message) => message,
+        Err(BodyError::TooLarge) => {
+            return Problem::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!("Message size exceeds {MESSAGE_SIZE_MAX} bytes maximum"),
+            )
+            .with_code("message-too-large")
+            .response()
+        }
+        Err(e) => return problem::body_error(&e, MESSAGE_SIZE_MAX),
+    };
+
+    let mut result = BroadcastResult {
+        topics: 0,
+        published: 0,
+        failed: 0,
+        cursor: None,
+    };
+
+    loop {
+        let list = match storage.list_retained(&prefix, cursor.as_deref(), BROADCAST_PAGE_SIZE) {
+            Ok(v) => v,
+            Err(StorageError::StoreNotFound) => {
+                return Problem::new(StatusCode::NOT_FOUND, "Storage not configured")
+                    .with_code("storage-not-configured")
+                    .response();
+            }
+            Err(e) => {
+                log_error!("failed to list retained topics: {e:?}");
+
+                return problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to list retained topics",
+                );
+            }
+        };
+
+        // a string prefix can match topics outside the tenant's namespace
+        let topics = list.topics.iter().filter(|t| match &root {
+            Some(root) => topic::is_within(t, root),
+            None => true,
+        });
+
+        // each page is published in one batch
+        let mut batch = Batch::default();
+
+        for t in topics {
+            result.topics += 1;
+
+            if let Err(e) = batch.add(config, t, None, &message, &meta, None, None) {
+                log_error!("failed to broadcast to topic {t}: {e:?}");
+
+                result.failed += 1;
+            }
+        }
+
+        let count = batch.len();
+
+        match batch.send(config, deadline) {
+            Ok(()) => result.published += count,
+            Err(e) => {
+                log_error!("failed to broadcast to {count} topics: {e:?}");
+
+                result.failed += count;
+            }
+        }
+
+        cursor = list.cursor;
+
+        if cursor.is_none() {
+            break;
+        }
+
+        if deadline.expired() {
+            result.cursor = cursor;
+            break;
+        }
+    }
+
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::Broadcast,
+        &prefix,
+        serde_json::json!({"topics": result.topics, "size": message.len()}),
+    );
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+pub fn parse_connection_close_path(path: &str) -> Option<String> {
+    let cid = path
+        .strip_prefix("/admin/connections/")?
+        .strip_suffix("/close")?;
+
+    if cid.is_empty() || cid.contains('/') {
+        return None;
+    }
+
+    publickeys::percent_decode(cid)
+}
+
+// closes an SSE dynamic stream or MQTT connection, given its connection ID.
+// a stream's topics are removed so that it can't be resumed
+pub fn post_connection_close(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    cid: &str,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    if let Err(e) = require_platform(auth, &req, AdminScope::Connections) {
+        return e.response();
+    }
+
+    if config.publish_token.is_empty() {
+        return problem::response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Publish token not configured",
+        );
+    }
+
+    match storage.delete_stream_topics(cid) {
+        Ok(()) | Err(StorageError::StoreNotFound) => {}
+        Err(e) => {
+            log_error!("failed to delete stream topics from storage: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete stream topics from storage",
+            );
+        }
+    }
+
+    let channel = format!("{CONNECTION_CHANNEL_PREFIX}{cid}");
+
+    if let Err(e) = publish_close(config, &channel, deadline) {
+        log_error!("failed to publish close: {e:?}");
+
+        return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Publish process failed");
+    }
+
+    audit::record(
+        config,
+        auth,
+        storage,
+        &req,
+        audit::Action::ConnectionClose,
+        cid,
+        serde_json::json!({}),
+    );
+
+    Response::from_status(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,186 @@
+use crate::auth::{self, Authorization};
+use crate::config::Config;
+use crate::events;
+use crate::log_error;
+use crate::storage::Storage;
+use fastly::log::Endpoint;
+use fastly::Request;
+use jwt_simple::prelude::*;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::io::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum Action {
+    #[serde(rename = "key.create")]
+    KeyCreate,
+
+    #[serde(rename = "key.delete")]
+    KeyDelete,
+
+    #[serde(rename = "key.rotate")]
+    KeyRotate,
+
+    #[serde(rename = "retained.purge")]
+    RetainedPurge,
+
+    #[serde(rename = "token.mint")]
+    TokenMint,
+
+    #[serde(rename = "topic.broadcast")]
+    Broadcast,
+
+    #[serde(rename = "connection.close")]
+    ConnectionClose,
+}
+
+// the credential an admin request was made with. tokens are identified by
+// a fingerprint rather than included
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Actor {
+    FastlyKey,
+    Token {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sub: Option<String>,
+
+        fingerprint: String,
+    },
+}
+
+impl Actor {
+    // the token is assumed to have been validated already
+    pub fn from_request(auth: &Authorization, req: &Request) -> Option<Self> {
+        if auth.fastly {
+            return Some(Self::FastlyKey);
+        }
+
+        let token = events::get_token(req, false).ok()??;
+
+        Some(Self::from_token(token))
+    }
+
+    fn from_token(token: &str) -> Self {
+        let key = Token::decode_metadata(token)
+            .ok()
+            .and_then(|m| m.key_id().map(|s| s.to_string()));
+
+        let sub = auth::token_claims(token)
+            .and_then(|c| c.get("sub").and_then(|v| v.as_str()).map(|s| s.to_string()));
+
+        let fingerprint = hex::encode(&Sha1::digest(token.as_bytes())[..8]);
+
+        Self::Token {
+            key,
+            sub,
+            fingerprint,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry<'a> {
+    // unix milliseconds
+    pub time: u64,
+
+    pub action: Action,
+
+    // what was acted on, such as a key ID or topic
+    pub target: &'a str,
+
+    pub actor: Option<Actor>,
+
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub details: serde_json::Value,
+}
+
+// records an admin mutation to the configured log endpoint and/or storage.
+// failures are logged, since the mutation has already happened
+pub fn record(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    req: &Request,
+    action: Action,
+    target: &str,
+    details: serde_json::Value,
+) {
+    if config.audit_log_endpoint.is_none() && !config.audit_kv {
+        return;
+    }
+
+    let entry = Entry {
+        time: Clock::now_since_epoch().as_millis(),
+        action,
+        target,
+        actor: Actor::from_request(auth, req),
+        details,
+    };
+
+    let data = serde_json::to_vec(&entry).expect("entry should always be serializable");
+
+    if let Some(name) = &config.audit_log_endpoint {
+        match Endpoint::try_from_name(name) {
+            Ok(mut endpoint) => {
+                if let Err(e) = endpoint.write_all(&data) {
+                    log_error!("failed to write audit entry to {name}: {e}");
+                }
+            }
+            Err(e) => log_error!("failed to open log endpoint {name}: {e}"),
+        }
+    }
+
+    if config.audit_kv {
+        if let Err(e) = storage.append_audit_entry(entry.time, data) {
+            log_error!("failed to write audit entry to storage: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry() {
+        let key = HS256Key::from_bytes(b"notasecret").with_key_id("k1");
+        let claims = Claims::create(Duration::from_secs(60)).with_subject("alice");
+        let token = key.authenticate(claims).unwrap();
+
+        let actor = Actor::from_token(&token);
+
+        let Actor::Token {
+            key,
+            sub,
+            fingerprint,
+        } = &actor
+        else {
+            panic!("expected a token actor");
+        };
+
+        assert_eq!(key.as_deref(), Some("k1"));
+        assert_eq!(sub.as_deref(), Some("alice"));
+        assert_eq!(fingerprint.len(), 16);
+        assert!(!token.contains(fingerprint.as_str()));
+
+        let entry = Entry {
+            time: 1000,
+            action: Action::KeyDelete,
+            target: "k2",
+            actor: Some(actor),
+            details: serde_json::Value::Null,
+        };
+
+        let v = serde_json::to_value(&entry).unwrap();
+        assert_eq!(v["action"], "key.delete");
+        assert_eq!(v["actor"]["type"], "token");
+        assert_eq!(v["actor"]["sub"], "alice");
+        assert!(v.get("details").is_none());
+
+        let v = serde_json::to_value(Actor::FastlyKey).unwrap();
+        assert_eq!(v, serde_json::json!({"type": "fastly-key"}));
+    }
+}
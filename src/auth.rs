@@ -1,8 +1,21 @@
+#[cfg(feature = "fastly")]
+use crate::cert;
+use crate::config::TokenValidation;
 use crate::grip;
+use crate::namespace;
+use crate::stats::{self, Counter};
+use crate::topic;
+use base64::Engine;
+#[cfg(feature = "fastly")]
 use fastly::kv_store;
+#[cfg(feature = "fastly")]
+use fastly::Request;
+#[cfg(feature = "fastly")]
+use fastly_shared::ClientCertVerifyResult;
 use jwt_simple::prelude::*;
-use std::borrow::Borrow;
+use sha1::{Digest, Sha1};
 use std::env;
+use std::ops::Not;
 
 const FASTLY_PUBLIC_KEY: &str = concat!(
     "-----BEGIN PUBLIC KEY-----\n",
@@ -33,43 +46,323 @@ impl GripAuthorizor for TestGripAuthorizor {
     }
 }
 
-fn slice_contains<T, Q>(s: &[T], value: &Q) -> bool
-where
-    T: Borrow<Q>,
-    Q: Eq + ?Sized,
-{
-    s.iter().any(|i| i.borrow() == value)
+fn any_matches(grants: &[String], topic: &str) -> bool {
+    grants.iter().any(|g| topic::matches(g, topic))
+}
+
+// grants may be patterns. if subtree is set, a grant on a topic also covers
+// its descendants
+fn topic_granted(grants: &[String], topic: &str, subtree: bool) -> bool {
+    if any_matches(grants, topic) {
+        return true;
+    }
+
+    subtree && topic::ancestors(topic).any(|a| any_matches(grants, a))
+}
+
+// placeholder in grants for the token's subject
+const SUBJECT_PLACEHOLDER: &str = "{sub}";
+
+// fills in the subject in templated grants. templated grants are dropped if
+// there is no subject or it can't be used as a single topic level
+fn resolve_grants(grants: Vec<String>, subject: Option<&str>) -> Vec<String> {
+    let subject =
+        subject.filter(|s| !s.is_empty() && !s.contains(topic::SEPARATOR) && *s != topic::WILDCARD);
+
+    grants
+        .into_iter()
+        .filter_map(|g| {
+            if !g.contains(SUBJECT_PLACEHOLDER) {
+                return Some(g);
+            }
+
+            subject.map(|s| g.replace(SUBJECT_PLACEHOLDER, s))
+        })
+        .collect()
+}
+
+// topics granted by the ACL, rather than by a token's claims
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclGrant {
+    topic: String,
+
+    #[serde(default, skip_serializing_if = "<&bool>::not")]
+    subtree: bool,
+}
+
+fn acl_granted(grants: &[AclGrant], topic: &str) -> bool {
+    grants
+        .iter()
+        .any(|g| topic_granted(std::slice::from_ref(&g.topic), topic, g.subtree))
+}
+
+// prefixes of the principals named in ACL rules
+const ACL_ROLE: &str = "role:";
+const ACL_KEY: &str = "key:";
+const ACL_SUBJECT: &str = "sub:";
+
+// name of the ACL entry in the keys store
+pub const ACL_ENTRY: &str = "acl";
+
+// whether an entry in the keys store is a signing key, rather than an API
+// key, certificate mapping, previous key value or the ACL
+pub fn is_signing_key_name(name: &str) -> bool {
+    name != ACL_ENTRY && !name.contains(':')
+}
+
+// grants access to a topic, or a topic and its descendants, to principals:
+// a role claimed by a token, the key a token is signed with, or a subject
+#[derive(Debug, Deserialize)]
+pub struct AclRule {
+    topic: String,
+
+    #[serde(default)]
+    subtree: bool,
+
+    #[serde(default)]
+    read: Vec<String>,
+
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+// the rules are kept centrally, so that access can be changed without
+// reissuing tokens
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+// admin operations that tokens can be limited to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminScope {
+    // creating keys and minting tokens
+    Keys,
+
+    // managing retained and scheduled messages
+    Retained,
+
+    // read-only diagnostics
+    Stats,
+
+    // closing client connections
+    Connections,
+}
+
+impl AdminScope {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "keys" => Some(Self::Keys),
+            "retained" => Some(Self::Retained),
+            "stats" => Some(Self::Stats),
+            "connections" => Some(Self::Connections),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Keys => "keys",
+            Self::Retained => "retained",
+            Self::Stats => "stats",
+            Self::Connections => "connections",
+        }
+    }
 }
 
 pub struct Capabilities {
     admin: bool,
+    subject: Option<String>,
+    subtree: bool,
     read: Vec<String>,
     write: Vec<String>,
+    expires_at: Option<UnixTimeStamp>,
+
+    // roles claimed by the token, to be matched against the ACL
+    roles: Vec<String>,
+    acl_read: Vec<AclGrant>,
+    acl_write: Vec<AclGrant>,
+
+    // publishes are counted per token or API key, under a hash of it
+    rate_key: Option<String>,
+    max_publish_per_min: Option<u32>,
+    max_subs: Option<u32>,
+
+    // tenant admins have full access to the topics in their namespace, and
+    // can administer it
+    admin_prefix: Option<String>,
+
+    // the admin operations allowed. none means all of them for tenant
+    // admins, and none of them otherwise
+    admin_scopes: Option<Vec<AdminScope>>,
+
+    // if set, the client names topics relative to it. grants are already
+    // resolved to full names
+    namespace: Option<String>,
 }
 
 impl Capabilities {
     pub fn new_admin() -> Self {
         Self {
             admin: true,
+            subject: None,
+            subtree: false,
             read: Vec::new(),
             write: Vec::new(),
+            expires_at: None,
+            roles: Vec::new(),
+            acl_read: Vec::new(),
+            acl_write: Vec::new(),
+            rate_key: None,
+            max_publish_per_min: None,
+            max_subs: None,
+            admin_prefix: None,
+            admin_scopes: None,
+            namespace: None,
         }
     }
 
-    pub fn can_subscribe(&self, topic: &str) -> bool {
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn admin_prefix(&self) -> Option<&str> {
+        self.admin_prefix.as_deref()
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    // the full name of a topic given by the client
+    pub fn resolve_topic(&self, topic: &str) -> String {
+        namespace::resolve(self.namespace(), topic)
+    }
+
+    // whether the token allows an admin operation, within its admin prefix
+    // if it has one, or across the whole app otherwise
+    pub fn has_admin_scope(&self, scope: AdminScope) -> bool {
+        match &self.admin_scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => self.admin_prefix.is_some(),
+        }
+    }
+
+    // the key to count publishes under, and how many are allowed per minute
+    pub fn publish_limit(&self) -> Option<(&str, u32)> {
+        Some((self.rate_key.as_deref()?, self.max_publish_per_min?))
+    }
+
+    // publishes are counted against quotas per token or API key, or else
+    // per subject, such as for client certificates
+    pub fn quota_key(&self) -> Option<String> {
         if self.admin {
+            return None;
+        }
+
+        match (&self.rate_key, &self.subject) {
+            (Some(key), _) => Some(key.clone()),
+            (None, Some(sub)) => Some(format!("sub:{sub}")),
+            (None, None) => None,
+        }
+    }
+
+    pub fn max_publish_per_min(&self) -> Option<u32> {
+        self.max_publish_per_min
+    }
+
+    pub fn max_subs(&self) -> Option<usize> {
+        self.max_subs.map(|x| x as usize)
+    }
+
+    fn is_tenant_admin_of(&self, topic: &str) -> bool {
+        match &self.admin_prefix {
+            Some(prefix) => topic::is_within(topic, prefix),
+            None => false,
+        }
+    }
+
+    pub fn can_subscribe(&self, topic: &str) -> bool {
+        if self.admin || self.is_tenant_admin_of(topic) {
             return true;
         }
 
-        slice_contains(&self.read, topic)
+        topic_granted(&self.read, topic, self.subtree) || acl_granted(&self.acl_read, topic)
     }
 
     pub fn can_publish(&self, topic: &str) -> bool {
-        if self.admin {
+        if self.admin || self.is_tenant_admin_of(topic) {
             return true;
         }
 
-        slice_contains(&self.write, topic)
+        topic_granted(&self.write, topic, self.subtree) || acl_granted(&self.acl_write, topic)
+    }
+
+    fn is_principal(&self, principal: &str, key_id: Option<&str>) -> bool {
+        if let Some(role) = principal.strip_prefix(ACL_ROLE) {
+            self.roles.iter().any(|r| r == role)
+        } else if let Some(id) = principal.strip_prefix(ACL_KEY) {
+            key_id == Some(id)
+        } else if let Some(sub) = principal.strip_prefix(ACL_SUBJECT) {
+            self.subject.as_deref() == Some(sub)
+        } else {
+            false
+        }
+    }
+
+    // adds the topics the ACL grants to any of the token's principals
+    pub fn apply_acl(&mut self, acl: &Acl, key_id: Option<&str>) {
+        for rule in &acl.rules {
+            let grant = AclGrant {
+                topic: rule.topic.clone(),
+                subtree: rule.subtree,
+            };
+
+            if rule.read.iter().any(|p| self.is_principal(p, key_id)) {
+                self.acl_read.push(grant.clone());
+            }
+
+            if rule.write.iter().any(|p| self.is_principal(p, key_id)) {
+                self.acl_write.push(grant);
+            }
+        }
+    }
+
+    // resolves the grants, which are relative to the namespace, and drops
+    // anything outside of it
+    fn enter_namespace(&mut self, namespace: &str) {
+        let resolve = |t: &String| namespace::resolve(Some(namespace), t);
+
+        self.read = self.read.iter().map(resolve).collect();
+        self.write = self.write.iter().map(resolve).collect();
+        self.admin_prefix = self.admin_prefix.as_ref().map(resolve);
+        self.namespace = Some(namespace.to_string());
+
+        self.restrict_to(namespace);
+    }
+
+    // drops anything outside of a namespace
+    fn restrict_to(&mut self, namespace: &str) {
+        self.admin = false;
+        self.read.retain(|t| topic::is_within(t, namespace));
+        self.write.retain(|t| topic::is_within(t, namespace));
+        self.acl_read
+            .retain(|g| topic::is_within(&g.topic, namespace));
+        self.acl_write
+            .retain(|g| topic::is_within(&g.topic, namespace));
+
+        // the claimed namespace must be within the key's
+        if let Some(prefix) = &self.admin_prefix {
+            if !topic::is_within(prefix, namespace) {
+                self.admin_prefix = None;
+            }
+        }
+
+        // scopes apply app-wide without a namespace
+        if self.admin_prefix.is_none() {
+            self.admin_scopes = None;
+        }
     }
 }
 
@@ -77,6 +370,7 @@ impl Capabilities {
 pub enum TokenError {
     Invalid,
     NoKeyId,
+    InvalidKey,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -87,25 +381,445 @@ struct CustomClaims {
 
     #[serde(default)]
     x_fastly_write: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "<&bool>::not")]
+    x_fastly_subtree: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x_fastly_admin_prefix: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    x_fastly_roles: Vec<String>,
+
+    // limits on the token's use, beyond which requests are rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x_fastly_max_publish_per_min: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x_fastly_max_subs: Option<u32>,
+
+    // unknown scopes are ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x_fastly_admin_scopes: Option<Vec<String>>,
+
+    // grants are relative to the namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x_fastly_namespace: Option<String>,
+}
+
+// stored alongside signing keys
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyMetadata {
+    // keys minted by tenant admins can only sign tokens for their namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+
+    // the algorithm tokens are signed with, if not HS256. for asymmetric
+    // algorithms, the key is a public key in PEM form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+
+    // unix timestamp in seconds, for keys created by the admin API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+
+    // operator-provided description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    // unix timestamp in seconds of the last rotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotated_at: Option<u64>,
+
+    // until when the value replaced by the last rotation remains valid. the
+    // value is kept under previous_key_name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_expires_at: Option<u64>,
+}
+
+impl KeyMetadata {
+    // whether the value replaced by the last rotation is still accepted
+    pub fn previous_valid(&self) -> bool {
+        self.previous_expires_at
+            .is_some_and(|at| at > Clock::now_since_epoch().as_secs())
+    }
+}
+
+// where a rotated key's previous value is kept during the grace period
+pub fn previous_key_name(key_id: &str) -> String {
+    format!("prev:{key_id}")
+}
+
+// a key that tokens can be verified with
+pub enum VerifyingKey {
+    Hs256(HS256Key),
+    Rs256(RS256PublicKey),
+    Es256(ES256PublicKey),
+    EdDsa(Ed25519PublicKey),
+}
+
+impl VerifyingKey {
+    // keys of asymmetric algorithms are stored as public keys in PEM form
+    pub fn from_stored(key: &[u8], alg: &str) -> Result<Self, TokenError> {
+        if alg == "HS256" {
+            return Ok(Self::Hs256(HS256Key::from_bytes(key)));
+        }
+
+        let Ok(pem) = std::str::from_utf8(key) else {
+            return Err(TokenError::InvalidKey);
+        };
+
+        let key = match alg {
+            "RS256" => RS256PublicKey::from_pem(pem).map(Self::Rs256),
+            "ES256" => ES256PublicKey::from_pem(pem).map(Self::Es256),
+            "EdDSA" => Ed25519PublicKey::from_pem(pem).map(Self::EdDsa),
+            _ => return Err(TokenError::InvalidKey),
+        };
+
+        key.map_err(|_| TokenError::InvalidKey)
+    }
+
+    // the token's alg must match the key's
+    fn verify(
+        &self,
+        token: &str,
+        options: VerificationOptions,
+    ) -> Result<JWTClaims<CustomClaims>, TokenError> {
+        let options = Some(options);
+
+        let result = match self {
+            Self::Hs256(key) => key.verify_token(token, options),
+            Self::Rs256(key) => key.verify_token(token, options),
+            Self::Es256(key) => key.verify_token(token, options),
+            Self::EdDsa(key) => key.verify_token(token, options),
+        };
+
+        result.map_err(|_| TokenError::Invalid)
+    }
+}
+
+fn verification_options(v: &TokenValidation) -> VerificationOptions {
+    let mut options = VerificationOptions {
+        allowed_issuers: v.issuers.as_ref().map(|l| l.iter().cloned().collect()),
+        allowed_audiences: v.audiences.as_ref().map(|l| l.iter().cloned().collect()),
+        max_validity: v.max_age.map(|x| Duration::from_secs(x.into())),
+        ..Default::default()
+    };
+
+    if let Some(x) = v.clock_skew {
+        options.time_tolerance = Some(Duration::from_secs(x.into()));
+    }
+
+    options
+}
+
+fn rate_key(token: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(token.as_bytes());
+
+    hex::encode(hasher.finalize())
+}
+
+pub fn validate_token(
+    token: &str,
+    key: &VerifyingKey,
+    validation: &TokenValidation,
+) -> Result<Capabilities, TokenError> {
+    let claims = key.verify(token, verification_options(validation))?;
+
+    // the age of a token is only known from its issue time
+    if validation.max_age.is_some() && claims.issued_at.is_none() {
+        return Err(TokenError::Invalid);
+    }
+
+    if validation.require_expiration && claims.expires_at.is_none() {
+        return Err(TokenError::Invalid);
+    }
+
+    let subject = claims.subject.as_deref();
+
+    let mut caps = Capabilities {
+        admin: false,
+        subtree: claims.custom.x_fastly_subtree,
+        read: resolve_grants(claims.custom.x_fastly_read, subject),
+        write: resolve_grants(claims.custom.x_fastly_write, subject),
+        subject: claims.subject,
+        expires_at: claims.expires_at,
+        roles: claims.custom.x_fastly_roles,
+        acl_read: Vec::new(),
+        acl_write: Vec::new(),
+        rate_key: Some(rate_key(token)),
+        max_publish_per_min: claims.custom.x_fastly_max_publish_per_min,
+        max_subs: claims.custom.x_fastly_max_subs,
+        admin_prefix: claims
+            .custom
+            .x_fastly_admin_prefix
+            .filter(|p| !p.is_empty()),
+        admin_scopes: claims
+            .custom
+            .x_fastly_admin_scopes
+            .map(|l| l.iter().filter_map(|s| AdminScope::parse(s)).collect()),
+        namespace: None,
+    };
+
+    if let Some(ns) = &claims.custom.x_fastly_namespace {
+        if !namespace::is_valid(ns) {
+            return Err(TokenError::Invalid);
+        }
+
+        caps.enter_namespace(ns);
+    }
+
+    Ok(caps)
+}
+
+// what a token created by the app grants
+pub struct TokenGrants {
+    pub subject: Option<String>,
+    pub read: Vec<String>,
+    pub write: Vec<String>,
+    pub subtree: bool,
+}
+
+// signs a client token with a key from the keys store, so that it can be
+// validated like any other
+pub fn create_token(
+    key_id: &str,
+    key: &[u8],
+    grants: &TokenGrants,
+    ttl: Duration,
+) -> Result<String, TokenError> {
+    let custom = CustomClaims {
+        x_fastly_read: grants.read.clone(),
+        x_fastly_write: grants.write.clone(),
+        x_fastly_subtree: grants.subtree,
+        x_fastly_admin_prefix: None,
+        x_fastly_roles: Vec::new(),
+        x_fastly_max_publish_per_min: None,
+        x_fastly_max_subs: None,
+        x_fastly_admin_scopes: None,
+        x_fastly_namespace: None,
+    };
+
+    let mut claims = Claims::with_custom_claims(custom, ttl);
+
+    if let Some(subject) = &grants.subject {
+        claims = claims.with_subject(subject);
+    }
+
+    match HS256Key::from_bytes(key)
+        .with_key_id(key_id)
+        .authenticate(claims)
+    {
+        Ok(s) => Ok(s),
+        Err(_) => Err(TokenError::Invalid),
+    }
+}
+
+// the claims of a token, without validating it
+pub fn token_claims(token: &str) -> Option<serde_json::Value> {
+    let payload = token.split('.').nth(1)?;
+
+    let data = base64::prelude::BASE64_URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()?;
+
+    serde_json::from_slice(&data).ok()
+}
+
+// a subscribe URL signed with a key from the keys store, for clients that
+// can't send headers, such as EventSource
+pub struct SignedUrl<'a> {
+    pub key_id: &'a str,
+    pub expires: u64,
+    pub topics: Vec<&'a str>,
+    pub sig: &'a str,
+}
+
+// the expiration and topics, one per line, in the order given
+fn signed_url_message(expires: u64, topics: &[&str]) -> String {
+    let mut out = expires.to_string();
+
+    for t in topics {
+        out.push('\n');
+        out.push_str(t);
+    }
+
+    out
+}
+
+// base64url of the HMAC-SHA256 of the message
+pub fn sign_url(key: &[u8], expires: u64, topics: &[&str]) -> String {
+    let mac = hmac_sha256::HMAC::mac(signed_url_message(expires, topics), key);
+
+    base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(mac)
+}
+
+// grants subscribing to the signed topics until the expiration
+pub fn validate_signed_url(key: &[u8], url: &SignedUrl) -> Result<Capabilities, TokenError> {
+    let expected = sign_url(key, url.expires, &url.topics);
+
+    // constant time
+    let equal = expected.len() == url.sig.len()
+        && expected
+            .bytes()
+            .zip(url.sig.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+
+    if !equal {
+        return Err(TokenError::Invalid);
+    }
+
+    let expires_at = UnixTimeStamp::from_secs(url.expires);
+
+    if expires_at <= Clock::now_since_epoch() {
+        return Err(TokenError::Invalid);
+    }
+
+    Ok(Capabilities {
+        admin: false,
+        subject: None,
+        subtree: false,
+        read: url.topics.iter().map(|t| t.to_string()).collect(),
+        write: Vec::new(),
+        expires_at: Some(expires_at),
+        roles: Vec::new(),
+        acl_read: Vec::new(),
+        acl_write: Vec::new(),
+        rate_key: None,
+        max_publish_per_min: None,
+        max_subs: None,
+        admin_prefix: None,
+        admin_scopes: None,
+        namespace: None,
+    })
+}
+
+// tickets without an expiration are limited to this
+const TICKET_TTL_MAX: u64 = 60 * 60 * 24;
+
+// tickets carry the subscribe capabilities of a stream across next link
+// requests, so that they don't need to be granted blanket access
+#[derive(Serialize, Deserialize)]
+struct TicketClaims {
+    #[serde(default, skip_serializing_if = "<&bool>::not")]
+    admin: bool,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    read: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "<&bool>::not")]
+    subtree: bool,
+
+    #[serde(default, skip_serializing_if = "<&bool>::not")]
+    durable: bool,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    acl_read: Vec<AclGrant>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_subs: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    admin_prefix: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cursor_key: Option<String>,
+}
+
+pub struct Ticket {
+    pub caps: Capabilities,
+    pub durable: bool,
+
+    // the storage key under which a named subscription's cursors are
+    // recorded. it is carried here rather than in the link, so that
+    // clients can't choose it
+    pub cursor_key: Option<String>,
 }
 
-fn validate_token(token: &str, key: &[u8]) -> Result<Capabilities, TokenError> {
+// the ticket expires when the capabilities do
+pub fn issue_ticket(
+    key: &[u8],
+    caps: &Capabilities,
+    durable: bool,
+    cursor_key: Option<&str>,
+) -> Result<String, TokenError> {
+    let ttl_max = Duration::from_secs(TICKET_TTL_MAX);
+
+    let ttl = match caps.expires_at {
+        Some(at) => {
+            let now = Clock::now_since_epoch();
+
+            if at <= now {
+                return Err(TokenError::Invalid);
+            }
+
+            (at - now).min(ttl_max)
+        }
+        None => ttl_max,
+    };
+
+    let custom = TicketClaims {
+        admin: caps.admin,
+        read: caps.read.clone(),
+        subtree: caps.subtree,
+        durable,
+        acl_read: caps.acl_read.clone(),
+        max_subs: caps.max_subs,
+        admin_prefix: caps.admin_prefix.clone(),
+        namespace: caps.namespace.clone(),
+        cursor_key: cursor_key.map(|s| s.to_string()),
+    };
+
+    let mut claims = Claims::with_custom_claims(custom, ttl);
+
+    if let Some(subject) = &caps.subject {
+        claims = claims.with_subject(subject);
+    }
+
+    match HS256Key::from_bytes(key).authenticate(claims) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(TokenError::Invalid),
+    }
+}
+
+pub fn validate_ticket(key: &[u8], ticket: &str) -> Result<Ticket, TokenError> {
     let key = HS256Key::from_bytes(key);
 
     let options = VerificationOptions::default();
 
-    let claims = match key.verify_token::<CustomClaims>(token, Some(options)) {
+    let claims = match key.verify_token::<TicketClaims>(ticket, Some(options)) {
         Ok(claims) => claims,
         Err(_) => return Err(TokenError::Invalid),
     };
 
     let caps = Capabilities {
-        admin: false,
-        read: claims.custom.x_fastly_read,
-        write: claims.custom.x_fastly_write,
+        admin: claims.custom.admin,
+        subject: claims.subject,
+        subtree: claims.custom.subtree,
+        read: claims.custom.read,
+        write: Vec::new(),
+        expires_at: claims.expires_at,
+        roles: Vec::new(),
+        acl_read: claims.custom.acl_read,
+        acl_write: Vec::new(),
+        rate_key: None,
+        max_publish_per_min: None,
+        max_subs: claims.custom.max_subs,
+        admin_prefix: claims.custom.admin_prefix,
+        admin_scopes: None,
+        namespace: claims.custom.namespace,
     };
 
-    Ok(caps)
+    Ok(Ticket {
+        caps,
+        durable: claims.custom.durable,
+        cursor_key: claims.custom.cursor_key,
+    })
 }
 
 #[derive(Debug)]
@@ -114,6 +828,7 @@ pub enum AuthorizationError {
     StoreNotFound,
     StoreError,
     KeyNotFound,
+    KeySetError(String),
 }
 
 impl From<TokenError> for AuthorizationError {
@@ -123,61 +838,414 @@ impl From<TokenError> for AuthorizationError {
 }
 
 pub trait AppTokenAuthorizor {
-    fn validate_token(&self, token: &str) -> Result<Capabilities, AuthorizationError>;
-}
+    fn validate_token(
+        &self,
+        token: &str,
+        validation: &TokenValidation,
+    ) -> Result<Capabilities, AuthorizationError>;
 
-pub struct KVStoreAppTokenAuthorizor {
-    store_name: String,
-}
+    // API keys are an alternative to tokens for trusted publishers. none
+    // are accepted by default
+    fn validate_api_key(&self, _key: &str) -> Result<Capabilities, AuthorizationError> {
+        Err(AuthorizationError::Token(TokenError::Invalid))
+    }
 
-impl KVStoreAppTokenAuthorizor {
-    pub fn new(store_name: &str) -> Self {
-        Self {
-            store_name: store_name.to_string(),
-        }
+    // maps the identities of a client certificate to capabilities. none are
+    // accepted by default
+    fn validate_client_cert(
+        &self,
+        _identities: &[String],
+    ) -> Result<Capabilities, AuthorizationError> {
+        Err(AuthorizationError::Token(TokenError::Invalid))
+    }
+
+    // signed URLs are accepted by default only if a key can be found for
+    // them
+    fn validate_signed_url(&self, _url: &SignedUrl) -> Result<Capabilities, AuthorizationError> {
+        Err(AuthorizationError::Token(TokenError::Invalid))
+    }
+
+    // rules granting access beyond what tokens claim. there are none by
+    // default
+    fn acl(&self) -> Result<Acl, AuthorizationError> {
+        Ok(Acl::default())
     }
 }
 
-impl AppTokenAuthorizor for KVStoreAppTokenAuthorizor {
-    fn validate_token(&self, token: &str) -> Result<Capabilities, AuthorizationError> {
-        let Ok(metadata) = Token::decode_metadata(token) else {
-            return Err(AuthorizationError::Token(TokenError::Invalid));
-        };
+// stored in the keys store for each API key, under api_key_name, and for
+// each client certificate identity, under client_cert_name
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CredentialRecord {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read: Vec<String>,
 
-        let Some(key_id) = metadata.key_id() else {
-            return Err(AuthorizationError::Token(TokenError::NoKeyId));
-        };
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write: Vec<String>,
 
-        let store = match kv_store::KVStore::open(&self.store_name) {
-            Ok(Some(store)) => store,
-            Ok(None) => return Err(AuthorizationError::StoreNotFound),
-            Err(_) => return Err(AuthorizationError::StoreError),
-        };
+    #[serde(default, skip_serializing_if = "<&bool>::not")]
+    pub subtree: bool,
 
-        let v = match store.lookup(key_id) {
-            Ok(mut lookup) => lookup.take_body_bytes(),
-            Err(kv_store::KVStoreError::ItemNotFound) => {
-                return Err(AuthorizationError::KeyNotFound)
-            }
-            Err(_) => return Err(AuthorizationError::StoreError),
-        };
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+}
 
-        Ok(validate_token(token, &v)?)
+impl From<CredentialRecord> for Capabilities {
+    fn from(r: CredentialRecord) -> Self {
+        Self {
+            admin: false,
+            subtree: r.subtree,
+            read: resolve_grants(r.read, r.sub.as_deref()),
+            write: resolve_grants(r.write, r.sub.as_deref()),
+            subject: r.sub,
+            expires_at: None,
+            roles: Vec::new(),
+            acl_read: Vec::new(),
+            acl_write: Vec::new(),
+            rate_key: None,
+            max_publish_per_min: None,
+            max_subs: None,
+            admin_prefix: None,
+            admin_scopes: None,
+            namespace: None,
+        }
     }
 }
 
-pub struct TestAppTokenAuthorizor;
+// API keys are stored hashed, so that the store doesn't hold them as-is
+pub fn api_key_name(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
 
-impl AppTokenAuthorizor for TestAppTokenAuthorizor {
-    fn validate_token(&self, token: &str) -> Result<Capabilities, AuthorizationError> {
-        Ok(validate_token(token, b"notasecret")?)
-    }
+    format!("api:{}", hex::encode(hasher.finalize()))
 }
 
-pub struct Authorization {
+pub fn client_cert_name(identity: &str) -> String {
+    format!("cert:{identity}")
+}
+
+// the identities of the client's TLS certificate, if it presented one that
+// was verified
+#[cfg(feature = "fastly")]
+pub fn client_cert_identities(req: &Request) -> Option<Vec<String>> {
+    if !matches!(
+        req.get_tls_client_cert_verify_result(),
+        Some(ClientCertVerifyResult::Ok)
+    ) {
+        return None;
+    }
+
+    let der = cert::from_pem(req.get_tls_raw_client_certificate()?)?;
+
+    cert::identities(&der).filter(|ids| !ids.is_empty())
+}
+
+#[cfg(feature = "fastly")]
+pub struct KVStoreAppTokenAuthorizor {
+    store_name: String,
+}
+
+#[cfg(feature = "fastly")]
+impl KVStoreAppTokenAuthorizor {
+    pub fn new(store_name: &str) -> Self {
+        Self {
+            store_name: store_name.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "fastly")]
+impl KVStoreAppTokenAuthorizor {
+    fn open(&self) -> Result<kv_store::KVStore, AuthorizationError> {
+        match kv_store::KVStore::open(&self.store_name) {
+            Ok(Some(store)) => Ok(store),
+            Ok(None) => Err(AuthorizationError::StoreNotFound),
+            Err(_) => Err(AuthorizationError::StoreError),
+        }
+    }
+
+    fn signing_key(
+        &self,
+        store: &kv_store::KVStore,
+        key_id: &str,
+    ) -> Result<(Vec<u8>, KeyMetadata), AuthorizationError> {
+        match store.lookup(key_id) {
+            Ok(mut lookup) => {
+                let key_meta: KeyMetadata = match lookup.metadata() {
+                    Some(data) => match serde_json::from_slice(&data) {
+                        Ok(v) => v,
+                        Err(_) => return Err(AuthorizationError::StoreError),
+                    },
+                    None => KeyMetadata::default(),
+                };
+
+                Ok((lookup.take_body_bytes(), key_meta))
+            }
+            Err(kv_store::KVStoreError::ItemNotFound) => Err(AuthorizationError::KeyNotFound),
+            Err(_) => Err(AuthorizationError::StoreError),
+        }
+    }
+
+    // the value a key had before it was rotated, if still valid
+    fn previous_key(
+        &self,
+        store: &kv_store::KVStore,
+        key_id: &str,
+        key_meta: &KeyMetadata,
+    ) -> Result<Option<Vec<u8>>, AuthorizationError> {
+        if !key_meta.previous_valid() {
+            return Ok(None);
+        }
+
+        match store.lookup(&previous_key_name(key_id)) {
+            Ok(mut lookup) => Ok(Some(lookup.take_body_bytes())),
+            Err(kv_store::KVStoreError::ItemNotFound) => Ok(None),
+            Err(_) => Err(AuthorizationError::StoreError),
+        }
+    }
+
+    fn credential(
+        &self,
+        store: &kv_store::KVStore,
+        name: &str,
+    ) -> Result<Option<CredentialRecord>, AuthorizationError> {
+        match store.lookup(name) {
+            Ok(mut lookup) => match serde_json::from_slice(&lookup.take_body_bytes()) {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(AuthorizationError::StoreError),
+            },
+            Err(kv_store::KVStoreError::ItemNotFound) => Ok(None),
+            Err(_) => Err(AuthorizationError::StoreError),
+        }
+    }
+}
+
+#[cfg(feature = "fastly")]
+impl AppTokenAuthorizor for KVStoreAppTokenAuthorizor {
+    fn validate_token(
+        &self,
+        token: &str,
+        validation: &TokenValidation,
+    ) -> Result<Capabilities, AuthorizationError> {
+        let Ok(metadata) = Token::decode_metadata(token) else {
+            return Err(AuthorizationError::Token(TokenError::Invalid));
+        };
+
+        let Some(key_id) = metadata.key_id() else {
+            return Err(AuthorizationError::Token(TokenError::NoKeyId));
+        };
+
+        let store = self.open()?;
+
+        let (v, key_meta) = self.signing_key(&store, key_id)?;
+
+        let alg = key_meta.alg.as_deref().unwrap_or("HS256");
+
+        let key = VerifyingKey::from_stored(&v, alg)?;
+
+        // rotated keys still accept tokens signed with their previous value
+        let mut caps = match validate_token(token, &key, validation) {
+            Err(TokenError::Invalid) if alg == "HS256" => {
+                match self.previous_key(&store, key_id, &key_meta)? {
+                    Some(prev) => {
+                        let key = VerifyingKey::Hs256(HS256Key::from_bytes(&prev));
+
+                        validate_token(token, &key, validation)?
+                    }
+                    None => return Err(AuthorizationError::Token(TokenError::Invalid)),
+                }
+            }
+            r => r?,
+        };
+
+        caps.apply_acl(&self.acl()?, Some(key_id));
+
+        if let Some(prefix) = &key_meta.prefix {
+            caps.restrict_to(prefix);
+        }
+
+        Ok(caps)
+    }
+
+    fn validate_api_key(&self, key: &str) -> Result<Capabilities, AuthorizationError> {
+        let store = self.open()?;
+
+        match self.credential(&store, &api_key_name(key))? {
+            Some(record) => Ok(record.into()),
+            None => Err(AuthorizationError::Token(TokenError::Invalid)),
+        }
+    }
+
+    // only keys used with HS256 can sign URLs
+    fn validate_signed_url(&self, url: &SignedUrl) -> Result<Capabilities, AuthorizationError> {
+        let store = self.open()?;
+
+        let (key, key_meta) = self.signing_key(&store, url.key_id)?;
+
+        if key_meta.alg.as_deref().is_some_and(|a| a != "HS256") {
+            return Err(AuthorizationError::Token(TokenError::InvalidKey));
+        }
+
+        let mut caps = match validate_signed_url(&key, url) {
+            Err(TokenError::Invalid) => match self.previous_key(&store, url.key_id, &key_meta)? {
+                Some(prev) => validate_signed_url(&prev, url)?,
+                None => return Err(AuthorizationError::Token(TokenError::Invalid)),
+            },
+            r => r?,
+        };
+
+        if let Some(prefix) = &key_meta.prefix {
+            caps.restrict_to(prefix);
+        }
+
+        Ok(caps)
+    }
+
+    // the first identity with an entry wins. its subject defaults to the
+    // identity
+    fn validate_client_cert(
+        &self,
+        identities: &[String],
+    ) -> Result<Capabilities, AuthorizationError> {
+        let store = self.open()?;
+
+        for id in identities {
+            if let Some(mut record) = self.credential(&store, &client_cert_name(id))? {
+                record.sub.get_or_insert_with(|| id.clone());
+
+                return Ok(record.into());
+            }
+        }
+
+        Err(AuthorizationError::Token(TokenError::Invalid))
+    }
+
+    // a missing entry means no rules
+    fn acl(&self) -> Result<Acl, AuthorizationError> {
+        let store = self.open()?;
+
+        match store.lookup(ACL_ENTRY) {
+            Ok(mut lookup) => match serde_json::from_slice(&lookup.take_body_bytes()) {
+                Ok(v) => Ok(v),
+                Err(_) => Err(AuthorizationError::StoreError),
+            },
+            Err(kv_store::KVStoreError::ItemNotFound) => Ok(Acl::default()),
+            Err(_) => Err(AuthorizationError::StoreError),
+        }
+    }
+}
+
+pub struct TestAppTokenAuthorizor;
+
+impl AppTokenAuthorizor for TestAppTokenAuthorizor {
+    fn validate_token(
+        &self,
+        token: &str,
+        validation: &TokenValidation,
+    ) -> Result<Capabilities, AuthorizationError> {
+        let key = VerifyingKey::Hs256(HS256Key::from_bytes(b"notasecret"));
+
+        Ok(validate_token(token, &key, validation)?)
+    }
+}
+
+pub struct Authorization {
     pub grip: Box<dyn GripAuthorizor>,
     pub fastly: bool,
     pub app_token: Box<dyn AppTokenAuthorizor>,
+
+    // requirements on app tokens beyond a valid signature
+    pub token_validation: TokenValidation,
+
+    // identities of the client's verified TLS certificate, if client
+    // certificates are accepted
+    pub client_cert: Option<Vec<String>>,
+
+    // the namespace of the host the request was made to, if any. see
+    // namespace::from_host
+    pub namespace: Option<String>,
+}
+
+// rejected credentials are counted, as opposed to failures to check them
+fn count_failure(
+    ret: Result<Capabilities, AuthorizationError>,
+) -> Result<Capabilities, AuthorizationError> {
+    if let Err(AuthorizationError::Token(_) | AuthorizationError::KeyNotFound) = &ret {
+        stats::incr(Counter::AuthFailures, 1);
+    }
+
+    ret
+}
+
+impl Authorization {
+    pub fn validate_token(&self, token: &str) -> Result<Capabilities, AuthorizationError> {
+        count_failure(
+            self.apply_namespace(self.app_token.validate_token(token, &self.token_validation)),
+        )
+    }
+
+    pub fn validate_api_key(&self, key: &str) -> Result<Capabilities, AuthorizationError> {
+        let ret = self.app_token.validate_api_key(key).map(|mut caps| {
+            caps.rate_key = Some(api_key_name(key));
+            caps
+        });
+
+        count_failure(self.apply_namespace(ret))
+    }
+
+    pub fn validate_signed_url(&self, url: &SignedUrl) -> Result<Capabilities, AuthorizationError> {
+        count_failure(self.apply_namespace(self.app_token.validate_signed_url(url)))
+    }
+
+    // none if there is no usable certificate
+    pub fn validate_client_cert(&self) -> Option<Result<Capabilities, AuthorizationError>> {
+        let ids = self.client_cert.as_ref()?;
+
+        Some(count_failure(
+            self.apply_namespace(self.app_token.validate_client_cert(ids)),
+        ))
+    }
+
+    // what clients without credentials can do: subscribe to the public
+    // topics and the topics beneath them. the topics are full names, so
+    // in a namespace, only those within it are kept
+    pub fn anonymous(&self, public_topics: &[String]) -> Capabilities {
+        let mut caps: Capabilities = CredentialRecord {
+            read: public_topics.to_vec(),
+            subtree: true,
+            ..Default::default()
+        }
+        .into();
+
+        if let Some(ns) = &self.namespace {
+            caps.namespace = Some(ns.clone());
+            caps.restrict_to(ns);
+        }
+
+        caps
+    }
+
+    // credentials without a namespace of their own are placed in the
+    // host's. credentials for another namespace are rejected
+    fn apply_namespace(
+        &self,
+        ret: Result<Capabilities, AuthorizationError>,
+    ) -> Result<Capabilities, AuthorizationError> {
+        let mut caps = ret?;
+
+        let Some(ns) = &self.namespace else {
+            return Ok(caps);
+        };
+
+        match caps.namespace() {
+            Some(claimed) if claimed != ns => Err(AuthorizationError::Token(TokenError::Invalid)),
+            Some(_) => Ok(caps),
+            None => {
+                caps.enter_namespace(ns);
+
+                Ok(caps)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -190,6 +1258,13 @@ mod tests {
             CustomClaims {
                 x_fastly_read: vec!["readable".to_string()],
                 x_fastly_write: vec!["writable".to_string()],
+                x_fastly_subtree: false,
+                x_fastly_admin_prefix: None,
+                x_fastly_roles: vec![],
+                x_fastly_max_publish_per_min: None,
+                x_fastly_max_subs: None,
+                x_fastly_admin_scopes: None,
+                x_fastly_namespace: None,
             },
             Duration::from_secs(60),
         );
@@ -197,13 +1272,616 @@ mod tests {
         let key = HS256Key::from_bytes(b"notasecret");
         let token = key.authenticate(claims).unwrap();
 
-        let caps = TestAppTokenAuthorizor.validate_token(&token).unwrap();
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
         assert!(caps.can_subscribe("readable"));
         assert!(!caps.can_subscribe("foo"));
         assert!(caps.can_publish("writable"));
         assert!(!caps.can_subscribe("foo"));
     }
 
+    #[test]
+    fn token_limits() {
+        let claims: JWTClaims<CustomClaims> =
+            serde_json::from_str(r#"{"x-fastly-max-publish-per-min":30,"x-fastly-max-subs":5}"#)
+                .unwrap();
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims).unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert_eq!(caps.publish_limit(), Some((rate_key(&token).as_str(), 30)));
+        assert_eq!(caps.max_subs(), Some(5));
+
+        // tickets carry the subscription limit
+        let ticket = issue_ticket(b"ticketkey", &caps, false, None).unwrap();
+        let ticket = validate_ticket(b"ticketkey", &ticket).unwrap();
+        assert_eq!(ticket.caps.max_subs(), Some(5));
+        assert_eq!(ticket.caps.publish_limit(), None);
+    }
+
+    #[test]
+    fn token_auth_subtree() {
+        let claims = Claims::with_custom_claims(
+            CustomClaims {
+                x_fastly_read: vec!["building1/floor2".to_string()],
+                x_fastly_write: vec![],
+                x_fastly_subtree: true,
+                x_fastly_admin_prefix: None,
+                x_fastly_roles: vec![],
+                x_fastly_max_publish_per_min: None,
+                x_fastly_max_subs: None,
+                x_fastly_admin_scopes: None,
+                x_fastly_namespace: None,
+            },
+            Duration::from_secs(60),
+        );
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims).unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert!(caps.can_subscribe("building1/floor2"));
+        assert!(caps.can_subscribe("building1/floor2/room3"));
+        assert!(!caps.can_subscribe("building1"));
+        assert!(!caps.can_subscribe("building1/floor20"));
+        assert!(!caps.can_publish("building1/floor2/room3"));
+
+        let claims = Claims::with_custom_claims(
+            CustomClaims {
+                x_fastly_read: vec!["building1/floor2".to_string()],
+                x_fastly_write: vec![],
+                x_fastly_subtree: false,
+                x_fastly_admin_prefix: None,
+                x_fastly_roles: vec![],
+                x_fastly_max_publish_per_min: None,
+                x_fastly_max_subs: None,
+                x_fastly_admin_scopes: None,
+                x_fastly_namespace: None,
+            },
+            Duration::from_secs(60),
+        );
+
+        let token = key.authenticate(claims).unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert!(caps.can_subscribe("building1/floor2"));
+        assert!(!caps.can_subscribe("building1/floor2/room3"));
+    }
+
+    #[test]
+    fn token_auth_patterns() {
+        let claims = || {
+            Claims::with_custom_claims(
+                CustomClaims {
+                    x_fastly_read: vec!["chat/*".to_string(), "user/{sub}/inbox".to_string()],
+                    x_fastly_write: vec!["user/{sub}/*".to_string()],
+                    x_fastly_subtree: false,
+                    x_fastly_admin_prefix: None,
+                    x_fastly_roles: vec![],
+                    x_fastly_max_publish_per_min: None,
+                    x_fastly_max_subs: None,
+                    x_fastly_admin_scopes: None,
+                    x_fastly_namespace: None,
+                },
+                Duration::from_secs(60),
+            )
+        };
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims().with_subject("alice")).unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert!(caps.can_subscribe("chat/room1"));
+        assert!(!caps.can_subscribe("chat"));
+        assert!(!caps.can_subscribe("chat/room1/x"));
+        assert!(caps.can_subscribe("user/alice/inbox"));
+        assert!(!caps.can_subscribe("user/bob/inbox"));
+        assert!(caps.can_publish("user/alice/outbox"));
+        assert!(!caps.can_publish("user/bob/outbox"));
+
+        // templated grants need a subject usable as a topic level
+        let token = key.authenticate(claims()).unwrap();
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert!(caps.can_subscribe("chat/room1"));
+        assert!(!caps.can_subscribe("user/{sub}/inbox"));
+
+        let token = key.authenticate(claims().with_subject("a/b")).unwrap();
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert!(!caps.can_subscribe("user/a/b/inbox"));
+        assert!(!caps.can_publish("user/a/b/x"));
+    }
+
+    #[test]
+    fn token_validation() {
+        let key = HS256Key::from_bytes(b"notasecret");
+
+        let claims = || {
+            Claims::with_custom_claims(
+                CustomClaims {
+                    x_fastly_read: vec!["readable".to_string()],
+                    x_fastly_write: vec![],
+                    x_fastly_subtree: false,
+                    x_fastly_admin_prefix: None,
+                    x_fastly_roles: vec![],
+                    x_fastly_max_publish_per_min: None,
+                    x_fastly_max_subs: None,
+                    x_fastly_admin_scopes: None,
+                    x_fastly_namespace: None,
+                },
+                Duration::from_secs(60),
+            )
+        };
+
+        let validation = TokenValidation {
+            issuers: Some(vec!["https://idp.example.com/".to_string()]),
+            audiences: Some(vec!["pubsub".to_string()]),
+            max_age: Some(300),
+            clock_skew: Some(0),
+            require_expiration: true,
+        };
+
+        let validate = |token: &str| TestAppTokenAuthorizor.validate_token(token, &validation);
+
+        let token = key
+            .authenticate(
+                claims()
+                    .with_issuer("https://idp.example.com/")
+                    .with_audience("pubsub"),
+            )
+            .unwrap();
+        assert!(validate(&token).is_ok());
+
+        let token = key
+            .authenticate(
+                claims()
+                    .with_issuer("https://other.example.com/")
+                    .with_audience("pubsub"),
+            )
+            .unwrap();
+        assert!(validate(&token).is_err());
+
+        let token = key
+            .authenticate(claims().with_issuer("https://idp.example.com/"))
+            .unwrap();
+        assert!(validate(&token).is_err());
+
+        // issued too long ago
+        let mut c = claims()
+            .with_issuer("https://idp.example.com/")
+            .with_audience("pubsub");
+        c.issued_at = Some(Clock::now_since_epoch() - Duration::from_secs(600));
+        assert!(validate(&key.authenticate(c).unwrap()).is_err());
+
+        // no expiration
+        let mut c = claims()
+            .with_issuer("https://idp.example.com/")
+            .with_audience("pubsub");
+        c.expires_at = None;
+        assert!(validate(&key.authenticate(c).unwrap()).is_err());
+    }
+
+    #[test]
+    fn create() {
+        let grants = TokenGrants {
+            subject: Some("alice".to_string()),
+            read: vec!["user/{sub}/inbox".to_string()],
+            write: vec!["chat".to_string()],
+            subtree: true,
+        };
+
+        let token = create_token("k1", b"notasecret", &grants, Duration::from_secs(60)).unwrap();
+
+        let metadata = Token::decode_metadata(&token).unwrap();
+        assert_eq!(metadata.key_id(), Some("k1"));
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert_eq!(caps.subject(), Some("alice"));
+        assert!(caps.can_subscribe("user/alice/inbox"));
+        assert!(caps.can_publish("chat/room1"));
+        assert!(!caps.can_publish("user/alice/inbox"));
+        assert!(caps.expires_at.is_some());
+
+        let claims = token_claims(&token).unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert_eq!(claims["x-fastly-write"][0], "chat");
+        assert!(claims["exp"].is_u64());
+        assert!(token_claims("foo").is_none());
+    }
+
+    #[test]
+    fn acl() {
+        let acl: Acl = serde_json::from_str(
+            r#"[
+                {"topic":"chat/*","read":["role:member"],"write":["role:mod"]},
+                {"topic":"billing","subtree":true,"write":["key:k1","sub:bob"]}
+            ]"#,
+        )
+        .unwrap();
+
+        let claims = Claims::with_custom_claims(
+            CustomClaims {
+                x_fastly_read: vec![],
+                x_fastly_write: vec![],
+                x_fastly_subtree: false,
+                x_fastly_admin_prefix: None,
+                x_fastly_roles: vec!["member".to_string()],
+                x_fastly_max_publish_per_min: None,
+                x_fastly_max_subs: None,
+                x_fastly_admin_scopes: None,
+                x_fastly_namespace: None,
+            },
+            Duration::from_secs(60),
+        );
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims).unwrap();
+
+        let mut caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert!(!caps.can_subscribe("chat/room1"));
+
+        caps.apply_acl(&acl, Some("k1"));
+        assert!(caps.can_subscribe("chat/room1"));
+        assert!(!caps.can_subscribe("chat/room1/x"));
+        assert!(!caps.can_publish("chat/room1"));
+        assert!(caps.can_publish("billing"));
+        assert!(caps.can_publish("billing/eu"));
+        assert!(!caps.can_publish("billingx"));
+
+        // carried by tickets
+        let ticket = issue_ticket(b"ticketkey", &caps, false, None).unwrap();
+        let ticket = validate_ticket(b"ticketkey", &ticket).unwrap();
+        assert!(ticket.caps.can_subscribe("chat/room1"));
+
+        // limited to a key's namespace
+        caps.restrict_to("chat");
+        assert!(caps.can_subscribe("chat/room1"));
+        assert!(!caps.can_publish("billing"));
+    }
+
+    #[test]
+    fn signed_url() {
+        let expires = Clock::now_since_epoch().as_secs() + 60;
+
+        let sig = sign_url(b"notasecret", expires, &["a", "b"]);
+
+        let url = SignedUrl {
+            key_id: "k1",
+            expires,
+            topics: vec!["a", "b"],
+            sig: &sig,
+        };
+
+        let caps = validate_signed_url(b"notasecret", &url).unwrap();
+        assert!(caps.can_subscribe("a"));
+        assert!(caps.can_subscribe("b"));
+        assert!(!caps.can_subscribe("c"));
+        assert!(!caps.can_publish("a"));
+
+        // topics can't be added
+        let url = SignedUrl {
+            topics: vec!["a", "b", "c"],
+            ..url
+        };
+        assert!(validate_signed_url(b"notasecret", &url).is_err());
+
+        let url = SignedUrl {
+            topics: vec!["a", "b"],
+            ..url
+        };
+        assert!(validate_signed_url(b"wrongkey", &url).is_err());
+
+        // expired
+        let expires = expires - 120;
+        let sig = sign_url(b"notasecret", expires, &["a"]);
+
+        let url = SignedUrl {
+            key_id: "k1",
+            expires,
+            topics: vec!["a"],
+            sig: &sig,
+        };
+        assert!(validate_signed_url(b"notasecret", &url).is_err());
+    }
+
+    #[test]
+    fn admin_scopes() {
+        let caps = |json: &str| {
+            let claims: JWTClaims<CustomClaims> = serde_json::from_str(json).unwrap();
+            let token = HS256Key::from_bytes(b"notasecret")
+                .authenticate(claims)
+                .unwrap();
+
+            TestAppTokenAuthorizor
+                .validate_token(&token, &TokenValidation::default())
+                .unwrap()
+        };
+
+        // tenant admins have all scopes unless limited
+        let c = caps(r#"{"x-fastly-admin-prefix":"acme"}"#);
+        assert!(c.has_admin_scope(AdminScope::Keys));
+        assert!(c.has_admin_scope(AdminScope::Stats));
+
+        let c = caps(r#"{"x-fastly-admin-prefix":"acme","x-fastly-admin-scopes":["stats","foo"]}"#);
+        assert!(!c.has_admin_scope(AdminScope::Keys));
+        assert!(c.has_admin_scope(AdminScope::Stats));
+
+        let c = caps(r#"{}"#);
+        assert!(!c.has_admin_scope(AdminScope::Retained));
+
+        // app-wide scopes are dropped for tenant keys
+        let mut c = caps(r#"{"x-fastly-admin-scopes":["retained"]}"#);
+        assert!(c.has_admin_scope(AdminScope::Retained));
+        c.restrict_to("acme");
+        assert!(!c.has_admin_scope(AdminScope::Retained));
+    }
+
+    #[test]
+    fn api_key_caps() {
+        assert_eq!(
+            api_key_name("secret"),
+            "api:e5e9fa1ba31ecd1ae84f75caaa474f3a663f05f4"
+        );
+
+        let record: CredentialRecord =
+            serde_json::from_str(r#"{"write":["orders/*","svc/{sub}"],"sub":"billing"}"#).unwrap();
+
+        let caps = Capabilities::from(record);
+        assert!(caps.can_publish("orders/eu"));
+        assert!(caps.can_publish("svc/billing"));
+        assert!(!caps.can_publish("orders"));
+        assert!(!caps.can_subscribe("orders/eu"));
+        assert_eq!(caps.subject(), Some("billing"));
+    }
+
+    #[test]
+    fn ticket() {
+        let claims = Claims::with_custom_claims(
+            CustomClaims {
+                x_fastly_read: vec!["readable".to_string()],
+                x_fastly_write: vec!["writable".to_string()],
+                x_fastly_subtree: false,
+                x_fastly_admin_prefix: None,
+                x_fastly_roles: vec![],
+                x_fastly_max_publish_per_min: None,
+                x_fastly_max_subs: None,
+                x_fastly_admin_scopes: None,
+                x_fastly_namespace: None,
+            },
+            Duration::from_secs(60),
+        )
+        .with_subject("alice");
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims).unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+
+        let ticket = issue_ticket(b"ticketkey", &caps, true, Some("abc123")).unwrap();
+
+        let t = validate_ticket(b"ticketkey", &ticket).unwrap();
+        assert!(t.durable);
+        assert_eq!(t.cursor_key.as_deref(), Some("abc123"));
+        assert_eq!(t.caps.subject(), Some("alice"));
+        assert!(t.caps.can_subscribe("readable"));
+        assert!(!t.caps.can_subscribe("foo"));
+        assert!(!t.caps.can_publish("writable"));
+        assert!(t.caps.expires_at.unwrap() <= caps.expires_at.unwrap());
+
+        assert!(validate_ticket(b"otherkey", &ticket).is_err());
+    }
+
+    #[test]
+    fn namespace() {
+        let claims: JWTClaims<CustomClaims> = serde_json::from_str(
+            r#"{"x-fastly-read":["orders"],"x-fastly-write":["orders"],"x-fastly-namespace":"acme"}"#,
+        )
+        .unwrap();
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims).unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert_eq!(caps.namespace(), Some("acme"));
+        assert_eq!(caps.resolve_topic("orders"), "acme/orders");
+        assert!(caps.can_subscribe("acme/orders"));
+        assert!(!caps.can_subscribe("orders"));
+        assert!(caps.can_publish("acme/orders"));
+
+        // tickets carry the namespace
+        let ticket = issue_ticket(b"ticketkey", &caps, false, None).unwrap();
+        let ticket = validate_ticket(b"ticketkey", &ticket).unwrap();
+        assert_eq!(ticket.caps.namespace(), Some("acme"));
+        assert!(ticket.caps.can_subscribe("acme/orders"));
+
+        let claims: JWTClaims<CustomClaims> =
+            serde_json::from_str(r#"{"x-fastly-namespace":"acme/"}"#).unwrap();
+        let token = key.authenticate(claims).unwrap();
+
+        assert!(TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .is_err());
+
+        // grants outside the namespace are dropped
+        let mut caps = Capabilities::new_admin();
+        caps.admin = false;
+        caps.read = vec!["orders".to_string()];
+        caps.admin_prefix = Some("other".to_string());
+        caps.enter_namespace("acme");
+        assert!(caps.can_subscribe("acme/orders"));
+        assert!(!caps.can_subscribe("other/orders"));
+        assert_eq!(caps.admin_prefix, Some("acme/other".to_string()));
+    }
+
+    #[test]
+    fn anonymous() {
+        let mut auth = Authorization {
+            grip: Box::new(TestGripAuthorizor),
+            fastly: false,
+            app_token: Box::new(TestAppTokenAuthorizor),
+            token_validation: Default::default(),
+            client_cert: None,
+            namespace: None,
+        };
+
+        let public = vec!["status".to_string(), "acme/dashboards".to_string()];
+
+        let caps = auth.anonymous(&public);
+        assert!(caps.can_subscribe("status"));
+        assert!(caps.can_subscribe("status/eu"));
+        assert!(!caps.can_subscribe("orders"));
+        assert!(!caps.can_publish("status"));
+        assert_eq!(caps.quota_key(), None);
+
+        auth.namespace = Some("acme".to_string());
+
+        let caps = auth.anonymous(&public);
+        assert_eq!(
+            caps.resolve_topic("dashboards/sales"),
+            "acme/dashboards/sales"
+        );
+        assert!(caps.can_subscribe("acme/dashboards/sales"));
+        assert!(!caps.can_subscribe("status"));
+    }
+
+    #[test]
+    fn tenant_admin() {
+        let claims = Claims::with_custom_claims(
+            CustomClaims {
+                x_fastly_read: vec!["acme/news".to_string(), "other".to_string()],
+                x_fastly_write: vec![],
+                x_fastly_subtree: false,
+                x_fastly_admin_prefix: Some("acme/admin".to_string()),
+                x_fastly_roles: vec![],
+                x_fastly_max_publish_per_min: None,
+                x_fastly_max_subs: None,
+                x_fastly_admin_scopes: None,
+                x_fastly_namespace: None,
+            },
+            Duration::from_secs(60),
+        );
+
+        let key = HS256Key::from_bytes(b"notasecret");
+        let token = key.authenticate(claims).unwrap();
+
+        let mut caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        assert_eq!(caps.admin_prefix(), Some("acme/admin"));
+        assert!(caps.can_publish("acme/admin"));
+        assert!(caps.can_subscribe("acme/admin/x"));
+        assert!(!caps.can_publish("acme/administration"));
+        assert!(caps.can_subscribe("other"));
+
+        // as if signed by a key minted for the acme namespace
+        caps.restrict_to("acme");
+        assert_eq!(caps.admin_prefix(), Some("acme/admin"));
+        assert!(caps.can_subscribe("acme/news"));
+        assert!(!caps.can_subscribe("other"));
+
+        caps.restrict_to("acme/news");
+        assert_eq!(caps.admin_prefix(), None);
+        assert!(!caps.can_publish("acme/admin"));
+
+        // carried in tickets
+        let token = key
+            .authenticate(Claims::with_custom_claims(
+                CustomClaims {
+                    x_fastly_read: vec![],
+                    x_fastly_write: vec![],
+                    x_fastly_subtree: false,
+                    x_fastly_admin_prefix: Some("acme".to_string()),
+                    x_fastly_roles: vec![],
+                    x_fastly_max_publish_per_min: None,
+                    x_fastly_max_subs: None,
+                    x_fastly_admin_scopes: None,
+                    x_fastly_namespace: None,
+                },
+                Duration::from_secs(60),
+            ))
+            .unwrap();
+
+        let caps = TestAppTokenAuthorizor
+            .validate_token(&token, &TokenValidation::default())
+            .unwrap();
+        let ticket = issue_ticket(b"ticketkey", &caps, false, None).unwrap();
+
+        let t = validate_ticket(b"ticketkey", &ticket).unwrap();
+        assert!(t.caps.can_subscribe("acme/news"));
+    }
+
+    fn validate_stored(token: &str, key: &[u8], alg: &str) -> Result<Capabilities, TokenError> {
+        let key = VerifyingKey::from_stored(key, alg)?;
+
+        validate_token(token, &key, &TokenValidation::default())
+    }
+
+    #[test]
+    fn token_auth_asymmetric() {
+        let claims = || {
+            Claims::with_custom_claims(
+                CustomClaims {
+                    x_fastly_read: vec!["readable".to_string()],
+                    x_fastly_write: vec![],
+                    x_fastly_subtree: false,
+                    x_fastly_admin_prefix: None,
+                    x_fastly_roles: vec![],
+                    x_fastly_max_publish_per_min: None,
+                    x_fastly_max_subs: None,
+                    x_fastly_admin_scopes: None,
+                    x_fastly_namespace: None,
+                },
+                Duration::from_secs(60),
+            )
+        };
+
+        let key_pair = ES256KeyPair::generate();
+        let pem = key_pair.public_key().to_pem().unwrap();
+        let token = key_pair.sign(claims()).unwrap();
+
+        let caps = validate_stored(&token, pem.as_bytes(), "ES256").unwrap();
+        assert!(caps.can_subscribe("readable"));
+
+        // alg must match the key's
+        assert!(validate_stored(&token, pem.as_bytes(), "EdDSA").is_err());
+        assert!(validate_stored(&token, pem.as_bytes(), "HS256").is_err());
+
+        let key_pair = Ed25519KeyPair::generate();
+        let pem = key_pair.public_key().to_pem();
+        let token = key_pair.sign(claims()).unwrap();
+
+        let caps = validate_stored(&token, pem.as_bytes(), "EdDSA").unwrap();
+        assert!(caps.can_subscribe("readable"));
+
+        // a symmetric token can't be verified with a public key
+        let token = HS256Key::from_bytes(pem.as_bytes())
+            .authenticate(claims())
+            .unwrap();
+        assert!(validate_stored(&token, pem.as_bytes(), "EdDSA").is_err());
+        assert!(validate_stored(&token, pem.as_bytes(), "XX256").is_err());
+    }
+
     #[test]
     fn parse_fastly_key() {
         ES256PublicKey::from_pem(FASTLY_PUBLIC_KEY).unwrap();
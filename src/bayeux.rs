@@ -0,0 +1,945 @@
+use crate::auth::{Authorization, AuthorizationError, Capabilities};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::events::get_token;
+use crate::geo::{self, Location};
+use crate::grip::ControlMessage;
+use crate::meta::MessageMeta;
+use crate::problem;
+use crate::publish::{
+    check_line_lengths, publish_response, read_body, Batch, MESSAGE_SIZE_MAX,
+    TRANSPORT_REQUEST_SIZE_MAX,
+};
+use crate::routing;
+use crate::storage::{Storage, StorageError};
+use crate::topic;
+use crate::websocket::{parse_websocket_event, write_websocket_event, WsEvent};
+use crate::{log_debug, log_error, log_warn};
+use fastly::http::{header, HeaderValue, StatusCode};
+use fastly::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+// topics are delivered to bayeux clients on fanout channels with this
+// prefix. long-polling clients also hold on a channel of their own
+pub const CHANNEL_PREFIX: &str = "b:";
+const CLIENT_CHANNEL_PREFIX: &str = "bc:";
+
+const VERSION: &str = "1.0";
+
+const CONNECTION_TYPES: [&str; 2] = ["long-polling", "websocket"];
+
+// seconds to hold long-polling connect requests for
+const CONNECT_TIMEOUT: u32 = 55;
+
+// websocket-over-http messages must be prefixed
+const MESSAGE_PREFIX: &str = "m:";
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(v) => vec![v],
+            Self::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    channel: String,
+
+    #[serde(default)]
+    id: Option<Value>,
+
+    #[serde(rename = "clientId", default)]
+    client_id: Option<String>,
+
+    #[serde(default)]
+    subscription: Option<OneOrMany<String>>,
+
+    #[serde(default)]
+    data: Option<Value>,
+
+    // clients that can't set headers pass their token here, as
+    // {"token": "..."}
+    #[serde(default)]
+    ext: Option<Value>,
+}
+
+impl Message {
+    fn token(&self) -> Option<&str> {
+        self.ext.as_ref()?.get("token")?.as_str()
+    }
+}
+
+// websocket sessions keep this in fanout's meta state. long-polling
+// sessions keep their subscriptions in storage instead
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(rename = "cid", default, skip_serializing_if = "String::is_empty")]
+    client_id: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+
+    // full topic names
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    subs: Vec<String>,
+}
+
+struct Context<'a> {
+    config: &'a Config,
+    auth: &'a Authorization,
+    storage: &'a dyn Storage,
+    deadline: Deadline,
+
+    // the websocket connection ID, or none for long-polling
+    cid: Option<&'a str>,
+
+    // from the request's Authorization header, if any
+    header_token: Option<&'a str>,
+
+    // where the client is, if known. see geo.rs
+    location: Option<Location>,
+
+    state: State,
+    subs_changed: bool,
+
+    // whether a long-polling connect should be held
+    hold: bool,
+    disconnect: bool,
+}
+
+// errors are of the form "{code}:{args}:{message}"
+fn error_reply(mut reply: Map<String, Value>, code: u16, args: &str, message: &str) -> Value {
+    reply.insert("successful".to_string(), false.into());
+    reply.insert(
+        "error".to_string(),
+        format!("{code}:{args}:{message}").into(),
+    );
+
+    Value::Object(reply)
+}
+
+fn success_reply(mut reply: Map<String, Value>) -> Value {
+    reply.insert("successful".to_string(), true.into());
+
+    Value::Object(reply)
+}
+
+fn connect_advice(ctx: &Context) -> Value {
+    // websocket clients receive messages without a pending connect, so
+    // their connects only serve as heartbeats
+    let interval = if ctx.cid.is_some() {
+        CONNECT_TIMEOUT * 1000
+    } else {
+        0
+    };
+
+    json!({
+        "reconnect": "retry",
+        "interval": interval,
+        "timeout": CONNECT_TIMEOUT * 1000,
+    })
+}
+
+// bayeux channels are topics with a leading slash. meta and service
+// channels aren't topics, and wildcards aren't supported
+fn channel_topic<'a>(
+    rules: &topic::Rules,
+    channel: &'a str,
+) -> Result<&'a str, (u16, &'static str)> {
+    let Some(name) = channel.strip_prefix('/') else {
+        return Err((400, "Invalid channel"));
+    };
+
+    let Ok(levels) = topic::parse(name) else {
+        return Err((400, "Invalid channel"));
+    };
+
+    if levels[0] == "meta" || levels[0] == "service" {
+        return Err((403, "Channel not allowed"));
+    }
+
+    if levels.iter().any(|l| *l == "*" || *l == "**") {
+        return Err((405, "Wildcard channels not supported"));
+    }
+
+    if topic::validate(rules, name).is_err() {
+        return Err((400, "Invalid channel"));
+    }
+
+    Ok(name)
+}
+
+// the token may be given in the message, the request, or the handshake of a
+// websocket session
+fn caps(ctx: &Context, msg: &Message) -> Result<Capabilities, (u16, &'static str)> {
+    let token = msg
+        .token()
+        .or(ctx.header_token)
+        .or(ctx.state.token.as_deref());
+
+    let Some(token) = token else {
+        return Err((401, "Missing token"));
+    };
+
+    match ctx.auth.validate_token(token) {
+        Ok(caps) => Ok(caps),
+        Err(AuthorizationError::Token(_)) => Err((403, "Invalid token")),
+        Err(e) => {
+            log_error!("auth failed: {e:?}");
+
+            Err((500, "Auth process failed"))
+        }
+    }
+}
+
+// long-polling clients are looked up in storage on each request
+fn known_client(ctx: &mut Context, client_id: Option<&str>) -> bool {
+    let Some(client_id) = client_id else {
+        return false;
+    };
+
+    if !ctx.state.client_id.is_empty() {
+        return client_id == ctx.state.client_id;
+    }
+
+    if ctx.cid.is_some() {
+        return false;
+    }
+
+    match ctx.storage.read_stream_topics(client_id) {
+        Ok(Some(subs)) => {
+            ctx.state.client_id = client_id.to_string();
+            ctx.state.subs = subs;
+
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            log_error!("failed to read bayeux client: {e:?}");
+
+            false
+        }
+    }
+}
+
+fn handshake(ctx: &mut Context, msg: &Message, mut reply: Map<String, Value>) -> Value {
+    let client_id = match ctx.cid {
+        Some(cid) => cid.to_string(),
+        None => {
+            let client_id = hex::encode(rand::random::<[u8; 16]>());
+
+            if let Err(e) = ctx.storage.write_stream_topics(&client_id, &[]) {
+                log_error!("failed to write bayeux client: {e:?}");
+
+                return error_reply(reply, 500, "", "Storage error");
+            }
+
+            client_id
+        }
+    };
+
+    ctx.state = State {
+        client_id: client_id.clone(),
+        token: msg.token().map(|s| s.to_string()),
+        subs: Vec::new(),
+    };
+
+    reply.insert("version".to_string(), VERSION.into());
+    reply.insert(
+        "supportedConnectionTypes".to_string(),
+        json!(CONNECTION_TYPES),
+    );
+    reply.insert("clientId".to_string(), client_id.into());
+    reply.insert("advice".to_string(), connect_advice(ctx));
+
+    success_reply(reply)
+}
+
+// the reply echoes the subscription as given
+fn subscription_channels<'a>(
+    msg: &'a Message,
+    reply: &mut Map<String, Value>,
+) -> Option<&'a [String]> {
+    let channels = match msg.subscription.as_ref()? {
+        OneOrMany::One(s) => {
+            reply.insert("subscription".to_string(), s.as_str().into());
+
+            std::slice::from_ref(s)
+        }
+        OneOrMany::Many(v) => {
+            reply.insert("subscription".to_string(), json!(v));
+
+            v.as_slice()
+        }
+    };
+
+    Some(channels)
+}
+
+fn subscribe(ctx: &mut Context, msg: &Message, mut reply: Map<String, Value>) -> Value {
+    let Some(channels) = subscription_channels(msg, &mut reply) else {
+        return error_reply(reply, 400, "", "Missing subscription");
+    };
+
+    let caps = match caps(ctx, msg) {
+        Ok(caps) => caps,
+        Err((code, message)) => return error_reply(reply, code, "", message),
+    };
+
+    let mut topics = Vec::new();
+
+    for channel in channels {
+        let name = match channel_topic(&ctx.config.topic_rules, channel) {
+            Ok(name) => name,
+            Err((code, message)) => return error_reply(reply, code, channel, message),
+        };
+
+        let topic = caps.resolve_topic(name);
+
+        if !caps.can_subscribe(&topic)
+            || !geo::can_subscribe(ctx.config, &topic, ctx.location.as_ref())
+        {
+            return error_reply(reply, 403, channel, "Forbidden");
+        }
+
+        topics.push(topic);
+    }
+
+    for topic in topics {
+        if !ctx.state.subs.contains(&topic) {
+            ctx.state.subs.push(topic);
+            ctx.subs_changed = true;
+        }
+    }
+
+    if let Some(max) = ctx.config.subscriptions_max(caps.max_subs()) {
+        if ctx.state.subs.len() > max {
+            ctx.state.subs.truncate(max);
+
+            return error_reply(reply, 403, "", "Too many subscriptions");
+        }
+    }
+
+    success_reply(reply)
+}
+
+fn unsubscribe(ctx: &mut Context, msg: &Message, mut reply: Map<String, Value>) -> Value {
+    let Some(channels) = subscription_channels(msg, &mut reply) else {
+        return error_reply(reply, 400, "", "Missing subscription");
+    };
+
+    // the namespace is needed to know the full names
+    let caps = match caps(ctx, msg) {
+        Ok(caps) => caps,
+        Err((code, message)) => return error_reply(reply, code, "", message),
+    };
+
+    for channel in channels {
+        if let Ok(name) = channel_topic(&ctx.config.topic_rules, channel) {
+            let topic = caps.resolve_topic(name);
+
+            let before = ctx.state.subs.len();
+            ctx.state.subs.retain(|s| *s != topic);
+
+            if ctx.state.subs.len() != before {
+                ctx.subs_changed = true;
+            }
+        }
+    }
+
+    success_reply(reply)
+}
+
+fn publish_message(ctx: &mut Context, msg: &Message, reply: Map<String, Value>) -> Value {
+    let name = match channel_topic(&ctx.config.topic_rules, &msg.channel) {
+        Ok(name) => name,
+        Err((code, message)) => return error_reply(reply, code, &msg.channel, message),
+    };
+
+    if topic::validate_publish(&ctx.config.topic_rules, name).is_err() {
+        return error_reply(reply, 403, &msg.channel, "Channel not allowed");
+    }
+
+    let Some(data) = &msg.data else {
+        return error_reply(reply, 400, &msg.channel, "Missing data");
+    };
+
+    let caps = match caps(ctx, msg) {
+        Ok(caps) => caps,
+        Err((code, message)) => return error_reply(reply, code, "", message),
+    };
+
+    let topic = caps.resolve_topic(name);
+
+    if !caps.can_publish(&topic) || !geo::can_publish(ctx.config, &topic, ctx.location.as_ref()) {
+        return error_reply(reply, 403, &msg.channel, "Forbidden");
+    }
+
+    // bayeux messages can't carry a signature
+    if ctx.config.topic_config(&topic).require_signature {
+        return error_reply(reply, 403, &msg.channel, "Channel requires signed messages");
+    }
+
+    let message = data.to_string().into_bytes();
+
+    if message.len() > MESSAGE_SIZE_MAX {
+        return error_reply(reply, 413, &msg.channel, "Message too large");
+    }
+
+    if let Some((key, limit)) = caps.publish_limit() {
+        match ctx.storage.count_publishes(key, 1, limit, ctx.deadline) {
+            Ok(()) | Err(StorageError::StoreNotFound) => {}
+            Err(StorageError::LimitReached) => {
+                return error_reply(reply, 429, &msg.channel, "Rate limit exceeded")
+            }
+            Err(e) => log_error!("failed to count publishes: {e:?}"),
+        }
+    }
+
+    let targets = routing::route(ctx.config, caps.namespace(), &topic, &message, |t| {
+        caps.can_publish(t) && geo::can_publish(ctx.config, t, ctx.location.as_ref())
+    });
+
+    for target in &targets {
+        if !check_line_lengths(ctx.config, target, &message) {
+            return error_reply(
+                reply,
+                400,
+                &msg.channel,
+                "Message has lines that are too long",
+            );
+        }
+    }
+
+    if ctx.config.publish_token.is_empty() {
+        log_warn!("publishing not configured, dropping bayeux message");
+
+        return error_reply(reply, 503, &msg.channel, "Publishing not configured");
+    }
+
+    let mut meta = MessageMeta {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+
+    meta.start_span(&topic);
+
+    let mut batch = Batch::default();
+
+    for target in targets {
+        if let Err(e) = batch.add(
+            ctx.config,
+            &target,
+            caps.namespace(),
+            &message,
+            &meta,
+            None,
+            Some(&ctx.state.client_id),
+        ) {
+            log_error!("failed to publish: {e:?}");
+
+            return error_reply(reply, 500, &msg.channel, "Failed to publish");
+        }
+    }
+
+    if let Err(e) = batch.send(ctx.config, ctx.deadline) {
+        log_error!("failed to publish: {e:?}");
+
+        return error_reply(reply, 500, &msg.channel, "Failed to publish");
+    }
+
+    success_reply(reply)
+}
+
+fn handle_message(ctx: &mut Context, msg: Message) -> Value {
+    let mut reply = Map::new();
+    reply.insert("channel".to_string(), msg.channel.as_str().into());
+
+    if let Some(id) = &msg.id {
+        reply.insert("id".to_string(), id.clone());
+    }
+
+    if msg.channel == "/meta/handshake" {
+        return handshake(ctx, &msg, reply);
+    }
+
+    if !known_client(ctx, msg.client_id.as_deref()) {
+        reply.insert(
+            "advice".to_string(),
+            json!({"reconnect": "handshake", "interval": 0}),
+        );
+
+        return error_reply(
+            reply,
+            402,
+            msg.client_id.as_deref().unwrap_or(""),
+            "Unknown client",
+        );
+    }
+
+    reply.insert("clientId".to_string(), ctx.state.client_id.as_str().into());
+
+    match msg.channel.as_str() {
+        "/meta/connect" => {
+            reply.insert("advice".to_string(), connect_advice(ctx));
+
+            ctx.hold = ctx.cid.is_none();
+
+            success_reply(reply)
+        }
+        "/meta/disconnect" => {
+            if !ctx.state.subs.is_empty() {
+                ctx.state.subs.clear();
+                ctx.subs_changed = true;
+            }
+
+            ctx.disconnect = true;
+
+            success_reply(reply)
+        }
+        "/meta/subscribe" => subscribe(ctx, &msg, reply),
+        "/meta/unsubscribe" => unsubscribe(ctx, &msg, reply),
+        _ => publish_message(ctx, &msg, reply),
+    }
+}
+
+// bayeux messages carry JSON. messages that aren't JSON are delivered as
+// strings, and binary messages can't be delivered
+fn message_data(message: &[u8], meta: &MessageMeta) -> Option<Value> {
+    let json = match &meta.content_type {
+        Some(t) => {
+            let t = t.split(';').next().unwrap_or("").trim();
+
+            t == "application/json" || t.ends_with("+json")
+        }
+        None => true,
+    };
+
+    if json {
+        if let Ok(v) = serde_json::from_slice(message) {
+            return Some(v);
+        }
+    }
+
+    std::str::from_utf8(message).ok().map(Value::from)
+}
+
+// the item that delivers a message to bayeux subscribers. name is the topic
+// relative to the publisher's namespace
+pub fn publish_item(topic: &str, name: &str, message: &[u8], meta: &MessageMeta) -> Option<Value> {
+    let data = message_data(message, meta)?;
+
+    let m = json!({
+        "channel": format!("/{name}"),
+        "data": data,
+    });
+
+    // a held connect is answered with the message, along with a reply to
+    // the connect itself
+    let connect = json!({
+        "channel": "/meta/connect",
+        "successful": true,
+        "advice": {"reconnect": "retry", "interval": 0},
+    });
+
+    Some(json!({
+        "channel": format!("{CHANNEL_PREFIX}{topic}"),
+        "formats": {
+            "http-response": {
+                "headers": {"Content-Type": "application/json"},
+                "body": json!([m, connect]).to_string(),
+            },
+            "ws-message": {
+                "content": json!([m]).to_string(),
+            },
+        },
+    }))
+}
+
+fn bad_request<T: AsRef<str>>(message: T) -> Response {
+    Response::from_status(StatusCode::BAD_REQUEST)
+        .with_body_text_plain(&format!("{}\n", message.as_ref()))
+}
+
+fn parse_messages(body: &[u8]) -> Option<Vec<Message>> {
+    serde_json::from_slice::<OneOrMany<Message>>(body)
+        .ok()
+        .map(|v| v.into_vec())
+}
+
+fn handle_long_polling(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+    body: Vec<u8>,
+) -> Response {
+    let Some(messages) = parse_messages(&body) else {
+        return bad_request("Invalid Bayeux messages");
+    };
+
+    let header_token = match get_token(&req, false) {
+        Ok(v) => v,
+        Err(e) => return bad_request(e),
+    };
+
+    let mut ctx = Context {
+        config,
+        auth,
+        storage,
+        deadline,
+        cid: None,
+        header_token,
+        location: geo::location(&req),
+        state: State::default(),
+        subs_changed: false,
+        hold: false,
+        disconnect: false,
+    };
+
+    let replies: Vec<Value> = messages
+        .into_iter()
+        .map(|m| handle_message(&mut ctx, m))
+        .collect();
+
+    let client_id = ctx.state.client_id.clone();
+
+    if ctx.subs_changed {
+        if let Err(e) = storage.write_stream_topics(&client_id, &ctx.state.subs) {
+            log_error!("failed to write bayeux client: {e:?}");
+        }
+
+        // a connect held by another request won't see the change, so
+        // have it return and be made again
+        if !ctx.hold && !config.publish_token.is_empty() {
+            let body = json!([{
+                "channel": "/meta/connect",
+                "clientId": client_id,
+                "successful": true,
+                "advice": connect_advice(&ctx),
+            }]);
+
+            if let Err(e) = publish_response(
+                config,
+                &format!("{CLIENT_CHANNEL_PREFIX}{client_id}"),
+                &body.to_string(),
+                deadline,
+            ) {
+                log_error!("failed to publish: {e:?}");
+            }
+        }
+    }
+
+    let mut resp = Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_body(serde_json::to_string(&replies).unwrap());
+
+    // if the connect times out, the replies are sent as they are
+    if ctx.hold && !ctx.disconnect {
+        resp.set_header("Grip-Hold", "response");
+        resp.set_header("Grip-Timeout", CONNECT_TIMEOUT.to_string());
+        resp.append_header(
+            "Grip-Channel",
+            format!("{CLIENT_CHANNEL_PREFIX}{client_id}"),
+        );
+
+        for topic in &ctx.state.subs {
+            resp.append_header("Grip-Channel", format!("{CHANNEL_PREFIX}{topic}"));
+        }
+    }
+
+    resp
+}
+
+fn handle_websocket_events(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+    body: Vec<u8>,
+) -> Response {
+    let grip_offered = req
+        .get_header_str("Sec-WebSocket-Extensions")
+        .is_some_and(|s| s.contains("grip"));
+
+    let Some(cid) = req.get_header_str("Connection-Id") else {
+        return bad_request("Missing Connection-Id header");
+    };
+
+    let mut state = State::default();
+
+    if let Some(v) = req.get_header("Meta-State") {
+        match serde_json::from_slice(v.as_bytes()) {
+            Ok(v) => state = v,
+            Err(e) => {
+                log_error!("failed to parse state: {e}");
+                return bad_request("Invalid header");
+            }
+        }
+    }
+
+    let connected_subs: HashSet<String> = state.subs.iter().cloned().collect();
+
+    let mut events = Vec::new();
+    let mut pos = 0;
+
+    while pos < body.len() {
+        match parse_websocket_event(&body[pos..]) {
+            Ok((e, size)) => {
+                events.push(e);
+                pos += size;
+            }
+            Err(_) => return bad_request("Failed to parse WebSocket events"),
+        }
+    }
+
+    let header_token = match get_token(&req, false) {
+        Ok(v) => v,
+        Err(e) => return bad_request(e),
+    };
+
+    let mut ctx = Context {
+        config,
+        auth,
+        storage,
+        deadline,
+        cid: Some(cid),
+        header_token,
+        location: geo::location(&req),
+        state,
+        subs_changed: false,
+        hold: false,
+        disconnect: false,
+    };
+
+    let mut opening = false;
+    let mut out = Vec::new();
+
+    for WsEvent { etype, content } in events {
+        log_debug!({cid = cid, event = etype, size = content.len()}, "websocket event");
+
+        match etype.as_str() {
+            "OPEN" => {
+                opening = true;
+
+                // ack
+                write_websocket_event(&mut out, &etype, &content).unwrap();
+            }
+            "CLOSE" => write_websocket_event(&mut out, &etype, &content).unwrap(), // ack
+            "TEXT" => {
+                let Some(messages) = parse_messages(&content) else {
+                    ctx.disconnect = true;
+                    break;
+                };
+
+                let replies: Vec<Value> = messages
+                    .into_iter()
+                    .map(|m| handle_message(&mut ctx, m))
+                    .collect();
+
+                let content = format!("{MESSAGE_PREFIX}{}", Value::from(replies));
+
+                write_websocket_event(&mut out, "TEXT", content.as_bytes()).unwrap();
+            }
+            _ => {} // unsupported event type, ignore
+        }
+    }
+
+    let mut cmsgs = Vec::new();
+
+    for topic in &ctx.state.subs {
+        if !connected_subs.contains(topic) {
+            cmsgs.push(ControlMessage {
+                ctype: "subscribe".to_string(),
+                channel: Some(format!("{CHANNEL_PREFIX}{topic}")),
+                ..Default::default()
+            });
+        }
+    }
+
+    for topic in &connected_subs {
+        if !ctx.state.subs.contains(topic) {
+            cmsgs.push(ControlMessage {
+                ctype: "unsubscribe".to_string(),
+                channel: Some(format!("{CHANNEL_PREFIX}{topic}")),
+                ..Default::default()
+            });
+        }
+    }
+
+    for cmsg in cmsgs {
+        let content = format!("c:{}", serde_json::to_string(&cmsg).unwrap());
+
+        write_websocket_event(&mut out, "TEXT", content.as_bytes()).unwrap();
+    }
+
+    if ctx.disconnect {
+        let code: u16 = 1000;
+
+        write_websocket_event(&mut out, "CLOSE", &code.to_be_bytes()).unwrap();
+    }
+
+    let mut resp = Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/websocket-events")
+        .with_body(Body::from(out));
+
+    if opening && grip_offered {
+        resp.append_header("Sec-WebSocket-Extensions", "grip");
+    }
+
+    resp.append_header("Set-Meta-State", serde_json::to_string(&ctx.state).unwrap());
+    resp.append_header("Keep-Alive-Interval", "120");
+
+    resp
+}
+
+// bayeux messages arrive either as long-polling requests or as
+// websocket-over-http events
+pub fn post(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    mut req: Request,
+) -> Response {
+    let body = match read_body(&mut req, TRANSPORT_REQUEST_SIZE_MAX) {
+        Ok(body) => body,
+        Err(e) => return problem::body_error(&e, TRANSPORT_REQUEST_SIZE_MAX),
+    };
+
+    if req.get_header(header::CONTENT_TYPE)
+        == Some(&HeaderValue::from_static("application/websocket-events"))
+    {
+        handle_websocket_events(config, auth, storage, deadline, req, body)
+    } else {
+        handle_long_polling(config, auth, storage, deadline, req, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_token, TestAppTokenAuthorizor, TestGripAuthorizor, TokenGrants};
+    use crate::kv::FastlyKv;
+    use crate::storage::KvStorage;
+    use jwt_simple::prelude::Duration;
+
+    #[test]
+    fn websocket_session() {
+        let config = Config::default();
+        let auth = Authorization {
+            grip: Box::new(TestGripAuthorizor),
+            fastly: false,
+            app_token: Box::new(TestAppTokenAuthorizor),
+            token_validation: Default::default(),
+            client_cert: None,
+            namespace: None,
+        };
+
+        // not used by websocket sessions, except to publish
+        let storage = KvStorage::new(Box::new(FastlyKv::new("messages")));
+
+        let grants = TokenGrants {
+            subject: None,
+            read: vec!["chat/room1".to_string()],
+            write: Vec::new(),
+            subtree: false,
+        };
+
+        let token = create_token("k1", b"notasecret", &grants, Duration::from_secs(60)).unwrap();
+
+        let mut ctx = Context {
+            config: &config,
+            auth: &auth,
+            storage: &storage,
+            deadline: Deadline::none(),
+            cid: Some("c1"),
+            header_token: None,
+            location: None,
+            state: State::default(),
+            subs_changed: false,
+            hold: false,
+            disconnect: false,
+        };
+
+        let messages = parse_messages(
+            format!(r#"[{{"channel":"/meta/handshake","id":"1","version":"1.0","ext":{{"token":"{token}"}}}}]"#)
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let r = handle_message(&mut ctx, messages.into_iter().next().unwrap());
+        assert_eq!(r["successful"], true);
+        assert_eq!(r["clientId"], "c1");
+        assert_eq!(r["id"], "1");
+
+        let msg = parse_messages(
+            br#"{"channel":"/meta/subscribe","clientId":"c1","subscription":"/chat/room1"}"#,
+        )
+        .unwrap();
+        let r = handle_message(&mut ctx, msg.into_iter().next().unwrap());
+        assert_eq!(r["successful"], true);
+        assert_eq!(ctx.state.subs, ["chat/room1"]);
+
+        let msg = parse_messages(
+            br#"{"channel":"/meta/subscribe","clientId":"c1","subscription":"/chat/room2"}"#,
+        )
+        .unwrap();
+        let r = handle_message(&mut ctx, msg.into_iter().next().unwrap());
+        assert_eq!(r["error"], "403:/chat/room2:Forbidden");
+
+        let msg = parse_messages(
+            br#"{"channel":"/meta/subscribe","clientId":"c1","subscription":"/chat/*"}"#,
+        )
+        .unwrap();
+        let r = handle_message(&mut ctx, msg.into_iter().next().unwrap());
+        assert_eq!(r["error"], "405:/chat/*:Wildcard channels not supported");
+
+        // can't publish without a write grant
+        let msg =
+            parse_messages(br#"{"channel":"/chat/room1","clientId":"c1","data":{"text":"hi"}}"#)
+                .unwrap();
+        let r = handle_message(&mut ctx, msg.into_iter().next().unwrap());
+        assert_eq!(r["error"], "403:/chat/room1:Forbidden");
+
+        let msg = parse_messages(br#"{"channel":"/meta/connect","clientId":"c2"}"#).unwrap();
+        let r = handle_message(&mut ctx, msg.into_iter().next().unwrap());
+        assert_eq!(r["successful"], false);
+        assert_eq!(r["advice"]["reconnect"], "handshake");
+
+        let msg = parse_messages(
+            br#"{"channel":"/meta/unsubscribe","clientId":"c1","subscription":["/chat/room1"]}"#,
+        )
+        .unwrap();
+        let r = handle_message(&mut ctx, msg.into_iter().next().unwrap());
+        assert_eq!(r["successful"], true);
+        assert!(ctx.state.subs.is_empty());
+
+        let item = publish_item(
+            "t1/chat",
+            "chat",
+            br#"{"text":"hi"}"#,
+            &MessageMeta::default(),
+        )
+        .unwrap();
+        assert_eq!(item["channel"], "b:t1/chat");
+        assert_eq!(
+            item["formats"]["ws-message"]["content"],
+            r#"[{"channel":"/chat","data":{"text":"hi"}}]"#
+        );
+        assert!(publish_item("chat", "chat", &[0xff], &MessageMeta::default()).is_none());
+    }
+}
@@ -0,0 +1,684 @@
+use crate::deadline::Deadline;
+use crate::encryption::{self, Keys};
+use crate::log::Limiter;
+use crate::meta::MessageMeta;
+use crate::stats::Counts;
+use crate::storage::{
+    base64_data, IdempotencyRecord, IdempotentResult, PresenceChange, RetainedEntry, RetainedList,
+    RetainedMessage, RetainedSettings, RetainedSlot, RetainedVersion, RetainedWrite,
+    ScheduledMessage, Storage, StorageError, TransactionMessage,
+};
+use fastly::cache::simple;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// the contents of a slot version never change, so this only bounds how long
+// the cache holds on to them
+const SLOT_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedMessage {
+    // unix time in milliseconds at which retention ends, if it does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+
+    #[serde(with = "base64_data")]
+    data: Vec<u8>,
+
+    // the data is encrypted with this key, as it is in storage, so that
+    // the cache doesn't hold plaintext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+
+    meta: MessageMeta,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedEntry {
+    version: RetainedVersion,
+    message: CachedMessage,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedSlot {
+    version: RetainedVersion,
+    deleted: bool,
+    depth: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<CachedMessage>,
+
+    // all earlier messages kept as of the version
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    earlier: Vec<CachedEntry>,
+}
+
+fn now_ms() -> u64 {
+    (time::UtcDateTime::now().unix_timestamp_nanos() / 1_000_000) as u64
+}
+
+fn to_cached_message(keys: &Keys, m: &RetainedMessage, now_ms: u64) -> CachedMessage {
+    let (data, key_id) = match keys.current() {
+        Some(key) => (encryption::encrypt(key, &m.data), Some(key.id.clone())),
+        None => (m.data.clone(), None),
+    };
+
+    CachedMessage {
+        expires_at: m.ttl.map(|ttl| now_ms + ttl.as_millis() as u64),
+        data,
+        key_id,
+        meta: m.meta.clone(),
+    }
+}
+
+// returns None if the data can't be decrypted, such as after the key was
+// replaced
+fn decrypt_cached_message(keys: &Keys, m: &mut CachedMessage) -> Option<()> {
+    if let Some(id) = m.key_id.take() {
+        m.data = encryption::decrypt(keys.find(&id)?, &m.data)?;
+    }
+
+    Some(())
+}
+
+// messages that expired while cached are treated as if read expired
+fn from_cached_message(m: CachedMessage, now_ms: u64) -> Option<RetainedMessage> {
+    let ttl = match m.expires_at {
+        Some(at) if at <= now_ms => return None,
+        Some(at) => Some(Duration::from_millis(at - now_ms)),
+        None => None,
+    };
+
+    if m.meta.is_expired(now_ms / 1000) {
+        return None;
+    }
+
+    Some(RetainedMessage {
+        ttl,
+        data: m.data,
+        meta: m.meta,
+    })
+}
+
+fn to_cached(keys: &Keys, slot: &RetainedSlot, now_ms: u64) -> CachedSlot {
+    CachedSlot {
+        version: slot.version,
+        deleted: slot.deleted,
+        depth: slot.depth,
+        message: slot
+            .message
+            .as_ref()
+            .map(|m| to_cached_message(keys, m, now_ms)),
+        earlier: slot
+            .earlier
+            .iter()
+            .map(|e| CachedEntry {
+                version: e.version,
+                message: to_cached_message(keys, &e.message, now_ms),
+            })
+            .collect(),
+    }
+}
+
+// earlier messages are limited to those newer than after, as storage would.
+// slots that can't be decrypted are treated as misses
+fn from_cached(
+    keys: &Keys,
+    mut c: CachedSlot,
+    after: Option<RetainedVersion>,
+    now_ms: u64,
+) -> Option<RetainedSlot> {
+    if let Some(m) = &mut c.message {
+        decrypt_cached_message(keys, m)?;
+    }
+
+    for e in &mut c.earlier {
+        decrypt_cached_message(keys, &mut e.message)?;
+    }
+
+    let earlier = c
+        .earlier
+        .into_iter()
+        .filter(|e| is_newer(&e.version, after))
+        .filter_map(|e| {
+            Some(RetainedEntry {
+                version: e.version,
+                message: from_cached_message(e.message, now_ms)?,
+            })
+        })
+        .collect();
+
+    Some(RetainedSlot {
+        version: c.version,
+        message: c.message.and_then(|m| from_cached_message(m, now_ms)),
+        deleted: c.deleted,
+        depth: c.depth,
+        earlier,
+    })
+}
+
+// whether a cached version is known to be newer than what a reader has
+// seen. a different generation of unknown order doesn't count
+fn is_newer(v: &RetainedVersion, after: Option<RetainedVersion>) -> bool {
+    match after {
+        Some(after) => v.order(&after) == Some(Ordering::Greater),
+        None => true,
+    }
+}
+
+fn version_key(topic: &str) -> String {
+    format!("retained-version:{topic}")
+}
+
+fn slot_key(topic: &str, v: &RetainedVersion) -> String {
+    format!("retained-slot:{:016x}-{}:{topic}", v.generation, v.seq)
+}
+
+fn get_json<T: DeserializeOwned>(key: String) -> Option<T> {
+    let body = simple::get(key).ok()??;
+
+    serde_json::from_slice(&body.into_bytes()).ok()
+}
+
+// caches retained slots in the POP, so that bursts of durable subscribers
+// reading the same topic don't each go to storage. slots are cached by
+// version, along with a pointer to the latest version of each topic. the
+// pointer may be stale for up to its TTL, so it is only trusted to point at
+// something newer than what a reader has seen. learning that there is
+// nothing newer always takes a storage read. slots of topics that keep
+// several messages are only cached if read in full. cache errors are
+// treated as misses
+pub struct CachedStorage<'a> {
+    inner: &'a dyn Storage,
+    version_ttl: Duration,
+
+    // messages are cached encrypted with the current key, if any
+    keys: Keys,
+}
+
+impl<'a> CachedStorage<'a> {
+    pub fn new(inner: &'a dyn Storage, version_ttl: Duration) -> Self {
+        Self {
+            inner,
+            version_ttl,
+            keys: Keys::default(),
+        }
+    }
+
+    pub fn with_keys(mut self, keys: Keys) -> Self {
+        self.keys = keys;
+
+        self
+    }
+
+    fn cached_slot(&self, topic: &str, after: Option<RetainedVersion>) -> Option<RetainedSlot> {
+        let version: RetainedVersion = get_json(version_key(topic))?;
+
+        if !is_newer(&version, after) {
+            return None;
+        }
+
+        let c: CachedSlot = get_json(slot_key(topic, &version))?;
+
+        from_cached(&self.keys, c, after, now_ms())
+    }
+
+    // complete is whether the slot includes all earlier messages
+    fn store(&self, topic: &str, slot: &RetainedSlot, complete: bool) {
+        let ttl = match slot.message.as_ref().and_then(|m| m.ttl) {
+            Some(ttl) => ttl.min(SLOT_TTL),
+            None => SLOT_TTL,
+        };
+
+        if !ttl.is_zero() && (complete || slot.depth <= 1) {
+            let value = serde_json::to_vec(&to_cached(&self.keys, slot, now_ms()))
+                .expect("slot should always be serializable");
+
+            let _ = simple::get_or_set(slot_key(topic, &slot.version), value, ttl);
+        }
+
+        let key = version_key(topic);
+
+        if get_json::<RetainedVersion>(key.clone()) == Some(slot.version) {
+            return;
+        }
+
+        // entries can't be replaced, only removed
+        let _ = simple::purge(key.clone());
+
+        let value =
+            serde_json::to_vec(&slot.version).expect("version should always be serializable");
+
+        let _ = simple::get_or_set(key, value, self.version_ttl);
+    }
+
+    // caches a message that was just written
+    fn store_written(&self, w: &RetainedWrite, version: RetainedVersion) {
+        let slot = RetainedSlot {
+            version,
+            message: Some(RetainedMessage {
+                ttl: w.ttl,
+                data: w.message.to_vec(),
+                meta: w.meta.clone(),
+            }),
+            deleted: false,
+            depth: w.settings.depth,
+            earlier: Vec::new(),
+        };
+
+        self.store(w.topic, &slot, false);
+    }
+}
+
+impl Storage for CachedStorage<'_> {
+    fn write_retained(
+        &self,
+        topic: &str,
+        message: &[u8],
+        meta: &MessageMeta,
+        ttl: Option<Duration>,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<RetainedVersion, StorageError> {
+        let version = self
+            .inner
+            .write_retained(topic, message, meta, ttl, settings, deadline)?;
+
+        self.store_written(
+            &RetainedWrite {
+                topic,
+                message,
+                meta,
+                ttl,
+                settings,
+            },
+            version,
+        );
+
+        Ok(version)
+    }
+
+    fn write_retained_many(
+        &self,
+        writes: &[RetainedWrite],
+        deadline: Deadline,
+    ) -> Result<Vec<Result<RetainedVersion, StorageError>>, StorageError> {
+        let results = self.inner.write_retained_many(writes, deadline)?;
+
+        for (w, result) in writes.iter().zip(&results) {
+            if let Ok(version) = result {
+                self.store_written(w, *version);
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn delete_retained(
+        &self,
+        topic: &str,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Option<RetainedVersion>, StorageError> {
+        let version = self.inner.delete_retained(topic, settings, deadline)?;
+
+        if let Some(version) = version {
+            let slot = RetainedSlot {
+                version,
+                message: None,
+                deleted: true,
+                depth: settings.depth,
+                earlier: Vec::new(),
+            };
+
+            self.store(topic, &slot, true);
+        }
+
+        Ok(version)
+    }
+
+    // cached slots are only reachable through the version pointer
+    fn purge_retained(&self, topic: &str) -> Result<bool, StorageError> {
+        let purged = self.inner.purge_retained(topic)?;
+
+        let _ = simple::purge(version_key(topic));
+
+        Ok(purged)
+    }
+
+    fn read_retained(
+        &self,
+        topic: &str,
+        after: Option<RetainedVersion>,
+    ) -> Result<Option<RetainedSlot>, StorageError> {
+        if let Some(slot) = self.cached_slot(topic, after) {
+            return Ok(Some(slot));
+        }
+
+        let slot = self.inner.read_retained(topic, after)?;
+
+        if let Some(slot) = &slot {
+            self.store(topic, slot, after.is_none());
+        }
+
+        Ok(slot)
+    }
+
+    // misses are read from storage together
+    fn read_retained_many(
+        &self,
+        reads: &[(&str, Option<RetainedVersion>)],
+    ) -> Result<Vec<Option<RetainedSlot>>, StorageError> {
+        let mut out: Vec<Option<Option<RetainedSlot>>> = reads
+            .iter()
+            .map(|(topic, after)| self.cached_slot(topic, *after).map(Some))
+            .collect();
+
+        let misses: Vec<(&str, Option<RetainedVersion>)> = reads
+            .iter()
+            .zip(&out)
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(read, _)| *read)
+            .collect();
+
+        if !misses.is_empty() {
+            let mut slots = self.inner.read_retained_many(&misses)?.into_iter();
+
+            for ((topic, after), slot) in reads.iter().zip(&mut out) {
+                if slot.is_some() {
+                    continue;
+                }
+
+                let s = slots.next().flatten();
+
+                if let Some(s) = &s {
+                    self.store(topic, s, after.is_none());
+                }
+
+                *slot = Some(s);
+            }
+        }
+
+        Ok(out.into_iter().map(|slot| slot.flatten()).collect())
+    }
+
+    fn list_retained(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<RetainedList, StorageError> {
+        self.inner.list_retained(prefix, cursor, limit)
+    }
+
+    fn write_transaction(
+        &self,
+        messages: &[TransactionMessage],
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<RetainedVersion>, StorageError> {
+        let versions = self.inner.write_transaction(messages, settings, deadline)?;
+
+        for (m, version) in messages.iter().zip(&versions) {
+            let slot = RetainedSlot {
+                version: *version,
+                message: Some(RetainedMessage {
+                    ttl: m.ttl.map(|x| Duration::from_secs(x.into())),
+                    data: m.data.clone(),
+                    meta: m.meta.clone(),
+                }),
+                deleted: false,
+                depth: m.depth.max(1),
+                earlier: Vec::new(),
+            };
+
+            self.store(&m.topic, &slot, false);
+        }
+
+        Ok(versions)
+    }
+
+    fn write_cursors(
+        &self,
+        key: &str,
+        cursors: &HashMap<String, String>,
+    ) -> Result<(), StorageError> {
+        self.inner.write_cursors(key, cursors)
+    }
+
+    fn read_cursors(&self, key: &str) -> Result<Option<HashMap<String, String>>, StorageError> {
+        self.inner.read_cursors(key)
+    }
+
+    fn write_stream_topics(&self, cid: &str, topics: &[String]) -> Result<(), StorageError> {
+        self.inner.write_stream_topics(cid, topics)
+    }
+
+    fn read_stream_topics(&self, cid: &str) -> Result<Option<Vec<String>>, StorageError> {
+        self.inner.read_stream_topics(cid)
+    }
+
+    fn delete_stream_topics(&self, cid: &str) -> Result<(), StorageError> {
+        self.inner.delete_stream_topics(cid)
+    }
+
+    fn write_session(&self, id: &str, state: &[u8]) -> Result<(), StorageError> {
+        self.inner.write_session(id, state)
+    }
+
+    fn read_session(&self, id: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.read_session(id)
+    }
+
+    fn update_presence(
+        &self,
+        topic: &str,
+        member: &str,
+        expires_at: Option<u64>,
+        deadline: Deadline,
+    ) -> Result<PresenceChange, StorageError> {
+        self.inner
+            .update_presence(topic, member, expires_at, deadline)
+    }
+
+    fn read_presence(&self, topic: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.read_presence(topic)
+    }
+
+    fn read_public_keys(&self, topic: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.read_public_keys(topic)
+    }
+
+    fn record_nonce(&self, nonce: &str, ttl: Duration) -> Result<bool, StorageError> {
+        self.inner.record_nonce(nonce, ttl)
+    }
+
+    fn reserve_idempotency_key(
+        &self,
+        key: &str,
+        hash: &str,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        self.inner.reserve_idempotency_key(key, hash)
+    }
+
+    fn write_idempotent_result(
+        &self,
+        key: &str,
+        hash: &str,
+        result: &IdempotentResult,
+    ) -> Result<(), StorageError> {
+        self.inner.write_idempotent_result(key, hash, result)
+    }
+
+    fn release_idempotency_key(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.release_idempotency_key(key)
+    }
+
+    fn write_scheduled(
+        &self,
+        topic: &str,
+        message: &ScheduledMessage,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .write_scheduled(topic, message, settings, deadline)
+    }
+
+    fn take_scheduled(
+        &self,
+        topic: &str,
+        due_by: u64,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<ScheduledMessage>, StorageError> {
+        self.inner.take_scheduled(topic, due_by, settings, deadline)
+    }
+
+    fn list_scheduled_topics(&self) -> Result<Vec<String>, StorageError> {
+        self.inner.list_scheduled_topics()
+    }
+
+    fn count_publishes(
+        &self,
+        key: &str,
+        count: u32,
+        limit: u32,
+        deadline: Deadline,
+    ) -> Result<(), StorageError> {
+        self.inner.count_publishes(key, count, limit, deadline)
+    }
+
+    fn read_quota_usage(&self, key: &str, period: &str) -> Result<u64, StorageError> {
+        self.inner.read_quota_usage(key, period)
+    }
+
+    fn add_quota_usage(
+        &self,
+        key: &str,
+        period: &str,
+        count: u32,
+        ttl: Duration,
+        deadline: Deadline,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .add_quota_usage(key, period, count, ttl, deadline)
+    }
+
+    fn add_stats(&self, counts: &Counts, deadline: Deadline) -> Result<(), StorageError> {
+        self.inner.add_stats(counts, deadline)
+    }
+
+    fn read_stats(&self, minutes: &[u64]) -> Result<Vec<Counts>, StorageError> {
+        self.inner.read_stats(minutes)
+    }
+
+    fn append_audit_entry(&self, time: u64, data: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.append_audit_entry(time, data)
+    }
+
+    fn write_log_limiter(&self, limiter: &Limiter) -> Result<(), StorageError> {
+        self.inner.write_log_limiter(limiter)
+    }
+
+    fn read_log_limiter(&self) -> Result<Option<Limiter>, StorageError> {
+        self.inner.read_log_limiter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_slots() {
+        let v = RetainedVersion {
+            epoch: 1,
+            generation: 7,
+            seq: 3,
+            started: 0,
+        };
+
+        assert!(is_newer(&v, None));
+        assert!(is_newer(&v, Some(RetainedVersion { seq: 2, ..v })));
+        assert!(!is_newer(&v, Some(v)));
+        assert!(!is_newer(&v, Some(RetainedVersion { generation: 8, ..v })));
+
+        // generations created at known times
+        let v2 = RetainedVersion {
+            generation: 8,
+            seq: 1,
+            started: 2000,
+            ..v
+        };
+        assert!(is_newer(&v2, Some(RetainedVersion { started: 1000, ..v })));
+        assert!(!is_newer(&v2, Some(RetainedVersion { started: 3000, ..v })));
+
+        let slot = RetainedSlot {
+            version: v,
+            message: Some(RetainedMessage {
+                ttl: Some(Duration::from_secs(10)),
+                data: b"hello".to_vec(),
+                meta: MessageMeta::default(),
+            }),
+            deleted: false,
+            depth: 3,
+            earlier: [1, 2]
+                .into_iter()
+                .map(|seq| RetainedEntry {
+                    version: RetainedVersion { seq, ..v },
+                    message: RetainedMessage {
+                        ttl: None,
+                        data: format!("hello {seq}").into_bytes(),
+                        meta: MessageMeta::default(),
+                    },
+                })
+                .collect(),
+        };
+
+        let keys = Keys::default();
+
+        let c = to_cached(&keys, &slot, 1_000_000);
+        let json = serde_json::to_vec(&c).unwrap();
+        let c: CachedSlot = serde_json::from_slice(&json).unwrap();
+
+        let s = from_cached(&keys, c, None, 1_004_000).unwrap();
+        assert_eq!(s.version, v);
+        assert_eq!(s.depth, 3);
+        assert_eq!(s.earlier.len(), 2);
+        let m = s.message.unwrap();
+        assert_eq!(m.ttl, Some(Duration::from_secs(6)));
+        assert_eq!(m.data, b"hello");
+
+        // only earlier messages newer than after
+        let after = Some(RetainedVersion { seq: 1, ..v });
+        let s = from_cached(&keys, to_cached(&keys, &slot, 1_000_000), after, 1_004_000).unwrap();
+        assert_eq!(s.earlier.len(), 1);
+        assert_eq!(s.earlier[0].message.data, b"hello 2");
+
+        // expired while cached
+        let s = from_cached(&keys, to_cached(&keys, &slot, 1_000_000), None, 1_010_000).unwrap();
+        assert!(s.message.is_none());
+        assert!(!s.deleted);
+
+        // encrypted messages are cached as ciphertext
+        let keys = Keys::new(Some(b"notasecret"), None);
+
+        let c = to_cached(&keys, &slot, 1_000_000);
+        let m = c.message.as_ref().unwrap();
+        assert!(m.key_id.is_some());
+        assert_ne!(m.data, b"hello");
+        assert!(c.earlier.iter().all(|e| e.message.key_id.is_some()));
+
+        let s = from_cached(&keys, c, None, 1_004_000).unwrap();
+        assert_eq!(s.message.unwrap().data, b"hello");
+        assert_eq!(s.earlier[0].message.data, b"hello 1");
+
+        // a miss if the key is gone
+        let c = to_cached(&keys, &slot, 1_000_000);
+        assert!(from_cached(&Keys::default(), c, None, 1_004_000).is_none());
+    }
+}
@@ -0,0 +1,223 @@
+use base64::Engine;
+use std::str;
+
+// DER tags
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+
+// subject alternative names
+const TAG_SAN_EMAIL: u8 = 0x81;
+const TAG_SAN_DNS: u8 = 0x82;
+const TAG_SAN_URI: u8 = 0x86;
+
+// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+// returns tag, content, and the rest of the input
+fn read_tlv(src: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, src) = src.split_first()?;
+    let (&first, mut src) = src.split_first()?;
+
+    let len = if first & 0x80 == 0 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+
+        if count == 0 || count > 4 || src.len() < count {
+            return None;
+        }
+
+        let mut len = 0;
+        for &b in &src[..count] {
+            len = (len << 8) | b as usize;
+        }
+
+        src = &src[count..];
+
+        len
+    };
+
+    if src.len() < len {
+        return None;
+    }
+
+    Some((tag, &src[..len], &src[len..]))
+}
+
+// reads a value that must have the given tag
+fn expect(src: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match read_tlv(src)? {
+        (t, content, rest) if t == tag => Some((content, rest)),
+        _ => None,
+    }
+}
+
+fn tlvs(mut src: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (tag, content, rest) = read_tlv(src)?;
+        src = rest;
+
+        Some((tag, content))
+    })
+}
+
+fn common_name(name: &[u8]) -> Option<String> {
+    for (tag, rdn) in tlvs(name) {
+        if tag != TAG_SET {
+            continue;
+        }
+
+        for (tag, attr) in tlvs(rdn) {
+            if tag != TAG_SEQUENCE {
+                continue;
+            }
+
+            let Some((oid, rest)) = expect(attr, TAG_OID) else {
+                continue;
+            };
+
+            if oid != OID_COMMON_NAME {
+                continue;
+            }
+
+            // any of the string types. they are all treated as UTF-8
+            let (_, value, _) = read_tlv(rest)?;
+
+            return str::from_utf8(value).ok().map(|s| s.to_string());
+        }
+    }
+
+    None
+}
+
+fn alt_names(extensions: &[u8]) -> Vec<String> {
+    let mut out = Vec::new();
+
+    for (tag, ext) in tlvs(extensions) {
+        if tag != TAG_SEQUENCE {
+            continue;
+        }
+
+        let Some((oid, rest)) = expect(ext, TAG_OID) else {
+            continue;
+        };
+
+        if oid != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+
+        // skip the critical flag, if present
+        let value = tlvs(rest).find(|(tag, _)| *tag == TAG_OCTET_STRING);
+
+        let Some((names, _)) = value.and_then(|(_, v)| expect(v, TAG_SEQUENCE)) else {
+            continue;
+        };
+
+        for (tag, name) in tlvs(names) {
+            if [TAG_SAN_DNS, TAG_SAN_URI, TAG_SAN_EMAIL].contains(&tag) {
+                if let Ok(s) = str::from_utf8(name) {
+                    out.push(s.to_string());
+                }
+            }
+        }
+    }
+
+    out
+}
+
+// the names a certificate identifies its subject by: its subject alternative
+// names (DNS, URI and email), followed by its subject common name
+pub fn identities(der: &[u8]) -> Option<Vec<String>> {
+    let (cert, _) = expect(der, TAG_SEQUENCE)?;
+    let (tbs, _) = expect(cert, TAG_SEQUENCE)?;
+
+    let mut fields = tlvs(tbs).peekable();
+
+    // version
+    if fields.peek()?.0 == TAG_VERSION {
+        fields.next();
+    }
+
+    // serial number, signature algorithm, issuer, validity
+    for _ in 0..4 {
+        fields.next()?;
+    }
+
+    let (tag, subject) = fields.next()?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+
+    let mut out = match fields.find(|(tag, _)| *tag == TAG_EXTENSIONS) {
+        Some((_, exts)) => match expect(exts, TAG_SEQUENCE) {
+            Some((exts, _)) => alt_names(exts),
+            None => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+
+    if let Some(cn) = common_name(subject) {
+        out.push(cn);
+    }
+
+    Some(out)
+}
+
+// the first certificate in PEM form
+pub fn from_pem(pem: &str) -> Option<Vec<u8>> {
+    let start = pem.find("-----BEGIN CERTIFICATE-----")?;
+    let pem = &pem[(start + "-----BEGIN CERTIFICATE-----".len())..];
+    let end = pem.find("-----END CERTIFICATE-----")?;
+
+    let data: String = pem[..end]
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+
+    base64::prelude::BASE64_STANDARD.decode(data).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &str = concat!(
+        "-----BEGIN CERTIFICATE-----\n",
+        "MIIB5zCCAY2gAwIBAgIUH+Zf9EOxhZLl18+vNwvVE2xijsMwCgYIKoZIzj0EAwIw\n",
+        "JjEQMA4GA1UECgwHRXhhbXBsZTESMBAGA1UEAwwJZGV2aWNlLTQyMCAXDTI2MTAx\n",
+        "NjEwMzkwOVoYDzIxMjYwOTIyMTAzOTA5WjAmMRAwDgYDVQQKDAdFeGFtcGxlMRIw\n",
+        "EAYDVQQDDAlkZXZpY2UtNDIwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAASZJy7F\n",
+        "lWbsnKgv1oqbSH85IifinFkgQZc3WaKFBm2YTN/8xo2XZPkVWWkwCY/u9FnaL3nC\n",
+        "WOlWl6ZogiBSaGrVo4GWMIGTMB0GA1UdDgQWBBQ4SOAurFlc8o16nW7uXyo2y0De\n",
+        "oTAfBgNVHSMEGDAWgBQ4SOAurFlc8o16nW7uXyo2y0DeoTAPBgNVHRMBAf8EBTAD\n",
+        "AQH/MEAGA1UdEQQ5MDeCG2RldmljZS00Mi5mbGVldC5leGFtcGxlLmNvbYYYc3Bp\n",
+        "ZmZlOi8vZmxlZXQvZGV2aWNlLTQyMAoGCCqGSM49BAMCA0gAMEUCIQDz2evbiVf0\n",
+        "b5xXLOxhnucOYcmrFw0YxVim9fyuWKz+7QIgBnQWXZamZxmS/yLF0pBXcRhmXnZj\n",
+        "oHzjfGqL3tM6iHQ=\n",
+        "-----END CERTIFICATE-----\n",
+    );
+
+    #[test]
+    fn parse_identities() {
+        let der = from_pem(CERT).unwrap();
+
+        assert_eq!(
+            identities(&der).unwrap(),
+            vec![
+                "device-42.fleet.example.com",
+                "spiffe://fleet/device-42",
+                "device-42"
+            ]
+        );
+
+        assert!(identities(&der[..100]).is_none());
+        assert!(from_pem("foo").is_none());
+    }
+}
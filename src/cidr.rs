@@ -0,0 +1,126 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// a network, such as "10.0.0.0/8" or "2001:db8::/32". a plain address is
+// a network of one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidCidr;
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // ipv4 clients may be seen as ipv4-mapped ipv6 addresses
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let full = usize::from(prefix_len / 8);
+    let rest = prefix_len % 8;
+
+    if net[..full] != addr[..full] {
+        return false;
+    }
+
+    if rest == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - rest);
+
+    net[full] & mask == addr[full] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| InvalidCidr)?;
+
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| InvalidCidr)?,
+            None => bits,
+        };
+
+        if prefix_len > bits {
+            return Err(InvalidCidr);
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+// whether the address is in any of the networks
+pub fn any_contains(list: &[Cidr], addr: IpAddr) -> bool {
+    list.iter().any(|c| c.contains(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        let net: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(net.contains(ip("192.168.1.200")));
+        assert!(!net.contains(ip("192.168.1.100")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::5")));
+        assert!(!net.contains(ip("2001:db9::5")));
+
+        let net: Cidr = "203.0.113.7".parse().unwrap();
+        assert_eq!(net.to_string(), "203.0.113.7/32");
+        assert!(net.contains(ip("203.0.113.7")));
+        assert!(!net.contains(ip("203.0.113.8")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("198.51.100.1")));
+
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(InvalidCidr));
+        assert_eq!("10.0.0/8".parse::<Cidr>(), Err(InvalidCidr));
+        assert_eq!("example.com".parse::<Cidr>(), Err(InvalidCidr));
+    }
+}
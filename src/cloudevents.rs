@@ -0,0 +1,324 @@
+use crate::meta::{MessageMeta, USER_META_SIZE_MAX};
+use base64::Engine;
+use serde_json::{Map, Value};
+
+// CloudEvents attributes are kept in message metadata under names with
+// this prefix, as in the HTTP binary mode headers
+pub const ATTR_PREFIX: &str = "ce-";
+
+pub const SPEC_VERSION: &str = "1.0";
+
+const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+const BATCH_CONTENT_TYPE: &str = "application/cloudevents-batch+json";
+
+// type of events made from messages published without one
+const DEFAULT_TYPE: &str = "com.fastly.pubsub.message";
+
+const REQUIRED_ATTRS: [&str; 4] = ["specversion", "id", "source", "type"];
+
+// names are lowercase letters and digits, per the spec
+fn is_valid_attr_name(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 20 && s.bytes().all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9'))
+}
+
+fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or("").trim()
+}
+
+fn is_json(content_type: &str) -> bool {
+    let t = media_type(content_type);
+
+    t == "application/json" || t.ends_with("+json")
+}
+
+// the most body to accept for a message of at most size_max bytes. a
+// structured event encodes the data, as base64 or a JSON string with
+// escapes, and adds attributes around it. the data is checked once decoded
+pub fn body_size_max(content_type: &str, size_max: usize) -> usize {
+    if media_type(content_type).eq_ignore_ascii_case(STRUCTURED_CONTENT_TYPE) {
+        size_max * 2 + USER_META_SIZE_MAX * 2
+    } else {
+        size_max
+    }
+}
+
+// reads a publish request in either CloudEvents HTTP mode, adding the
+// event's attributes to meta and returning its data. requests that aren't
+// CloudEvents are returned as-is. headers are name and value pairs, as for
+// meta::parse
+pub fn read<'a, I>(
+    content_type: &str,
+    headers: I,
+    body: Vec<u8>,
+    meta: &mut MessageMeta,
+) -> Result<Vec<u8>, String>
+where
+    I: Iterator<Item = (&'a str, Option<&'a str>)>,
+{
+    if media_type(content_type).eq_ignore_ascii_case(BATCH_CONTENT_TYPE) {
+        return Err("CloudEvents batch mode not supported".to_string());
+    }
+
+    if media_type(content_type).eq_ignore_ascii_case(STRUCTURED_CONTENT_TYPE) {
+        return read_structured(&body, meta);
+    }
+
+    let headers: Vec<_> = headers.collect();
+
+    if headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("ce-specversion"))
+    {
+        read_binary(headers.into_iter(), meta)?;
+    }
+
+    Ok(body)
+}
+
+fn add_attrs(meta: &mut MessageMeta, attrs: Vec<(String, String)>) -> Result<(), String> {
+    for name in REQUIRED_ATTRS {
+        if !attrs.iter().any(|(k, _)| k == name) {
+            return Err(format!("Missing CloudEvents attribute: {name}"));
+        }
+    }
+
+    if let Some((_, v)) = attrs.iter().find(|(k, _)| k == "specversion") {
+        if v != SPEC_VERSION {
+            return Err(format!("Unsupported CloudEvents specversion: {v}"));
+        }
+    }
+
+    let mut size: usize = meta.user.iter().map(|(k, v)| k.len() + v.len()).sum();
+
+    for (name, value) in attrs {
+        let name = format!("{ATTR_PREFIX}{name}");
+
+        size += name.len() + value.len();
+
+        if size > USER_META_SIZE_MAX {
+            return Err(format!(
+                "Metadata exceeds {USER_META_SIZE_MAX} bytes maximum"
+            ));
+        }
+
+        meta.user.push((name, value));
+    }
+
+    Ok(())
+}
+
+// attributes are in ce-* headers, and the data is the body
+fn read_binary<'a, I>(headers: I, meta: &mut MessageMeta) -> Result<(), String>
+where
+    I: Iterator<Item = (&'a str, Option<&'a str>)>,
+{
+    let mut attrs = Vec::new();
+
+    for (name, value) in headers {
+        let lname = name.to_ascii_lowercase();
+
+        let Some(name) = lname.strip_prefix(ATTR_PREFIX) else {
+            continue;
+        };
+
+        if !is_valid_attr_name(name) {
+            return Err(format!("Invalid CloudEvents attribute name: {name}"));
+        }
+
+        let Some(value) = value else {
+            return Err(format!("Invalid value for CloudEvents attribute: {name}"));
+        };
+
+        attrs.push((name.to_string(), value.to_string()));
+    }
+
+    add_attrs(meta, attrs)
+}
+
+// the body is a JSON object holding the attributes and the data
+fn read_structured(body: &[u8], meta: &mut MessageMeta) -> Result<Vec<u8>, String> {
+    let Ok(Value::Object(event)) = serde_json::from_slice::<Value>(body) else {
+        return Err("Invalid CloudEvents JSON".to_string());
+    };
+
+    // the request's content type describes the envelope, not the data
+    meta.content_type = None;
+
+    let mut attrs = Vec::new();
+    let mut data = Vec::new();
+
+    for (name, value) in event {
+        match (name.as_str(), value) {
+            ("data", Value::String(s)) => data = s.into_bytes(),
+            ("data", v) => data = v.to_string().into_bytes(),
+            ("data_base64", Value::String(s)) => {
+                data = match base64::prelude::BASE64_STANDARD.decode(s) {
+                    Ok(v) => v,
+                    Err(e) => return Err(format!("Invalid CloudEvents data_base64: {e}")),
+                };
+            }
+            ("datacontenttype", Value::String(s)) => meta.content_type = Some(s),
+            // the distributed tracing extension
+            ("traceparent", Value::String(s)) => {
+                if meta.traceparent.is_none() {
+                    meta.traceparent = Some(s);
+                }
+            }
+            ("tracestate", _) => {}
+            (name, v) if is_valid_attr_name(name) => {
+                let value = match v {
+                    Value::String(s) => s,
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return Err(format!("Invalid value for CloudEvents attribute: {name}")),
+                };
+
+                attrs.push((name.to_string(), value));
+            }
+            (name, _) => return Err(format!("Invalid CloudEvents attribute name: {name}")),
+        }
+    }
+
+    add_attrs(meta, attrs)?;
+
+    Ok(data)
+}
+
+// renders a message as a structured mode event. messages published as
+// CloudEvents keep their attributes. others get attributes made from the
+// topic and the event ID, or a random ID for live messages
+pub fn event(topic: &str, id: Option<&str>, message: &[u8], meta: &MessageMeta) -> Value {
+    let mut event = Map::new();
+
+    for (name, value) in &meta.user {
+        if let Some(name) = name.strip_prefix(ATTR_PREFIX) {
+            event.insert(name.to_string(), Value::String(value.clone()));
+        }
+    }
+
+    event.insert("specversion".to_string(), SPEC_VERSION.into());
+
+    if !event.contains_key("id") {
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => hex::encode(rand::random::<[u8; 16]>()),
+        };
+
+        event.insert("id".to_string(), id.into());
+    }
+
+    if !event.contains_key("source") {
+        event.insert("source".to_string(), format!("/topics/{topic}").into());
+    }
+
+    if !event.contains_key("type") {
+        event.insert("type".to_string(), DEFAULT_TYPE.into());
+    }
+
+    if !event.contains_key("subject") {
+        event.insert("subject".to_string(), topic.into());
+    }
+
+    if let Some(t) = &meta.traceparent {
+        event.insert("traceparent".to_string(), t.clone().into());
+    }
+
+    let text = std::str::from_utf8(message).ok();
+
+    let content_type = match (&meta.content_type, text) {
+        (Some(t), _) => t.as_str(),
+        (None, Some(_)) => "text/plain",
+        (None, None) => "application/octet-stream",
+    };
+
+    event.insert("datacontenttype".to_string(), content_type.into());
+
+    let json = if is_json(content_type) {
+        serde_json::from_slice::<Value>(message).ok()
+    } else {
+        None
+    };
+
+    match (json, text) {
+        (Some(v), _) => {
+            event.insert("data".to_string(), v);
+        }
+        (None, Some(s)) => {
+            event.insert("data".to_string(), s.into());
+        }
+        (None, None) => {
+            let data = base64::prelude::BASE64_STANDARD.encode(message);
+            event.insert("data_base64".to_string(), data.into());
+        }
+    }
+
+    Value::Object(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn structured() {
+        let body = br#"{"specversion":"1.0","id":"e1","source":"/orders","type":"order.created","datacontenttype":"application/json","data":{"n":1},"priority":2}"#;
+
+        let mut meta = MessageMeta {
+            content_type: Some(STRUCTURED_CONTENT_TYPE.to_string()),
+            ..Default::default()
+        };
+
+        let data = read_structured(body, &mut meta).unwrap();
+        assert_eq!(data, br#"{"n":1}"#);
+        assert_eq!(meta.content_type.as_deref(), Some("application/json"));
+        assert!(meta.user.contains(&("ce-id".to_string(), "e1".to_string())));
+        assert!(meta
+            .user
+            .contains(&("ce-priority".to_string(), "2".to_string())));
+
+        let e = event("orders", Some("orders:a-1"), &data, &meta);
+        assert_eq!(e["id"], "e1");
+        assert_eq!(e["type"], "order.created");
+        assert_eq!(e["subject"], "orders");
+        assert_eq!(e["data"]["n"], 1);
+
+        let mut meta = MessageMeta::default();
+        assert!(read_structured(br#"{"specversion":"1.0","id":"e1"}"#, &mut meta).is_err());
+
+        let mut meta = MessageMeta::default();
+        let body = br#"{"specversion":"0.3","id":"e1","source":"/s","type":"t"}"#;
+        assert!(read_structured(body, &mut meta).is_err());
+
+        let mut meta = MessageMeta::default();
+        let body =
+            br#"{"specversion":"1.0","id":"e1","source":"/s","type":"t","data_base64":"/wA="}"#;
+        assert_eq!(read_structured(body, &mut meta).unwrap(), [0xff, 0x00]);
+    }
+
+    #[test]
+    fn binary() {
+        let headers = [
+            ("ce-specversion", Some("1.0")),
+            ("ce-id", Some("e1")),
+            ("ce-source", Some("/orders")),
+            ("ce-type", Some("order.created")),
+            ("content-type", Some("text/plain")),
+        ];
+
+        let mut meta = MessageMeta::default();
+        read_binary(headers.into_iter(), &mut meta).unwrap();
+        assert_eq!(meta.user.len(), 4);
+
+        let headers = [("ce-specversion", Some("1.0")), ("ce-Bad_Name", Some("x"))];
+        let mut meta = MessageMeta::default();
+        assert!(read_binary(headers.into_iter(), &mut meta).is_err());
+
+        // messages published without attributes get defaults
+        let e = event("orders", None, &[0xff], &MessageMeta::default());
+        assert_eq!(e["specversion"], "1.0");
+        assert_eq!(e["source"], "/topics/orders");
+        assert_eq!(e["type"], DEFAULT_TYPE);
+        assert_eq!(e["data_base64"], "/w==");
+        assert_eq!(e["id"].as_str().unwrap().len(), 32);
+    }
+}
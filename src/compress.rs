@@ -0,0 +1,141 @@
+#[cfg(feature = "fastly")]
+use fastly::http::header;
+#[cfg(feature = "fastly")]
+use fastly::{Request, Response};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+// not worth compressing below this size
+#[cfg(feature = "fastly")]
+const SIZE_MIN: usize = 1024;
+
+// whether an Accept-Encoding header value allows gzip
+pub fn gzip_acceptable(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|t| {
+        let mut parts = t.split(';');
+
+        let coding = parts.next().unwrap_or("").trim();
+
+        if coding != "gzip" && coding != "*" {
+            return false;
+        }
+
+        // a q-value of 0 means not acceptable
+        !parts.any(|p| {
+            let p = p.trim();
+
+            match p.strip_prefix("q=") {
+                Some(q) => q.parse::<f32>().map(|q| q == 0.0).unwrap_or(false),
+                None => false,
+            }
+        })
+    })
+}
+
+pub fn gzip_data(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+
+    e.write_all(data)?;
+
+    e.finish()
+}
+
+pub fn gunzip_data(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+
+    GzDecoder::new(data).read_to_end(&mut out)?;
+
+    Ok(out)
+}
+
+#[cfg(feature = "fastly")]
+pub fn accepts_gzip(req: &Request) -> bool {
+    match req.get_header_str(header::ACCEPT_ENCODING) {
+        Some(v) => gzip_acceptable(v),
+        None => false,
+    }
+}
+
+// compresses the body of a response. held responses are left as-is,
+// including any replay they start with, since published content is appended
+// to them or replaces them uncompressed. so are websocket-over-http events,
+// which Fanout reads itself
+#[cfg(feature = "fastly")]
+pub fn gzip(mut resp: Response) -> Response {
+    if resp.contains_header("Grip-Hold")
+        || resp.contains_header(header::CONTENT_ENCODING)
+        || resp.get_header_str(header::CONTENT_TYPE) == Some("application/websocket-events")
+    {
+        return resp;
+    }
+
+    resp.append_header(header::VARY, "Accept-Encoding");
+
+    let body = resp.take_body_bytes();
+
+    if body.len() < SIZE_MIN {
+        return resp.with_body(body);
+    }
+
+    let compressed = match gzip_data(&body) {
+        Ok(v) => v,
+        Err(_) => return resp.with_body(body),
+    };
+
+    resp.with_header(header::CONTENT_ENCODING, "gzip")
+        .with_body(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acceptable() {
+        assert!(gzip_acceptable("gzip"));
+        assert!(gzip_acceptable("deflate, gzip;q=0.8"));
+        assert!(gzip_acceptable("*"));
+        assert!(!gzip_acceptable("gzip;q=0"));
+        assert!(!gzip_acceptable("br, deflate"));
+        assert!(!gzip_acceptable("identity"));
+    }
+
+    #[test]
+    fn data() {
+        let data = "hello world. ".repeat(100);
+
+        let compressed = gzip_data(data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(gunzip_data(&compressed).unwrap(), data.as_bytes());
+
+        assert!(gunzip_data(b"not gzip").is_err());
+    }
+
+    #[cfg(feature = "fastly")]
+    #[test]
+    fn response() {
+        let replay = "event: message\ndata: hello world\n\n".repeat(100);
+
+        let resp = gzip(Response::new().with_body(replay.as_str()));
+        assert_eq!(resp.get_header_str(header::CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(
+            gunzip_data(&resp.into_body_bytes()).unwrap(),
+            replay.as_bytes()
+        );
+
+        // streams are never compressed, even when they start with a replay
+        let resp = gzip(
+            Response::new()
+                .with_header("Grip-Hold", "stream")
+                .with_body(replay.as_str()),
+        );
+        assert!(!resp.contains_header(header::CONTENT_ENCODING));
+        assert_eq!(resp.into_body_bytes(), replay.as_bytes());
+
+        // too small to be worth it
+        let resp = gzip(Response::new().with_body("hello"));
+        assert!(!resp.contains_header(header::CONTENT_ENCODING));
+    }
+}
@@ -1,12 +1,351 @@
+use crate::cidr::Cidr;
+use crate::log::{Level, ERROR_INTERVAL_DEFAULT};
+#[cfg(feature = "fastly")]
+use crate::namespace;
+use crate::sse;
+use crate::storage::{RetainedSettings, RETAINED_DEPTH_MAX};
+use crate::topic;
+#[cfg(feature = "fastly")]
 use fastly::{config_store, secret_store};
+use serde::{Deserialize, Serialize, Serializer};
+#[cfg(feature = "fastly")]
+use sha1::{Digest, Sha1};
+#[cfg(feature = "fastly")]
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "fastly")]
+use std::ops::RangeInclusive;
 use std::str;
+use std::time::Duration;
 
+// what to do with messages containing lines too long for SSE consumers
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LongLines {
+    #[default]
+    Allow,
+
+    // split across data fields, marking each continued line with a
+    // trailing backslash
+    Split,
+
+    // refuse to publish
+    Reject,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteAction {
+    // deliver to the derived topic in addition to the original topic
+    #[default]
+    Copy,
+
+    // deliver to the derived topic instead of the original topic
+    Move,
+}
+
+// routes JSON messages to a topic derived from one of their fields. any
+// "{value}" in the topic is replaced with the field's value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RouteRule {
+    // dotted path, e.g. "meta.region"
+    pub field: String,
+
+    // only route if the field has this value
+    #[serde(default)]
+    pub equals: Option<serde_json::Value>,
+
+    pub topic: String,
+
+    #[serde(default)]
+    pub action: RouteAction,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TopicConfig {
+    #[serde(default)]
+    pub long_lines: LongLines,
+
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+
+    // overrides of the global settings of the same names
+    #[serde(default)]
+    pub retained_linger: Option<u32>,
+
+    #[serde(default)]
+    pub write_tries_max: Option<u32>,
+
+    // how many of the latest messages to retain, up to RETAINED_DEPTH_MAX
+    #[serde(default)]
+    pub retain_depth: Option<u32>,
+
+    // whether messages published by clients must be signed with a key from
+    // the topic's public keys document. see signature.rs
+    #[serde(default)]
+    pub require_signature: bool,
+
+    // where clients may subscribe or publish from, as country codes or
+    // country and region codes, e.g. "DE" or "US-CA". see geo.rs
+    #[serde(default)]
+    pub subscribe_countries: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub publish_countries: Option<Vec<String>>,
+}
+
+// a key-value service to use for storage instead of the KV store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RemoteStorage {
+    pub url: String,
+
+    // backend to send requests through. if unset, one is created for the
+    // URL's host
+    pub backend: Option<String>,
+
+    #[serde(serialize_with = "redact_option")]
+    pub token: Option<String>,
+}
+
+// an HTTP ingestion endpoint that published messages are copied to, such
+// as a kafka REST proxy. see mirror.rs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mirror {
+    pub url: String,
+
+    // backend to send requests through. if unset, one is created for the
+    // URL's host
+    pub backend: Option<String>,
+
+    #[serde(serialize_with = "redact_option")]
+    pub token: Option<String>,
+}
+
+// an endpoint notified when clients subscribe to or unsubscribe from
+// matching topics. see webhook.rs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubscriptionWebhook {
+    pub url: String,
+
+    // backend to send requests through. if unset, one is created for the
+    // URL's host
+    pub backend: Option<String>,
+
+    // topic patterns, with wildcard levels as in ACLs. all topics if empty
+    pub topics: Vec<String>,
+
+    // for signing requests, as with delivery receipts
+    #[serde(serialize_with = "redact_option")]
+    pub key: Option<Vec<u8>>,
+}
+
+// a JSON Web Key Set to validate tokens against, in addition to the keys
+// in the keys store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Jwks {
+    pub url: String,
+
+    // backend to send requests through. if unset, one is created for the
+    // URL's host
+    pub backend: Option<String>,
+
+    // seconds to cache the key set for
+    pub cache_ttl: u32,
+}
+
+// requirements on app tokens beyond a valid signature. unset fields aren't
+// checked
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TokenValidation {
+    // the token's issuer must be one of these
+    pub issuers: Option<Vec<String>>,
+
+    // the token must be for one of these audiences
+    pub audiences: Option<Vec<String>>,
+
+    // seconds since the token was issued
+    pub max_age: Option<u32>,
+
+    // seconds of clock difference to tolerate when checking token times
+    pub clock_skew: Option<u32>,
+
+    pub require_expiration: bool,
+}
+
+// where a setting came from, if not its default
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SettingSource {
+    ConfigStore,
+    SecretStore,
+
+    // computed from other settings
+    Derived,
+}
+
+// secrets are reported as set or not, without their values
+fn redact_option<T, S: Serializer>(v: &Option<T>, s: S) -> Result<S::Ok, S::Error> {
+    match v {
+        Some(_) => s.serialize_str("[redacted]"),
+        None => s.serialize_none(),
+    }
+}
+
+fn redact_str<S: Serializer>(v: &str, s: S) -> Result<S::Ok, S::Error> {
+    if v.is_empty() {
+        s.serialize_none()
+    } else {
+        s.serialize_str("[redacted]")
+    }
+}
+
+#[derive(Serialize)]
 pub struct Config {
     pub sse_enabled: bool,
     pub http_publish_enabled: bool,
     pub mqtt_enabled: bool,
+
+    // bayeux clients, such as those of faye and cometd. see bayeux.rs
+    pub bayeux_enabled: bool,
+
+    // engine.io and socket.io clients. see socketio.rs
+    pub socketio_enabled: bool,
     pub admin_enabled: bool,
+
+    // check that linked resources exist on each request. see wiring::check
+    pub validate_wiring: bool,
+
+    // base path that all routes are under, e.g. "/pubsub/v1", or empty
+    pub route_prefix: String,
+
+    // namespaces of clients by the host they connect to. see namespace.rs
+    pub namespace_hosts: HashMap<String, String>,
+
+    pub remote_storage: Option<RemoteStorage>,
+
+    pub mirror: Option<Mirror>,
+
+    pub subscription_webhook: Option<SubscriptionWebhook>,
+
+    pub jwks: Option<Jwks>,
+
+    pub token_validation: TokenValidation,
+
+    // whether publishers can authenticate with a TLS client certificate
+    pub client_cert_auth: bool,
+
+    // whether to keep counts of activity for the stats endpoint
+    pub stats_enabled: bool,
+
+    // whether to track the subscribers of each topic. see presence.rs
+    pub presence_enabled: bool,
+
+    // where to record admin mutations: a log endpoint, and/or storage
+    pub audit_log_endpoint: Option<String>,
+    pub audit_kv: bool,
+
+    // lines below the level aren't logged. lines go to stdout unless a log
+    // endpoint is named
+    pub log_level: Level,
+    pub log_endpoint: Option<String>,
+
+    // seconds between logging repeats of an error line
+    pub log_error_interval: u32,
+
+    // a record for each request is written here, if set
+    pub metrics_log_endpoint: Option<String>,
+
+    // ID of the key in the keys store that tokens minted by the app are
+    // signed with
+    pub token_signing_key: Option<String>,
+
+    #[serde(serialize_with = "redact_str")]
     pub publish_token: String,
+
+    // for signing tickets in SSE next links
+    #[serde(serialize_with = "redact_option")]
+    pub ticket_key: Option<Vec<u8>>,
+    pub sse_keep_alive_timeout: u32,
+    pub sse_next_timeout: u32,
+    pub sync_time_budget_ms: u32,
+    pub request_time_budget_ms: u32,
+    pub sse_retry_ms: Option<u32>,
+    pub public_keys_max_age: u32,
+
+    // none means any origin is allowed
+    pub cors_allowed_origins: Option<Vec<String>>,
+
+    // networks that publishing and admin requests may come from. none
+    // means any client address is allowed
+    pub publish_allowed_ips: Option<Vec<Cidr>>,
+    pub admin_allowed_ips: Option<Vec<Cidr>>,
+
+    // hosts that publishers may have delivery receipts sent to. receipts
+    // are disabled unless this and the receipt key are set
+    pub receipt_hosts: Option<Vec<String>>,
+
+    // for signing delivery receipts
+    #[serde(serialize_with = "redact_option")]
+    pub receipt_key: Option<Vec<u8>>,
+
+    // for encrypting retained messages at rest, and for reading messages
+    // encrypted before the key was replaced. see encryption.rs
+    #[serde(serialize_with = "redact_option")]
+    pub retained_key: Option<Vec<u8>>,
+    #[serde(serialize_with = "redact_option")]
+    pub retained_previous_key: Option<Vec<u8>>,
+
+    pub sse_line_length_max: usize,
+
+    // whether durable SSE deliveries carry the message, rather than a hint
+    // that makes Fanout fetch it
+    pub sse_durable_content: bool,
+
+    // the SSE formats subscribers may use, besides plain. each rendering is
+    // published as an item of its own, so unused ones can be turned off
+    pub sse_formats: Vec<sse::Format>,
+
+    // whether subscribers may name events after their topic
+    pub sse_topic_event_names: bool,
+
+    // seconds to keep retained slots around after their messages expire
+    pub retained_linger: u32,
+
+    pub write_tries_max: u32,
+
+    // how long to cache the latest version of each topic's retained slot, or
+    // 0 to read it from storage every time. see cache::CachedStorage
+    pub retained_cache_ms: u32,
+
+    // settings for specific topics. settings for a topic also apply to the
+    // topics beneath it, unless overridden
+    pub topics: HashMap<String, TopicConfig>,
+
+    // what topic names clients may use. see topic::validate
+    pub topic_rules: topic::Rules,
+
+    // topics that can be subscribed to without a token, along with the
+    // topics beneath them
+    pub public_topics: Vec<String>,
+
+    // the most topics a connection can be subscribed to, in any transport
+    pub subscriptions_max: Option<u32>,
+
+    // if set, HTTP publishing requests must carry a timestamp within this
+    // many seconds of now, and a nonce not seen before
+    pub replay_window: Option<u32>,
+
+    // the most messages each publisher can publish via HTTP per UTC day
+    // and calendar month. see quota.rs
+    pub publish_quota_daily: Option<u32>,
+    pub publish_quota_monthly: Option<u32>,
+
+    // where each setting was read from, by store key. settings not listed
+    // have their defaults
+    #[serde(skip)]
+    pub sources: BTreeMap<String, SettingSource>,
 }
 
 impl Default for Config {
@@ -15,8 +354,171 @@ impl Default for Config {
             sse_enabled: true,
             http_publish_enabled: true,
             mqtt_enabled: true,
+            bayeux_enabled: false,
+            socketio_enabled: false,
             admin_enabled: true,
+            validate_wiring: false,
+            route_prefix: String::new(),
+            namespace_hosts: HashMap::new(),
+            remote_storage: None,
+            mirror: None,
+            subscription_webhook: None,
+            jwks: None,
+            token_validation: TokenValidation::default(),
+            client_cert_auth: false,
+            stats_enabled: false,
+            presence_enabled: false,
+            audit_log_endpoint: None,
+            audit_kv: false,
+            log_level: Level::Info,
+            log_endpoint: None,
+            log_error_interval: ERROR_INTERVAL_DEFAULT,
+            metrics_log_endpoint: None,
+            token_signing_key: None,
             publish_token: String::new(),
+            ticket_key: None,
+            sse_keep_alive_timeout: 55,
+            sse_next_timeout: 120,
+            sync_time_budget_ms: 2_000,
+            request_time_budget_ms: 10_000,
+            sse_retry_ms: None,
+            public_keys_max_age: 300,
+            cors_allowed_origins: None,
+            publish_allowed_ips: None,
+            admin_allowed_ips: None,
+            receipt_hosts: None,
+            receipt_key: None,
+            retained_key: None,
+            retained_previous_key: None,
+            sse_line_length_max: 16_384,
+            sse_durable_content: true,
+            sse_formats: sse::Format::ALL.to_vec(),
+            sse_topic_event_names: true,
+            retained_linger: 60 * 60 * 24,
+            write_tries_max: 5,
+            retained_cache_ms: 0,
+            topics: HashMap::new(),
+            topic_rules: topic::Rules::default(),
+            public_topics: Vec::new(),
+            subscriptions_max: None,
+            replay_window: None,
+            publish_quota_daily: None,
+            publish_quota_monthly: None,
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn topic_config(&self, t: &str) -> TopicConfig {
+        if let Some(c) = self.topics.get(t) {
+            return c.clone();
+        }
+
+        for a in topic::ancestors(t) {
+            if let Some(c) = self.topics.get(a) {
+                return c.clone();
+            }
+        }
+
+        TopicConfig::default()
+    }
+
+    // whether any topic limits where clients may subscribe or publish from
+    pub fn has_geo_policies(&self) -> bool {
+        self.topics
+            .values()
+            .any(|c| c.subscribe_countries.is_some() || c.publish_countries.is_some())
+    }
+
+    // the path of a request relative to the route prefix, or None if the
+    // request is outside of it
+    pub fn route_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.route_prefix.as_str())?;
+
+        if rest.is_empty() {
+            Some("/")
+        } else if rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    // settings that are likely mistakes, for reporting to operators
+    pub fn warnings(&self) -> Vec<String> {
+        let mut out = Vec::new();
+
+        if self.publish_token.is_empty() {
+            out.push("publish-token is not set, so messages can't be published".to_string());
+        }
+
+        if self.ticket_key.is_none() {
+            out.push(
+                "neither ticket-key nor publish-token is set, so SSE next links aren't limited to the subscriber's grants"
+                    .to_string(),
+            );
+        }
+
+        if self.receipt_hosts.is_some() && self.receipt_key.is_none() {
+            out.push(
+                "receipt-hosts is set but receipt-key is not, so receipts are disabled".to_string(),
+            );
+        }
+
+        if self.retained_previous_key.is_some() && self.retained_key.is_none() {
+            out.push(
+                "retained-previous-key is set but retained-key is not, so new retained messages aren't encrypted"
+                    .to_string(),
+            );
+        }
+
+        if !self.sse_enabled && !self.http_publish_enabled && !self.mqtt_enabled {
+            out.push("sse, http-publish and mqtt are all disabled".to_string());
+        }
+
+        out
+    }
+
+    // the most topics a connection can be subscribed to, given the limit of
+    // the subscriber's token if any. the lower of the two applies
+    pub fn subscriptions_max(&self, token_max: Option<usize>) -> Option<usize> {
+        let config_max = self.subscriptions_max.map(|x| x as usize);
+
+        match (config_max, token_max) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // the plain format with generic event names is always enabled, since
+    // its channel is shared with MQTT subscribers
+    pub fn sse_rendering_enabled(&self, opts: sse::Options) -> bool {
+        let format = opts.format == sse::Format::Plain || self.sse_formats.contains(&opts.format);
+
+        format && (opts.event_names == sse::EventNames::Generic || self.sse_topic_event_names)
+    }
+
+    // the SSE renderings messages are published in
+    pub fn sse_renderings(&self) -> impl Iterator<Item = sse::Options> + '_ {
+        sse::Options::all().filter(|opts| self.sse_rendering_enabled(*opts))
+    }
+
+    // the effective storage settings for a topic
+    pub fn retained_settings(&self, t: &str) -> RetainedSettings {
+        let tc = self.topic_config(t);
+
+        let linger = tc.retained_linger.unwrap_or(self.retained_linger);
+        let write_tries_max = tc.write_tries_max.unwrap_or(self.write_tries_max);
+        let depth = tc.retain_depth.unwrap_or(1);
+
+        RetainedSettings {
+            linger: Duration::from_secs(linger.into()),
+
+            // always try at least once
+            write_tries_max: write_tries_max.max(1) as usize,
+
+            depth: depth.clamp(1, RETAINED_DEPTH_MAX),
         }
     }
 }
@@ -27,12 +529,14 @@ pub enum ConfigError {
     InvalidValue,
 }
 
+#[cfg(feature = "fastly")]
 impl From<config_store::LookupError> for ConfigError {
     fn from(_e: config_store::LookupError) -> Self {
         Self::StoreError
     }
 }
 
+#[cfg(feature = "fastly")]
 fn str_to_bool(s: &str) -> Result<bool, ConfigError> {
     match s.parse() {
         Ok(b) => Ok(b),
@@ -40,15 +544,121 @@ fn str_to_bool(s: &str) -> Result<bool, ConfigError> {
     }
 }
 
+#[cfg(feature = "fastly")]
+fn str_to_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+#[cfg(feature = "fastly")]
+fn str_to_formats(s: &str) -> Result<Vec<sse::Format>, ConfigError> {
+    str_to_list(s)
+        .iter()
+        .map(|v| sse::Format::parse(v).map_err(|_| ConfigError::InvalidValue))
+        .collect()
+}
+
+#[cfg(feature = "fastly")]
+fn str_to_cidrs(s: &str) -> Result<Vec<Cidr>, ConfigError> {
+    str_to_list(s)
+        .iter()
+        .map(|v| v.parse().map_err(|_| ConfigError::InvalidValue))
+        .collect()
+}
+
+// a trailing slash is ignored, so "/" means no prefix
+#[cfg(feature = "fastly")]
+fn str_to_route_prefix(s: &str) -> Result<String, ConfigError> {
+    let s = s.trim().trim_end_matches('/');
+
+    if !s.is_empty() && !s.starts_with('/') {
+        return Err(ConfigError::InvalidValue);
+    }
+
+    if s.contains(['?', '#']) || s.contains("//") {
+        return Err(ConfigError::InvalidValue);
+    }
+
+    Ok(s.to_string())
+}
+
+#[cfg(feature = "fastly")]
+fn str_to_u32(s: &str) -> Result<u32, ConfigError> {
+    match s.parse() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(ConfigError::InvalidValue),
+    }
+}
+
+// long lines are split at sse-line-length-max, which must leave room for
+// more than a character and the continuation marker
+#[cfg(feature = "fastly")]
+const SSE_LINE_LENGTH_MIN: u32 = 64;
+
+// in seconds. keep-alives must be sent before idle connections are closed
+#[cfg(feature = "fastly")]
+const SSE_KEEP_ALIVE_TIMEOUT_RANGE: RangeInclusive<u32> = 1..=600;
+
+// in seconds
+#[cfg(feature = "fastly")]
+const SSE_NEXT_TIMEOUT_RANGE: RangeInclusive<u32> = 1..=3600;
+
+// in seconds, up to a day
+#[cfg(feature = "fastly")]
+const LOG_ERROR_INTERVAL_RANGE: RangeInclusive<u32> = 1..=86400;
+
+#[cfg(feature = "fastly")]
+fn str_to_u32_in(s: &str, range: RangeInclusive<u32>) -> Result<u32, ConfigError> {
+    let x = str_to_u32(s)?;
+
+    if !range.contains(&x) {
+        return Err(ConfigError::InvalidValue);
+    }
+
+    Ok(x)
+}
+
+// a config store that records which keys were found in it
+#[cfg(feature = "fastly")]
+struct TrackedConfigStore<'a> {
+    store: &'a config_store::ConfigStore,
+    found: RefCell<Vec<String>>,
+}
+
+#[cfg(feature = "fastly")]
+impl<'a> TrackedConfigStore<'a> {
+    fn new(store: &'a config_store::ConfigStore) -> Self {
+        Self {
+            store,
+            found: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn try_get(&self, key: &str) -> Result<Option<String>, config_store::LookupError> {
+        let v = self.store.try_get(key)?;
+
+        if v.is_some() {
+            self.found.borrow_mut().push(key.to_string());
+        }
+
+        Ok(v)
+    }
+}
+
 pub trait Source {
     fn config(&self) -> Result<Config, ConfigError>;
 }
 
+#[cfg(feature = "fastly")]
 pub struct ConfigAndSecretStoreSource {
     config_store_name: String,
     secret_store_name: String,
 }
 
+#[cfg(feature = "fastly")]
 impl ConfigAndSecretStoreSource {
     pub fn new(config_store_name: &str, secret_store_name: &str) -> Self {
         Self {
@@ -58,6 +668,7 @@ impl ConfigAndSecretStoreSource {
     }
 }
 
+#[cfg(feature = "fastly")]
 impl Source for ConfigAndSecretStoreSource {
     fn config(&self) -> Result<Config, ConfigError> {
         let config_store = match config_store::ConfigStore::try_open(&self.config_store_name) {
@@ -75,6 +686,8 @@ impl Source for ConfigAndSecretStoreSource {
         let mut config = Config::default();
 
         if let Some(store) = &config_store {
+            let store = TrackedConfigStore::new(store);
+
             if let Some(v) = store.try_get("sse")? {
                 config.sse_enabled = str_to_bool(&v)?;
             }
@@ -83,9 +696,271 @@ impl Source for ConfigAndSecretStoreSource {
                 config.http_publish_enabled = str_to_bool(&v)?;
             }
 
+            if let Some(v) = store.try_get("bayeux")? {
+                config.bayeux_enabled = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("socket-io")? {
+                config.socketio_enabled = str_to_bool(&v)?;
+            }
+
             if let Some(v) = store.try_get("admin")? {
                 config.admin_enabled = str_to_bool(&v)?;
             }
+
+            if let Some(v) = store.try_get("validate-wiring")? {
+                config.validate_wiring = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("route-prefix")? {
+                config.route_prefix = str_to_route_prefix(&v)?;
+            }
+
+            if let Some(v) = store.try_get("namespace-hosts")? {
+                let hosts: HashMap<String, String> = match serde_json::from_str(&v) {
+                    Ok(v) => v,
+                    Err(_) => return Err(ConfigError::InvalidValue),
+                };
+
+                if !hosts.values().all(|ns| namespace::is_valid(ns)) {
+                    return Err(ConfigError::InvalidValue);
+                }
+
+                config.namespace_hosts = hosts;
+            }
+
+            if let Some(url) = store.try_get("storage-url")? {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    return Err(ConfigError::InvalidValue);
+                }
+
+                config.remote_storage = Some(RemoteStorage {
+                    url,
+                    backend: store.try_get("storage-backend")?,
+                    token: None,
+                });
+            }
+
+            if let Some(url) = store.try_get("mirror-url")? {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    return Err(ConfigError::InvalidValue);
+                }
+
+                config.mirror = Some(Mirror {
+                    url,
+                    backend: store.try_get("mirror-backend")?,
+                    token: None,
+                });
+            }
+
+            if let Some(url) = store.try_get("subscription-webhook-url")? {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    return Err(ConfigError::InvalidValue);
+                }
+
+                let topics = match store.try_get("subscription-webhook-topics")? {
+                    Some(v) => str_to_list(&v),
+                    None => Vec::new(),
+                };
+
+                config.subscription_webhook = Some(SubscriptionWebhook {
+                    url,
+                    backend: store.try_get("subscription-webhook-backend")?,
+                    topics,
+                    key: None,
+                });
+            }
+
+            if let Some(url) = store.try_get("jwks-url")? {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    return Err(ConfigError::InvalidValue);
+                }
+
+                let cache_ttl = match store.try_get("jwks-cache-ttl")? {
+                    Some(v) => str_to_u32(&v)?,
+                    None => 600,
+                };
+
+                config.jwks = Some(Jwks {
+                    url,
+                    backend: store.try_get("jwks-backend")?,
+                    cache_ttl,
+                });
+            }
+
+            if let Some(v) = store.try_get("token-issuers")? {
+                config.token_validation.issuers = Some(str_to_list(&v));
+            }
+
+            if let Some(v) = store.try_get("token-audiences")? {
+                config.token_validation.audiences = Some(str_to_list(&v));
+            }
+
+            if let Some(v) = store.try_get("token-max-age")? {
+                config.token_validation.max_age = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("token-clock-skew")? {
+                config.token_validation.clock_skew = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("token-require-exp")? {
+                config.token_validation.require_expiration = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("client-cert-auth")? {
+                config.client_cert_auth = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("stats-enabled")? {
+                config.stats_enabled = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("presence-enabled")? {
+                config.presence_enabled = str_to_bool(&v)?;
+            }
+
+            config.audit_log_endpoint = store.try_get("audit-log-endpoint")?;
+
+            if let Some(v) = store.try_get("audit-kv")? {
+                config.audit_kv = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("log-level")? {
+                config.log_level = match Level::parse(&v) {
+                    Some(level) => level,
+                    None => return Err(ConfigError::InvalidValue),
+                };
+            }
+
+            config.log_endpoint = store.try_get("log-endpoint")?;
+
+            if let Some(v) = store.try_get("log-error-interval")? {
+                config.log_error_interval = str_to_u32_in(&v, LOG_ERROR_INTERVAL_RANGE)?;
+            }
+            config.metrics_log_endpoint = store.try_get("metrics-log-endpoint")?;
+
+            config.token_signing_key = store.try_get("token-signing-key")?;
+
+            if let Some(v) = store.try_get("sse-keep-alive-timeout")? {
+                config.sse_keep_alive_timeout = str_to_u32_in(&v, SSE_KEEP_ALIVE_TIMEOUT_RANGE)?;
+            }
+
+            if let Some(v) = store.try_get("sse-next-timeout")? {
+                config.sse_next_timeout = str_to_u32_in(&v, SSE_NEXT_TIMEOUT_RANGE)?;
+            }
+
+            if let Some(v) = store.try_get("sync-time-budget-ms")? {
+                config.sync_time_budget_ms = str_to_u32(&v)?;
+            }
+
+            if let Some(v) = store.try_get("request-time-budget-ms")? {
+                config.request_time_budget_ms = str_to_u32(&v)?;
+            }
+
+            if let Some(v) = store.try_get("sse-retry-ms")? {
+                config.sse_retry_ms = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("public-keys-max-age")? {
+                config.public_keys_max_age = str_to_u32(&v)?;
+            }
+
+            if let Some(v) = store.try_get("cors-allowed-origins")? {
+                let origins = str_to_list(&v);
+
+                if !origins.iter().any(|o| o == "*") {
+                    config.cors_allowed_origins = Some(origins);
+                }
+            }
+
+            if let Some(v) = store.try_get("publish-allowed-ips")? {
+                config.publish_allowed_ips = Some(str_to_cidrs(&v)?);
+            }
+
+            if let Some(v) = store.try_get("admin-allowed-ips")? {
+                config.admin_allowed_ips = Some(str_to_cidrs(&v)?);
+            }
+
+            if let Some(v) = store.try_get("receipt-hosts")? {
+                config.receipt_hosts = Some(str_to_list(&v));
+            }
+
+            if let Some(v) = store.try_get("sse-line-length-max")? {
+                config.sse_line_length_max =
+                    str_to_u32_in(&v, SSE_LINE_LENGTH_MIN..=u32::MAX)? as usize;
+            }
+
+            if let Some(v) = store.try_get("sse-durable-content")? {
+                config.sse_durable_content = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("sse-formats")? {
+                config.sse_formats = str_to_formats(&v)?;
+            }
+
+            if let Some(v) = store.try_get("sse-topic-event-names")? {
+                config.sse_topic_event_names = str_to_bool(&v)?;
+            }
+
+            if let Some(v) = store.try_get("retained-linger")? {
+                config.retained_linger = str_to_u32(&v)?;
+            }
+
+            if let Some(v) = store.try_get("write-tries-max")? {
+                config.write_tries_max = str_to_u32(&v)?;
+            }
+
+            if let Some(v) = store.try_get("retained-cache-ms")? {
+                config.retained_cache_ms = str_to_u32(&v)?;
+            }
+
+            if let Some(v) = store.try_get("topics")? {
+                config.topics = match serde_json::from_str(&v) {
+                    Ok(v) => v,
+                    Err(_) => return Err(ConfigError::InvalidValue),
+                };
+            }
+
+            if let Some(v) = store.try_get("public-topics")? {
+                config.public_topics = str_to_list(&v);
+            }
+
+            if let Some(v) = store.try_get("subscriptions-max")? {
+                config.subscriptions_max = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("replay-window")? {
+                config.replay_window = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("publish-quota-daily")? {
+                config.publish_quota_daily = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("publish-quota-monthly")? {
+                config.publish_quota_monthly = Some(str_to_u32(&v)?);
+            }
+
+            if let Some(v) = store.try_get("topic-length-max")? {
+                config.topic_rules.length_max = str_to_u32(&v)? as usize;
+            }
+
+            if let Some(v) = store.try_get("topic-depth-max")? {
+                config.topic_rules.depth_max = str_to_u32(&v)? as usize;
+            }
+
+            if let Some(v) = store.try_get("topic-allowed-chars")? {
+                config.topic_rules.allowed_chars = Some(v);
+            }
+
+            if let Some(v) = store.try_get("topic-reserved-prefixes")? {
+                config.topic_rules.reserved_prefixes = str_to_list(&v);
+            }
+
+            for key in store.found.take() {
+                config.sources.insert(key, SettingSource::ConfigStore);
+            }
         }
 
         if let Some(store) = &secret_store {
@@ -97,10 +972,130 @@ impl Source for ConfigAndSecretStoreSource {
                     };
 
                     config.publish_token = v;
+
+                    config
+                        .sources
+                        .insert("publish-token".to_string(), SettingSource::SecretStore);
+                }
+                Ok(None) => {}
+                Err(_) => return Err(ConfigError::StoreError),
+            }
+
+            match store.try_get("ticket-key") {
+                Ok(Some(v)) => {
+                    config.ticket_key = Some(v.plaintext().to_vec());
+
+                    config
+                        .sources
+                        .insert("ticket-key".to_string(), SettingSource::SecretStore);
+                }
+                Ok(None) => {}
+                Err(_) => return Err(ConfigError::StoreError),
+            }
+
+            match store.try_get("receipt-key") {
+                Ok(Some(v)) => {
+                    config.receipt_key = Some(v.plaintext().to_vec());
+
+                    config
+                        .sources
+                        .insert("receipt-key".to_string(), SettingSource::SecretStore);
+                }
+                Ok(None) => {}
+                Err(_) => return Err(ConfigError::StoreError),
+            }
+
+            match store.try_get("retained-key") {
+                Ok(Some(v)) => {
+                    config.retained_key = Some(v.plaintext().to_vec());
+
+                    config
+                        .sources
+                        .insert("retained-key".to_string(), SettingSource::SecretStore);
                 }
                 Ok(None) => {}
                 Err(_) => return Err(ConfigError::StoreError),
             }
+
+            match store.try_get("retained-previous-key") {
+                Ok(Some(v)) => {
+                    config.retained_previous_key = Some(v.plaintext().to_vec());
+
+                    config.sources.insert(
+                        "retained-previous-key".to_string(),
+                        SettingSource::SecretStore,
+                    );
+                }
+                Ok(None) => {}
+                Err(_) => return Err(ConfigError::StoreError),
+            }
+
+            if let Some(mirror) = &mut config.mirror {
+                match store.try_get("mirror-token") {
+                    Ok(Some(v)) => {
+                        let v = match str::from_utf8(&v.plaintext()) {
+                            Ok(s) => s.to_string(),
+                            Err(_) => return Err(ConfigError::InvalidValue),
+                        };
+
+                        mirror.token = Some(v);
+
+                        config
+                            .sources
+                            .insert("mirror-token".to_string(), SettingSource::SecretStore);
+                    }
+                    Ok(None) => {}
+                    Err(_) => return Err(ConfigError::StoreError),
+                }
+            }
+
+            if let Some(webhook) = &mut config.subscription_webhook {
+                match store.try_get("subscription-webhook-key") {
+                    Ok(Some(v)) => {
+                        webhook.key = Some(v.plaintext().to_vec());
+
+                        config.sources.insert(
+                            "subscription-webhook-key".to_string(),
+                            SettingSource::SecretStore,
+                        );
+                    }
+                    Ok(None) => {}
+                    Err(_) => return Err(ConfigError::StoreError),
+                }
+            }
+
+            if let Some(remote) = &mut config.remote_storage {
+                match store.try_get("storage-token") {
+                    Ok(Some(v)) => {
+                        let v = match str::from_utf8(&v.plaintext()) {
+                            Ok(s) => s.to_string(),
+                            Err(_) => return Err(ConfigError::InvalidValue),
+                        };
+
+                        remote.token = Some(v);
+
+                        config
+                            .sources
+                            .insert("storage-token".to_string(), SettingSource::SecretStore);
+                    }
+                    Ok(None) => {}
+                    Err(_) => return Err(ConfigError::StoreError),
+                }
+            }
+        }
+
+        // if no ticket key is set, derive one from the publish token, which
+        // is also secret
+        if config.ticket_key.is_none() && !config.publish_token.is_empty() {
+            let mut hasher = Sha1::new();
+            hasher.update(b"ticket-key\0");
+            hasher.update(config.publish_token.as_bytes());
+
+            config.ticket_key = Some(hasher.finalize().to_vec());
+
+            config
+                .sources
+                .insert("ticket-key".to_string(), SettingSource::Derived);
         }
 
         Ok(config)
@@ -111,6 +1106,142 @@ pub struct TestSource;
 
 impl Source for TestSource {
     fn config(&self) -> Result<Config, ConfigError> {
-        Ok(Config::default())
+        Ok(Config {
+            ticket_key: Some(b"notasecret".to_vec()),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retained_settings() {
+        let mut config = Config {
+            retained_linger: 600,
+            ..Default::default()
+        };
+
+        let tc: TopicConfig = serde_json::from_value(serde_json::json!({
+            "retained-linger": 60,
+            "write-tries-max": 0,
+            "retain-depth": 1000,
+        }))
+        .unwrap();
+
+        config.topics.insert("fruit".to_string(), tc);
+
+        let s = config.retained_settings("news");
+        assert_eq!(s.linger, Duration::from_secs(600));
+        assert_eq!(s.write_tries_max, 5);
+        assert_eq!(s.depth, 1);
+
+        // applies beneath the topic too
+        let s = config.retained_settings("fruit/apple");
+        assert_eq!(s.linger, Duration::from_secs(60));
+        assert_eq!(s.write_tries_max, 1);
+        assert_eq!(s.depth, RETAINED_DEPTH_MAX);
+    }
+
+    #[test]
+    fn report() {
+        let mut config = Config::default();
+        assert_eq!(config.warnings().len(), 2);
+
+        config.publish_token = "notasecret".to_string();
+        config.ticket_key = Some(b"notasecret".to_vec());
+        assert!(config.warnings().is_empty());
+
+        // secrets are only reported as set
+        let v = serde_json::to_value(&config).unwrap();
+        assert_eq!(v["publish_token"], "[redacted]");
+        assert_eq!(v["ticket_key"], "[redacted]");
+        assert_eq!(v["remote_storage"], serde_json::Value::Null);
+        assert_eq!(v["sse_keep_alive_timeout"], 55);
+    }
+
+    #[cfg(feature = "fastly")]
+    #[test]
+    fn route_prefix() {
+        let mut config = Config::default();
+        assert_eq!(config.route_path("/events"), Some("/events"));
+
+        config.route_prefix = str_to_route_prefix("/pubsub/v1/").unwrap();
+        assert_eq!(config.route_prefix, "/pubsub/v1");
+        assert_eq!(config.route_path("/pubsub/v1/events"), Some("/events"));
+        assert_eq!(config.route_path("/pubsub/v1"), Some("/"));
+        assert_eq!(config.route_path("/pubsub/v10/events"), None);
+        assert_eq!(config.route_path("/events"), None);
+
+        assert_eq!(str_to_route_prefix("/").unwrap(), "");
+        assert!(str_to_route_prefix("pubsub").is_err());
+        assert!(str_to_route_prefix("/a?b").is_err());
+    }
+
+    #[cfg(feature = "fastly")]
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            str_to_u32_in("64", SSE_LINE_LENGTH_MIN..=u32::MAX).unwrap(),
+            64
+        );
+        assert!(str_to_u32_in("4", SSE_LINE_LENGTH_MIN..=u32::MAX).is_err());
+        assert!(str_to_u32_in("-1", SSE_LINE_LENGTH_MIN..=u32::MAX).is_err());
+
+        assert_eq!(
+            str_to_u32_in("55", SSE_KEEP_ALIVE_TIMEOUT_RANGE).unwrap(),
+            55
+        );
+        assert!(str_to_u32_in("0", SSE_KEEP_ALIVE_TIMEOUT_RANGE).is_err());
+        assert!(str_to_u32_in("601", SSE_KEEP_ALIVE_TIMEOUT_RANGE).is_err());
+
+        assert_eq!(str_to_u32_in("120", SSE_NEXT_TIMEOUT_RANGE).unwrap(), 120);
+        assert!(str_to_u32_in("0", SSE_NEXT_TIMEOUT_RANGE).is_err());
+        assert!(str_to_u32_in("3601", SSE_NEXT_TIMEOUT_RANGE).is_err());
+
+        assert_eq!(str_to_u32_in("300", LOG_ERROR_INTERVAL_RANGE).unwrap(), 300);
+        assert!(str_to_u32_in("0", LOG_ERROR_INTERVAL_RANGE).is_err());
+        assert!(str_to_u32_in("86401", LOG_ERROR_INTERVAL_RANGE).is_err());
+    }
+
+    #[test]
+    fn sse_renderings() {
+        let mut config = Config::default();
+        assert_eq!(config.sse_renderings().count(), 7);
+
+        config.sse_formats = vec![sse::Format::Json];
+        config.sse_topic_event_names = false;
+
+        let renderings: Vec<sse::Options> = config.sse_renderings().collect();
+        assert_eq!(
+            renderings,
+            vec![
+                sse::Options::default(),
+                sse::Options::new(sse::Format::Json, sse::EventNames::Generic),
+            ]
+        );
+
+        assert!(!config.sse_rendering_enabled(sse::Options::new(
+            sse::Format::Ndjson,
+            sse::EventNames::Generic
+        )));
+        assert!(!config.sse_rendering_enabled(sse::Options::new(
+            sse::Format::Plain,
+            sse::EventNames::Topic
+        )));
+    }
+
+    #[test]
+    fn subscriptions_max() {
+        let mut config = Config::default();
+        assert_eq!(config.subscriptions_max(None), None);
+        assert_eq!(config.subscriptions_max(Some(5)), Some(5));
+
+        config.subscriptions_max = Some(3);
+        assert_eq!(config.subscriptions_max(None), Some(3));
+        assert_eq!(config.subscriptions_max(Some(5)), Some(3));
+        assert_eq!(config.subscriptions_max(Some(2)), Some(2));
     }
 }
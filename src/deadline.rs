@@ -0,0 +1,85 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("deadline exceeded")]
+pub struct DeadlineExceeded;
+
+// the point in time by which a request should be responded to. operations
+// that retry consult it before each retry, so that a slow dependency
+// results in a timely error rather than the platform timing out the request
+#[derive(Debug, Copy, Clone)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            at: Some(Instant::now() + budget),
+        }
+    }
+
+    pub fn none() -> Self {
+        Self { at: None }
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    // waits before retrying, for longer after each try, but not past the
+    // deadline. returns false if the deadline has been reached
+    pub fn backoff(&self, tries: u32, base: Duration) -> bool {
+        let mut delay = backoff_delay(tries, base);
+
+        if let Some(remaining) = self.remaining() {
+            if remaining.is_zero() {
+                return false;
+            }
+
+            delay = delay.min(remaining);
+        }
+
+        thread::sleep(delay);
+
+        !self.expired()
+    }
+}
+
+// doubles with each try, up to 16 times the base
+fn backoff_delay(tries: u32, base: Duration) -> Duration {
+    base * 2u32.pow(tries.saturating_sub(1).min(4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        assert!(!Deadline::none().expired());
+        assert_eq!(Deadline::none().remaining(), None);
+
+        assert!(!Deadline::new(Duration::from_secs(60)).expired());
+        assert!(Deadline::new(Duration::ZERO).expired());
+    }
+
+    #[test]
+    fn backoff() {
+        let base = Duration::from_millis(10);
+        assert_eq!(backoff_delay(1, base), base);
+        assert_eq!(backoff_delay(2, base), base * 2);
+        assert_eq!(backoff_delay(3, base), base * 4);
+        assert_eq!(backoff_delay(100, base), base * 16);
+
+        assert!(!Deadline::new(Duration::ZERO).backoff(1, base));
+        assert!(Deadline::none().backoff(1, Duration::from_millis(1)));
+    }
+}
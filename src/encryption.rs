@@ -0,0 +1,131 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+
+const NONCE_SIZE: usize = 12;
+
+// a key for encrypting retained messages at rest, derived from a secret
+#[derive(Clone)]
+pub struct Key {
+    // recorded with each value encrypted with the key, so that values can
+    // still be read after the key is replaced
+    pub id: String,
+
+    bytes: [u8; 32],
+}
+
+impl Key {
+    // the secret can be of any length. the ID is derived separately, so
+    // that it doesn't reveal anything about the key
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let id = hmac_sha256::HMAC::mac(b"retained-key-id", secret);
+
+        Self {
+            id: hex::encode(&id[..8]),
+            bytes: hmac_sha256::HMAC::mac(b"retained-key", secret),
+        }
+    }
+}
+
+// the keys a storage encrypts and decrypts with. without a current key,
+// values are stored unencrypted
+#[derive(Clone, Default)]
+pub struct Keys {
+    // for encrypting new values
+    current: Option<Key>,
+
+    // only for reading values encrypted before the current key was set
+    previous: Option<Key>,
+}
+
+impl Keys {
+    pub fn new(current: Option<&[u8]>, previous: Option<&[u8]>) -> Self {
+        Self {
+            current: current.map(Key::from_secret),
+            previous: previous.map(Key::from_secret),
+        }
+    }
+
+    pub fn current(&self) -> Option<&Key> {
+        self.current.as_ref()
+    }
+
+    pub fn find(&self, id: &str) -> Option<&Key> {
+        [&self.current, &self.previous]
+            .into_iter()
+            .flatten()
+            .find(|k| k.id == id)
+    }
+}
+
+// the nonce is random, and is stored in front of the ciphertext
+pub fn encrypt(key: &Key, data: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(&key.bytes.into());
+
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), data)
+        .expect("encryption should always succeed");
+
+    let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend(ciphertext);
+
+    out
+}
+
+// returns None if the value wasn't encrypted with the key or was altered
+pub fn decrypt(key: &Key, value: &[u8]) -> Option<Vec<u8>> {
+    if value.len() < NONCE_SIZE {
+        return None;
+    }
+
+    let (nonce, ciphertext) = value.split_at(NONCE_SIZE);
+
+    let cipher = Aes256Gcm::new(&key.bytes.into());
+
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = Key::from_secret(b"notasecret");
+        assert_eq!(key.id.len(), 16);
+        assert_eq!(key.id, Key::from_secret(b"notasecret").id);
+
+        let value = encrypt(&key, b"hello");
+        assert_ne!(&value[NONCE_SIZE..], b"hello");
+        assert_eq!(decrypt(&key, &value).unwrap(), b"hello");
+
+        // nonces differ
+        assert_ne!(encrypt(&key, b"hello"), value);
+
+        let other = Key::from_secret(b"othersecret");
+        assert_ne!(other.id, key.id);
+        assert!(decrypt(&other, &value).is_none());
+
+        let mut altered = value.clone();
+        altered[NONCE_SIZE] ^= 1;
+        assert!(decrypt(&key, &altered).is_none());
+
+        assert!(decrypt(&key, b"short").is_none());
+    }
+
+    #[test]
+    fn keys() {
+        let keys = Keys::new(Some(b"new"), Some(b"old"));
+
+        let current = keys.current().unwrap();
+        assert_eq!(current.id, Key::from_secret(b"new").id);
+
+        let old = Key::from_secret(b"old");
+        assert_eq!(keys.find(&old.id).unwrap().id, old.id);
+        assert!(keys.find("unknown").is_none());
+
+        assert!(Keys::default().current().is_none());
+    }
+}
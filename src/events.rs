@@ -1,33 +1,147 @@
-use crate::auth::{Authorization, AuthorizationError, Capabilities};
+use crate::auth::{
+    issue_ticket, validate_ticket, Authorization, AuthorizationError, Capabilities, SignedUrl,
+    TokenError,
+};
+use crate::cloudevents;
 use crate::config::Config;
-use crate::publish::{publish, Sequencing, MESSAGE_SIZE_MAX};
-use crate::storage::{RetainedVersion, Storage, StorageError};
+use crate::deadline::{Deadline, DeadlineExceeded};
+use crate::geo;
+use crate::grip::parse_grip_last;
+use crate::meta::MessageMeta;
+use crate::namespace;
+use crate::presence;
+use crate::problem::{self, Problem};
+use crate::publish::{
+    check_line_lengths, publish, publish_hint, read_body, sse_line_max, Batch, BodyError,
+    Sequencing, CONNECTION_CHANNEL_PREFIX, LARGE_MESSAGE_SIZE_MAX, MESSAGE_SIZE_MAX,
+};
+use crate::quota::{self, Usage};
+use crate::receipt::{self, Transport};
+use crate::routing;
+use crate::signature::{self, SignatureError};
+use crate::sse;
+use crate::stats::{self, Counter};
+use crate::storage::{
+    unix_now, IdempotentResult, RetainedMessage, RetainedSettings, RetainedVersion, RetainedWrite,
+    ScheduledMessage, Storage, StorageError, TransactionMessage, SCHEDULED_MAX,
+};
+use crate::topic;
+use crate::trace::TRACEPARENT;
+use crate::webhook;
+use crate::{log_error, log_info};
 use base64::Engine;
 use fastly::http::{header, StatusCode};
 use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 use std::collections::HashMap;
-use std::fmt::Write;
 use std::str;
 use std::time::Duration;
-use thiserror::Error;
 
 const TOPICS_PER_REQUEST_MAX: usize = 10;
-const NEXT_TIMEOUT_SECS: usize = 120;
+
+// bytes of a topic's hash in compact event IDs
+const TOPIC_HASH_SIZE: usize = 4;
+
+// messages in a transaction are encoded in JSON, as strings or base64, so
+// allow room for escapes and padding
+const TRANSACTION_BODY_SIZE_MAX: usize = TOPICS_PER_REQUEST_MAX * MESSAGE_SIZE_MAX * 2;
+const SUBSCRIPTION_NAME_LENGTH_MAX: usize = 64;
+const IDEMPOTENCY_KEY_LENGTH_MAX: usize = 255;
+
+// scheduled messages are kept in storage until delivered
+const DELAY_MAX: u32 = 60 * 60 * 24 * 7;
+
+// lets trusted publishers authenticate with an API key instead of a token
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+// sent when a retained message was stored but couldn't be published
+const PUBLISH_FAILED_WARNING: &str = "199 - \"Message retained but not published\"";
 
 struct VersionParseError;
 
+// the ID a retained version is delivered with
+pub fn version_id(v: &RetainedVersion) -> String {
+    Version::from(v).as_id()
+}
+
 #[derive(Debug, Copy, Clone)]
 struct Version {
+    epoch: u32,
+    started: u64,
     generation: u64,
     seq: u64,
 }
 
+impl From<&RetainedVersion> for Version {
+    fn from(v: &RetainedVersion) -> Self {
+        Self {
+            epoch: v.epoch,
+            started: v.started,
+            generation: v.generation,
+            seq: v.seq,
+        }
+    }
+}
+
+impl From<Version> for RetainedVersion {
+    fn from(v: Version) -> Self {
+        Self {
+            epoch: v.epoch,
+            generation: v.generation,
+            seq: v.seq,
+            started: v.started,
+        }
+    }
+}
+
 impl Version {
+    // the epoch is omitted if unknown, unless the generation's start time
+    // is known
     fn as_id(&self) -> String {
-        format!("{:16x}-{}", self.generation, self.seq)
+        if self.started > 0 {
+            format!(
+                "{}.{:x}.{:16x}-{}",
+                self.epoch, self.started, self.generation, self.seq
+            )
+        } else if self.epoch > 0 {
+            format!("{}.{:16x}-{}", self.epoch, self.generation, self.seq)
+        } else {
+            format!("{:16x}-{}", self.generation, self.seq)
+        }
+    }
+
+    // the previous version in the same generation
+    fn prev(&self) -> Self {
+        Self {
+            seq: self.seq - 1,
+            ..*self
+        }
     }
 
     fn parse(s: &str) -> Result<Self, VersionParseError> {
+        let (epoch, s) = match s.find('.') {
+            Some(pos) => {
+                let Ok(epoch) = s[..pos].parse() else {
+                    return Err(VersionParseError);
+                };
+
+                (epoch, &s[(pos + 1)..])
+            }
+            None => (0, s),
+        };
+
+        let (started, s) = match s.find('.') {
+            Some(pos) => {
+                let Ok(started) = u64::from_str_radix(&s[..pos], 16) else {
+                    return Err(VersionParseError);
+                };
+
+                (started, &s[(pos + 1)..])
+            }
+            None => (0, s),
+        };
+
         let pos = match s.find('-') {
             Some(pos) => pos,
             None => return Err(VersionParseError),
@@ -44,93 +158,241 @@ impl Version {
             return Err(VersionParseError);
         };
 
-        Ok(Self { generation, seq })
+        Ok(Self {
+            epoch,
+            started,
+            generation,
+            seq,
+        })
     }
 }
 
-#[derive(Error, Debug)]
-enum GripLastError<'a> {
-    #[error("invalid header: [{0}]")]
-    ParseHeader(&'a str),
-}
+// returns the bearer token from the Authorization header, or from the
+// 'auth' query parameter if allowed
+pub fn get_token(req: &Request, allow_param: bool) -> Result<Option<&str>, String> {
+    if allow_param {
+        if let Some(v) = req.get_query_parameter("auth") {
+            return Ok(Some(v));
+        }
+    }
 
-// if there is at least one Grip-Last header, this function is guaranteed
-// to return at least one item or error
-fn parse_grip_last(req: &Request) -> Result<Vec<(&str, &str)>, GripLastError<'_>> {
-    let mut out = Vec::new();
+    let Some(v) = req.get_header_str(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
 
-    for hvalue in req.get_header_all_str("Grip-Last") {
-        for value in hvalue.split(',') {
-            let Some(pos) = value.find(';') else {
-                return Err(GripLastError::ParseHeader(hvalue));
-            };
+    let Some(pos) = v.find(' ') else {
+        return Err("Invalid 'Authorization' header".to_string());
+    };
 
-            let channel = value[..pos].trim();
-            let params = &value[(pos + 1)..];
+    let scheme = &v[..pos];
+    let value = &v[(pos + 1)..];
 
-            let Some(pos) = params.find("last-id=") else {
-                return Err(GripLastError::ParseHeader(hvalue));
-            };
+    if scheme != "Bearer" {
+        return Err(format!("Unsupported authorization scheme: {scheme}"));
+    }
 
-            let remainder = &params[(pos + 8)..];
+    Ok(Some(value))
+}
 
-            let end = match remainder.find(';') {
-                Some(pos) => pos,
-                None => remainder.len(),
-            };
+fn is_valid_subscription_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= SUBSCRIPTION_NAME_LENGTH_MAX
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || ['-', '_', '.'].contains(&c))
+}
 
-            let id = remainder[..end].trim();
+// cursors are stored per token subject, so that callers can't see each
+// other's subscriptions. the key is hashed to keep it URL-safe
+fn cursor_key(subject: Option<&str>, name: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(subject.unwrap_or("").as_bytes());
+    hasher.update(b"\0");
+    hasher.update(name.as_bytes());
 
-            out.push((channel, id));
-        }
-    }
+    hex::encode(hasher.finalize())
+}
 
-    Ok(out)
+fn is_valid_idempotency_key(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= IDEMPOTENCY_KEY_LENGTH_MAX
+        && s.chars().all(|c| c.is_ascii_graphic())
 }
 
-fn text_response(status: StatusCode, text: &str) -> Response {
-    Response::from_status(status).with_body_text_plain(&format!("{text}\n"))
+// idempotency keys are scoped to the token subject and topic, so that
+// unrelated producers can't collide
+fn idempotency_key(subject: Option<&str>, topic: &str, key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(subject.unwrap_or("").as_bytes());
+    hasher.update(b"\0");
+    hasher.update(topic.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(key.as_bytes());
+
+    hex::encode(hasher.finalize())
 }
 
-fn sse_error(condition: &str, text: &str) -> Response {
-    let mut data = HashMap::new();
+// identifies the content of a publish, so that an idempotency key can't be
+// reused for a different message
+fn publish_hash(topic: &str, message: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(topic.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(message);
 
-    data.insert("condition".to_string(), condition.to_string());
-    data.insert("text".to_string(), text.to_string());
+    hex::encode(hasher.finalize())
+}
+
+fn is_valid_cid(s: &str) -> bool {
+    s.len() == 32 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
 
-    let data = serde_json::to_string(&data).unwrap();
+fn message_too_large(size_max: usize) -> Response {
+    Problem::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        &format!("Message size exceeds {size_max} bytes maximum"),
+    )
+    .with_code("message-too-large")
+    .response()
+}
 
+fn stream_error(format: sse::Format, condition: &str, text: &str) -> Response {
     Response::new()
-        .with_header(header::CONTENT_TYPE, "text/event-stream")
-        .with_body(format!("event: stream-error\ndata: {data}\n\n"))
+        .with_header(header::CONTENT_TYPE, format.content_type())
+        .with_body(sse::error_event(condition, text, format))
+}
+
+fn accepts_ndjson(req: &Request) -> bool {
+    let Some(accept) = req.get_header_str(header::ACCEPT) else {
+        return false;
+    };
+
+    accept.split(',').any(|t| {
+        let t = match t.find(';') {
+            Some(pos) => &t[..pos],
+            None => t,
+        };
+
+        t.trim() == "application/x-ndjson"
+    })
+}
+
+// escape content for a GRIP header value with format=cstring
+fn cstring_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
 }
 
-pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Response {
+pub fn get(config: &Config, auth: &Authorization, storage: &dyn Storage, req: Request) -> Response {
+    let format = match req.get_query_parameter("format") {
+        Some(s) => match sse::Format::parse(s) {
+            Ok(f) => f,
+            Err(_) => {
+                return stream_error(
+                    sse::Format::Plain,
+                    "bad-request",
+                    &format!("Unsupported format: {s}"),
+                )
+            }
+        },
+        None if accepts_ndjson(&req) => sse::Format::Ndjson,
+        // a JSON envelope with the message's metadata
+        None if req.get_query_parameter("meta") == Some("true") => sse::Format::Json,
+        None => sse::Format::Plain,
+    };
+
+    let event_names = match req.get_query_parameter("events") {
+        Some(s) => match sse::EventNames::parse(s) {
+            Ok(n) => n,
+            Err(_) => {
+                return stream_error(
+                    format,
+                    "bad-request",
+                    &format!("Unsupported 'events' param: {s}"),
+                )
+            }
+        },
+        None => sse::EventNames::Generic,
+    };
+
+    let opts = sse::Options::new(format, event_names);
+
+    if !config.sse_rendering_enabled(opts) {
+        return stream_error(
+            format,
+            "bad-request",
+            &format!(
+                "Format {} with '{}' events is not enabled",
+                format.as_str(),
+                opts.event_names.as_str()
+            ),
+        );
+    }
+
     let grip_last = match parse_grip_last(&req) {
         Ok(v) => v,
         Err(e) => {
-            println!("failed to parse Grip-Last: {e}");
+            log_error!("failed to parse Grip-Last: {e}");
 
             // close (200 w/o grip instructions when stream is open means close)
             return Response::new();
         }
     };
 
-    let is_next = !grip_last.is_empty();
+    // dynamic streams are identified by a connection ID, and their topics
+    // are kept in storage so that they can be changed while the stream is
+    // open. the ID is unguessable, so knowing it is sufficient to resume
+    let cid = match req.get_query_parameter("cid") {
+        Some(s) if is_valid_cid(s) => Some(s.to_string()),
+        Some(_) => return stream_error(format, "bad-request", "Invalid 'cid' parameter"),
+        None => None,
+    };
+
+    // the topics of a dynamic stream are looked up before anything else,
+    // so that an unknown ID doesn't make the request look like a next
+    // request
+    let stream_topics = match &cid {
+        Some(cid) => match storage.read_stream_topics(cid) {
+            Ok(Some(v)) => Some(v),
+            Ok(None) | Err(StorageError::StoreNotFound) => {
+                log_info!("stream topics not found");
+
+                if grip_last.is_empty() {
+                    return stream_error(format, "bad-request", "Unknown 'cid' parameter");
+                }
+
+                // close (200 w/o grip instructions when stream is open means close)
+                return Response::new();
+            }
+            Err(e) => {
+                log_error!("failed to read stream topics from storage: {e:?}");
+
+                // close (200 w/o grip instructions when stream is open means close)
+                return Response::new();
+            }
+        },
+        None => None,
+    };
+
+    let is_next = !grip_last.is_empty() || stream_topics.is_some();
 
     let mut topics = HashMap::new();
 
     if is_next {
+        let mut last_versions = HashMap::new();
+
+        // streams opened without durable content are subscribed to "d:"
+        let durable_prefix = opts.durable_channel_prefix();
+
         for &(channel, last_id) in &grip_last {
-            if !channel.starts_with("d:") {
+            let Some(topic) = channel
+                .strip_prefix("d:")
+                .or_else(|| channel.strip_prefix(durable_prefix.as_str()))
+            else {
                 continue;
-            }
-
-            let topic = &channel[2..];
+            };
 
             let version = if last_id != "none" {
                 let Ok(version) = Version::parse(last_id) else {
-                    println!("grip last ID not a valid version: [last_id]");
+                    log_error!("grip last ID not a valid version: [last_id]");
 
                     // close (200 w/o grip instructions when stream is open means close)
                     return Response::new();
@@ -141,14 +403,24 @@ pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Respons
                 None
             };
 
-            topics.insert(topic.to_string(), version);
+            last_versions.insert(topic.to_string(), version);
         }
 
-        if topics.is_empty() {
-            println!("no valid grip last topics");
+        if let Some(stream_topics) = stream_topics {
+            // topics added since the last request start without a version
+            for topic in stream_topics {
+                let version = last_versions.get(&topic).copied().flatten();
+                topics.insert(topic, version);
+            }
+        } else {
+            topics = last_versions;
 
-            // close (200 w/o grip instructions when stream is open means close)
-            return Response::new();
+            if topics.is_empty() {
+                log_info!("no valid grip last topics");
+
+                // close (200 w/o grip instructions when stream is open means close)
+                return Response::new();
+            }
         }
     } else {
         for (k, v) in req.get_url().query_pairs() {
@@ -158,14 +430,27 @@ pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Respons
         }
 
         if topics.is_empty() {
-            return sse_error("bad-request", "Missing 'topic' parameter");
+            return stream_error(format, "bad-request", "Missing 'topic' parameter");
+        }
+
+        for topic in topics.keys() {
+            if let Err(e) = topic::validate(&config.topic_rules, topic) {
+                return stream_error(
+                    format,
+                    "bad-request",
+                    &format!("Invalid topic {topic}: {e}"),
+                );
+            }
         }
     }
 
     if topics.len() >= TOPICS_PER_REQUEST_MAX {
-        return sse_error("bad-request", "Too many topics");
+        return stream_error(format, "bad-request", "Too many topics");
     }
 
+    // applied once topic names are resolved, since event IDs use full names
+    let mut last_event_versions = Vec::new();
+
     if !is_next {
         let last_event_id = if let Some(s) = req.get_query_parameter("lastEventId") {
             Some(s)
@@ -175,172 +460,343 @@ pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Respons
 
         if let Some(last_event_id) = last_event_id {
             for part in last_event_id.split(',') {
-                let Some(pos) = part.find(':') else {
-                    return sse_error("bad-request", "Last-Event-ID part missing ':'\n");
+                // parts of compact IDs name topics by hash
+                let (topic, version) = if let Some((hash, version)) = part.split_once(';') {
+                    (IdTopic::Hash(hash), version)
+                } else if let Some((topic, version)) = part.split_once(':') {
+                    (IdTopic::Name(topic), version)
+                } else {
+                    return stream_error(format, "bad-request", "Last-Event-ID part missing ':'\n");
                 };
 
-                let topic = &part[..pos];
-                let version = &part[(pos + 1)..];
+                // IDs built by Fanout include topics that have no message yet
+                if version == "none" {
+                    continue;
+                }
 
                 let Ok(version) = Version::parse(version) else {
-                    return sse_error(
+                    return stream_error(
+                        format,
                         "bad-request",
                         &format!("Last-Event-ID part not a valid version: [{version}]\n"),
                     );
                 };
 
-                if let Some(v) = topics.get_mut(topic) {
-                    *v = Some(version);
-                }
+                last_event_versions.push((topic, version));
             }
         }
     }
 
     let durable = req.get_query_parameter("durable") == Some("true");
 
-    let caps = if is_next || auth.fastly {
-        Capabilities::new_admin()
-    } else {
-        let token = if let Some(v) = req.get_query_parameter("auth") {
-            v
-        } else if let Some(v) = req.get_header_str(header::AUTHORIZATION) {
-            let pos = match v.find(' ') {
-                Some(pos) => pos,
-                None => return sse_error("bad-request", "Invalid 'Authorization' header"),
-            };
+    // whether the subscriber can receive messages larger than can be
+    // published
+    let large = req.get_query_parameter("large") == Some("true");
+
+    let dynamic = req.get_query_parameter("dynamic") == Some("true");
 
-            let scheme = &v[..pos];
-            let value = &v[(pos + 1)..];
+    // whether durable event IDs name topics by hash, to keep them short
+    let compact = req.get_query_parameter("compact") == Some("true");
 
-            if scheme != "Bearer" {
-                return sse_error(
+    let retry_ms = match req.get_query_parameter("retry") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                return stream_error(
+                    format,
                     "bad-request",
-                    &format!("Unsupported authorization scheme: {scheme}"),
-                );
+                    &format!("Invalid 'retry' param: {e}"),
+                )
             }
+        },
+        None => config.sse_retry_ms,
+    };
 
-            value
-        } else {
-            return sse_error(
+    // the cursor key of a named subscription, for next requests
+    let mut ticket_cursor_key = None;
+
+    let caps = if is_next {
+        let Some((caps, cursor_key)) = ticket_caps(config, &req, durable) else {
+            // close (200 w/o grip instructions when stream is open means close)
+            return Response::new();
+        };
+
+        ticket_cursor_key = cursor_key;
+
+        caps
+    } else if auth.fastly {
+        Capabilities::new_admin()
+    } else if let Some(sig) = req.get_query_parameter("sig") {
+        // pre-signed URL, as an alternative to a token in the query
+        let (Some(key_id), Some(expires)) = (
+            req.get_query_parameter("kid"),
+            req.get_query_parameter("expires"),
+        ) else {
+            return stream_error(
+                format,
                 "bad-request",
-                "Missing 'Authorization' header or 'auth' parameter",
+                "Signed URL missing 'kid' or 'expires' parameter",
             );
         };
 
-        let caps = match auth.app_token.validate_token(token) {
+        let Ok(expires) = expires.parse::<u64>() else {
+            return stream_error(format, "bad-request", "Invalid 'expires' parameter");
+        };
+
+        // in the order signed
+        let signed_topics: Vec<String> = req
+            .get_url()
+            .query_pairs()
+            .filter(|(k, _)| k == "topic")
+            .map(|(_, v)| v.to_string())
+            .collect();
+
+        let url = SignedUrl {
+            key_id,
+            expires,
+            topics: signed_topics.iter().map(|s| s.as_str()).collect(),
+            sig,
+        };
+
+        match auth.validate_signed_url(&url) {
+            Ok(caps) => caps,
+            Err(AuthorizationError::Token(_)) => {
+                return stream_error(format, "forbidden", "Invalid signature");
+            }
+            Err(e) => {
+                log_error!("auth failed: {e:?}");
+
+                return stream_error(format, "internal-server-error", "Auth process failed");
+            }
+        }
+    } else if !config.public_topics.is_empty() && matches!(get_token(&req, true), Ok(None)) {
+        // without a token, only public topics can be subscribed to
+        auth.anonymous(&config.public_topics)
+    } else {
+        let token = match get_token(&req, true) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return stream_error(
+                    format,
+                    "bad-request",
+                    "Missing 'Authorization' header or 'auth' parameter",
+                )
+            }
+            Err(e) => return stream_error(format, "bad-request", &e),
+        };
+
+        let caps = match auth.validate_token(token) {
             Ok(caps) => caps,
             Err(AuthorizationError::Token(_)) => {
-                return sse_error("forbidden", "Invalid token");
+                return stream_error(format, "forbidden", "Invalid token");
             }
             Err(e) => {
-                println!("auth failed: {e:?}");
+                log_error!("auth failed: {e:?}");
 
-                return sse_error("internal-server-error", "Auth process failed");
+                return stream_error(format, "internal-server-error", "Auth process failed");
             }
         };
 
         caps
     };
 
-    for topic in topics.keys() {
-        if !caps.can_subscribe(topic) {
-            return sse_error("forbidden", &format!("Cannot subscribe to topic: {topic}"));
-        }
+    // topics given by the client are relative to its namespace. topics of
+    // next requests are already full names
+    if !is_next {
+        topics = topics
+            .into_iter()
+            .map(|(topic, v)| (caps.resolve_topic(&topic), v))
+            .collect();
     }
 
-    let mut events = Vec::new();
+    // maps hashes in compact IDs back to the stream's topics
+    let hashed_topics: HashMap<String, String> = {
+        let keys: Vec<String> = topics.keys().cloned().collect();
 
-    if durable {
-        let mut keys: Vec<String> = topics.keys().cloned().collect();
-        keys.sort();
+        topic_hashes(&keys)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(topic, hash)| (hash, topic.to_string()))
+            .collect()
+    };
 
-        for topic in &keys {
-            let version = topics.get_mut(topic).unwrap();
+    for (topic, version) in last_event_versions {
+        let topic = match topic {
+            IdTopic::Name(name) => name,
+            IdTopic::Hash(hash) => match hashed_topics.get(hash) {
+                Some(name) => name.as_str(),
+                None => continue,
+            },
+        };
 
-            let after = version.map(|v| RetainedVersion {
-                generation: v.generation,
-                seq: v.seq,
-            });
+        if let Some(v) = topics.get_mut(topic) {
+            *v = Some(version);
+        }
+    }
 
-            let retained = match storage.read_retained(topic, after) {
-                Ok(Some(r)) => r,
-                Ok(None) | Err(StorageError::StoreNotFound) => continue,
-                Err(e) => {
-                    println!("failed to read message from storage: {e:?}");
+    // next requests come from Fanout without the client's location, which
+    // was checked when the stream opened
+    let location = geo::location(&req);
 
-                    return sse_error(
-                        "internal-server-error",
-                        "Failed to read message from storage",
-                    );
-                }
-            };
+    for topic in topics.keys() {
+        if !caps.can_subscribe(topic)
+            || (!is_next && !geo::can_subscribe(config, topic, location.as_ref()))
+        {
+            return stream_error(
+                format,
+                "forbidden",
+                &format!(
+                    "Cannot subscribe to topic: {}",
+                    namespace::strip(caps.namespace(), topic)
+                ),
+            );
+        }
+    }
 
-            let v = Version {
-                generation: retained.version.generation,
-                seq: retained.version.seq,
-            };
+    if let Some(max) = config.subscriptions_max(caps.max_subs()) {
+        if topics.len() > max {
+            return stream_error(
+                format,
+                "too-many-subscriptions",
+                &format!("Subscriptions are limited to {max} topics"),
+            );
+        }
+    }
 
-            *version = Some(v);
+    // durable subscribers can name their subscription, in which case the
+    // server records their cursors for later introspection. next requests
+    // take the key from their ticket, never from the link
+    let cursor_key = match req.get_query_parameter("subscription") {
+        _ if is_next => ticket_cursor_key,
+        Some(s) => {
+            if !is_valid_subscription_name(s) {
+                return stream_error(format, "bad-request", "Invalid 'subscription' parameter");
+            }
 
-            let Some(message) = retained.message else {
-                continue;
-            };
+            Some(cursor_key(caps.subject(), s))
+        }
+        None => None,
+    };
 
-            let id = {
-                let mut parts = Vec::new();
+    let cid = match cid {
+        Some(cid) => Some(cid),
+        None if dynamic => {
+            let cid = hex::encode(rand::random::<[u8; 16]>());
 
-                for topic in &keys {
-                    if let Some(v) = &topics[topic] {
-                        let id = v.as_id();
-                        parts.push(format!("{topic}:{id}"));
-                    }
-                }
+            let mut stream_topics: Vec<String> = topics.keys().cloned().collect();
+            stream_topics.sort();
 
-                parts.join(",")
-            };
+            if let Err(e) = storage.write_stream_topics(&cid, &stream_topics) {
+                log_error!("failed to write stream topics to storage: {e:?}");
 
-            let sse_content = match str::from_utf8(&message.data) {
-                Ok(s) => {
-                    let mut content = String::new();
-                    content.push_str("event: message\n");
-                    content.write_fmt(format_args!("id: {id}\n")).unwrap();
+                return stream_error(
+                    format,
+                    "internal-server-error",
+                    "Failed to write stream topics to storage",
+                );
+            }
 
-                    for line in s.split('\n') {
-                        content.write_fmt(format_args!("data: {line}\n")).unwrap();
-                    }
+            Some(cid)
+        }
+        None => None,
+    };
 
-                    content.push('\n');
+    let mut events = Vec::new();
 
-                    content
-                }
-                Err(_) => {
-                    let encoded = base64::prelude::BASE64_STANDARD.encode(message.data);
+    if durable {
+        events = match replay(
+            config,
+            storage,
+            &mut topics,
+            caps.namespace(),
+            opts,
+            large,
+            compact,
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                log_error!("failed to read message from storage: {e:?}");
 
-                    let mut content = String::new();
-                    content.push_str("event: message-base64\n");
-                    content.write_fmt(format_args!("id: {id}\n")).unwrap();
-                    content.push_str("data: ");
-                    content.push_str(&encoded);
-                    content.push_str("\n\n");
+                return stream_error(
+                    format,
+                    "internal-server-error",
+                    "Failed to read message from storage",
+                );
+            }
+        };
 
-                    content
-                }
-            };
+        if let Some(key) = &cursor_key {
+            let cursors = topics
+                .iter()
+                .map(|(topic, version)| {
+                    let id = match version {
+                        Some(v) => v.as_id(),
+                        None => "none".to_string(),
+                    };
+
+                    (topic.clone(), id)
+                })
+                .collect();
+
+            if let Err(e) = storage.write_cursors(key, &cursors) {
+                // not critical. only log
+                log_error!("failed to write cursors to storage: {e:?}");
+            }
+        }
+    }
+
+    // subscribers are identified by their stream, or else by their token's
+    // subject. anonymous streams are counted once, when they open
+    let member = match (&cid, caps.subject()) {
+        (Some(cid), _) => Some(cid.clone()),
+        (None, Some(sub)) => Some(sub.to_string()),
+        (None, None) if !is_next => Some(hex::encode(rand::random::<[u8; 8]>())),
+        (None, None) => None,
+    };
+
+    if let Some(member) = &member {
+        for topic in topics.keys() {
+            presence::joined(
+                config,
+                caps.namespace(),
+                topic,
+                member,
+                presence::SSE_MEMBER_TTL,
+            );
 
-            events.push(sse_content);
+            // next requests continue the same subscriptions
+            if !is_next {
+                webhook::changed(
+                    config,
+                    webhook::Action::Subscribe,
+                    topic,
+                    member,
+                    Transport::Sse,
+                );
+            }
         }
     }
 
+    let keep_alive = cstring_escape(&sse::signal_event("keep-alive", format));
+
     let mut resp = Response::new()
-        .with_header(header::CONTENT_TYPE, "text/event-stream")
+        .with_header(header::CONTENT_TYPE, format.content_type())
         .with_header("Grip-Hold", "stream")
         .with_header(
             "Grip-Keep-Alive",
-            "event: keep-alive\\ndata: \\n\\n; format=cstring; timeout=55",
+            format!(
+                "{keep_alive}; format=cstring; timeout={}",
+                config.sse_keep_alive_timeout
+            ),
         );
 
+    // the ID format can't name channels containing ')', so such streams
+    // are sent hints
+    let durable_content =
+        durable && config.sse_durable_content && topics.keys().all(|topic| !topic.contains(')'));
+
     for (topic, version) in &topics {
-        resp.append_header("Grip-Channel", format!("s:{topic}"));
+        resp.append_header("Grip-Channel", format!("{}{topic}", opts.channel_prefix()));
 
         if durable {
             let prev_id = match version {
@@ -348,21 +804,71 @@ pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Respons
                 None => "none".to_string(),
             };
 
-            resp.append_header("Grip-Channel", format!("d:{topic}; prev-id={prev_id}"));
+            if durable_content {
+                resp.append_header(
+                    "Grip-Channel",
+                    format!(
+                        "{}{topic}; prev-id={prev_id}; filter=build-id",
+                        opts.durable_channel_prefix()
+                    ),
+                );
+            } else {
+                resp.append_header("Grip-Channel", format!("d:{topic}; prev-id={prev_id}"));
+            }
         }
     }
 
-    if durable {
+    // the build-id filter makes event IDs from this format, the same as
+    // those of events sent in this response
+    if durable_content {
+        let mut keys: Vec<String> = topics.keys().cloned().collect();
+        keys.sort();
+
+        let hashes = if compact { topic_hashes(&keys) } else { None };
+
+        let id_format = stream_id_format(&keys, hashes.as_ref(), &opts.durable_channel_prefix());
+
+        resp.append_header(
+            "Grip-Set-Meta",
+            format!("id_format=\"{}\"", quote_escape(&id_format)),
+        );
+    }
+
+    if let Some(cid) = &cid {
+        resp.append_header("Grip-Channel", format!("x:{cid}"));
+        resp.append_header("Grip-Channel", format!("{CONNECTION_CHANNEL_PREFIX}{cid}"));
+    }
+
+    if durable || cid.is_some() {
+        let link = match next_link(
+            config,
+            &caps,
+            opts,
+            durable,
+            large,
+            compact,
+            cid.as_deref(),
+            cursor_key.as_deref(),
+        ) {
+            Ok(link) => link,
+            Err(e) => {
+                // the token expired
+                log_info!("failed to issue next link ticket: {e:?}");
+
+                return stream_error(format, "forbidden", "Token expired");
+            }
+        };
+
         resp.append_header(
             "Grip-Link",
-            format!("</events?durable=true>; rel=next; timeout={NEXT_TIMEOUT_SECS}"),
+            format!("<{link}>; rel=next; timeout={}", config.sse_next_timeout),
         );
     }
 
     let mut body = String::new();
 
     if !is_next {
-        body.push_str("event: stream-open\ndata: \n\n");
+        body.push_str(&sse::stream_open_event(retry_ms, cid.as_deref(), format));
     }
 
     for s in events {
@@ -372,135 +878,1783 @@ pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Respons
     resp.with_body(body)
 }
 
-pub fn post(
+// the capabilities of a next request, taken from the ticket in its link,
+// along with the cursor key of a named subscription. None if the stream
+// should be closed
+fn ticket_caps(
     config: &Config,
-    auth: &Authorization,
-    storage: &dyn Storage,
-    mut req: Request,
-) -> Response {
-    let body = req.take_body();
-
-    let Some(topic) = req.get_query_parameter("topic") else {
-        return text_response(StatusCode::BAD_REQUEST, "Missing 'topic' param");
-    };
+    req: &Request,
+    durable: bool,
+) -> Option<(Capabilities, Option<String>)> {
+    match (req.get_query_parameter("ticket"), &config.ticket_key) {
+        (Some(ticket), Some(key)) => match validate_ticket(key, ticket) {
+            Ok(t) if t.durable == durable => Some((t.caps, t.cursor_key)),
+            _ => {
+                // invalid or expired
+                log_info!("next link ticket not valid");
 
-    let retain = req.get_query_parameter("retain") == Some("true");
-
-    let ttl: Option<Duration> = match req.get_query_parameter("ttl") {
-        Some(x) => match x.parse::<u32>() {
-            Ok(x) => Some(Duration::from_secs(x.into())),
-            Err(e) => {
-                return text_response(
-                    StatusCode::BAD_REQUEST,
-                    &format!("Invalid 'ttl' param: {e}"),
-                )
+                None
             }
         },
-        None => None,
-    };
+        // links issued before tickets were configured. access is still
+        // limited to topics taken from grip last or stream storage
+        (_, None) => Some((Capabilities::new_admin(), None)),
+        (None, Some(_)) => {
+            log_info!("next link ticket missing");
 
-    let caps = if auth.fastly {
-        Capabilities::new_admin()
-    } else {
-        let token = if let Some(v) = req.get_header_str(header::AUTHORIZATION) {
-            let pos = match v.find(' ') {
-                Some(pos) => pos,
-                None => {
-                    return text_response(StatusCode::BAD_REQUEST, "Invalid 'Authorization' header")
-                }
-            };
+            None
+        }
+    }
+}
 
-            let scheme = &v[..pos];
-            let value = &v[(pos + 1)..];
+// the link Fanout follows to continue a stream. it repeats the stream's
+// options, and carries a ticket in place of the client's token
+#[allow(clippy::too_many_arguments)]
+fn next_link(
+    config: &Config,
+    caps: &Capabilities,
+    opts: sse::Options,
+    durable: bool,
+    large: bool,
+    compact: bool,
+    cid: Option<&str>,
+    cursor_key: Option<&str>,
+) -> Result<String, TokenError> {
+    let mut params = Vec::new();
 
-            if scheme != "Bearer" {
-                return text_response(
-                    StatusCode::BAD_REQUEST,
-                    &format!("Unsupported authorization scheme: {scheme}"),
-                );
-            }
+    if durable {
+        params.push("durable=true".to_string());
+    }
+
+    if large {
+        params.push("large=true".to_string());
+    }
+
+    if compact {
+        params.push("compact=true".to_string());
+    }
+
+    if opts.format != sse::Format::Plain {
+        params.push(format!("format={}", opts.format.as_str()));
+    }
+
+    if opts.event_names != sse::EventNames::Generic {
+        params.push(format!("events={}", opts.event_names.as_str()));
+    }
+
+    if let Some(cid) = cid {
+        params.push(format!("cid={cid}"));
+    }
+
+    if let Some(key) = &config.ticket_key {
+        let ticket = issue_ticket(key, caps, durable, cursor_key)?;
+
+        params.push(format!("ticket={ticket}"));
+    }
+
+    Ok(format!(
+        "{}/events?{}",
+        config.route_prefix,
+        params.join("&")
+    ))
+}
+
+// the events for retained messages newer than each topic's version, in a
+// durable stream. the versions are advanced to those of the events
+#[allow(clippy::too_many_arguments)]
+fn replay(
+    config: &Config,
+    storage: &dyn Storage,
+    topics: &mut HashMap<String, Option<Version>>,
+    namespace: Option<&str>,
+    opts: sse::Options,
+    large: bool,
+    compact: bool,
+) -> Result<Vec<String>, StorageError> {
+    let mut keys: Vec<String> = topics.keys().cloned().collect();
+    keys.sort();
+
+    // IDs fall back to topic names if hashes collide
+    let hashes = if compact { topic_hashes(&keys) } else { None };
+
+    let reads: Vec<(&str, Option<RetainedVersion>)> = keys
+        .iter()
+        .map(|topic| {
+            let after = topics[topic].map(RetainedVersion::from);
+
+            (topic.as_str(), after)
+        })
+        .collect();
+
+    // read all topics at once, rather than paying a round trip for each
+    let slots = match storage.read_retained_many(&reads) {
+        Ok(v) => v,
+        Err(StorageError::StoreNotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut events = Vec::new();
+
+    for ((topic, after), retained) in reads.iter().zip(slots) {
+        let Some(retained) = retained else {
+            continue;
+        };
+
+        // messages kept from before the latest come first, each with the ID
+        // of its own version
+        for entry in &retained.earlier {
+            *topics.get_mut(*topic).unwrap() = Some(Version::from(&entry.version));
+
+            let id = stream_id(&keys, topics, hashes.as_ref());
+
+            events.push(retained_event(
+                config,
+                topic,
+                namespace,
+                &id,
+                &entry.version,
+                Some(&entry.message),
+                opts,
+                large,
+            ));
+        }
+
+        *topics.get_mut(*topic).unwrap() = Some(Version::from(&retained.version));
+
+        // subscribers only need to hear about a deletion if they may have
+        // seen the message
+        if retained.message.is_none() && !(retained.deleted && after.is_some()) {
+            continue;
+        }
+
+        let id = stream_id(&keys, topics, hashes.as_ref());
+
+        events.push(retained_event(
+            config,
+            topic,
+            namespace,
+            &id,
+            &retained.version,
+            retained.message.as_ref(),
+            opts,
+            large,
+        ));
+    }
+
+    Ok(events)
+}
+
+// the ID of a durable stream event, made up of the last version of each
+// topic. if hashes are given, topics are named by them, as
+// "{HASH};{VERSION}" rather than "{TOPIC}:{VERSION}"
+fn stream_id(
+    keys: &[String],
+    topics: &HashMap<String, Option<Version>>,
+    hashes: Option<&HashMap<&str, String>>,
+) -> String {
+    let mut parts = Vec::new();
+
+    for topic in keys {
+        if let Some(v) = &topics[topic] {
+            let id = v.as_id();
+
+            match hashes {
+                Some(hashes) => parts.push(format!("{};{id}", hashes[topic.as_str()])),
+                None => parts.push(format!("{topic}:{id}")),
+            }
+        }
+    }
+
+    parts.join(",")
+}
+
+// the format of a stream's event IDs, for Fanout to fill in with the last
+// ID of each topic's channel, as "%(CHANNEL)s". topics without a message
+// yet get "none". channel names can't contain ')'
+fn stream_id_format(
+    keys: &[String],
+    hashes: Option<&HashMap<&str, String>>,
+    channel_prefix: &str,
+) -> String {
+    let mut parts = Vec::new();
+
+    for topic in keys {
+        let channel = format!("{channel_prefix}{topic}");
+
+        match hashes {
+            Some(hashes) => parts.push(format!("{};%({channel})s", hashes[topic.as_str()])),
+            None => parts.push(format!("{}:%({channel})s", topic.replace('%', "%%"))),
+        }
+    }
+
+    parts.join(",")
+}
+
+// escape a value for a quoted GRIP header parameter
+fn quote_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// how a part of a Last-Event-ID names its topic
+enum IdTopic<'a> {
+    Name(&'a str),
+    Hash(&'a str),
+}
+
+// short hashes of a stream's topics, for compact event IDs. a stream's
+// topics are known whenever it resumes, so the hashes can be mapped back to
+// them. None if any two collide
+fn topic_hashes(topics: &[String]) -> Option<HashMap<&str, String>> {
+    let mut hashes = HashMap::new();
+
+    for topic in topics {
+        let mut hasher = Sha1::new();
+        hasher.update(topic.as_bytes());
+
+        let hash = hex::encode(&hasher.finalize()[..TOPIC_HASH_SIZE]);
+
+        if hashes.values().any(|h| *h == hash) {
+            return None;
+        }
+
+        hashes.insert(topic.as_str(), hash);
+    }
+
+    Some(hashes)
+}
+
+// the event for a retained message, or for its deletion if None. the
+// topic is named relative to the subscriber's namespace
+#[allow(clippy::too_many_arguments)]
+fn retained_event(
+    config: &Config,
+    topic: &str,
+    namespace: Option<&str>,
+    id: &str,
+    version: &RetainedVersion,
+    message: Option<&RetainedMessage>,
+    opts: sse::Options,
+    large: bool,
+) -> String {
+    let name = namespace::strip(namespace, topic);
+
+    match message {
+        Some(message) if message.data.len() > MESSAGE_SIZE_MAX && !large => {
+            sse::too_large_event(name, id, message.data.len(), opts.format)
+        }
+        Some(message) => {
+            stats::incr(Counter::Deliveries, 1);
+            stats::incr(Counter::PayloadBytes, message.data.len() as u64);
+
+            receipt::delivered(topic, version, message, Transport::Sse);
+
+            sse::message_event(
+                name,
+                Some(id),
+                &message.data,
+                &message.meta,
+                opts,
+                sse_line_max(config, topic),
+            )
+        }
+        None => sse::deleted_event(name, id, opts.format),
+    }
+}
+
+// publishing requests must carry a token in the Authorization header,
+// unless they come from fastly
+fn publisher_caps(auth: &Authorization, req: &Request) -> Result<Capabilities, Problem> {
+    if auth.fastly {
+        return Ok(Capabilities::new_admin());
+    }
+
+    // trusted publishers can use an API key instead of a token, or, if they
+    // don't send a token, a client certificate
+    let result = if let Some(key) = req.get_header_str(API_KEY_HEADER) {
+        auth.validate_api_key(key)
+    } else if let Some(result) = req
+        .get_header_str(header::AUTHORIZATION)
+        .is_none()
+        .then(|| auth.validate_client_cert())
+        .flatten()
+    {
+        result
+    } else {
+        let token = match get_token(req, false) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return Err(
+                    Problem::new(StatusCode::BAD_REQUEST, "Missing 'Authorization' header")
+                        .with_code("missing-token"),
+                )
+            }
+            Err(e) => return Err(Problem::new(StatusCode::BAD_REQUEST, &e)),
+        };
+
+        auth.validate_token(token)
+    };
+
+    match result {
+        Ok(caps) => Ok(caps),
+        Err(AuthorizationError::Token(_)) => {
+            Err(Problem::new(StatusCode::FORBIDDEN, "Invalid token").with_code("invalid-token"))
+        }
+        Err(e) => {
+            log_error!("auth failed: {e:?}");
+
+            Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Auth process failed",
+            ))
+        }
+    }
+}
+
+// enforces the token's publish rate limit, if any. counting is best effort,
+// so publishes are allowed if the counter can't be updated
+fn check_publish_rate(
+    caps: &Capabilities,
+    storage: &dyn Storage,
+    count: u32,
+    deadline: Deadline,
+) -> Result<(), Problem> {
+    let Some((key, limit)) = caps.publish_limit() else {
+        return Ok(());
+    };
+
+    match storage.count_publishes(key, count, limit, deadline) {
+        Ok(()) | Err(StorageError::StoreNotFound) => Ok(()),
+        Err(StorageError::LimitReached) => Err(Problem::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            &format!("Token is limited to {limit} publishes per minute"),
+        )),
+        Err(e) => {
+            log_error!("failed to count publishes: {e:?}");
+
+            Ok(())
+        }
+    }
+}
+
+// checks publishes against the publisher's quotas, if any are configured.
+// like the rate limit, this is best effort. on success, the usage once the
+// publishes are counted is returned
+fn check_publish_quota(
+    config: &Config,
+    caps: &Capabilities,
+    storage: &dyn Storage,
+    count: u32,
+) -> Result<Option<Usage>, Usage> {
+    let Some(key) = caps.quota_key() else {
+        return Ok(None);
+    };
+
+    quota::check(config, storage, &key, count)
+}
+
+// counts publishes against the publisher's quotas, once they have been
+// published
+fn count_publish_quota(
+    config: &Config,
+    caps: &Capabilities,
+    storage: &dyn Storage,
+    count: u32,
+    deadline: Deadline,
+) {
+    if let Some(key) = caps.quota_key() {
+        quota::count(config, storage, &key, count, deadline);
+    }
+}
+
+fn quota_exceeded(u: &Usage) -> Response {
+    let mut resp = Problem::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        &format!("Publish quota of {} messages exceeded", u.limit),
+    )
+    .with_code("quota-exceeded")
+    .response();
+
+    resp.set_header(header::RETRY_AFTER, u.reset.to_string());
+    u.add_headers(&mut resp);
+
+    resp
+}
+
+const TIMESTAMP_HEADER: &str = "Pubsub-Timestamp";
+const NONCE_HEADER: &str = "Pubsub-Nonce";
+
+fn is_valid_nonce(s: &str) -> bool {
+    (16..=128).contains(&s.len())
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// if a replay window is configured, requests must carry a recent timestamp
+// and a nonce that hasn't been used within twice the window, so that a
+// captured request can't be sent again. unlike rate limiting, this fails
+// closed
+fn check_replay(config: &Config, storage: &dyn Storage, req: &Request) -> Result<(), Problem> {
+    let Some(window) = config.replay_window else {
+        return Ok(());
+    };
+
+    let Some(timestamp) = req
+        .get_header_str(TIMESTAMP_HEADER)
+        .and_then(|s| s.parse::<u64>().ok())
+    else {
+        return Err(Problem::new(
+            StatusCode::BAD_REQUEST,
+            &format!("Missing or invalid '{TIMESTAMP_HEADER}' header"),
+        ));
+    };
+
+    let Some(nonce) = req
+        .get_header_str(NONCE_HEADER)
+        .filter(|s| is_valid_nonce(s))
+    else {
+        return Err(Problem::new(
+            StatusCode::BAD_REQUEST,
+            &format!("Missing or invalid '{NONCE_HEADER}' header"),
+        ));
+    };
+
+    if unix_now().abs_diff(timestamp) > u64::from(window) {
+        return Err(
+            Problem::new(StatusCode::FORBIDDEN, "Request is stale").with_code("stale-request")
+        );
+    }
+
+    let ttl = Duration::from_secs(u64::from(window) * 2);
+
+    match storage.record_nonce(nonce, ttl) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Problem::new(StatusCode::FORBIDDEN, "Request was replayed")
+            .with_code("replayed-request")),
+        Err(e) => {
+            log_error!("failed to record nonce: {e:?}");
+
+            Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to record nonce",
+            ))
+        }
+    }
+}
+
+fn check_signature(
+    config: &Config,
+    storage: &dyn Storage,
+    topic: &str,
+    message: &[u8],
+    meta: &MessageMeta,
+) -> Result<(), Problem> {
+    match signature::check(config, storage, topic, message, meta) {
+        Ok(()) => Ok(()),
+        Err(SignatureError::Storage(e)) => {
+            log_error!("failed to read public keys from storage: {e:?}");
+
+            Err(Problem::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read public keys from storage",
+            ))
+        }
+        Err(e) => Err(
+            Problem::new(StatusCode::FORBIDDEN, &format!("Message not accepted: {e}"))
+                .with_code("message-rejected"),
+        ),
+    }
+}
+
+// responses to publishers with a quota say how much of it is left
+fn with_quota_headers(mut resp: Response, usage: Option<Usage>) -> Response {
+    if let Some(usage) = usage {
+        usage.add_headers(&mut resp);
+    }
+
+    resp
+}
+
+pub fn post(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    let mut usage = None;
+    let mut reserved = None;
+
+    let resp = post_message(
+        config,
+        auth,
+        storage,
+        deadline,
+        req,
+        &mut usage,
+        &mut reserved,
+    );
+
+    // an idempotency key left without a result is freed, so that the
+    // request can be retried
+    if let Some(key) = reserved {
+        if let Err(e) = storage.release_idempotency_key(&key) {
+            log_error!("failed to release idempotency key: {e:?}");
+        }
+    }
+
+    with_quota_headers(resp, usage)
+}
+
+fn post_message(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    mut req: Request,
+    usage: &mut Option<Usage>,
+    reserved: &mut Option<String>,
+) -> Response {
+    let Some(topic) = req.get_query_parameter("topic") else {
+        return problem::response(StatusCode::BAD_REQUEST, "Missing 'topic' param");
+    };
+
+    if let Err(e) = topic::validate_publish(&config.topic_rules, topic) {
+        return Problem::new(
+            StatusCode::BAD_REQUEST,
+            &format!("Invalid topic {topic}: {e}"),
+        )
+        .with_code("invalid-topic")
+        .response();
+    }
+
+    let retain = req.get_query_parameter("retain") == Some("true");
+
+    let ttl: Option<Duration> = match req.get_query_parameter("ttl") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(x) => Some(Duration::from_secs(x.into())),
+            Err(e) => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid 'ttl' param: {e}"),
+                )
+            }
+        },
+        None => None,
+    };
+
+    // how long the message may be delivered for, independent of retention
+    let expiry: Option<u32> = match req.get_query_parameter("expiry") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid 'expiry' param: {e}"),
+                )
+            }
+        },
+        None => None,
+    };
+
+    // zero means no delay
+    let delay = match req.get_query_parameter("delay") {
+        Some(x) => match x.parse::<u32>() {
+            Ok(x) if x > DELAY_MAX => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("'delay' param exceeds {DELAY_MAX} seconds maximum"),
+                )
+            }
+            Ok(x) => Some(x).filter(|&x| x > 0),
+            Err(e) => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid 'delay' param: {e}"),
+                )
+            }
+        },
+        None => None,
+    };
+
+    // deliveries can only be seen for messages delivered from storage
+    let receipt_url = match req.get_query_parameter("receipt") {
+        Some(_) if !retain => {
+            return problem::response(StatusCode::BAD_REQUEST, "'receipt' param requires 'retain'")
+        }
+        Some(url) => match receipt::check_url(config, url) {
+            Ok(()) => Some(url.to_string()),
+            Err(e) => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid 'receipt' param: {e}"),
+                )
+            }
+        },
+        None => None,
+    };
+
+    let caps = match publisher_caps(auth, &req) {
+        Ok(caps) => caps,
+        Err(e) => return e.response(),
+    };
+
+    if let Err(e) = check_replay(config, storage, &req) {
+        return e.response();
+    }
+
+    let name = topic;
+    let topic = &caps.resolve_topic(name);
+
+    let location = geo::location(&req);
+
+    if !caps.can_publish(topic) || !geo::can_publish(config, topic, location.as_ref()) {
+        return Problem::new(
+            StatusCode::FORBIDDEN,
+            &format!("Cannot publish to topic: {name}"),
+        )
+        .with_code("publish-not-allowed")
+        .response();
+    }
+
+    if let Err(e) = check_publish_rate(&caps, storage, 1, deadline) {
+        return e.response();
+    }
+
+    let quota_usage = match check_publish_quota(config, &caps, storage, 1) {
+        Ok(u) => u,
+        Err(u) => return quota_exceeded(&u),
+    };
+
+    let idempotency_key = match req.get_header_str("Idempotency-Key") {
+        Some(s) if is_valid_idempotency_key(s) => Some(idempotency_key(caps.subject(), topic, s)),
+        Some(_) => {
+            return problem::response(StatusCode::BAD_REQUEST, "Invalid 'Idempotency-Key' header")
+        }
+        None => None,
+    };
+
+    let mut meta = match MessageMeta::from_request(&req) {
+        Ok(m) => m,
+        Err(e) => return problem::response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    meta.start_span(topic);
+
+    meta.receipt = receipt_url;
+
+    // delayed messages expire relative to when they are due
+    if let Some(expiry) = expiry {
+        meta.expires_at = Some(unix_now() + u64::from(delay.unwrap_or(0)) + u64::from(expiry));
+    }
+
+    // scheduled messages are stored together, so they must stay small
+    let size_max = if retain && delay.is_none() {
+        LARGE_MESSAGE_SIZE_MAX
+    } else {
+        MESSAGE_SIZE_MAX
+    };
+
+    let body_max = cloudevents::body_size_max(
+        req.get_header_str(header::CONTENT_TYPE).unwrap_or(""),
+        size_max,
+    );
+
+    // the body is read only up to the limit, so that oversized messages
+    // are rejected without buffering them
+    let body = match read_body(&mut req, body_max) {
+        Ok(body) => body,
+        Err(BodyError::TooLarge) => return message_too_large(size_max),
+        Err(e) => return problem::body_error(&e, body_max),
+    };
+
+    let content_type = req.get_header_str(header::CONTENT_TYPE).unwrap_or("");
+
+    // CloudEvents attributes become metadata
+    let headers = req
+        .get_headers()
+        .map(|(name, value)| (name.as_str(), value.to_str().ok()));
+
+    let message = match cloudevents::read(content_type, headers, body, &mut meta) {
+        Ok(m) => m,
+        Err(e) => return problem::response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    if message.len() > size_max {
+        return message_too_large(size_max);
+    }
+
+    if let Err(e) = check_signature(config, storage, topic, &message, &meta) {
+        return e.response();
+    }
+
+    // routing rules may deliver the message to other topics
+    let targets = routing::route(config, caps.namespace(), topic, &message, |t| {
+        caps.can_publish(t) && geo::can_publish(config, t, location.as_ref())
+    });
+
+    for target in &targets {
+        if !check_line_lengths(config, target, &message) {
+            return Problem::new(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "Message has a line exceeding {} bytes, which is not allowed for this topic",
+                    config.sse_line_length_max
+                ),
+            )
+            .with_code("line-too-long")
+            .response();
+        }
+    }
+
+    let hash = publish_hash(topic, &message);
+
+    // the key is reserved before publishing, so that concurrent retries
+    // don't both publish
+    if let Some(key) = &idempotency_key {
+        match storage.reserve_idempotency_key(key, &hash) {
+            Ok(None) => *reserved = Some(key.clone()),
+            Ok(Some(r)) if r.hash != hash => {
+                return Problem::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Idempotency-Key was used for a different message",
+                )
+                .with_code("idempotency-key-reused")
+                .response();
+            }
+            Ok(Some(r)) => {
+                let Some(r) = r.result else {
+                    return problem::response(
+                        StatusCode::CONFLICT,
+                        "A request with this Idempotency-Key is in progress",
+                    );
+                };
+
+                let status = StatusCode::from_u16(r.status).unwrap_or(StatusCode::OK);
+
+                let resp = match &r.content_type {
+                    Some(t) => Response::from_status(status)
+                        .with_header(header::CONTENT_TYPE, t)
+                        .with_body(r.body),
+                    None => Response::from_status(status).with_body_text_plain(&r.body),
+                };
+
+                return resp.with_header("Idempotent-Replayed", "true");
+            }
+            Err(StorageError::StoreNotFound) => {}
+            Err(e) => {
+                // not critical. only log
+                log_error!("failed to reserve idempotency key in storage: {e:?}");
+            }
+        }
+    }
+
+    let mut publish_failed = false;
+
+    let (status, body, content_type) = if let Some(delay) = delay {
+        let m = ScheduledMessage {
+            due: unix_now() + u64::from(delay),
+            data: message,
+            meta,
+            retain,
+            ttl: ttl.map(|d| d.as_secs() as u32),
+            namespace: caps.namespace().map(|s| s.to_string()),
+            targets,
+        };
+
+        match storage.write_scheduled(topic, &m, config.retained_settings(topic), deadline) {
+            Ok(()) => {}
+            Err(StorageError::LimitReached) => {
+                return problem::response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &format!("Topic has {SCHEDULED_MAX} scheduled messages already"),
+                );
+            }
+            Err(StorageError::DeadlineExceeded) => {
+                return Problem::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Timed out writing message to storage",
+                )
+                .with_code("timeout")
+                .response();
+            }
+            Err(e) => {
+                log_error!("failed to write scheduled message to storage: {e:?}");
+
+                return problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to write message to storage",
+                );
+            }
+        }
+
+        (StatusCode::ACCEPTED, "Scheduled\n".to_string(), None)
+    } else {
+        // deliver any messages that have come due first, so they arrive in
+        // order
+        match deliver_scheduled(config, storage, topic, deadline) {
+            Ok(_) | Err(StorageError::StoreNotFound) => {}
+            Err(e) => {
+                // not critical. only log
+                log_error!("failed to deliver scheduled messages: {e:?}");
+            }
+        }
+
+        let delivery = match deliver(
+            config,
+            storage,
+            &targets,
+            caps.namespace(),
+            &message,
+            &meta,
+            retain,
+            ttl,
+            deadline,
+        ) {
+            Ok(d) => d,
+            Err(e) => return delivery_error_response(e),
+        };
+
+        // other targets were published, so the request isn't safe to retry.
+        // the idempotency key stays reserved rather than being freed
+        if let Some(e) = delivery.storage_error {
+            *reserved = None;
+
+            return delivery_error_response(DeliveryError::Storage(e));
+        }
+
+        if let Some(e) = &delivery.publish_error {
+            log_error!("failed to publish retained message: {e:?}");
+
+            publish_failed = true;
+        }
+
+        if retain {
+            let mut result = PublishResult {
+                topic: namespace::strip(caps.namespace(), topic).to_string(),
+                id: None,
+                prev_id: None,
+                routed: Vec::new(),
+            };
+
+            for mut p in delivery.published {
+                if p.topic == *topic {
+                    result.id = Some(p.id);
+                    result.prev_id = Some(p.prev_id);
+                } else {
+                    p.topic = namespace::strip(caps.namespace(), &p.topic).to_string();
+                    result.routed.push(p);
+                }
+            }
+
+            let body = serde_json::to_string(&result).unwrap();
+
+            (StatusCode::OK, body, Some("application/json"))
+        } else {
+            (StatusCode::OK, "Published\n".to_string(), None)
+        }
+    };
+
+    if quota_usage.is_some() {
+        count_publish_quota(config, &caps, storage, 1, deadline);
+
+        *usage = quota_usage;
+    }
+
+    let mut resp = match content_type {
+        Some(t) => Response::from_status(status)
+            .with_header(header::CONTENT_TYPE, t)
+            .with_body(body.as_str()),
+        None => Response::from_status(status).with_body_text_plain(&body),
+    };
+
+    if publish_failed {
+        resp.set_header(header::WARNING, PUBLISH_FAILED_WARNING);
+    }
+
+    // only successful results are kept, so that failed requests can be retried
+    if let Some(key) = &idempotency_key {
+        let result = IdempotentResult {
+            status: status.as_u16(),
+            body,
+            content_type: content_type.map(|s| s.to_string()),
+        };
+
+        match storage.write_idempotent_result(key, &hash, &result) {
+            Ok(()) => *reserved = None,
+            Err(e) => {
+                // not critical. only log
+                log_error!("failed to write idempotent result to storage: {e:?}");
+            }
+        }
+    }
+
+    resp
+}
+
+#[derive(Debug)]
+enum DeliveryError {
+    Storage(StorageError),
+    Publish(fastly::Error),
+}
+
+fn delivery_error_response(e: DeliveryError) -> Response {
+    match e {
+        DeliveryError::Storage(StorageError::DeadlineExceeded) => Problem::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Timed out writing message to storage",
+        )
+        .with_code("timeout")
+        .response(),
+        DeliveryError::Storage(e) => {
+            log_error!("failed to write message to storage: {e:?}");
+
+            problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to write message to storage",
+            )
+        }
+        DeliveryError::Publish(e) if e.is::<DeadlineExceeded>() => {
+            Problem::new(StatusCode::SERVICE_UNAVAILABLE, "Publish process timed out")
+                .with_code("timeout")
+                .response()
+        }
+        DeliveryError::Publish(e) => {
+            log_error!("failed to publish: {e:?}");
+
+            problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Publish process failed")
+        }
+    }
+}
+
+// the sequencing of a newly written message
+fn sequencing(v: &RetainedVersion) -> Sequencing {
+    let version = Version::from(v);
+
+    let prev_id = if v.seq > 1 {
+        // if we wrote version 2 or later, it implies the slot existed and
+        // thus the previous write would have been for the same generation
+        version.prev().as_id()
+    } else {
+        // if we wrote version 1, it implies the slot was empty
+        "none".to_string()
+    };
+
+    Sequencing {
+        id: version.as_id(),
+        prev_id,
+        deleted: false,
+    }
+}
+
+// the version a message was retained as
+#[derive(Serialize)]
+struct Published {
+    topic: String,
+    id: String,
+    prev_id: String,
+}
+
+// the result of delivering a message. retained messages are stored before
+// being published, so a failure to publish them is reported alongside, as
+// is a failure to store some of the targets while others were published
+struct Delivery {
+    published: Vec<Published>,
+    publish_error: Option<fastly::Error>,
+    storage_error: Option<StorageError>,
+}
+
+#[derive(Serialize)]
+struct PublishResult {
+    topic: String,
+
+    // absent if routing rules moved the message to other topics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_id: Option<String>,
+
+    // other topics the message was delivered to by routing rules
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    routed: Vec<Published>,
+}
+
+// retains the message for each target topic if requested, and publishes it.
+// returns the versions of the retained messages
+#[allow(clippy::too_many_arguments)]
+fn deliver(
+    config: &Config,
+    storage: &dyn Storage,
+    targets: &[String],
+    namespace: Option<&str>,
+    message: &[u8],
+    meta: &MessageMeta,
+    retain: bool,
+    ttl: Option<Duration>,
+    deadline: Deadline,
+) -> Result<Delivery, DeliveryError> {
+    let mut published = Vec::new();
+
+    // write to all targets together. a target whose write failed isn't
+    // published to, but the others still are
+    let versions: Vec<Option<Result<RetainedVersion, StorageError>>> = if retain {
+        let writes: Vec<RetainedWrite> = targets
+            .iter()
+            .map(|target| RetainedWrite {
+                topic: target,
+                message,
+                meta,
+                ttl,
+                settings: config.retained_settings(target),
+            })
+            .collect();
+
+        match storage.write_retained_many(&writes, deadline) {
+            Ok(v) => v.into_iter().map(Some).collect(),
+            Err(e) => return Err(DeliveryError::Storage(e)),
+        }
+    } else {
+        targets.iter().map(|_| None).collect()
+    };
+
+    let mut storage_error = None;
+
+    // the targets are published in one batch
+    let mut batch = Batch::default();
+
+    for (target, version) in targets.iter().zip(versions) {
+        let version = match version {
+            Some(Ok(v)) => Some(v),
+            Some(Err(e)) => {
+                if storage_error.is_none() {
+                    storage_error = Some(e);
+                }
+
+                continue;
+            }
+            None => None,
+        };
+
+        let seq = version.map(|v| sequencing(&v));
+
+        if let Some(seq) = &seq {
+            published.push(Published {
+                topic: target.clone(),
+                id: seq.id.clone(),
+                prev_id: seq.prev_id.clone(),
+            });
+        }
+
+        if let Err(e) = batch.add(config, target, namespace, message, meta, seq, None) {
+            return Err(DeliveryError::Publish(e));
+        }
+    }
+
+    let mut publish_error = None;
+
+    // once a retained write is committed, the message isn't failed, since
+    // durable subscribers will still receive it from storage
+    if let Err(e) = batch.send(config, deadline) {
+        if published.is_empty() {
+            return Err(DeliveryError::Publish(e));
+        }
+
+        publish_error = Some(e);
+    }
+
+    if published.is_empty() {
+        if let Some(e) = storage_error {
+            return Err(DeliveryError::Storage(e));
+        }
+    }
+
+    Ok(Delivery {
+        published,
+        publish_error,
+        storage_error,
+    })
+}
+
+// publishes a topic's scheduled messages that have come due. returns the
+// number delivered. messages that fail to be delivered are put back, to be
+// tried again later
+pub fn deliver_scheduled(
+    config: &Config,
+    storage: &dyn Storage,
+    topic: &str,
+    deadline: Deadline,
+) -> Result<usize, StorageError> {
+    let settings = config.retained_settings(topic);
+
+    let mut due = storage
+        .take_scheduled(topic, unix_now(), settings, deadline)?
+        .into_iter();
 
-            value
+    let mut delivered = 0;
+
+    while let Some(m) = due.next() {
+        // may have expired while the app wasn't called
+        if m.meta.is_expired(unix_now()) {
+            continue;
+        }
+
+        // routed with the publisher's capabilities when scheduled
+        let own = [topic.to_string()];
+        let targets: &[String] = if m.targets.is_empty() {
+            &own
         } else {
-            return text_response(StatusCode::BAD_REQUEST, "Missing 'Authorization' header");
+            &m.targets
         };
 
-        match auth.app_token.validate_token(token) {
-            Ok(caps) => caps,
-            Err(AuthorizationError::Token(_)) => {
-                return text_response(StatusCode::FORBIDDEN, "Invalid token");
+        let ttl = m.ttl.map(|x| Duration::from_secs(x.into()));
+
+        match deliver(
+            config,
+            storage,
+            targets,
+            m.namespace.as_deref(),
+            &m.data,
+            &m.meta,
+            m.retain,
+            ttl,
+            deadline,
+        ) {
+            Ok(d) => {
+                if let Some(e) = d.publish_error {
+                    log_error!("failed to publish scheduled message to topic {topic}: {e:?}");
+                }
+
+                // not put back, since other targets were delivered
+                if let Some(e) = d.storage_error {
+                    log_error!("failed to write scheduled message to topic {topic}: {e:?}");
+                }
             }
             Err(e) => {
-                println!("auth failed: {e:?}");
+                log_error!("failed to deliver scheduled message to topic {topic}: {e:?}");
 
-                return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Auth process failed");
+                for m in std::iter::once(m).chain(due) {
+                    // not bound by the request deadline, to avoid losing messages
+                    if let Err(e) = storage.write_scheduled(topic, &m, settings, Deadline::none()) {
+                        log_error!("failed to reschedule message to topic {topic}: {e:?}");
+                    }
+                }
+
+                break;
             }
         }
+
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+#[derive(Deserialize)]
+struct TransactionRequest {
+    messages: Vec<TransactionRequestMessage>,
+}
+
+#[derive(Deserialize)]
+struct TransactionRequestMessage {
+    topic: String,
+    content: Option<String>,
+    #[serde(rename = "content-bin")]
+    content_bin: Option<String>,
+    ttl: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct TransactionResult {
+    messages: Vec<Published>,
+}
+
+// retains messages for several topics as a unit, and publishes them
+pub fn post_transaction(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    let mut usage = None;
+
+    let resp = post_transaction_messages(config, auth, storage, deadline, req, &mut usage);
+
+    with_quota_headers(resp, usage)
+}
+
+fn post_transaction_messages(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    mut req: Request,
+    usage: &mut Option<Usage>,
+) -> Response {
+    let body = match read_body(&mut req, TRANSACTION_BODY_SIZE_MAX) {
+        Ok(body) => body,
+        Err(e) => return problem::body_error(&e, TRANSACTION_BODY_SIZE_MAX),
+    };
+
+    let r: TransactionRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return problem::response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid request body: {e}"),
+            )
+        }
     };
 
-    if !caps.can_publish(topic) {
-        return text_response(
-            StatusCode::FORBIDDEN,
-            &format!("Cannot publish to topic: {topic}"),
+    if r.messages.is_empty() {
+        return problem::response(StatusCode::BAD_REQUEST, "No messages");
+    }
+
+    if r.messages.len() > TOPICS_PER_REQUEST_MAX {
+        return problem::response(
+            StatusCode::BAD_REQUEST,
+            &format!("Too many messages, maximum {TOPICS_PER_REQUEST_MAX}"),
         );
     }
 
-    let message = body.into_bytes();
+    let caps = match publisher_caps(auth, &req) {
+        Ok(caps) => caps,
+        Err(e) => return e.response(),
+    };
+
+    if let Err(e) = check_replay(config, storage, &req) {
+        return e.response();
+    }
+
+    // the messages are handled in one span
+    let mut meta = MessageMeta {
+        traceparent: req.get_header_str(TRACEPARENT).map(|s| s.to_string()),
+        ..Default::default()
+    };
+
+    meta.start_span("transaction");
+
+    let mut messages: Vec<TransactionMessage> = Vec::new();
+
+    // the longest linger and most tries of the topics involved
+    let mut settings = RetainedSettings {
+        linger: Duration::ZERO,
+        write_tries_max: 0,
+        depth: 1,
+    };
+
+    let location = geo::location(&req);
+
+    for m in r.messages {
+        if let Err(e) = topic::validate_publish(&config.topic_rules, &m.topic) {
+            return Problem::new(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid topic {}: {e}", m.topic),
+            )
+            .with_code("invalid-topic")
+            .response();
+        }
+
+        let topic = caps.resolve_topic(&m.topic);
+
+        if !caps.can_publish(&topic) || !geo::can_publish(config, &topic, location.as_ref()) {
+            return Problem::new(
+                StatusCode::FORBIDDEN,
+                &format!("Cannot publish to topic: {}", m.topic),
+            )
+            .with_code("publish-not-allowed")
+            .response();
+        }
+
+        if messages.iter().any(|x| x.topic == topic) {
+            return problem::response(
+                StatusCode::BAD_REQUEST,
+                &format!("Topic specified more than once: {topic}"),
+            );
+        }
+
+        let data = match (m.content, m.content_bin) {
+            (Some(s), None) => s.into_bytes(),
+            (None, Some(s)) => match base64::prelude::BASE64_STANDARD.decode(s) {
+                Ok(v) => v,
+                Err(e) => {
+                    return problem::response(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid 'content-bin' for topic {topic}: {e}"),
+                    )
+                }
+            },
+            _ => {
+                return problem::response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Expected one of 'content' or 'content-bin' for topic {topic}"),
+                )
+            }
+        };
+
+        if data.len() > MESSAGE_SIZE_MAX {
+            return message_too_large(MESSAGE_SIZE_MAX);
+        }
+
+        // messages in a transaction have no metadata of their own, so topics
+        // that require signatures are refused
+        if let Err(e) = check_signature(config, storage, &topic, &data, &meta) {
+            return e.response();
+        }
+
+        if !check_line_lengths(config, &topic, &data) {
+            return Problem::new(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "Message has a line exceeding {} bytes, which is not allowed for this topic",
+                    config.sse_line_length_max
+                ),
+            )
+            .with_code("line-too-long")
+            .response();
+        }
+
+        let s = config.retained_settings(&topic);
+        settings.linger = settings.linger.max(s.linger);
+        settings.write_tries_max = settings.write_tries_max.max(s.write_tries_max);
+
+        messages.push(TransactionMessage {
+            topic,
+            data,
+            meta: meta.clone(),
+            ttl: m.ttl,
+            depth: if s.depth > 1 { s.depth } else { 0 },
+        });
+    }
+
+    if let Err(e) = check_publish_rate(&caps, storage, messages.len() as u32, deadline) {
+        return e.response();
+    }
+
+    let quota_usage = match check_publish_quota(config, &caps, storage, messages.len() as u32) {
+        Ok(u) => u,
+        Err(u) => return quota_exceeded(&u),
+    };
+
+    let versions = match storage.write_transaction(&messages, settings, deadline) {
+        Ok(v) => v,
+        Err(e) => return delivery_error_response(DeliveryError::Storage(e)),
+    };
+
+    let mut result = TransactionResult {
+        messages: Vec::new(),
+    };
+
+    let mut batch = Batch::default();
+
+    for (m, v) in messages.iter().zip(versions) {
+        let seq = sequencing(&v);
+
+        result.messages.push(Published {
+            topic: namespace::strip(caps.namespace(), &m.topic).to_string(),
+            id: seq.id.clone(),
+            prev_id: seq.prev_id.clone(),
+        });
+
+        if let Err(e) = batch.add(
+            config,
+            &m.topic,
+            caps.namespace(),
+            &m.data,
+            &m.meta,
+            Some(seq),
+            None,
+        ) {
+            return delivery_error_response(DeliveryError::Publish(e));
+        }
+    }
+
+    if let Err(e) = batch.send(config, deadline) {
+        return delivery_error_response(DeliveryError::Publish(e));
+    }
+
+    if quota_usage.is_some() {
+        count_publish_quota(config, &caps, storage, messages.len() as u32, deadline);
+
+        *usage = quota_usage;
+    }
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&result)
+        .unwrap()
+}
+
+// publishes the tombstone written by Storage::delete_retained, so that
+// subscribers learn of the deletion
+pub fn publish_tombstone(
+    config: &Config,
+    topic: &str,
+    namespace: Option<&str>,
+    v: &RetainedVersion,
+    deadline: Deadline,
+) -> Result<(), fastly::Error> {
+    let version = Version::from(v);
+
+    // a tombstone is always written to an existing slot
+    let prev_id = version.prev().as_id();
+
+    let seq = Sequencing {
+        id: version.as_id(),
+        prev_id,
+        deleted: true,
+    };
+
+    publish(
+        config,
+        topic,
+        namespace,
+        b"",
+        &MessageMeta::default(),
+        Some(seq),
+        None,
+        deadline,
+    )
+}
+
+// removes a topic's retained message. durable subscribers are notified via
+// a tombstone version, so the deletion is sequenced like any other write
+pub fn delete(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    let Some(topic) = req.get_query_parameter("topic") else {
+        return problem::response(StatusCode::BAD_REQUEST, "Missing 'topic' param");
+    };
+
+    if let Err(e) = topic::validate_publish(&config.topic_rules, topic) {
+        return Problem::new(
+            StatusCode::BAD_REQUEST,
+            &format!("Invalid topic {topic}: {e}"),
+        )
+        .with_code("invalid-topic")
+        .response();
+    }
+
+    let caps = match publisher_caps(auth, &req) {
+        Ok(caps) => caps,
+        Err(e) => return e.response(),
+    };
+
+    if let Err(e) = check_replay(config, storage, &req) {
+        return e.response();
+    }
+
+    let name = topic;
+    let topic = &caps.resolve_topic(name);
+
+    if !caps.can_publish(topic) || !geo::can_publish(config, topic, geo::location(&req).as_ref()) {
+        return Problem::new(
+            StatusCode::FORBIDDEN,
+            &format!("Cannot publish to topic: {name}"),
+        )
+        .with_code("publish-not-allowed")
+        .response();
+    }
+
+    let v = match storage.delete_retained(topic, config.retained_settings(topic), deadline) {
+        Ok(Some(v)) => v,
+        Ok(None) | Err(StorageError::StoreNotFound) => {
+            return problem::response(StatusCode::NOT_FOUND, "No retained message for topic");
+        }
+        Err(StorageError::DeadlineExceeded) => {
+            return Problem::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Timed out writing to storage",
+            )
+            .with_code("timeout")
+            .response();
+        }
+        Err(e) => {
+            log_error!("failed to delete message from storage: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete message from storage",
+            );
+        }
+    };
+
+    if let Err(e) = publish_tombstone(config, topic, caps.namespace(), &v, deadline) {
+        if e.is::<DeadlineExceeded>() {
+            return Problem::new(StatusCode::SERVICE_UNAVAILABLE, "Publish process timed out")
+                .with_code("timeout")
+                .response();
+        }
+
+        log_error!("failed to publish: {e:?}");
+
+        return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Publish process failed");
+    }
+
+    Response::from_status(StatusCode::OK).with_body_text_plain("Deleted\n")
+}
+
+#[derive(Serialize)]
+struct SubscriptionState {
+    subscription: String,
+    cursors: HashMap<String, String>,
+}
+
+pub fn get_subscriptions(auth: &Authorization, storage: &dyn Storage, req: Request) -> Response {
+    let Some(name) = req.get_query_parameter("subscription") else {
+        return problem::response(StatusCode::BAD_REQUEST, "Missing 'subscription' param");
+    };
+
+    if !is_valid_subscription_name(name) {
+        return problem::response(StatusCode::BAD_REQUEST, "Invalid 'subscription' param");
+    }
+
+    let token = match get_token(&req, true) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Problem::new(
+                StatusCode::BAD_REQUEST,
+                "Missing 'Authorization' header or 'auth' parameter",
+            )
+            .with_code("missing-token")
+            .response()
+        }
+        Err(e) => return problem::response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let caps = match auth.validate_token(token) {
+        Ok(caps) => caps,
+        Err(AuthorizationError::Token(_)) => {
+            return Problem::new(StatusCode::FORBIDDEN, "Invalid token")
+                .with_code("invalid-token")
+                .response();
+        }
+        Err(e) => {
+            log_error!("auth failed: {e:?}");
+
+            return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Auth process failed");
+        }
+    };
+
+    let cursors = match storage.read_cursors(&cursor_key(caps.subject(), name)) {
+        Ok(Some(cursors)) => cursors,
+        Ok(None) | Err(StorageError::StoreNotFound) => {
+            return problem::response(StatusCode::NOT_FOUND, "Subscription not found");
+        }
+        Err(e) => {
+            log_error!("failed to read cursors from storage: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read cursors from storage",
+            );
+        }
+    };
+
+    // only reveal cursors for topics the caller can currently access
+    let cursors = cursors
+        .into_iter()
+        .filter(|(topic, _)| caps.can_subscribe(topic))
+        .collect();
+
+    let state = SubscriptionState {
+        subscription: name.to_string(),
+        cursors,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&state)
+        .unwrap()
+}
+
+#[derive(Serialize)]
+struct StreamState {
+    cid: String,
+    topics: Vec<String>,
+}
+
+pub fn post_subscriptions(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+) -> Response {
+    let Some(cid) = req.get_query_parameter("cid") else {
+        return problem::response(StatusCode::BAD_REQUEST, "Missing 'cid' param");
+    };
+
+    if !is_valid_cid(cid) {
+        return problem::response(StatusCode::BAD_REQUEST, "Invalid 'cid' param");
+    }
+
+    let mut subscribe = Vec::new();
+    let mut unsubscribe = Vec::new();
 
-    if message.len() > MESSAGE_SIZE_MAX {
-        return text_response(
+    for (k, v) in req.get_url().query_pairs() {
+        if k == "subscribe" {
+            subscribe.push(v.to_string());
+        } else if k == "unsubscribe" {
+            unsubscribe.push(v.to_string());
+        }
+    }
+
+    if subscribe.is_empty() && unsubscribe.is_empty() {
+        return problem::response(
             StatusCode::BAD_REQUEST,
-            &format!("Message size exceeds {MESSAGE_SIZE_MAX} bytes maximum"),
+            "Missing 'subscribe' or 'unsubscribe' param",
         );
     }
 
-    let mut version = None;
+    let caps = if auth.fastly {
+        Capabilities::new_admin()
+    } else {
+        let token = match get_token(&req, false) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return Problem::new(StatusCode::BAD_REQUEST, "Missing 'Authorization' header")
+                    .with_code("missing-token")
+                    .response()
+            }
+            Err(e) => return problem::response(StatusCode::BAD_REQUEST, &e),
+        };
 
-    if retain {
-        match storage.write_retained(topic, &message, ttl) {
-            Ok(v) => version = Some(v),
+        match auth.validate_token(token) {
+            Ok(caps) => caps,
+            Err(AuthorizationError::Token(_)) => {
+                return Problem::new(StatusCode::FORBIDDEN, "Invalid token")
+                    .with_code("invalid-token")
+                    .response();
+            }
             Err(e) => {
-                println!("failed to write message to storage: {e:?}");
+                log_error!("auth failed: {e:?}");
 
-                return text_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to write message to storage",
-                );
+                return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Auth process failed");
             }
         }
+    };
+
+    let location = geo::location(&req);
+
+    for topic in &subscribe {
+        if let Err(e) = topic::validate(&config.topic_rules, topic) {
+            return Problem::new(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid topic {topic}: {e}"),
+            )
+            .with_code("invalid-topic")
+            .response();
+        }
+
+        if !caps.can_subscribe(topic) || !geo::can_subscribe(config, topic, location.as_ref()) {
+            return Problem::new(
+                StatusCode::FORBIDDEN,
+                &format!("Cannot subscribe to topic: {topic}"),
+            )
+            .with_code("subscribe-not-allowed")
+            .response();
+        }
     }
 
-    let seq = version.map(|v| {
-        let version = Version {
-            generation: v.generation,
-            seq: v.seq,
-        };
+    let mut topics = match storage.read_stream_topics(cid) {
+        Ok(Some(v)) => v,
+        Ok(None) | Err(StorageError::StoreNotFound) => {
+            return problem::response(StatusCode::NOT_FOUND, "Stream not found");
+        }
+        Err(e) => {
+            log_error!("failed to read stream topics from storage: {e:?}");
 
-        let prev_id = if v.seq > 1 {
-            // if we wrote version 2 or later, it implies the slot
-            // existed and thus the previous write would have been
-            // for the same generation
-            Version {
-                generation: v.generation,
-                seq: v.seq - 1,
-            }
-            .as_id()
-        } else {
-            // if we wrote version 1, it implies the slot was empty
-            "none".to_string()
-        };
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read stream topics from storage",
+            );
+        }
+    };
+
+    let previous = topics.clone();
 
-        Sequencing {
-            id: version.as_id(),
-            prev_id,
+    topics.retain(|t| !unsubscribe.contains(t));
+
+    for topic in subscribe {
+        if !topics.contains(&topic) {
+            topics.push(topic);
         }
-    });
+    }
+
+    topics.sort();
+
+    if topics.is_empty() {
+        return problem::response(
+            StatusCode::BAD_REQUEST,
+            "Cannot unsubscribe from all topics",
+        );
+    }
+
+    if topics.len() >= TOPICS_PER_REQUEST_MAX {
+        return problem::response(StatusCode::BAD_REQUEST, "Too many topics");
+    }
+
+    if let Some(max) = config.subscriptions_max(caps.max_subs()) {
+        if topics.len() > max {
+            return Problem::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                &format!("Subscriptions are limited to {max} topics"),
+            )
+            .with_code("too-many-subscriptions")
+            .response();
+        }
+    }
+
+    if let Err(e) = storage.write_stream_topics(cid, &topics) {
+        log_error!("failed to write stream topics to storage: {e:?}");
+
+        return problem::response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to write stream topics to storage",
+        );
+    }
+
+    for topic in topics.iter().filter(|t| !previous.contains(t)) {
+        webhook::changed(
+            config,
+            webhook::Action::Subscribe,
+            topic,
+            cid,
+            Transport::Sse,
+        );
+    }
+
+    for topic in previous.iter().filter(|t| !topics.contains(t)) {
+        webhook::changed(
+            config,
+            webhook::Action::Unsubscribe,
+            topic,
+            cid,
+            Transport::Sse,
+        );
+    }
 
-    if let Err(e) = publish(&config.publish_token, topic, &message, seq, None) {
-        println!("failed to publish: {e:?}");
+    // the stream picks up the new topics when it re-requests its next link
+    if let Err(e) = publish_hint(config, &format!("x:{cid}"), deadline) {
+        if e.is::<DeadlineExceeded>() {
+            return Problem::new(StatusCode::SERVICE_UNAVAILABLE, "Publish process timed out")
+                .with_code("timeout")
+                .response();
+        }
+
+        log_error!("failed to publish: {e:?}");
 
-        return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Publish process failed");
+        return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Publish process failed");
     }
 
-    text_response(StatusCode::OK, "Published")
+    let state = StreamState {
+        cid: cid.to_string(),
+        topics,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_body_json(&state)
+        .unwrap()
 }
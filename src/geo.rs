@@ -0,0 +1,157 @@
+use crate::config::Config;
+#[cfg(feature = "fastly")]
+use fastly::Request;
+use std::fmt;
+
+// the client's location, as looked up on the request that reached the app
+// first. it is passed along in this header when the request is handed off
+// to Fanout, since requests from Fanout don't come from the client's
+// address
+pub const LOCATION_HEADER: &str = "Pubsub-Client-Location";
+
+// an ISO 3166-1 country code and, if known, the code of a region of the
+// country (an ISO 3166-2 subdivision)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub country: String,
+    pub region: Option<String>,
+}
+
+impl Location {
+    // of the form "US" or "US-CA"
+    pub fn parse(s: &str) -> Option<Self> {
+        let (country, region) = match s.split_once('-') {
+            Some((country, region)) => (country, Some(region)),
+            None => (s, None),
+        };
+
+        if country.len() != 2 || !is_code(country) || !region.is_none_or(is_code) {
+            return None;
+        }
+
+        Some(Self {
+            country: country.to_ascii_uppercase(),
+            region: region.map(|r| r.to_ascii_uppercase()),
+        })
+    }
+}
+
+fn is_code(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.region {
+            Some(region) => write!(f, "{}-{}", self.country, region),
+            None => write!(f, "{}", self.country),
+        }
+    }
+}
+
+#[cfg(feature = "fastly")]
+fn lookup(req: &Request) -> Option<Location> {
+    let geo = fastly::geo::geo_lookup(req.get_client_ip_addr()?)?;
+
+    // unknown locations have no country
+    let country = geo.country_code();
+
+    if country.len() != 2 || !is_code(country) {
+        return None;
+    }
+
+    Some(Location {
+        country: country.to_ascii_uppercase(),
+        region: geo
+            .region()
+            .filter(|r| is_code(r))
+            .map(|r| r.to_ascii_uppercase()),
+    })
+}
+
+// sets the location header from the client's address, replacing any value
+// sent by the client. only for requests that didn't come from Fanout
+#[cfg(feature = "fastly")]
+pub fn tag(req: &mut Request) {
+    match lookup(req) {
+        Some(loc) => req.set_header(LOCATION_HEADER, loc.to_string()),
+        None => {
+            req.remove_header(LOCATION_HEADER);
+        }
+    }
+}
+
+#[cfg(feature = "fastly")]
+pub fn location(req: &Request) -> Option<Location> {
+    req.get_header_str(LOCATION_HEADER)
+        .and_then(Location::parse)
+}
+
+// entries are country codes, or country and region codes joined by '-'.
+// clients with an unknown location aren't allowed
+fn allows(list: &[String], loc: Option<&Location>) -> bool {
+    let Some(loc) = loc else {
+        return false;
+    };
+
+    list.iter()
+        .filter_map(|s| Location::parse(s))
+        .any(|x| x.country == loc.country && (x.region.is_none() || x.region == loc.region))
+}
+
+// whether a client may subscribe to a topic from where it is, according
+// to the topic's settings
+pub fn can_subscribe(config: &Config, topic: &str, loc: Option<&Location>) -> bool {
+    match &config.topic_config(topic).subscribe_countries {
+        Some(list) => allows(list, loc),
+        None => true,
+    }
+}
+
+pub fn can_publish(config: &Config, topic: &str, loc: Option<&Location>) -> bool {
+    match &config.topic_config(topic).publish_countries {
+        Some(list) => allows(list, loc),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopicConfig;
+
+    #[test]
+    fn policies() {
+        assert_eq!(
+            Location::parse("us-ca"),
+            Some(Location {
+                country: "US".to_string(),
+                region: Some("CA".to_string()),
+            })
+        );
+        assert_eq!(Location::parse("DE").unwrap().to_string(), "DE");
+        assert_eq!(Location::parse("USA"), None);
+        assert_eq!(Location::parse("US-"), None);
+        assert_eq!(Location::parse("**"), None);
+
+        let mut config = Config::default();
+        config.topics.insert(
+            "eu".to_string(),
+            TopicConfig {
+                subscribe_countries: Some(vec!["DE".to_string(), "US-CA".to_string()]),
+                ..Default::default()
+            },
+        );
+
+        let de = Location::parse("DE-BE");
+        let ca = Location::parse("US-CA");
+        let ny = Location::parse("US-NY");
+
+        assert!(can_subscribe(&config, "eu/orders", de.as_ref()));
+        assert!(can_subscribe(&config, "eu/orders", ca.as_ref()));
+        assert!(!can_subscribe(&config, "eu/orders", ny.as_ref()));
+        assert!(!can_subscribe(&config, "eu/orders", None));
+        assert!(can_subscribe(&config, "us/orders", None));
+        assert!(can_publish(&config, "eu/orders", None));
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "fastly")]
+use fastly::Request;
 use jwt_simple::prelude::*;
 use thiserror::Error;
 
@@ -29,6 +31,47 @@ pub fn validate_grip_sig(sig: &str, key: &str, service_id: &str) -> Result<(), V
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum GripLastError<'a> {
+    #[error("invalid header: [{0}]")]
+    ParseHeader(&'a str),
+}
+
+// if there is at least one Grip-Last header, this function is guaranteed
+// to return at least one item or error
+#[cfg(feature = "fastly")]
+pub fn parse_grip_last(req: &Request) -> Result<Vec<(&str, &str)>, GripLastError<'_>> {
+    let mut out = Vec::new();
+
+    for hvalue in req.get_header_all_str("Grip-Last") {
+        for value in hvalue.split(',') {
+            let Some(pos) = value.find(';') else {
+                return Err(GripLastError::ParseHeader(hvalue));
+            };
+
+            let channel = value[..pos].trim();
+            let params = &value[(pos + 1)..];
+
+            let Some(pos) = params.find("last-id=") else {
+                return Err(GripLastError::ParseHeader(hvalue));
+            };
+
+            let remainder = &params[(pos + 8)..];
+
+            let end = match remainder.find(';') {
+                Some(pos) => pos,
+                None => remainder.len(),
+            };
+
+            let id = remainder[..end].trim();
+
+            out.push((channel, id));
+        }
+    }
+
+    Ok(out)
+}
+
 #[derive(Debug, Default, PartialEq, serde::Serialize)]
 pub struct ControlMessage {
     #[serde(rename(serialize = "type"))]
@@ -45,4 +88,17 @@ pub struct ControlMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub value: Option<String>,
+
+    #[serde(rename = "prev-id", skip_serializing_if = "Option::is_none")]
+    pub prev_id: Option<String>,
+
+    // for keep-alives, the message to send and how often, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
 }
@@ -0,0 +1,211 @@
+use crate::config::{Config, ConfigError};
+use crate::kv::{FastlyKv, Kv, KvError};
+use crate::remotekv::RemoteKv;
+use crate::wiring::Resources;
+use fastly::http::{header, StatusCode};
+use fastly::{config_store, secret_store, Response};
+use serde::Serialize;
+use std::time::Instant;
+
+// looked up to check that a store can be read. it needn't exist
+const PROBE_KEY: &str = "healthz";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Component {
+    ConfigStore,
+    SecretStore,
+    KeysStore,
+    MessagesStore,
+    PublishToken,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub component: Component,
+    pub ok: bool,
+
+    // whether enabled features can't work without the component, as with
+    // wiring::Missing
+    pub required: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    // true if every required component is ok
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+fn kv_error(e: KvError) -> String {
+    match e {
+        KvError::StoreNotFound => "Store not found".to_string(),
+        KvError::TooManyRequests => "Too many requests".to_string(),
+        KvError::Remote(s) => s,
+        e => format!("{e:?}"),
+    }
+}
+
+// runs the probe for each component. config is the result of loading the
+// config, which decides what is required
+fn run<F>(config: Result<&Config, &ConfigError>, probe: F) -> Health
+where
+    F: Fn(Component) -> Result<(), String>,
+{
+    let default_config = Config::default();
+    let c = config.unwrap_or(&default_config);
+
+    let publishing =
+        c.http_publish_enabled || c.mqtt_enabled || c.bayeux_enabled || c.socketio_enabled;
+    let tokens = publishing || c.sse_enabled;
+
+    // remote storage replaces the messages store, so it's needed if
+    // configured
+    let components = [
+        (Component::ConfigStore, true),
+        (Component::SecretStore, publishing),
+        (Component::KeysStore, tokens),
+        (Component::MessagesStore, c.remote_storage.is_some()),
+        (Component::PublishToken, publishing),
+    ];
+
+    let mut checks = Vec::new();
+
+    for (component, required) in components {
+        let start = Instant::now();
+
+        let result = match (component, config) {
+            (Component::ConfigStore, Err(e)) => Err(format!("Failed to load config: {e:?}")),
+            (Component::PublishToken, Err(_)) => Err("Config not loaded".to_string()),
+            (Component::PublishToken, Ok(c)) if c.publish_token.is_empty() => {
+                Err("Publish token not configured".to_string())
+            }
+            (Component::PublishToken, Ok(_)) => Ok(()),
+            _ => probe(component),
+        };
+
+        // the token is only checked for presence
+        let latency_ms =
+            (component != Component::PublishToken).then(|| start.elapsed().as_millis());
+
+        checks.push(Check {
+            component,
+            ok: result.is_ok(),
+            required,
+            latency_ms,
+            error: result.err(),
+        });
+    }
+
+    Health {
+        ok: checks.iter().all(|c| c.ok || !c.required),
+        checks,
+    }
+}
+
+// reports whether the stores the app reads from can be reached. no auth is
+// required, so that load balancers and monitors can call it
+pub fn get(config: Result<&Config, &ConfigError>, r: &Resources) -> Response {
+    let remote = config.ok().and_then(|c| c.remote_storage.as_ref());
+
+    let health = run(config, |component| match component {
+        Component::ConfigStore => match config_store::ConfigStore::try_open(r.config_store) {
+            Ok(_) => Ok(()),
+            Err(config_store::OpenError::ConfigStoreDoesNotExist) => {
+                Err("Store not found".to_string())
+            }
+            Err(e) => Err(format!("{e:?}")),
+        },
+        Component::SecretStore => match secret_store::SecretStore::open(r.secret_store) {
+            Ok(_) => Ok(()),
+            Err(secret_store::OpenError::SecretStoreDoesNotExist(_)) => {
+                Err("Store not found".to_string())
+            }
+            Err(e) => Err(format!("{e:?}")),
+        },
+        Component::KeysStore => FastlyKv::new(r.keys_store)
+            .lookup(PROBE_KEY)
+            .map(|_| ())
+            .map_err(kv_error),
+        Component::MessagesStore => {
+            let kv: Box<dyn Kv> = match remote {
+                Some(remote) => Box::new(RemoteKv::new(remote)),
+                None => Box::new(FastlyKv::new(r.messages_store)),
+            };
+
+            kv.lookup(PROBE_KEY).map(|_| ()).map_err(kv_error)
+        }
+        Component::PublishToken => Ok(()),
+    });
+
+    let status = if health.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::from_status(status)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_json(&health)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health() {
+        let config = Config {
+            publish_token: "token".to_string(),
+            ..Default::default()
+        };
+
+        let h = run(Ok(&config), |_| Ok(()));
+        assert!(h.ok);
+        assert_eq!(h.checks.len(), 5);
+        assert!(h.checks.iter().all(|c| c.ok));
+
+        // the messages store is optional without remote storage
+        let h = run(Ok(&config), |c| match c {
+            Component::MessagesStore => Err("Store not found".to_string()),
+            _ => Ok(()),
+        });
+        assert!(h.ok);
+        assert!(!h.checks[3].ok);
+
+        let h = run(Ok(&config), |c| match c {
+            Component::KeysStore => Err("Store not found".to_string()),
+            _ => Ok(()),
+        });
+        assert!(!h.ok);
+
+        let h = run(Ok(&Config::default()), |_| Ok(()));
+        assert!(!h.ok);
+        assert_eq!(h.checks[4].component, Component::PublishToken);
+        assert!(!h.checks[4].ok);
+
+        // with publishing disabled, the token isn't needed
+        let config = Config {
+            http_publish_enabled: false,
+            mqtt_enabled: false,
+            ..Default::default()
+        };
+
+        let h = run(Ok(&config), |_| Ok(()));
+        assert!(h.ok);
+
+        let h = run(Err(&ConfigError::InvalidValue), |_| Ok(()));
+        assert!(!h.ok);
+        assert!(!h.checks[0].ok);
+
+        let v = serde_json::to_value(&h).unwrap();
+        assert_eq!(v["checks"][0]["component"], "config-store");
+    }
+}
@@ -0,0 +1,252 @@
+use crate::auth::{
+    validate_token, Acl, AppTokenAuthorizor, AuthorizationError, Capabilities, SignedUrl,
+    TokenError, VerifyingKey,
+};
+use crate::config::{Jwks, TokenValidation};
+use crate::remotekv::url_backend;
+use base64::Engine;
+use fastly::cache::simple;
+use fastly::http::StatusCode;
+use fastly::Request;
+use jwt_simple::prelude::*;
+use std::time::Duration;
+
+// name of the backend created for the key set URL, if no backend is named
+const DYNAMIC_BACKEND: &str = "jwks";
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    alg: Option<String>,
+    crv: Option<String>,
+
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+
+    // EC and OKP
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+fn decode_param(s: Option<&str>) -> Result<Vec<u8>, TokenError> {
+    let Some(s) = s else {
+        return Err(TokenError::InvalidKey);
+    };
+
+    base64::prelude::BASE64_URL_SAFE_NO_PAD
+        .decode(s)
+        .map_err(|_| TokenError::InvalidKey)
+}
+
+// supports the same algorithms as keys in the keys store, except HS256,
+// since a published key set can't contain secrets
+fn to_verifying_key(jwk: &Jwk) -> Result<VerifyingKey, TokenError> {
+    let (alg, key) = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+        ("RSA", _) => {
+            let n = decode_param(jwk.n.as_deref())?;
+            let e = decode_param(jwk.e.as_deref())?;
+
+            let key = RS256PublicKey::from_components(&n, &e).map(VerifyingKey::Rs256);
+
+            ("RS256", key)
+        }
+        ("EC", Some("P-256")) => {
+            // uncompressed SEC1 form
+            let mut raw = vec![0x04];
+            raw.extend(decode_param(jwk.x.as_deref())?);
+            raw.extend(decode_param(jwk.y.as_deref())?);
+
+            (
+                "ES256",
+                ES256PublicKey::from_bytes(&raw).map(VerifyingKey::Es256),
+            )
+        }
+        ("OKP", Some("Ed25519")) => {
+            let x = decode_param(jwk.x.as_deref())?;
+
+            (
+                "EdDSA",
+                Ed25519PublicKey::from_bytes(&x).map(VerifyingKey::EdDsa),
+            )
+        }
+        _ => return Err(TokenError::InvalidKey),
+    };
+
+    if jwk.alg.as_deref().is_some_and(|a| a != alg) {
+        return Err(TokenError::InvalidKey);
+    }
+
+    key.map_err(|_| TokenError::InvalidKey)
+}
+
+// validates tokens against a key set published by an identity provider.
+// tokens whose key isn't in the set are passed on to the fallback, so that
+// keys in the keys store keep working
+pub struct JwksAppTokenAuthorizor {
+    jwks: Jwks,
+    fallback: Box<dyn AppTokenAuthorizor>,
+}
+
+impl JwksAppTokenAuthorizor {
+    pub fn new(jwks: &Jwks, fallback: Box<dyn AppTokenAuthorizor>) -> Self {
+        Self {
+            jwks: jwks.clone(),
+            fallback,
+        }
+    }
+
+    fn fetch(&self) -> Result<Vec<u8>, AuthorizationError> {
+        let backend = url_backend(
+            self.jwks.backend.as_deref(),
+            DYNAMIC_BACKEND,
+            &self.jwks.url,
+        )
+        .map_err(AuthorizationError::KeySetError)?;
+
+        let resp = match Request::get(&self.jwks.url).send(backend) {
+            Ok(resp) => resp,
+            Err(e) => return Err(AuthorizationError::KeySetError(e.to_string())),
+        };
+
+        if resp.get_status() != StatusCode::OK {
+            return Err(AuthorizationError::KeySetError(format!(
+                "unexpected status {}",
+                resp.get_status().as_u16()
+            )));
+        }
+
+        Ok(resp.into_body_bytes())
+    }
+
+    // the key set is cached in each POP, so it is fetched at most once per
+    // cache TTL
+    fn key_set(&self) -> Result<JwkSet, AuthorizationError> {
+        let cache_key = format!("jwks:{}", self.jwks.url);
+
+        // cache errors are treated as misses
+        let data = match simple::get(cache_key.clone()) {
+            Ok(Some(body)) => body.into_bytes(),
+            Ok(None) | Err(_) => {
+                let data = self.fetch()?;
+
+                if self.jwks.cache_ttl > 0 {
+                    let ttl = Duration::from_secs(self.jwks.cache_ttl.into());
+
+                    let _ = simple::get_or_set(cache_key, data.clone(), ttl);
+                }
+
+                data
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(set) => Ok(set),
+            Err(_) => Err(AuthorizationError::KeySetError(
+                "invalid key set".to_string(),
+            )),
+        }
+    }
+}
+
+impl AppTokenAuthorizor for JwksAppTokenAuthorizor {
+    fn validate_token(
+        &self,
+        token: &str,
+        validation: &TokenValidation,
+    ) -> Result<Capabilities, AuthorizationError> {
+        let Ok(metadata) = Token::decode_metadata(token) else {
+            return Err(AuthorizationError::Token(TokenError::Invalid));
+        };
+
+        let Some(key_id) = metadata.key_id() else {
+            return self.fallback.validate_token(token, validation);
+        };
+
+        let set = self.key_set()?;
+
+        let Some(jwk) = set.keys.iter().find(|k| k.kid.as_deref() == Some(key_id)) else {
+            return self.fallback.validate_token(token, validation);
+        };
+
+        let key = to_verifying_key(jwk)?;
+
+        let mut caps = validate_token(token, &key, validation)?;
+
+        caps.apply_acl(&self.fallback.acl()?, Some(key_id));
+
+        Ok(caps)
+    }
+
+    fn validate_api_key(&self, key: &str) -> Result<Capabilities, AuthorizationError> {
+        self.fallback.validate_api_key(key)
+    }
+
+    fn validate_client_cert(
+        &self,
+        identities: &[String],
+    ) -> Result<Capabilities, AuthorizationError> {
+        self.fallback.validate_client_cert(identities)
+    }
+
+    fn validate_signed_url(&self, url: &SignedUrl) -> Result<Capabilities, AuthorizationError> {
+        self.fallback.validate_signed_url(url)
+    }
+
+    fn acl(&self) -> Result<Acl, AuthorizationError> {
+        self.fallback.acl()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwk(kty: &str, crv: Option<&str>, x: &[u8], y: Option<&[u8]>) -> Jwk {
+        let encode = |v: &[u8]| base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(v);
+
+        Jwk {
+            kid: Some("k1".to_string()),
+            kty: kty.to_string(),
+            alg: None,
+            crv: crv.map(|s| s.to_string()),
+            n: None,
+            e: None,
+            x: Some(encode(x)),
+            y: y.map(encode),
+        }
+    }
+
+    #[test]
+    fn key_conversion() {
+        let claims = || Claims::create(jwt_simple::prelude::Duration::from_secs(60));
+
+        let key_pair = ES256KeyPair::generate();
+        let raw = key_pair.public_key().public_key().to_bytes_uncompressed();
+        let token = key_pair.sign(claims()).unwrap();
+
+        let key = to_verifying_key(&jwk("EC", Some("P-256"), &raw[1..33], Some(&raw[33..])));
+        assert!(validate_token(&token, &key.unwrap(), &TokenValidation::default()).is_ok());
+
+        let key_pair = Ed25519KeyPair::generate();
+        let raw = key_pair.public_key().to_bytes();
+        let token = key_pair.sign(claims()).unwrap();
+
+        let key = to_verifying_key(&jwk("OKP", Some("Ed25519"), &raw, None));
+        assert!(validate_token(&token, &key.unwrap(), &TokenValidation::default()).is_ok());
+
+        // alg must agree with the key type
+        let mut k = jwk("OKP", Some("Ed25519"), &raw, None);
+        k.alg = Some("ES256".to_string());
+        assert!(to_verifying_key(&k).is_err());
+
+        assert!(to_verifying_key(&jwk("EC", Some("P-384"), &raw, Some(&raw))).is_err());
+        assert!(to_verifying_key(&jwk("oct", None, &raw, None)).is_err());
+    }
+}
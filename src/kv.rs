@@ -0,0 +1,225 @@
+#[cfg(feature = "fastly")]
+use fastly::kv_store::{InsertMode, KVStoreError, LookupResponse};
+#[cfg(feature = "fastly")]
+use fastly::KVStore;
+#[cfg(feature = "fastly")]
+use std::cell::OnceCell;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum KvError {
+    StoreNotFound,
+    PreconditionFailed,
+    TooManyRequests,
+    #[cfg(feature = "fastly")]
+    KVStore(KVStoreError),
+    Remote(String),
+}
+
+#[cfg(feature = "fastly")]
+impl From<KVStoreError> for KvError {
+    fn from(e: KVStoreError) -> Self {
+        match e {
+            KVStoreError::StoreNotFound(_) => Self::StoreNotFound,
+            KVStoreError::ItemPreconditionFailed => Self::PreconditionFailed,
+            KVStoreError::TooManyRequests => Self::TooManyRequests,
+            e => Self::KVStore(e),
+        }
+    }
+}
+
+pub struct Item {
+    pub value: Vec<u8>,
+    pub metadata: Option<Vec<u8>>,
+
+    // changes whenever the item is written
+    pub generation: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Always,
+
+    // only if there is no item
+    Absent,
+
+    // only if the item hasn't been written since it had this generation
+    Generation(u64),
+}
+
+pub struct Insert<'a> {
+    pub metadata: Option<&'a str>,
+    pub ttl: Option<Duration>,
+    pub condition: Condition,
+}
+
+impl Default for Insert<'_> {
+    fn default() -> Self {
+        Self {
+            metadata: None,
+            ttl: None,
+            condition: Condition::Always,
+        }
+    }
+}
+
+pub struct ListPage {
+    pub keys: Vec<String>,
+
+    // for requesting the next page, if there may be more
+    pub cursor: Option<String>,
+}
+
+// the operations storage needs from a key-value store
+pub trait Kv {
+    fn lookup(&self, key: &str) -> Result<Option<Item>, KvError>;
+
+    // looks up several keys, returning results in the same order
+    fn lookup_many(&self, keys: &[String]) -> Result<Vec<Option<Item>>, KvError> {
+        keys.iter().map(|key| self.lookup(key)).collect()
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>, insert: &Insert) -> Result<(), KvError>;
+
+    // succeeds if there is no such key
+    fn delete(&self, key: &str) -> Result<(), KvError>;
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<ListPage, KvError>;
+}
+
+#[cfg(feature = "fastly")]
+pub struct FastlyKv {
+    store_name: String,
+    store: OnceCell<KVStore>,
+}
+
+#[cfg(feature = "fastly")]
+impl FastlyKv {
+    pub fn new(store_name: &str) -> Self {
+        Self {
+            store_name: store_name.to_string(),
+            store: OnceCell::new(),
+        }
+    }
+
+    fn open(&self) -> Result<&KVStore, KvError> {
+        if let Some(store) = self.store.get() {
+            return Ok(store);
+        }
+
+        let store = match KVStore::open(&self.store_name) {
+            Ok(Some(store)) => store,
+            Ok(None) => return Err(KvError::StoreNotFound),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(self.store.get_or_init(|| store))
+    }
+}
+
+#[cfg(feature = "fastly")]
+fn to_item(mut lookup: LookupResponse) -> Item {
+    Item {
+        metadata: lookup.metadata().map(|data| data.to_vec()),
+        generation: lookup.current_generation(),
+        value: lookup.take_body_bytes(),
+    }
+}
+
+#[cfg(feature = "fastly")]
+impl Kv for FastlyKv {
+    fn lookup(&self, key: &str) -> Result<Option<Item>, KvError> {
+        match self.open()?.lookup(key) {
+            Ok(lookup) => Ok(Some(to_item(lookup))),
+            Err(KVStoreError::ItemNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // lookups are issued together and then waited on, so that reading many
+    // keys takes about as long as reading one
+    fn lookup_many(&self, keys: &[String]) -> Result<Vec<Option<Item>>, KvError> {
+        let store = self.open()?;
+
+        let mut pending = Vec::new();
+
+        for key in keys {
+            match store.build_lookup().execute_async(key) {
+                Ok(h) => pending.push(Some(h)),
+                Err(KVStoreError::ItemNotFound) => pending.push(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        pending
+            .into_iter()
+            .map(|h| match h.map(|h| store.pending_lookup_wait(h)) {
+                Some(Ok(lookup)) => Ok(Some(to_item(lookup))),
+                Some(Err(KVStoreError::ItemNotFound)) | None => Ok(None),
+                Some(Err(e)) => Err(e.into()),
+            })
+            .collect()
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>, insert: &Insert) -> Result<(), KvError> {
+        let builder = self.open()?.build_insert();
+
+        let builder = match insert.condition {
+            Condition::Always => builder,
+            Condition::Absent => builder.mode(InsertMode::Add),
+            Condition::Generation(generation) => builder.if_generation_match(generation),
+        };
+
+        let builder = match insert.metadata {
+            Some(metadata) => builder.metadata(metadata),
+            None => builder,
+        };
+
+        let builder = match insert.ttl {
+            Some(ttl) => builder.time_to_live(ttl),
+            None => builder,
+        };
+
+        Ok(builder.execute(key, value)?)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), KvError> {
+        match self.open()?.delete(key) {
+            Ok(()) | Err(KVStoreError::ItemNotFound) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<ListPage, KvError> {
+        let builder = self.open()?.build_list().prefix(prefix);
+
+        let builder = match cursor {
+            Some(cursor) => builder.cursor(cursor),
+            None => builder,
+        };
+
+        let builder = match limit {
+            Some(limit) => builder.limit(limit),
+            None => builder,
+        };
+
+        let page = builder.execute()?;
+
+        let cursor = page.next_cursor();
+
+        Ok(ListPage {
+            keys: page.into_keys(),
+            cursor,
+        })
+    }
+}
@@ -1,12 +1,71 @@
+#[cfg(feature = "fastly")]
 pub mod admin;
+#[cfg(feature = "fastly")]
+pub mod audit;
 pub mod auth;
+#[cfg(feature = "fastly")]
+pub mod bayeux;
+#[cfg(feature = "fastly")]
+pub mod cache;
+pub mod cert;
+pub mod cidr;
+pub mod cloudevents;
+pub mod compress;
 pub mod config;
+pub mod deadline;
+pub mod encryption;
+#[cfg(feature = "fastly")]
 pub mod events;
+pub mod geo;
 pub mod grip;
+#[cfg(feature = "fastly")]
+pub mod health;
+#[cfg(feature = "fastly")]
+pub mod jwks;
+pub mod kv;
+pub mod log;
+#[cfg(any(test, feature = "memory-storage"))]
+pub mod memorykv;
+pub mod meta;
+#[cfg(feature = "fastly")]
+pub mod metrics;
+#[cfg(feature = "fastly")]
+pub mod mirror;
 pub mod mqtthandler;
 pub mod mqttpacket;
+#[cfg(feature = "fastly")]
 pub mod mqtttransport;
+pub mod namespace;
+#[cfg(feature = "fastly")]
+pub mod openapi;
+#[cfg(feature = "fastly")]
+pub mod presence;
+#[cfg(feature = "fastly")]
+pub mod problem;
+#[cfg(feature = "fastly")]
+pub mod publickeys;
 pub mod publish;
+#[cfg(feature = "fastly")]
+pub mod quota;
+#[cfg(feature = "fastly")]
+pub mod receipt;
+#[cfg(feature = "fastly")]
+pub mod remotekv;
+#[cfg(feature = "fastly")]
 pub mod routes;
+pub mod routing;
+pub mod signature;
+#[cfg(feature = "fastly")]
+pub mod socketio;
+pub mod sse;
+pub mod stats;
 pub mod storage;
+pub mod topic;
+pub mod trace;
+#[cfg(feature = "fastly")]
+pub mod version;
+#[cfg(feature = "fastly")]
+pub mod webhook;
 pub mod websocket;
+#[cfg(feature = "fastly")]
+pub mod wiring;
@@ -0,0 +1,513 @@
+#[cfg(feature = "fastly")]
+use fastly::log::Endpoint;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "fastly")]
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// by default, repeats of an error line are logged at most once a minute
+pub const ERROR_INTERVAL_DEFAULT: u32 = 60;
+
+// error lines are keyed by their message, up to this many bytes
+const ERROR_KEY_SIZE_MAX: usize = 200;
+
+// bound on distinct error lines tracked at once. others are always logged
+const ERROR_KEYS_MAX: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    // unix time the line was last logged, in seconds
+    last: u64,
+
+    // repeats since then that weren't logged
+    #[serde(default)]
+    suppressed: u64,
+}
+
+// rate-limits error lines by message. a line is logged the first time, and
+// repeats within the interval are only counted. the count is logged with
+// the next repeat after the interval, or in a summary line if there is
+// none. the state is serializable, so that it can be shared by instances
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limiter {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Limiter {
+    // returns the number of repeats not logged since the line was last
+    // logged, if it should be logged now
+    pub fn check(&mut self, line: &str, now: u64, interval: u64) -> Option<u64> {
+        let key = error_key(line);
+
+        if let Some(e) = self.entries.get_mut(key) {
+            if now < e.last + interval {
+                e.suppressed += 1;
+
+                return None;
+            }
+
+            let suppressed = e.suppressed;
+            e.last = now;
+            e.suppressed = 0;
+
+            return Some(suppressed);
+        }
+
+        // too many distinct lines. log without tracking
+        if self.entries.len() < ERROR_KEYS_MAX {
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    last: now,
+                    suppressed: 0,
+                },
+            );
+        }
+
+        Some(0)
+    }
+
+    // forgets lines whose interval has passed. returns those that had
+    // repeats not logged, with their counts, for summary lines
+    pub fn expire(&mut self, now: u64, interval: u64) -> Vec<(String, u64)> {
+        let mut expired = Vec::new();
+
+        self.entries.retain(|key, e| {
+            if now < e.last + interval {
+                return true;
+            }
+
+            if e.suppressed > 0 {
+                expired.push((key.clone(), e.suppressed));
+            }
+
+            false
+        });
+
+        expired
+    }
+}
+
+fn error_key(line: &str) -> &str {
+    if line.len() <= ERROR_KEY_SIZE_MAX {
+        return line;
+    }
+
+    let mut end = ERROR_KEY_SIZE_MAX;
+
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &line[..end]
+}
+
+// an error line held until the end of a request, and how many more times
+// it was repeated meanwhile
+pub struct HeldError {
+    time: u64,
+    line: String,
+    repeats: u64,
+}
+
+struct Errors {
+    // used by instances that outlive requests, or when no shared state is
+    // available
+    limiter: Limiter,
+
+    // lines held to be checked against shared state, if holding
+    held: Option<Vec<HeldError>>,
+}
+
+static ERRORS: Mutex<Errors> = Mutex::new(Errors {
+    limiter: Limiter {
+        entries: BTreeMap::new(),
+    },
+    held: None,
+});
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+
+    #[default]
+    Info,
+
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "debug" => Some(Self::Debug),
+            "info" => Some(Self::Info),
+            "warn" => Some(Self::Warn),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        }
+    }
+}
+
+struct Settings {
+    level: Level,
+
+    // name of the log endpoint to write to. stdout if unset
+    endpoint: Option<String>,
+
+    // included in every line, to correlate the lines of a request
+    request_id: Option<String>,
+
+    // seconds between logging repeats of an error line
+    error_interval: u32,
+}
+
+static SETTINGS: Mutex<Settings> = Mutex::new(Settings {
+    level: Level::Info,
+    endpoint: None,
+    request_id: None,
+    error_interval: ERROR_INTERVAL_DEFAULT,
+});
+
+// applies the config's log settings. until called, lines at info and above
+// are written to stdout
+pub fn configure(level: Level, endpoint: Option<&str>, error_interval: u32) {
+    let mut settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+    settings.level = level;
+    settings.endpoint = endpoint.map(|s| s.to_string());
+    settings.error_interval = error_interval;
+}
+
+fn error_interval() -> u64 {
+    SETTINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .error_interval
+        .into()
+}
+
+pub fn set_request_id(id: &str) {
+    SETTINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .request_id = Some(id.to_string());
+}
+
+pub fn request_id() -> Option<String> {
+    SETTINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .request_id
+        .clone()
+}
+
+pub fn enabled(level: Level) -> bool {
+    level >= SETTINGS.lock().unwrap_or_else(|e| e.into_inner()).level
+}
+
+// a line is a JSON object with the time in unix milliseconds, the level,
+// the message and any fields. fields can't replace the standard keys
+fn format_line(
+    time: u64,
+    level: Level,
+    msg: &str,
+    request_id: Option<&str>,
+    fields: &[(&str, String)],
+) -> String {
+    let mut obj = serde_json::Map::new();
+
+    obj.insert("time".to_string(), time.into());
+    obj.insert("level".to_string(), level.as_str().into());
+    obj.insert("msg".to_string(), msg.into());
+
+    if let Some(id) = request_id {
+        obj.insert("request_id".to_string(), id.into());
+    }
+
+    for (k, v) in fields {
+        if !obj.contains_key(*k) {
+            obj.insert(k.to_string(), v.as_str().into());
+        }
+    }
+
+    serde_json::Value::Object(obj).to_string()
+}
+
+#[cfg(feature = "fastly")]
+fn write_endpoint(name: &str, line: &str) -> bool {
+    match Endpoint::try_from_name(name) {
+        Ok(mut endpoint) => endpoint.write_all(line.as_bytes()).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// log endpoints only exist on Compute
+#[cfg(not(feature = "fastly"))]
+fn write_endpoint(_name: &str, _line: &str) -> bool {
+    false
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn write_line(time: u64, level: Level, msg: &str, fields: &[(&str, String)]) {
+    let (endpoint, request_id) = {
+        let settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+        (settings.endpoint.clone(), settings.request_id.clone())
+    };
+
+    let line = format_line(time, level, msg, request_id.as_deref(), fields);
+
+    // fall back to stdout, so that lines aren't lost
+    if let Some(name) = endpoint {
+        if write_endpoint(&name, &line) {
+            return;
+        }
+    }
+
+    println!("{line}");
+}
+
+// logs a line if its level is enabled. see the log_* macros
+pub fn emit(level: Level, fields: &[(&str, String)], args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    write_line(now_ms(), level, &args.to_string(), fields);
+}
+
+// logs an error line through a limiter, along with summaries of lines
+// whose repeats weren't logged. lines include the count of repeats not
+// logged since they were last logged, as "suppressed"
+fn limit_error(limiter: &mut Limiter, time: u64, line: &str, repeats: u64, interval: u64) {
+    let now = time / 1000;
+
+    for _ in 0..=repeats {
+        match limiter.check(line, now, interval) {
+            Some(0) => write_line(time, Level::Error, line, &[]),
+            Some(suppressed) => write_line(
+                time,
+                Level::Error,
+                line,
+                &[("suppressed", suppressed.to_string())],
+            ),
+            None => {}
+        }
+    }
+
+    for (summarized, suppressed) in limiter.expire(now, interval) {
+        write_line(
+            time,
+            Level::Error,
+            &summarized,
+            &[
+                ("suppressed", suppressed.to_string()),
+                ("summary", "true".to_string()),
+            ],
+        );
+    }
+}
+
+// logs an error line, rate-limited by message. while errors are held, the
+// line is kept to be logged at the end of the request instead
+pub fn error(args: fmt::Arguments) {
+    let line = args.to_string();
+    let time = now_ms();
+    let interval = error_interval();
+
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(held) = &mut errors.held {
+        if let Some(h) = held.iter_mut().find(|h| h.line == line) {
+            h.repeats += 1;
+
+            return;
+        }
+
+        if held.len() < ERROR_KEYS_MAX {
+            held.push(HeldError {
+                time,
+                line,
+                repeats: 0,
+            });
+
+            return;
+        }
+    }
+
+    limit_error(&mut errors.limiter, time, &line, 0, interval);
+}
+
+// holds error lines until take_held_errors is called. Compute starts each
+// request in a new instance, so lines are held to be limited with state
+// shared across requests, by release_errors
+pub fn hold_errors() {
+    ERRORS.lock().unwrap_or_else(|e| e.into_inner()).held = Some(Vec::new());
+}
+
+// stops holding error lines, and returns those held
+pub fn take_held_errors() -> Vec<HeldError> {
+    ERRORS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .held
+        .take()
+        .unwrap_or_default()
+}
+
+// logs held error lines through the given limiter, or the instance's if
+// there is none
+pub fn release_errors(held: Vec<HeldError>, limiter: Option<&mut Limiter>) {
+    let interval = error_interval();
+
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let limiter = match limiter {
+        Some(l) => l,
+        None => &mut errors.limiter,
+    };
+
+    for h in held {
+        limit_error(limiter, h.time, &h.line, h.repeats, interval);
+    }
+}
+
+// logs at a level, optionally with fields given in braces before the
+// message, e.g. log_at!(Level::Info, {cid = cid}, "closing {reason}")
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, { $($k:ident = $v:expr),* $(,)? }, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::emit($level, &[$((stringify!($k), $v.to_string())),*], format_args!($($arg)+))
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log_at!($level, {}, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::log::Level::Debug, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::log::Level::Info, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::log::Level::Warn, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::log::error(format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiting() {
+        let mut l = Limiter::default();
+
+        assert_eq!(l.check("a", 100, 10), Some(0));
+        assert_eq!(l.check("a", 101, 10), None);
+        assert_eq!(l.check("a", 102, 10), None);
+        assert_eq!(l.check("b", 102, 10), Some(0));
+        assert_eq!(l.check("b", 103, 10), None);
+
+        // logged with the count once the interval has passed
+        assert_eq!(l.check("a", 110, 10), Some(2));
+        assert_eq!(l.check("a", 111, 10), None);
+
+        // "b" wasn't repeated after its interval, so it's summarized
+        assert_eq!(l.expire(112, 10), vec![("b".to_string(), 1)]);
+        assert_eq!(l.check("b", 112, 10), Some(0));
+
+        // lines without repeats are forgotten quietly
+        assert_eq!(l.expire(125, 10), vec![("a".to_string(), 1)]);
+        assert_eq!(l.expire(125, 10), vec![]);
+        assert_eq!(l, Limiter::default());
+
+        // long lines are keyed by their start
+        let long = "x".repeat(ERROR_KEY_SIZE_MAX);
+        assert_eq!(l.check(&format!("{long}1"), 200, 10), Some(0));
+        assert_eq!(l.check(&format!("{long}2"), 200, 10), None);
+
+        // the state can be kept between instances
+        let s = serde_json::to_string(&l).unwrap();
+        let mut l: Limiter = serde_json::from_str(&s).unwrap();
+        assert_eq!(l.check(&long, 210, 10), Some(1));
+    }
+
+    #[test]
+    fn too_many_lines() {
+        let mut l = Limiter::default();
+
+        for i in 0..ERROR_KEYS_MAX {
+            assert_eq!(l.check(&i.to_string(), 100, 10), Some(0));
+        }
+
+        // not tracked, so never suppressed
+        assert_eq!(l.check("new", 100, 10), Some(0));
+        assert_eq!(l.check("new", 100, 10), Some(0));
+        assert_eq!(l.check("0", 100, 10), None);
+    }
+
+    #[test]
+    fn format() {
+        let line = format_line(
+            1000,
+            Level::Warn,
+            "hello",
+            Some("r1"),
+            &[
+                ("cid", "c1".to_string()),
+                ("msg", "x".to_string()),
+                ("request_id", "x".to_string()),
+            ],
+        );
+
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            v,
+            serde_json::json!({"time": 1000, "level": "warn", "msg": "hello", "request_id": "r1", "cid": "c1"})
+        );
+
+        assert_eq!(Level::parse("debug"), Some(Level::Debug));
+        assert_eq!(Level::parse("loud"), None);
+        assert!(Level::Error > Level::Warn && Level::Info > Level::Debug);
+    }
+}
@@ -1,5 +1,6 @@
 use fastly::{Error, Request};
-use pubsub::{auth, config, routes, storage};
+use pubsub::kv::Kv;
+use pubsub::{auth, config, routes, wiring};
 use std::env;
 
 fn main() -> Result<(), Error> {
@@ -7,8 +8,20 @@ fn main() -> Result<(), Error> {
     let local = fastly_host == "localhost";
     let req = Request::from_client();
 
-    let app_token_authorizor = Box::new(auth::KVStoreAppTokenAuthorizor::new("keys"));
-    let storage = storage::KVStoreStorage::new("messages");
+    let resources = wiring::Resources {
+        config_store: "config",
+        keys_store: "keys",
+        messages_store: "messages",
+        secret_store: "secrets",
+    };
+
+    let app_token_authorizor = Box::new(auth::KVStoreAppTokenAuthorizor::new(resources.keys_store));
+
+    #[cfg(not(feature = "memory-storage"))]
+    let kv: Box<dyn Kv> = Box::new(pubsub::kv::FastlyKv::new(resources.messages_store));
+
+    #[cfg(feature = "memory-storage")]
+    let kv: Box<dyn Kv> = Box::new(pubsub::memorykv::MemoryKv::new());
 
     let (config_source, auth) = if local {
         let config_source: Box<dyn config::Source> = Box::new(config::TestSource);
@@ -17,23 +30,30 @@ fn main() -> Result<(), Error> {
             grip: Box::new(auth::TestGripAuthorizor),
             fastly: false,
             app_token: app_token_authorizor,
+            token_validation: config::TokenValidation::default(),
+            client_cert: auth::client_cert_identities(&req),
+            namespace: None,
         };
 
         (config_source, auth)
     } else {
-        let config_source: Box<dyn config::Source> =
-            Box::new(config::ConfigAndSecretStoreSource::new("config", "secrets"));
+        let config_source: Box<dyn config::Source> = Box::new(
+            config::ConfigAndSecretStoreSource::new(resources.config_store, resources.secret_store),
+        );
 
         let auth = auth::Authorization {
             grip: Box::new(auth::FanoutGripAuthorizor),
             fastly: req.fastly_key_is_valid(),
             app_token: app_token_authorizor,
+            token_validation: config::TokenValidation::default(),
+            client_cert: auth::client_cert_identities(&req),
+            namespace: None,
         };
 
         (config_source, auth)
     };
 
-    routes::handle_request(&*config_source, &auth, &storage, req)?;
+    routes::handle_request(&*config_source, auth, kv, &resources, req)?;
 
     Ok(())
 }
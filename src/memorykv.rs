@@ -0,0 +1,192 @@
+use crate::kv::{Condition, Insert, Item, Kv, KvError, ListPage};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Instant;
+
+// the most keys listed at once, if no limit is given
+const LIST_LIMIT_DEFAULT: u32 = 1000;
+
+struct Entry {
+    value: Vec<u8>,
+    metadata: Option<Vec<u8>>,
+    generation: u64,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|t| t > now)
+    }
+}
+
+// a key-value store held in memory, with the same conditions,
+// generations and expiry as a KV store. for tests, and for running
+// locally without a KV store. nothing is shared between instances, so
+// under viceroy, items only last for the request
+#[derive(Default)]
+pub struct MemoryKv {
+    entries: RefCell<BTreeMap<String, Entry>>,
+    last_generation: Cell<u64>,
+}
+
+impl MemoryKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Kv for MemoryKv {
+    fn lookup(&self, key: &str) -> Result<Option<Item>, KvError> {
+        let entries = self.entries.borrow();
+
+        let item = entries
+            .get(key)
+            .filter(|e| e.is_live(Instant::now()))
+            .map(|e| Item {
+                value: e.value.clone(),
+                metadata: e.metadata.clone(),
+                generation: e.generation,
+            });
+
+        Ok(item)
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>, insert: &Insert) -> Result<(), KvError> {
+        let now = Instant::now();
+
+        let mut entries = self.entries.borrow_mut();
+
+        let current = entries.get(key).filter(|e| e.is_live(now));
+
+        let allowed = match insert.condition {
+            Condition::Always => true,
+            Condition::Absent => current.is_none(),
+            Condition::Generation(g) => current.is_some_and(|e| e.generation == g),
+        };
+
+        if !allowed {
+            return Err(KvError::PreconditionFailed);
+        }
+
+        let generation = self.last_generation.get() + 1;
+        self.last_generation.set(generation);
+
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                metadata: insert.metadata.map(|s| s.as_bytes().to_vec()),
+                generation,
+                expires_at: insert.ttl.map(|ttl| now + ttl),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), KvError> {
+        self.entries.borrow_mut().remove(key);
+
+        Ok(())
+    }
+
+    // keys are listed in order, and the cursor is the last key listed
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<ListPage, KvError> {
+        let now = Instant::now();
+        let limit = limit.unwrap_or(LIST_LIMIT_DEFAULT) as usize;
+
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Included(prefix),
+        };
+
+        let entries = self.entries.borrow();
+
+        let mut keys = entries
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .filter(|(_, e)| e.is_live(now))
+            .map(|(k, _)| k.clone());
+
+        let page: Vec<String> = keys.by_ref().take(limit).collect();
+
+        let cursor = match keys.next() {
+            Some(_) => page.last().cloned(),
+            None => None,
+        };
+
+        Ok(ListPage { keys: page, cursor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn conditions() {
+        let kv = MemoryKv::new();
+
+        assert!(kv.lookup("a").unwrap().is_none());
+
+        kv.insert("a", b"1".to_vec(), &Insert::default()).unwrap();
+        let g1 = kv.lookup("a").unwrap().unwrap().generation;
+
+        let absent = Insert {
+            condition: Condition::Absent,
+            ..Default::default()
+        };
+        assert!(matches!(
+            kv.insert("a", b"2".to_vec(), &absent),
+            Err(KvError::PreconditionFailed)
+        ));
+
+        let matching = Insert {
+            metadata: Some("m"),
+            condition: Condition::Generation(g1),
+            ..Default::default()
+        };
+        kv.insert("a", b"2".to_vec(), &matching).unwrap();
+
+        let item = kv.lookup("a").unwrap().unwrap();
+        assert_eq!(item.value, b"2");
+        assert_eq!(item.metadata.as_deref(), Some(&b"m"[..]));
+        assert_ne!(item.generation, g1);
+
+        // the generation has moved on
+        assert!(matches!(
+            kv.insert("a", b"3".to_vec(), &matching),
+            Err(KvError::PreconditionFailed)
+        ));
+
+        let expired = Insert {
+            ttl: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        kv.insert("b", b"1".to_vec(), &expired).unwrap();
+        assert!(kv.lookup("b").unwrap().is_none());
+        kv.insert("b", b"1".to_vec(), &absent).unwrap();
+
+        for key in ["p:1", "p:2", "p:3", "q:1"] {
+            kv.insert(key, Vec::new(), &Insert::default()).unwrap();
+        }
+
+        let page = kv.list("p:", None, Some(2)).unwrap();
+        assert_eq!(page.keys, ["p:1", "p:2"]);
+
+        let page = kv.list("p:", page.cursor.as_deref(), Some(2)).unwrap();
+        assert_eq!(page.keys, ["p:3"]);
+        assert!(page.cursor.is_none());
+
+        kv.delete("a").unwrap();
+        kv.delete("a").unwrap();
+        assert!(kv.lookup("a").unwrap().is_none());
+    }
+}
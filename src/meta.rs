@@ -0,0 +1,286 @@
+use crate::log_info;
+use crate::trace::TraceParent;
+#[cfg(feature = "fastly")]
+use crate::trace::TRACEPARENT;
+use base64::Engine;
+#[cfg(feature = "fastly")]
+use fastly::http::header;
+#[cfg(feature = "fastly")]
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+
+const HEADER_PREFIX: &str = "pubsub-meta-";
+
+const CONTENT_TYPE_LEN_MAX: usize = 255;
+
+// metadata of retained messages is stored in KV item metadata, which is
+// limited in size
+pub const USER_META_SIZE_MAX: usize = 1024;
+
+// publisher-provided information about a message, carried to subscribers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageMeta {
+    #[serde(
+        rename = "content-type",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub content_type: Option<String>,
+
+    // name/value pairs, in the order given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user: Vec<(String, String)>,
+
+    // unix timestamp, in seconds, after which the message should no longer
+    // be delivered. independent of how long a retained message is kept
+    #[serde(
+        rename = "expires-at",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expires_at: Option<u64>,
+
+    // W3C trace context of the span that handled the publish, so that
+    // delivery can be traced back to the producer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+
+    // MQTT request/response properties: where to send a reply, and data
+    // identifying the request, base64-encoded
+    #[serde(
+        rename = "response-topic",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub response_topic: Option<String>,
+
+    #[serde(
+        rename = "correlation-data",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub correlation_data: Option<String>,
+
+    // URL to send delivery receipts to. not passed on to subscribers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+}
+
+impl MessageMeta {
+    pub fn is_empty(&self) -> bool {
+        self.content_type.is_none()
+            && self.user.is_empty()
+            && self.expires_at.is_none()
+            && self.traceparent.is_none()
+            && self.response_topic.is_none()
+            && self.correlation_data.is_none()
+            && self.receipt.is_none()
+    }
+
+    // size of the metadata that counts toward USER_META_SIZE_MAX.
+    // correlation data counts as its raw size, not as encoded
+    pub fn user_size(&self) -> usize {
+        let user: usize = self.user.iter().map(|(k, v)| k.len() + v.len()).sum();
+
+        user + self.response_topic.as_ref().map_or(0, |s| s.len())
+            + self.correlation_bytes().map_or(0, |b| b.len())
+    }
+
+    pub fn set_correlation_data(&mut self, data: &[u8]) {
+        self.correlation_data = Some(base64::prelude::BASE64_STANDARD.encode(data));
+    }
+
+    pub fn correlation_bytes(&self) -> Option<Vec<u8>> {
+        base64::prelude::BASE64_STANDARD
+            .decode(self.correlation_data.as_ref()?)
+            .ok()
+    }
+
+    // seconds until expiration, as of now
+    pub fn expiry_interval(&self, now: u64) -> Option<u32> {
+        self.expires_at
+            .map(|at| at.saturating_sub(now).min(u32::MAX.into()) as u32)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(at) => at <= now,
+            None => false,
+        }
+    }
+
+    // reads the Content-Type header, any Pubsub-Meta-{NAME} headers and
+    // the traceparent header. an invalid traceparent is ignored
+    #[cfg(feature = "fastly")]
+    pub fn from_request(req: &Request) -> Result<Self, String> {
+        let headers = req
+            .get_headers()
+            .map(|(name, value)| (name.as_str(), value.to_str().ok()));
+
+        let mut meta = parse(req.get_header_str(header::CONTENT_TYPE), headers)?;
+
+        meta.traceparent = req.get_header_str(TRACEPARENT).map(|s| s.to_string());
+
+        Ok(meta)
+    }
+
+    // continues the producer's trace, if any, in a new span for handling
+    // the message. the span is logged, and replaces the producer's so that
+    // it is what Fanout and subscribers see
+    pub fn start_span(&mut self, topic: &str) {
+        let Some(parent) = self.traceparent.take() else {
+            return;
+        };
+
+        let Some(parent) = TraceParent::parse(&parent) else {
+            return;
+        };
+
+        let span = parent.child();
+
+        log_info!(
+            {trace_id = span.trace_id, span_id = span.parent_id, parent_id = parent.parent_id},
+            "publishing to {topic}"
+        );
+
+        self.traceparent = Some(span.to_string());
+    }
+}
+
+// reads metadata from a message's content type and its headers, as name
+// and value pairs. values that aren't valid strings are None
+pub fn parse<'a, I>(content_type: Option<&str>, headers: I) -> Result<MessageMeta, String>
+where
+    I: Iterator<Item = (&'a str, Option<&'a str>)>,
+{
+    let content_type = match content_type {
+        // what curl sends by default. it doesn't describe the message
+        Some("application/x-www-form-urlencoded") | None => None,
+        Some(s) if s.len() > CONTENT_TYPE_LEN_MAX => {
+            return Err(format!(
+                "Content type exceeds {CONTENT_TYPE_LEN_MAX} bytes maximum"
+            ))
+        }
+        Some(s) => Some(s.to_string()),
+    };
+
+    let mut user = Vec::new();
+    let mut size = 0;
+
+    for (name, value) in headers {
+        let lname = name.to_ascii_lowercase();
+
+        let Some(name) = lname.strip_prefix(HEADER_PREFIX) else {
+            continue;
+        };
+
+        if name.is_empty() {
+            return Err("Invalid metadata header name".to_string());
+        }
+
+        let Some(value) = value else {
+            return Err(format!("Invalid value for metadata header: {name}"));
+        };
+
+        size += name.len() + value.len();
+
+        if size > USER_META_SIZE_MAX {
+            return Err(format!(
+                "Metadata exceeds {USER_META_SIZE_MAX} bytes maximum"
+            ));
+        }
+
+        user.push((name.to_string(), value.to_string()));
+    }
+
+    Ok(MessageMeta {
+        content_type,
+        user,
+        expires_at: None,
+        traceparent: None,
+        response_topic: None,
+        correlation_data: None,
+        receipt: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let headers = [
+            ("authorization", Some("Bearer x")),
+            ("pubsub-meta-encoding", Some("utf-8")),
+            ("Pubsub-Meta-Schema", Some("v2")),
+        ];
+
+        let m = parse(Some("application/json"), headers.into_iter()).unwrap();
+        assert_eq!(m.content_type.as_deref(), Some("application/json"));
+        assert_eq!(
+            m.user,
+            vec![
+                ("encoding".to_string(), "utf-8".to_string()),
+                ("schema".to_string(), "v2".to_string()),
+            ]
+        );
+
+        let m = parse(Some("application/x-www-form-urlencoded"), [].into_iter()).unwrap();
+        assert!(m.is_empty());
+
+        assert!(parse(None, [("pubsub-meta-", Some("x"))].into_iter()).is_err());
+        assert!(parse(None, [("pubsub-meta-a", None)].into_iter()).is_err());
+
+        let big = "x".repeat(USER_META_SIZE_MAX);
+        assert!(parse(None, [("pubsub-meta-a", Some(big.as_str()))].into_iter()).is_err());
+    }
+
+    #[test]
+    fn span() {
+        let producer = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+        let mut m = MessageMeta {
+            traceparent: Some(producer.to_string()),
+            ..Default::default()
+        };
+        m.start_span("a");
+
+        let t = TraceParent::parse(m.traceparent.as_deref().unwrap()).unwrap();
+        assert_eq!(t.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(t.parent_id, "00f067aa0ba902b7");
+
+        let mut m = MessageMeta {
+            traceparent: Some("invalid".to_string()),
+            ..Default::default()
+        };
+        m.start_span("a");
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn expiry() {
+        let m = MessageMeta::default();
+        assert_eq!(m.expiry_interval(1000), None);
+        assert!(!m.is_expired(1000));
+
+        let m = MessageMeta {
+            expires_at: Some(1030),
+            ..Default::default()
+        };
+        assert_eq!(m.expiry_interval(1000), Some(30));
+        assert!(!m.is_expired(1000));
+        assert_eq!(m.expiry_interval(2000), Some(0));
+        assert!(m.is_expired(1030));
+    }
+
+    #[test]
+    fn user_size() {
+        let mut m = MessageMeta::default();
+        m.user.push(("a".to_string(), "bc".to_string()));
+        m.response_topic = Some("replies".to_string());
+        m.set_correlation_data(&[0; 10]);
+
+        assert_eq!(m.user_size(), 3 + 7 + 10);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::admin::{self, KeyPath};
+use crate::log_error;
+use crate::publickeys;
+use crate::stats::Counts;
+use fastly::http::StatusCode;
+use fastly::log::Endpoint;
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    ClientError,
+    ServerError,
+}
+
+impl Outcome {
+    pub fn from_status(status: StatusCode) -> Self {
+        if status.is_server_error() {
+            Self::ServerError
+        } else if status.is_client_error() {
+            Self::ClientError
+        } else {
+            Self::Success
+        }
+    }
+}
+
+// one record per request
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    // unix milliseconds
+    pub time: u64,
+
+    pub request_id: &'a str,
+
+    pub route: &'a str,
+    pub method: &'a str,
+    pub status: u16,
+    pub outcome: Outcome,
+
+    // from receiving the request until the response was sent
+    pub latency_ms: u64,
+
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+// the route a path is handled by, with path parameters left as names, so
+// that records can be grouped by route
+pub fn route_name(path: &str) -> &'static str {
+    match path {
+        "/" => return "/",
+        "/healthz" => return "/healthz",
+        "/openapi.json" => return "/openapi.json",
+        "/version" => return "/version",
+        "/events" => return "/events",
+        "/events/transaction" => return "/events/transaction",
+        "/events/subscriptions" => return "/events/subscriptions",
+        "/mqtt" => return "/mqtt",
+        "/presence" => return "/presence",
+        "/bayeux" => return "/bayeux",
+        "/socket.io" | "/socket.io/" => return "/socket.io/",
+        "/admin/keys" => return "/admin/keys",
+        "/tokens" => return "/tokens",
+        "/admin/tokens" => return "/admin/tokens",
+        "/admin/selftest" => return "/admin/selftest",
+        "/admin/config" => return "/admin/config",
+        "/admin/stats" => return "/admin/stats",
+        "/admin/retained" => return "/admin/retained",
+        "/admin/broadcast" => return "/admin/broadcast",
+        "/admin/scheduled" => return "/admin/scheduled",
+        _ => {}
+    }
+
+    match admin::parse_key_path(path) {
+        Some(KeyPath::Key(_)) => return "/admin/keys/{id}",
+        Some(KeyPath::Rotate(_)) => return "/admin/keys/{id}/rotate",
+        None => {}
+    }
+
+    if admin::parse_retained_path(path).is_some() {
+        return "/admin/retained/{topic}";
+    }
+
+    if admin::parse_connection_close_path(path).is_some() {
+        return "/admin/connections/{cid}/close";
+    }
+
+    if publickeys::parse_path(path).is_some() {
+        return "/topics/{topic}/public-keys";
+    }
+
+    "other"
+}
+
+// failures are logged, since the request has already been handled
+pub fn emit(endpoint: &str, record: &Record) {
+    let data = serde_json::to_vec(record).expect("record should always be serializable");
+
+    match Endpoint::try_from_name(endpoint) {
+        Ok(mut e) => {
+            if let Err(e) = e.write_all(&data) {
+                log_error!("failed to write metrics to {endpoint}: {e}");
+            }
+        }
+        Err(e) => log_error!("failed to open log endpoint {endpoint}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        assert_eq!(route_name("/events"), "/events");
+        assert_eq!(route_name("/admin/keys/k1"), "/admin/keys/{id}");
+        assert_eq!(
+            route_name("/admin/keys/k1/rotate"),
+            "/admin/keys/{id}/rotate"
+        );
+        assert_eq!(
+            route_name("/admin/retained/a%2Fb"),
+            "/admin/retained/{topic}"
+        );
+        assert_eq!(
+            route_name("/admin/connections/c1/close"),
+            "/admin/connections/{cid}/close"
+        );
+        assert_eq!(
+            route_name("/topics/a/public-keys"),
+            "/topics/{topic}/public-keys"
+        );
+        assert_eq!(route_name("/nope"), "other");
+
+        assert_eq!(Outcome::from_status(StatusCode::OK), Outcome::Success);
+        assert_eq!(
+            Outcome::from_status(StatusCode::FORBIDDEN),
+            Outcome::ClientError
+        );
+        assert_eq!(
+            Outcome::from_status(StatusCode::BAD_GATEWAY),
+            Outcome::ServerError
+        );
+
+        let counts = Counts {
+            publishes: 2,
+            payload_bytes: 10,
+            ..Default::default()
+        };
+
+        let record = Record {
+            time: 1000,
+            request_id: "r1",
+            route: "/events",
+            method: "POST",
+            status: 200,
+            outcome: Outcome::Success,
+            latency_ms: 12,
+            counts,
+        };
+
+        let v = serde_json::to_value(&record).unwrap();
+        assert_eq!(v["route"], "/events");
+        assert_eq!(v["outcome"], "success");
+        assert_eq!(v["publishes"], 2);
+        assert_eq!(v["payload_bytes"], 10);
+        assert_eq!(v["storage_retries"], 0);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::config::{Config, Mirror};
+use crate::deadline::Deadline;
+use crate::log;
+use crate::log_error;
+use crate::meta::MessageMeta;
+use crate::remotekv::url_backend;
+use crate::storage::unix_now_ms;
+use base64::Engine;
+use fastly::http::header;
+use fastly::Request;
+use serde::Serialize;
+use std::sync::Mutex;
+
+// records are sent in batches of up to this many. a request that publishes
+// to many topics, such as a broadcast, sends several batches at once
+const BATCH_SIZE_MAX: usize = 100;
+
+// the format of the kafka REST proxy's produce API, which collectors can
+// also accept as plain JSON
+const CONTENT_TYPE: &str = "application/vnd.kafka.json.v2+json";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub topic: String,
+
+    // text messages are sent as they are, and others base64-encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_base64: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub meta: Vec<(String, String)>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+
+    // unix milliseconds
+    pub published_at: u64,
+}
+
+// keyed by topic, so that a topic's messages stay in order within a
+// partition
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    key: &'a str,
+    value: &'a Message,
+}
+
+#[derive(Debug, Serialize)]
+struct Batch<'a> {
+    records: Vec<Record<'a>>,
+}
+
+// messages published while handling the current request, not yet sent
+static PENDING: Mutex<Vec<Message>> = Mutex::new(Vec::new());
+
+// notes a published message, if mirroring is enabled
+pub fn published(config: &Config, topic: &str, message: &[u8], meta: &MessageMeta) {
+    if config.mirror.is_none() {
+        return;
+    }
+
+    let (data, data_base64) = match std::str::from_utf8(message) {
+        Ok(s) => (Some(s.to_string()), None),
+        Err(_) => (None, Some(base64::prelude::BASE64_STANDARD.encode(message))),
+    };
+
+    let m = Message {
+        topic: topic.to_string(),
+        data,
+        data_base64,
+        content_type: meta.content_type.clone(),
+        meta: meta.user.clone(),
+        traceparent: meta.traceparent.clone(),
+        request_id: log::request_id(),
+        published_at: unix_now_ms(),
+    };
+
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push(m);
+}
+
+// returns the pending messages and clears them
+pub fn take() -> Vec<Message> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+fn batch_body(messages: &[Message]) -> Vec<u8> {
+    let batch = Batch {
+        records: messages
+            .iter()
+            .map(|m| Record {
+                key: &m.topic,
+                value: m,
+            })
+            .collect(),
+    };
+
+    serde_json::to_vec(&batch).expect("batch should always be serializable")
+}
+
+// batches are sent together and then waited on. failures are logged, and
+// batches aren't retried
+pub fn send(mirror: &Mirror, messages: Vec<Message>, deadline: Deadline) {
+    let backend = match url_backend(mirror.backend.as_deref(), "mirror", &mirror.url) {
+        Ok(b) => b,
+        Err(e) => {
+            log_error!("failed to mirror {} messages: {e}", messages.len());
+            return;
+        }
+    };
+
+    let mut pending = Vec::new();
+
+    for chunk in messages.chunks(BATCH_SIZE_MAX) {
+        let mut req = Request::post(&mirror.url)
+            .with_header(header::CONTENT_TYPE, CONTENT_TYPE)
+            .with_body(batch_body(chunk));
+
+        if let Some(token) = &mirror.token {
+            req.set_header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        match req.send_async(backend.clone()) {
+            Ok(p) => pending.push((chunk.len(), p)),
+            Err(e) => log_error!("failed to mirror {} messages: {e}", chunk.len()),
+        }
+
+        if deadline.expired() {
+            break;
+        }
+    }
+
+    for (count, p) in pending {
+        match p.wait() {
+            Ok(resp) if resp.get_status().is_success() => {}
+            Ok(resp) => log_error!(
+                "mirroring {count} messages failed with status {}",
+                resp.get_status().as_u16()
+            ),
+            Err(e) => log_error!("failed to mirror {count} messages: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let meta = MessageMeta {
+            content_type: Some("text/plain".to_string()),
+            user: vec![("k".to_string(), "v".to_string())],
+            ..Default::default()
+        };
+
+        take();
+
+        published(&Config::default(), "a", b"hello", &meta);
+        assert!(take().is_empty());
+
+        let config = Config {
+            mirror: Some(Mirror {
+                url: "https://example.com/topics/pubsub".to_string(),
+                backend: None,
+                token: None,
+            }),
+            ..Default::default()
+        };
+
+        published(&config, "a", b"hello", &meta);
+        published(&config, "b", &[0xff], &MessageMeta::default());
+
+        let messages = take();
+        assert_eq!(messages.len(), 2);
+        assert!(take().is_empty());
+
+        let v: serde_json::Value = serde_json::from_slice(&batch_body(&messages)).unwrap();
+        assert_eq!(v["records"][0]["key"], "a");
+        assert_eq!(v["records"][0]["value"]["data"], "hello");
+        assert_eq!(v["records"][0]["value"]["meta"][0][1], "v");
+        assert_eq!(v["records"][1]["value"]["data_base64"], "/w==");
+        assert!(v["records"][1]["value"].get("data").is_none());
+    }
+}
@@ -1,30 +1,93 @@
 use crate::auth::Authorization;
 use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::geo::{self, Location};
+use crate::meta::{MessageMeta, USER_META_SIZE_MAX};
 use crate::mqttpacket::{
     ConnAck, ConnAckV4, Connect, Disconnect, Packet, PingReq, PingResp, Publish, Reason, SubAck,
     Subscribe, UnsubAck, Unsubscribe,
 };
-use crate::publish::{publish, Sequencing, MESSAGE_SIZE_MAX};
-use crate::storage::{RetainedVersion, Storage, StorageError};
+use crate::namespace;
+use crate::publish::{check_line_lengths, Batch, Sequencing, MESSAGE_SIZE_MAX};
+#[cfg(feature = "fastly")]
+// receipts are sent over Fastly backends
+#[cfg(feature = "fastly")]
+use crate::receipt::{self, Transport};
+use crate::routing;
+use crate::signature::{self, SignatureError};
+use crate::stats::{self, Counter};
+use crate::storage::{unix_now, RetainedMessage, RetainedVersion, Storage, StorageError};
+use crate::topic::{self, TopicError};
+use crate::trace::TRACEPARENT;
+use crate::{log_debug, log_error, log_info, log_warn};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::ops::Not;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const PACKET_SIZE_MAX: usize = 32_768;
 
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
+fn is_zero_u64(x: &u64) -> bool {
+    *x == 0
+}
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct Version {
+    #[serde(rename = "e", skip_serializing_if = "is_zero", default)]
+    pub epoch: u32,
+
     #[serde(rename = "g")]
     pub generation: u64,
 
     #[serde(rename = "s")]
     pub seq: u64,
+
+    #[serde(rename = "t", skip_serializing_if = "is_zero_u64", default)]
+    pub started: u64,
+}
+
+impl From<&RetainedVersion> for Version {
+    fn from(v: &RetainedVersion) -> Self {
+        Self {
+            epoch: v.epoch,
+            generation: v.generation,
+            seq: v.seq,
+            started: v.started,
+        }
+    }
+}
+
+impl From<&Version> for RetainedVersion {
+    fn from(v: &Version) -> Self {
+        Self {
+            epoch: v.epoch,
+            generation: v.generation,
+            seq: v.seq,
+            started: v.started,
+        }
+    }
 }
 
 impl Version {
+    // the epoch is omitted if unknown, unless the generation's start time
+    // is known. same form as for SSE
     pub fn to_id(&self) -> String {
-        format!("{:16x}-{}", self.generation, self.seq)
+        if self.started > 0 {
+            format!(
+                "{}.{:x}.{:16x}-{}",
+                self.epoch, self.started, self.generation, self.seq
+            )
+        } else if self.epoch > 0 {
+            format!("{}.{:16x}-{}", self.epoch, self.generation, self.seq)
+        } else {
+            format!("{:16x}-{}", self.generation, self.seq)
+        }
     }
 }
 
@@ -49,12 +112,38 @@ pub struct Subscription {
     pub ignore: Vec<Version>,
 }
 
+impl Subscription {
+    // the ID of the last durable message known to the subscriber, for use
+    // as a GRIP prev-id
+    pub fn cursor_id(&self) -> String {
+        match self.last.as_ref().and_then(|l| l.version.as_ref()) {
+            Some(v) => v.to_id(),
+            None => "none".to_string(),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct State {
     pub connected: bool,
     pub client_id: String,
     pub token: Option<String>,
     pub subs: HashMap<String, Subscription>,
+
+    #[serde(rename = "sc", skip_serializing_if = "Option::is_none", default)]
+    pub sync_cursor: Option<String>,
+
+    // publishes counted against the token's rate limit, in the minute
+    // since the unix epoch given by publish_window
+    #[serde(rename = "pw", skip_serializing_if = "is_zero_u64", default)]
+    pub publish_window: u64,
+
+    #[serde(rename = "pc", skip_serializing_if = "is_zero", default)]
+    pub publish_count: u32,
+
+    // namespace of the token, which subscription topics are relative to
+    #[serde(rename = "ns", skip_serializing_if = "Option::is_none", default)]
+    pub namespace: Option<String>,
 }
 
 impl State {
@@ -63,6 +152,28 @@ impl State {
         self.client_id.clear();
         self.token = None;
         self.subs.clear();
+        self.sync_cursor = None;
+        self.publish_window = 0;
+        self.publish_count = 0;
+        self.namespace = None;
+    }
+
+    // counts a publish, returning false if the limit was already reached
+    fn count_publish(&mut self, limit: u32, now: u64) -> bool {
+        let window = now / 60;
+
+        if self.publish_window != window {
+            self.publish_window = window;
+            self.publish_count = 0;
+        }
+
+        if self.publish_count >= limit {
+            return false;
+        }
+
+        self.publish_count += 1;
+
+        true
     }
 }
 
@@ -70,8 +181,18 @@ pub struct Context<'a> {
     pub config: &'a Config,
     pub auth: &'a Authorization,
     pub storage: &'a dyn Storage,
+    pub deadline: Deadline,
+
+    // where the client is, if known. see geo.rs
+    pub location: Option<Location>,
+
     pub disconnect: bool,
+    pub sync_incomplete: bool,
     pub state: State,
+
+    // messages published by the packets of the current request, sent
+    // together once they have all been handled
+    pub publishes: Batch,
 }
 
 fn handle_connect<'a>(ctx: &mut Context, p: Connect<'a>) -> Vec<Packet<'a>> {
@@ -123,45 +244,65 @@ fn handle_pingreq(_ctx: &mut Context, _p: PingReq) -> Vec<Packet<'static>> {
 }
 
 fn handle_subscribe<'a>(ctx: &mut Context, p: Subscribe<'a>) -> Vec<Packet<'a>> {
-    if p.topic.is_empty() {
+    // reject wildcards, for now
+    if p.topic.chars().any(|c| ['#', '+'].contains(&c)) {
         return vec![Packet::SubAck(SubAck {
             id: p.id,
-            reason: Reason::UnspecifiedError,
+            reason: Reason::WildcardSubscriptionsNotSupported,
         })];
     }
 
-    // reject wildcards, for now
-    if p.topic.chars().any(|c| ['#', '+'].contains(&c)) {
+    if topic::validate(&ctx.config.topic_rules, p.topic).is_err() {
         return vec![Packet::SubAck(SubAck {
             id: p.id,
-            reason: Reason::WildcardSubscriptionsNotSupported,
+            reason: Reason::TopicFilterInvalid,
         })];
     }
 
-    let mut allowed = false;
-
-    if let Some(s) = &ctx.state.token {
-        if let Ok(caps) = ctx.auth.app_token.validate_token(s) {
-            if caps.can_subscribe(p.topic) {
-                allowed = true;
-            }
+    // without a token, only public topics can be subscribed to
+    let caps = match &ctx.state.token {
+        Some(s) => ctx.auth.validate_token(s).ok(),
+        None if !ctx.config.public_topics.is_empty() => {
+            Some(ctx.auth.anonymous(&ctx.config.public_topics))
         }
-    }
+        None => None,
+    };
 
-    if !allowed {
+    let Some(caps) = caps else {
         return vec![Packet::SubAck(SubAck {
             id: p.id,
             reason: Reason::NotAuthorized,
         })];
+    };
+
+    let topic = caps.resolve_topic(p.topic);
+
+    if !caps.can_subscribe(&topic) || !geo::can_subscribe(ctx.config, &topic, ctx.location.as_ref())
+    {
+        return vec![Packet::SubAck(SubAck {
+            id: p.id,
+            reason: Reason::NotAuthorized,
+        })];
+    }
+
+    ctx.state.namespace = caps.namespace().map(|s| s.to_string());
+
+    if let Some(max) = ctx.config.subscriptions_max(caps.max_subs()) {
+        if !ctx.state.subs.contains_key(&topic) && ctx.state.subs.len() >= max {
+            return vec![Packet::SubAck(SubAck {
+                id: p.id,
+                reason: Reason::QuotaExceeded,
+            })];
+        }
     }
 
     let mut retained = None;
 
-    match ctx.storage.read_retained(p.topic, None) {
+    match ctx.storage.read_retained(&topic, None) {
         Ok(Some(r)) => retained = Some(r),
         Ok(None) | Err(StorageError::StoreNotFound) => {}
         Err(e) => {
-            println!("failed to read message from storage: {e:?}");
+            log_error!("failed to read message from storage: {e:?}");
 
             return vec![Packet::SubAck(SubAck {
                 id: p.id,
@@ -170,13 +311,10 @@ fn handle_subscribe<'a>(ctx: &mut Context, p: Subscribe<'a>) -> Vec<Packet<'a>>
         }
     }
 
-    let version = retained.as_ref().map(|r| Version {
-        generation: r.version.generation,
-        seq: r.version.seq,
-    });
+    let version = retained.as_ref().map(|r| Version::from(&r.version));
 
     ctx.state.subs.insert(
-        p.topic.to_string(),
+        topic.clone(),
         Subscription {
             no_local: p.no_local,
             retain_as_published: p.retain_as_published,
@@ -190,18 +328,19 @@ fn handle_subscribe<'a>(ctx: &mut Context, p: Subscribe<'a>) -> Vec<Packet<'a>>
         reason: Reason::Success,
     })];
 
-    // 0 means send upon new subscription
+    // 0 means send upon new subscription. large messages are only
+    // delivered over SSE
     if p.retain_handling == 0 {
         if let Some(r) = retained {
-            if let Some(message) = r.message {
-                out.push(Packet::Publish(Publish {
-                    topic: p.topic.into(),
-                    message: message.data.into(),
-                    dup: false,
-                    qos: 0,
-                    retain: true,
-                    message_expiry_interval: message.ttl.map(|d| d.as_secs() as u32),
-                }));
+            if let Some(message) = r.message.filter(|m| m.data.len() <= MESSAGE_SIZE_MAX) {
+                #[cfg(feature = "fastly")]
+                receipt::delivered(&topic, &r.version, &message, Transport::Mqtt);
+
+                out.push(Packet::Publish(retained_publish(
+                    p.topic.into(),
+                    message,
+                    true,
+                )));
             }
         }
     }
@@ -209,9 +348,89 @@ fn handle_subscribe<'a>(ctx: &mut Context, p: Subscribe<'a>) -> Vec<Packet<'a>>
     out
 }
 
+// metadata is carried in the content type and user properties. the expiry
+// interval is whichever comes first of the message's expiration and the end
+// of its retention
+fn retained_publish<'a>(
+    topic: Cow<'a, str>,
+    message: RetainedMessage,
+    retain: bool,
+) -> Publish<'a> {
+    stats::incr(Counter::Deliveries, 1);
+    stats::incr(Counter::PayloadBytes, message.data.len() as u64);
+
+    let retention = message.ttl.map(|d| d.as_secs() as u32);
+
+    let message_expiry_interval = match (retention, message.meta.expiry_interval(unix_now())) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    let correlation_data = message.meta.correlation_bytes().map(Cow::from);
+
+    let user_properties = message
+        .meta
+        .user
+        .into_iter()
+        .map(|(k, v)| (Cow::from(k), Cow::from(v)))
+        .chain(
+            message
+                .meta
+                .traceparent
+                .map(|t| (Cow::from(TRACEPARENT), Cow::from(t))),
+        )
+        .collect();
+
+    Publish {
+        topic,
+        message: message.data.into(),
+        dup: false,
+        qos: 0,
+        retain,
+        message_expiry_interval,
+        content_type: message.meta.content_type.map(Cow::from),
+        response_topic: message.meta.response_topic.map(Cow::from),
+        correlation_data,
+        user_properties,
+    }
+}
+
+// a traceparent user property is read as the trace context rather than
+// user metadata
+fn packet_meta(p: &Publish) -> MessageMeta {
+    let mut user = Vec::new();
+    let mut traceparent = None;
+
+    for (k, v) in &p.user_properties {
+        if k == TRACEPARENT {
+            traceparent = Some(v.to_string());
+        } else {
+            user.push((k.to_string(), v.to_string()));
+        }
+    }
+
+    let mut meta = MessageMeta {
+        content_type: p.content_type.as_ref().map(|s| s.to_string()),
+        user,
+        expires_at: p.message_expiry_interval.map(|x| unix_now() + u64::from(x)),
+        traceparent,
+        response_topic: p.response_topic.as_ref().map(|s| s.to_string()),
+        correlation_data: None,
+        receipt: None,
+    };
+
+    if let Some(data) = &p.correlation_data {
+        meta.set_correlation_data(data);
+    }
+
+    meta
+}
+
 fn handle_unsubscribe<'a>(ctx: &mut Context, p: Unsubscribe<'a>) -> Vec<Packet<'a>> {
-    let reason = if ctx.state.subs.contains_key(p.topic) {
-        ctx.state.subs.remove(p.topic);
+    let topic = namespace::resolve(ctx.state.namespace.as_deref(), p.topic);
+
+    let reason = if ctx.state.subs.contains_key(&topic) {
+        ctx.state.subs.remove(&topic);
 
         Reason::Success
     } else {
@@ -222,9 +441,20 @@ fn handle_unsubscribe<'a>(ctx: &mut Context, p: Unsubscribe<'a>) -> Vec<Packet<'
 }
 
 fn handle_publish<'a>(ctx: &mut Context, p: Publish<'a>) -> Vec<Packet<'a>> {
-    if p.topic.starts_with('$') {
-        // don't accept publishes to topics beginning with $, per the spec
-        return vec![];
+    match topic::validate_publish(&ctx.config.topic_rules, &p.topic) {
+        Ok(()) => {}
+        // don't accept publishes to reserved topics, such as those beginning
+        // with $ per the spec
+        Err(TopicError::Reserved) => return vec![],
+        Err(_) => {
+            let out = vec![Packet::Disconnect(Disconnect {
+                reason: Reason::TopicNameInvalid,
+            })];
+
+            ctx.disconnect = true;
+
+            return out;
+        }
     }
 
     // QoS must be 0
@@ -238,90 +468,166 @@ fn handle_publish<'a>(ctx: &mut Context, p: Publish<'a>) -> Vec<Packet<'a>> {
         return out;
     }
 
-    let mut allowed = false;
+    let caps = match &ctx.state.token {
+        Some(s) => ctx.auth.validate_token(s).ok(),
+        None => None,
+    };
 
-    if let Some(s) = &ctx.state.token {
-        if let Ok(caps) = ctx.auth.app_token.validate_token(s) {
-            if caps.can_publish(p.topic.as_ref()) {
-                allowed = true;
-            }
-        }
+    let Some(caps) = caps else {
+        return vec![];
+    };
+
+    let topic = caps.resolve_topic(&p.topic);
+
+    if !caps.can_publish(&topic) || !geo::can_publish(ctx.config, &topic, ctx.location.as_ref()) {
+        return vec![];
     }
 
-    if !allowed || p.message.len() > MESSAGE_SIZE_MAX {
+    if p.message.len() > MESSAGE_SIZE_MAX {
         return vec![];
     }
 
-    let mut out = vec![];
+    let mut meta = packet_meta(&p);
 
-    let mut version = None;
+    // metadata is stored along with retained messages, so it is limited as
+    // for HTTP publishers. at QoS 0 there is no acknowledgement to refuse
+    // the message with, so the connection is ended instead
+    if meta.user_size() > USER_META_SIZE_MAX {
+        log_info!("refusing publish to {topic}: metadata too large");
 
-    if p.retain {
-        let ttl = p
-            .message_expiry_interval
-            .map(|x| Duration::from_secs(x.into()));
+        ctx.disconnect = true;
 
-        match ctx.storage.write_retained(&p.topic, &p.message, ttl) {
-            Ok(v) => version = Some(v),
-            Err(e) => {
-                // no error response. only log
-                println!("failed to write message to storage: {e:?}");
-            }
-        }
+        return vec![Packet::Disconnect(Disconnect {
+            reason: Reason::PacketTooLarge,
+        })];
     }
 
-    let seq = version.map(|v| {
-        let version = Version {
-            generation: v.generation,
-            seq: v.seq,
-        };
+    match signature::check(ctx.config, ctx.storage, &topic, &p.message, &meta) {
+        Ok(()) => {}
+        Err(SignatureError::Storage(e)) => {
+            log_error!("failed to read public keys from storage: {e:?}");
 
-        let prev_id = if v.seq > 1 {
-            // if we wrote version 2 or later, it implies the slot
-            // existed and thus the previous write would have been
-            // for the same generation
-            Version {
-                generation: v.generation,
-                seq: v.seq - 1,
-            }
-            .to_id()
-        } else {
-            // if we wrote version 1, it implies the slot was empty
-            "none".to_string()
-        };
+            return vec![];
+        }
+        Err(e) => {
+            log_info!("dropping publish to {topic}: {e}");
 
-        Sequencing {
-            id: version.to_id(),
-            prev_id,
+            return vec![];
         }
-    });
+    }
 
-    let ignore = match ctx.state.subs.get(&*p.topic) {
-        Some(sub) => sub.no_local,
-        None => false,
-    };
+    // counted per connection, in the session state
+    if let Some(limit) = caps.max_publish_per_min() {
+        if !ctx.state.count_publish(limit, unix_now()) {
+            ctx.disconnect = true;
+
+            return vec![Packet::Disconnect(Disconnect {
+                reason: Reason::QuotaExceeded,
+            })];
+        }
+    }
+
+    // routing rules may deliver the message to other topics
+    let targets = routing::route(ctx.config, caps.namespace(), &topic, &p.message, |t| {
+        caps.can_publish(t) && geo::can_publish(ctx.config, t, ctx.location.as_ref())
+    });
 
-    if !ctx.config.publish_token.is_empty() {
-        if let Err(e) = publish(
-            &ctx.config.publish_token,
-            &p.topic,
-            &p.message,
-            seq,
-            Some(&ctx.state.client_id),
-        ) {
+    for target in &targets {
+        if !check_line_lengths(ctx.config, target, &p.message) {
             // no error response. only log
-            println!("failed to publish: {e:?}");
+            log_info!("rejecting message with long lines to topic {target}");
+
+            return vec![];
+        }
+    }
+
+    let mut out = vec![];
+
+    let ttl = p
+        .message_expiry_interval
+        .map(|x| Duration::from_secs(x.into()));
+
+    meta.start_span(&topic);
+
+    for target in targets {
+        let mut version = None;
+
+        if p.retain {
+            match ctx.storage.write_retained(
+                &target,
+                &p.message,
+                &meta,
+                ttl,
+                ctx.config.retained_settings(&target),
+                ctx.deadline,
+            ) {
+                Ok(v) => version = Some(v),
+                Err(e) => {
+                    // no error response. only log
+                    log_error!("failed to write message to storage: {e:?}");
+                }
+            }
+        }
+
+        let seq = version.map(|v| {
+            let version = Version::from(&v);
+
+            let prev_id = if v.seq > 1 {
+                // if we wrote version 2 or later, it implies the slot
+                // existed and thus the previous write would have been
+                // for the same generation
+                Version {
+                    seq: v.seq - 1,
+                    ..Version::from(&v)
+                }
+                .to_id()
+            } else {
+                // if we wrote version 1, it implies the slot was empty
+                "none".to_string()
+            };
+
+            Sequencing {
+                id: version.to_id(),
+                prev_id,
+                deleted: false,
+            }
+        });
+
+        let ignore = match ctx.state.subs.get(&target) {
+            Some(sub) => sub.no_local,
+            None => false,
+        };
+
+        if !ctx.config.publish_token.is_empty() {
+            if let Err(e) = ctx.publishes.add(
+                ctx.config,
+                &target,
+                caps.namespace(),
+                &p.message,
+                &meta,
+                seq,
+                Some(&ctx.state.client_id),
+            ) {
+                // no error response. only log
+                log_error!("failed to publish: {e:?}");
+            }
+        } else if seq.is_none() && !ignore {
+            log_warn!("publishing not configured, echoing back to sender");
+            out.push(Packet::Publish(Publish {
+                topic: namespace::strip(caps.namespace(), &target)
+                    .to_string()
+                    .into(),
+                message: p.message.clone(),
+                dup: false,
+                qos: 0,
+                retain: false, // always false for non-durable
+                message_expiry_interval: meta.expiry_interval(unix_now()),
+                content_type: p.content_type.clone(),
+                response_topic: p.response_topic.clone(),
+                correlation_data: p.correlation_data.clone(),
+                user_properties: p.user_properties.clone(),
+            }));
         }
-    } else if seq.is_none() && !ignore {
-        println!("publishing not configured, echoing back to sender");
-        out.push(Packet::Publish(Publish {
-            topic: p.topic,
-            message: p.message,
-            dup: false,
-            qos: 0,
-            retain: false,                 // always false for non-durable
-            message_expiry_interval: None, // always none for non-durable
-        }));
     }
 
     out
@@ -338,9 +644,9 @@ pub fn handle_packet<'a>(ctx: &mut Context, p: Packet<'a>) -> Vec<Packet<'a>> {
         Packet::Unsubscribe(p) => out.extend(handle_unsubscribe(ctx, p)),
         Packet::Publish(p) => out.extend(handle_publish(ctx, p)),
         Packet::Unsupported(ptype) => {
-            println!("skipping unsupported packet type {ptype}")
+            log_debug!("skipping unsupported packet type {ptype}")
         }
-        _ => println!("skipping unexpected packet"),
+        _ => log_debug!("skipping unexpected packet"),
     }
 
     out
@@ -349,21 +655,44 @@ pub fn handle_packet<'a>(ctx: &mut Context, p: Packet<'a>) -> Vec<Packet<'a>> {
 pub fn handle_sync(ctx: &mut Context) -> Vec<Packet<'static>> {
     let mut out = Vec::new();
 
-    for (topic, sub) in &mut ctx.state.subs {
+    let start = Instant::now();
+    let budget = Duration::from_millis(ctx.config.sync_time_budget_ms.into());
+
+    let mut topics: Vec<String> = ctx.state.subs.keys().cloned().collect();
+    topics.sort();
+
+    // resume after the last topic processed by the previous cycle
+    if let Some(cursor) = ctx.state.sync_cursor.take() {
+        let pos = topics.partition_point(|t| *t <= cursor);
+        topics.rotate_left(pos);
+    }
+
+    let mut last_topic = None;
+
+    for topic in topics {
+        // always process at least one topic, to guarantee progress
+        if last_topic.is_some() && start.elapsed() >= budget {
+            ctx.sync_incomplete = true;
+            break;
+        }
+
+        let Some(sub) = ctx.state.subs.get_mut(&topic) else {
+            continue;
+        };
+
+        last_topic = Some(topic.clone());
+
         let Some(last) = &mut sub.last else {
             continue;
         };
 
-        let after = last.version.as_ref().map(|v| RetainedVersion {
-            generation: v.generation,
-            seq: v.seq,
-        });
+        let after = last.version.as_ref().map(RetainedVersion::from);
 
-        let r = match ctx.storage.read_retained(topic, after) {
+        let r = match ctx.storage.read_retained(&topic, after) {
             Ok(Some(r)) => r,
             Ok(None) | Err(StorageError::StoreNotFound) => continue,
             Err(e) => {
-                println!("failed to read message from storage: {e:?}");
+                log_error!("failed to read message from storage: {e:?}");
 
                 out.push(Packet::Disconnect(Disconnect {
                     reason: Reason::UnspecifiedError,
@@ -375,10 +704,7 @@ pub fn handle_sync(ctx: &mut Context) -> Vec<Packet<'static>> {
             }
         };
 
-        last.version = Some(Version {
-            generation: r.version.generation,
-            seq: r.version.seq,
-        });
+        last.version = Some(Version::from(&r.version));
 
         let mut ignore = false;
 
@@ -388,22 +714,185 @@ pub fn handle_sync(ctx: &mut Context) -> Vec<Packet<'static>> {
             }
 
             // keep later ignored versions
-            i.generation == r.version.generation && i.seq > r.version.seq
+            RetainedVersion::from(i).order(&r.version) == Some(Ordering::Greater)
         });
 
-        if let Some(message) = r.message {
+        if let Some(message) = r.message.filter(|m| m.data.len() <= MESSAGE_SIZE_MAX) {
             if !ignore {
-                out.push(Packet::Publish(Publish {
-                    topic: topic.to_string().into(),
-                    message: message.data.into(),
-                    dup: false,
-                    qos: 0,
-                    retain: sub.retain_as_published,
-                    message_expiry_interval: message.ttl.map(|d| d.as_secs() as u32),
-                }));
+                #[cfg(feature = "fastly")]
+                receipt::delivered(&topic, &r.version, &message, Transport::Mqtt);
+
+                let name = namespace::strip(ctx.state.namespace.as_deref(), &topic);
+
+                out.push(Packet::Publish(retained_publish(
+                    name.to_string().into(),
+                    message,
+                    sub.retain_as_published,
+                )));
             }
         }
     }
 
+    if ctx.sync_incomplete {
+        ctx.state.sync_cursor = last_topic;
+    }
+
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{TestAppTokenAuthorizor, TestGripAuthorizor};
+    use crate::kv::{Insert, Item, Kv, KvError, ListPage};
+    use crate::memorykv::MemoryKv;
+    use crate::storage::KvStorage;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // records the keys looked up, so that reads can be checked
+    struct RecordingKv {
+        inner: MemoryKv,
+        lookups: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Kv for RecordingKv {
+        fn lookup(&self, key: &str) -> Result<Option<Item>, KvError> {
+            self.lookups.borrow_mut().push(key.to_string());
+
+            self.inner.lookup(key)
+        }
+
+        fn insert(&self, key: &str, value: Vec<u8>, insert: &Insert) -> Result<(), KvError> {
+            self.inner.insert(key, value, insert)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), KvError> {
+            self.inner.delete(key)
+        }
+
+        fn list(
+            &self,
+            prefix: &str,
+            cursor: Option<&str>,
+            limit: Option<u32>,
+        ) -> Result<ListPage, KvError> {
+            self.inner.list(prefix, cursor, limit)
+        }
+    }
+
+    #[test]
+    fn publish_rate() {
+        let mut state = State::default();
+
+        assert!(state.count_publish(2, 60));
+        assert!(state.count_publish(2, 90));
+        assert!(!state.count_publish(2, 119));
+
+        // next minute
+        assert!(state.count_publish(2, 120));
+        assert_eq!(state.publish_count, 1);
+    }
+
+    #[test]
+    fn retained_expiry() {
+        let now = unix_now();
+
+        let message = |ttl: Option<u64>, expires_at: Option<u64>| RetainedMessage {
+            ttl: ttl.map(Duration::from_secs),
+            data: b"hello".to_vec(),
+            meta: MessageMeta {
+                expires_at,
+                ..Default::default()
+            },
+        };
+
+        let p = retained_publish("a".into(), message(None, None), true);
+        assert_eq!(p.message_expiry_interval, None);
+
+        let p = retained_publish("a".into(), message(Some(60), None), true);
+        assert_eq!(p.message_expiry_interval, Some(60));
+
+        // expiration can come before the end of retention, or vice versa
+        let p = retained_publish("a".into(), message(Some(60), Some(now + 3600)), true);
+        assert_eq!(p.message_expiry_interval, Some(60));
+
+        let p = retained_publish("a".into(), message(None, Some(now + 3600)), true);
+        assert!(matches!(p.message_expiry_interval, Some(x) if x > 3500 && x <= 3600));
+    }
+
+    #[test]
+    fn sync_budget() {
+        let config = Config {
+            sync_time_budget_ms: 0,
+            ..Default::default()
+        };
+        let auth = Authorization {
+            grip: Box::new(TestGripAuthorizor),
+            fastly: false,
+            app_token: Box::new(TestAppTokenAuthorizor),
+            token_validation: Default::default(),
+            client_cert: None,
+            namespace: None,
+        };
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let storage = KvStorage::new(Box::new(RecordingKv {
+            inner: MemoryKv::new(),
+            lookups: reads.clone(),
+        }));
+
+        let mut state = State::default();
+
+        for topic in ["a", "b", "c"] {
+            state.subs.insert(
+                topic.to_string(),
+                Subscription {
+                    last: Some(Last::default()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        for _ in 0..4 {
+            let mut ctx = Context {
+                config: &config,
+                auth: &auth,
+                storage: &storage,
+                deadline: Deadline::none(),
+                location: None,
+                disconnect: false,
+                sync_incomplete: false,
+                state,
+                publishes: Batch::default(),
+            };
+
+            handle_sync(&mut ctx);
+            assert!(ctx.sync_incomplete);
+
+            state = ctx.state;
+        }
+
+        // one topic per cycle, resuming where the previous cycle left off
+        assert_eq!(*reads.borrow(), ["r:a", "r:b", "r:c", "r:a"]);
+        assert_eq!(state.sync_cursor.as_deref(), Some("a"));
+
+        let config = Config::default();
+
+        let mut ctx = Context {
+            config: &config,
+            auth: &auth,
+            storage: &storage,
+            deadline: Deadline::none(),
+            location: None,
+            disconnect: false,
+            sync_incomplete: false,
+            state,
+            publishes: Batch::default(),
+        };
+
+        handle_sync(&mut ctx);
+        assert!(!ctx.sync_incomplete);
+        assert!(ctx.state.sync_cursor.is_none());
+        assert_eq!(reads.borrow()[4..], ["r:b", "r:c", "r:a"]);
+    }
+}
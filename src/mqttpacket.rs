@@ -43,6 +43,25 @@ fn write_int<W: Write>(dest: &mut W, value: u32) -> Result<(), io::Error> {
     Ok(())
 }
 
+// size of a variable byte integer
+fn int_len(value: u32) -> u32 {
+    match value {
+        0..=0x7f => 1,
+        0x80..=0x3fff => 2,
+        0x4000..=0x1f_ffff => 3,
+        _ => 4,
+    }
+}
+
+fn write_string<W: Write>(dest: &mut W, s: &str) -> Result<(), io::Error> {
+    write_binary(dest, s.as_bytes())
+}
+
+fn write_binary<W: Write>(dest: &mut W, data: &[u8]) -> Result<(), io::Error> {
+    dest.write_all(&(data.len() as u16).to_be_bytes())?;
+    dest.write_all(data)
+}
+
 fn parse_binary(src: &[u8]) -> Result<(&[u8], usize), io::Error> {
     if src.len() < 2 {
         return Err(io::ErrorKind::InvalidData.into());
@@ -69,6 +88,78 @@ fn parse_string(src: &[u8]) -> Result<(&str, usize), io::Error> {
     Ok((s, read))
 }
 
+// size of a property, including its identifier, so that properties we
+// don't use can be skipped
+fn property_len(src: &[u8]) -> Result<usize, io::Error> {
+    let value_len = match src[0] {
+        // byte
+        0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2a => 1,
+        // two byte integer
+        0x13 | 0x21 | 0x22 | 0x23 => 2,
+        // four byte integer
+        0x02 | 0x11 | 0x18 | 0x27 => 4,
+        // variable byte integer
+        0x0b => match parse_int(&src[1..]) {
+            Some(Ok((_, read))) => read,
+            Some(Err(e)) => return Err(e),
+            None => return Err(io::ErrorKind::InvalidData.into()),
+        },
+        // string or binary data
+        0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1a | 0x1c | 0x1f => parse_binary(&src[1..])?.1,
+        // string pair
+        0x26 => {
+            let (_, read) = parse_binary(&src[1..])?;
+
+            read + parse_binary(&src[(1 + read)..])?.1
+        }
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+
+    if src.len() < 1 + value_len {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    Ok(1 + value_len)
+}
+
+// splits off the properties section, returning it and the rest
+fn parse_props(src: &[u8]) -> Result<(&[u8], &[u8]), io::Error> {
+    let (props_len, read) = match parse_int(src) {
+        Some(Ok(ret)) => ret,
+        Some(Err(e)) => return Err(e),
+        None => return Err(io::ErrorKind::InvalidData.into()),
+    };
+
+    let props_len = props_len as usize;
+    let src = &src[read..];
+
+    if src.len() < props_len {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    Ok(src.split_at(props_len))
+}
+
+// parses the packet ID and first reason code of a SUBACK or UNSUBACK
+fn parse_ack(src: &[u8]) -> Result<(u16, Reason), io::Error> {
+    if src.len() < 2 {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    let id = u16::from_be_bytes(src[..2].try_into().unwrap());
+
+    let (_, src) = parse_props(&src[2..])?;
+
+    if src.is_empty() {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    Ok((
+        id,
+        Reason::try_from(src[0]).unwrap_or(Reason::UnspecifiedError),
+    ))
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum Reason {
@@ -78,6 +169,10 @@ pub enum Reason {
     ProtocolError = 0x82,
     UnsupportedProtocolVersion = 0x84,
     NotAuthorized = 0x87,
+    TopicFilterInvalid = 0x8f,
+    TopicNameInvalid = 0x90,
+    PacketTooLarge = 0x95,
+    QuotaExceeded = 0x97,
     QoSNotSupported = 0x9b,
     WildcardSubscriptionsNotSupported = 0xa2,
 }
@@ -95,6 +190,10 @@ impl TryFrom<u8> for Reason {
                 Ok(Self::UnsupportedProtocolVersion)
             }
             x if x == Self::NotAuthorized as u8 => Ok(Self::NotAuthorized),
+            x if x == Self::TopicFilterInvalid as u8 => Ok(Self::TopicFilterInvalid),
+            x if x == Self::TopicNameInvalid as u8 => Ok(Self::TopicNameInvalid),
+            x if x == Self::PacketTooLarge as u8 => Ok(Self::PacketTooLarge),
+            x if x == Self::QuotaExceeded as u8 => Ok(Self::QuotaExceeded),
             x if x == Self::QoSNotSupported as u8 => Ok(Self::QoSNotSupported),
             x if x == Self::WildcardSubscriptionsNotSupported as u8 => {
                 Ok(Self::WildcardSubscriptionsNotSupported)
@@ -107,6 +206,7 @@ impl TryFrom<u8> for Reason {
 #[derive(Debug)]
 pub struct Connect<'a> {
     pub version: u8,
+    pub keep_alive: u16,
     pub client_id: &'a str,
     pub password: Option<&'a str>,
 }
@@ -169,6 +269,14 @@ pub struct Publish<'a> {
     pub qos: u8,
     pub retain: bool,
     pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<Cow<'a, str>>,
+
+    // for request/response: where to send a reply, and data identifying
+    // the request
+    pub response_topic: Option<Cow<'a, str>>,
+    pub correlation_data: Option<Cow<'a, [u8]>>,
+
+    pub user_properties: Vec<(Cow<'a, str>, Cow<'a, str>)>,
 }
 
 #[derive(Debug)]
@@ -234,6 +342,7 @@ impl<'a> Packet<'a> {
                     return Some(Ok((
                         Self::Connect(Connect {
                             version,
+                            keep_alive: 0,
                             client_id: "",
                             password: None,
                         }),
@@ -249,6 +358,7 @@ impl<'a> Packet<'a> {
                 }
 
                 let cflags = src[0];
+                let keep_alive = u16::from_be_bytes(src[1..3].try_into().unwrap());
 
                 let src = &src[3..];
 
@@ -332,10 +442,54 @@ impl<'a> Packet<'a> {
 
                 Self::Connect(Connect {
                     version,
+                    keep_alive,
                     client_id,
                     password,
                 })
             }
+            2 => {
+                if len < 2 {
+                    return Some(Err(io::ErrorKind::InvalidData.into()));
+                }
+
+                let src = &src[..len];
+
+                // versions before 5 have no properties
+                if len == 2 {
+                    return Some(Ok((
+                        Self::ConnAckV4(ConnAckV4 { ret: src[1] }),
+                        packet_size,
+                    )));
+                }
+
+                let reason = Reason::try_from(src[1]).unwrap_or(Reason::UnspecifiedError);
+
+                let (mut psrc, _) = match parse_props(&src[2..]) {
+                    Ok(ret) => ret,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                let mut maximum_packet_size = None;
+
+                while !psrc.is_empty() {
+                    let read = match property_len(psrc) {
+                        Ok(read) => read,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if psrc[0] == 0x27 {
+                        maximum_packet_size =
+                            Some(u32::from_be_bytes(psrc[1..5].try_into().unwrap()));
+                    }
+
+                    psrc = &psrc[read..];
+                }
+
+                Self::ConnAck(ConnAck {
+                    reason,
+                    maximum_packet_size,
+                })
+            }
             3 => {
                 let retain = flags & 0x01 > 0;
                 let qos = (flags >> 1) & 0x03;
@@ -362,6 +516,10 @@ impl<'a> Packet<'a> {
                 }
 
                 let mut message_expiry_interval = None;
+                let mut content_type = None;
+                let mut response_topic = None;
+                let mut correlation_data = None;
+                let mut user_properties = Vec::new();
 
                 let mut psrc = &src[..props_len];
                 while !psrc.is_empty() {
@@ -399,38 +557,44 @@ impl<'a> Packet<'a> {
                         0x08 => {
                             // response topic
 
-                            let (_, read) = match parse_string(&psrc[1..]) {
+                            let (s, read) = match parse_string(&psrc[1..]) {
                                 Ok(s) => s,
                                 Err(e) => return Some(Err(e)),
                             };
 
+                            response_topic = Some(Cow::from(s));
+
                             psrc = &psrc[(1 + read)..];
                         }
                         0x09 => {
                             // correlation data
 
-                            let (_, read) = match parse_binary(&psrc[1..]) {
+                            let (data, read) = match parse_binary(&psrc[1..]) {
                                 Ok(s) => s,
                                 Err(e) => return Some(Err(e)),
                             };
 
+                            correlation_data = Some(Cow::from(data));
+
                             psrc = &psrc[(1 + read)..];
                         }
                         0x26 => {
                             // user property
 
-                            let (_, read) = match parse_string(&psrc[1..]) {
+                            let (name, read) = match parse_string(&psrc[1..]) {
                                 Ok(s) => s,
                                 Err(e) => return Some(Err(e)),
                             };
 
                             psrc = &psrc[(1 + read)..];
 
-                            let (_, read) = match parse_string(psrc) {
+                            let (value, read) = match parse_string(psrc) {
                                 Ok(s) => s,
                                 Err(e) => return Some(Err(e)),
                             };
 
+                            user_properties.push((Cow::from(name), Cow::from(value)));
+
                             psrc = &psrc[read..];
                         }
                         0x0b => {
@@ -447,11 +611,13 @@ impl<'a> Packet<'a> {
                         0x03 => {
                             // content type
 
-                            let (_, read) = match parse_string(&psrc[1..]) {
+                            let (s, read) = match parse_string(&psrc[1..]) {
                                 Ok(s) => s,
                                 Err(e) => return Some(Err(e)),
                             };
 
+                            content_type = Some(Cow::from(s));
+
                             psrc = &psrc[(1 + read)..];
                         }
                         _ => return Some(Err(io::ErrorKind::InvalidData.into())),
@@ -467,6 +633,10 @@ impl<'a> Packet<'a> {
                     qos,
                     retain,
                     message_expiry_interval,
+                    content_type,
+                    response_topic,
+                    correlation_data,
+                    user_properties,
                 })
             }
             8 => {
@@ -525,6 +695,10 @@ impl<'a> Packet<'a> {
                     retain_handling,
                 })
             }
+            9 => match parse_ack(&src[..len]) {
+                Ok((id, reason)) => Self::SubAck(SubAck { id, reason }),
+                Err(e) => return Some(Err(e)),
+            },
             10 => {
                 if src.len() < 2 {
                     return Some(Err(io::ErrorKind::InvalidData.into()));
@@ -556,24 +730,18 @@ impl<'a> Packet<'a> {
 
                 Self::Unsubscribe(Unsubscribe { id, topic })
             }
+            11 => match parse_ack(&src[..len]) {
+                Ok((id, reason)) => Self::UnsubAck(UnsubAck { id, reason }),
+                Err(e) => return Some(Err(e)),
+            },
             12 => Self::PingReq(PingReq),
+            13 => Self::PingResp(PingResp),
             14 => {
-                let (vheader_len, read) = match parse_int(src) {
-                    Some(Ok(ret)) => ret,
-                    Some(Err(e)) => return Some(Err(e)),
-                    None => return Some(Err(io::ErrorKind::InvalidData.into())),
-                };
-
-                let vheader_len = vheader_len as usize;
-                let src = &src[read..];
-
-                if src.len() < vheader_len {
-                    return Some(Err(io::ErrorKind::InvalidData.into()));
-                }
-
+                // the reason code may be left out, meaning success. any
+                // properties after it are ignored
                 let mut reason = 0;
 
-                if !src.is_empty() {
+                if len > 0 {
                     reason = src[0];
                 }
 
@@ -587,118 +755,287 @@ impl<'a> Packet<'a> {
         Some(Ok((p, packet_size)))
     }
 
+    // length of the properties section, excluding its own length field
+    fn props_len(&self) -> u32 {
+        match self {
+            Self::ConnAck(p) => {
+                // maximum qos, retain available, wildcard subscription
+                // available, shared subscription available
+                let mut len = 8;
+
+                if p.maximum_packet_size.is_some() {
+                    len += 5;
+                }
+
+                len
+            }
+            Self::Publish(p) => {
+                let mut len = 0;
+
+                if p.message_expiry_interval.is_some() {
+                    len += 5;
+                }
+
+                if let Some(s) = &p.content_type {
+                    len += 3 + s.len();
+                }
+
+                if let Some(s) = &p.response_topic {
+                    len += 3 + s.len();
+                }
+
+                if let Some(data) = &p.correlation_data {
+                    len += 3 + data.len();
+                }
+
+                for (name, value) in &p.user_properties {
+                    len += 5 + name.len() + value.len();
+                }
+
+                len as u32
+            }
+            _ => 0,
+        }
+    }
+
+    // the first byte of the fixed header, or None if the packet can't be
+    // serialized
+    fn type_and_flags(&self) -> Option<u8> {
+        let b = match self {
+            Self::Connect(_) => 0x10,
+            Self::ConnAck(_) | Self::ConnAckV4(_) => 0x20,
+            Self::Publish(p) => {
+                let mut flags = 0;
+
+                if p.retain {
+                    flags |= 0x01;
+                }
+
+                flags |= (p.qos & 0x03) << 1;
+
+                if p.dup {
+                    flags |= 0x08;
+                }
+
+                0x30 | flags
+            }
+            Self::Subscribe(_) => 0x82, // flags must be 2
+            Self::SubAck(_) => 0x90,
+            Self::Unsubscribe(_) => 0xa2, // flags must be 2
+            Self::UnsubAck(_) => 0xb0,
+            Self::PingReq(_) => 0xc0,
+            Self::PingResp(_) => 0xd0,
+            Self::Disconnect(_) => 0xe0,
+            Self::Unsupported(_) => return None,
+        };
+
+        Some(b)
+    }
+
+    // length of everything after the fixed header, given the properties
+    // length. None if the packet can't be serialized
+    fn remaining_len(&self, props_len: u32) -> Option<u32> {
+        let len = match self {
+            Self::Connect(p) => {
+                // protocol name, version, flags and keep-alive
+                let mut len = 10 + 2 + p.client_id.len() as u32;
+
+                if p.version == 5 {
+                    len += int_len(props_len) + props_len;
+                }
+
+                if let Some(s) = p.password {
+                    len += 2 + s.len() as u32;
+
+                    // versions before 5 require a username with a password
+                    if p.version != 5 {
+                        len += 2;
+                    }
+                }
+
+                len
+            }
+            Self::ConnAck(_) => 2 + int_len(props_len) + props_len,
+            Self::ConnAckV4(_) => 2,
+            Self::PingReq(_) | Self::PingResp(_) => 0,
+            Self::Subscribe(p) => 2 + 1 + 2 + p.topic.len() as u32 + 1,
+            Self::Unsubscribe(p) => 2 + 1 + 2 + p.topic.len() as u32,
+            Self::SubAck(_) | Self::UnsubAck(_) => 4,
+            Self::Publish(p) => {
+                (2 + p.topic.len() + p.message.len()) as u32 + int_len(props_len) + props_len
+            }
+            Self::Disconnect(_) => 1,
+            Self::Unsupported(_) => return None,
+        };
+
+        Some(len)
+    }
+
+    // the number of bytes serialize will write, or None if the packet can't
+    // be serialized
+    pub fn serialized_len(&self) -> Option<usize> {
+        let len = self.remaining_len(self.props_len())?;
+
+        Some((1 + int_len(len) + len) as usize)
+    }
+
+    // writes directly to dest, without intermediate buffers. the lengths
+    // are computed up front, so that nothing is written for packets that
+    // can't be serialized
     pub fn serialize<W: Write>(&self, dest: &mut W) -> Result<(), io::Error> {
-        let mut out = Vec::new();
+        let props_len = self.props_len();
+
+        let (Some(first), Some(len)) = (self.type_and_flags(), self.remaining_len(props_len))
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot serialize packet type",
+            ));
+        };
+
+        dest.write_all(&[first])?;
+        write_int(dest, len)?;
 
         match self {
+            Self::Connect(p) => {
+                write_string(dest, "MQTT")?;
+
+                // clean start
+                let mut cflags = 0x02;
+
+                if p.password.is_some() {
+                    cflags |= 0x40;
+
+                    if p.version != 5 {
+                        cflags |= 0x80;
+                    }
+                }
+
+                dest.write_all(&[p.version, cflags])?;
+                dest.write_all(&p.keep_alive.to_be_bytes())?;
+
+                if p.version == 5 {
+                    write_int(dest, props_len)?;
+                }
+
+                write_string(dest, p.client_id)?;
+
+                if let Some(s) = p.password {
+                    if p.version != 5 {
+                        write_string(dest, "")?; // username
+                    }
+
+                    write_string(dest, s)?;
+                }
+            }
             Self::ConnAck(p) => {
-                let mut props = vec![
+                dest.write_all(&[
+                    0x00, // acknowledge flags
+                    p.reason as u8,
+                ])?;
+
+                write_int(dest, props_len)?;
+
+                dest.write_all(&[
                     0x24, // maximum qos
                     0x00, // QoS 0
                     0x25, // retain available
                     0x01, // yes
-                ];
+                ])?;
 
                 if let Some(x) = p.maximum_packet_size {
                     // maximum packet size
-                    props.push(0x27);
-                    props.extend(x.to_be_bytes());
+                    dest.write_all(&[0x27])?;
+                    dest.write_all(&x.to_be_bytes())?;
                 }
 
-                // wildcard subscription available
-                props.push(0x28);
-                props.push(0x00); // no
+                dest.write_all(&[
+                    0x28, // wildcard subscription available
+                    0x00, // no
+                    0x2a, // shared subscription available
+                    0x00, // no
+                ])?;
+            }
+            Self::ConnAckV4(ConnAckV4 { ret }) => {
+                dest.write_all(&[
+                    0x00, // acknowledge flags
+                    *ret,
+                ])?;
+            }
+            Self::PingReq(_) | Self::PingResp(_) => {}
+            Self::Subscribe(p) => {
+                dest.write_all(&p.id.to_be_bytes())?;
+                write_int(dest, props_len)?;
+                write_string(dest, p.topic)?;
 
-                // shared subscription available
-                props.push(0x2a);
-                props.push(0x00); // no
+                let mut opts = p.maximum_qos & 0x03;
 
-                let mut props_with_len = Vec::new();
-                write_int(&mut props_with_len, props.len() as u32)?; // property length
-                props_with_len.extend(&props);
+                if p.no_local {
+                    opts |= 0x04;
+                }
 
-                out.push(0x20); // type=2 flags=0
-                write_int(&mut out, (props_with_len.len() + 2) as u32)?; // remaining length
+                if p.retain_as_published {
+                    opts |= 0x08;
+                }
 
-                out.push(0x00); // acknowledge flags
-                out.push(p.reason as u8);
-                out.extend(&props_with_len);
-            }
-            Self::ConnAckV4(ConnAckV4 { ret }) => {
-                out.push(0x20); // type=2 flags=0
-                write_int(&mut out, 2)?; // remaining length
+                opts |= (p.retain_handling & 0x03) << 4;
 
-                out.push(0x00); // acknowledge flags
-                out.push(*ret);
-            }
-            Self::PingResp(_) => {
-                out.push(0xd0); // type=13 flags=0
-                write_int(&mut out, 0)?; // remaining length
+                dest.write_all(&[opts])?;
             }
-            Self::SubAck(SubAck { id, reason }) => {
-                out.push(0x90); // type=9 flags=0
-                write_int(&mut out, 4)?; // remaining length
-
-                out.extend(&id.to_be_bytes());
-                write_int(&mut out, 0)?; // property length
-                out.push(*reason as u8);
+            Self::Unsubscribe(p) => {
+                dest.write_all(&p.id.to_be_bytes())?;
+                write_int(dest, props_len)?;
+                write_string(dest, p.topic)?;
             }
-            Self::UnsubAck(UnsubAck { id, reason }) => {
-                out.push(0x90); // type=11 flags=0
-                write_int(&mut out, 4)?; // remaining length
-
-                out.extend(&id.to_be_bytes());
-                write_int(&mut out, 0)?; // property length
-                out.push(*reason as u8);
+            Self::SubAck(SubAck { id, reason }) | Self::UnsubAck(UnsubAck { id, reason }) => {
+                dest.write_all(&id.to_be_bytes())?;
+                write_int(dest, props_len)?;
+                dest.write_all(&[*reason as u8])?;
             }
             Self::Publish(p) => {
-                let mut props = Vec::new();
+                write_string(dest, &p.topic)?;
+
+                write_int(dest, props_len)?;
 
                 if let Some(x) = p.message_expiry_interval {
                     // message expiry interval
-                    props.push(0x02);
-                    props.extend(x.to_be_bytes());
+                    dest.write_all(&[0x02])?;
+                    dest.write_all(&x.to_be_bytes())?;
                 }
 
-                let mut props_with_len = Vec::new();
-                write_int(&mut props_with_len, props.len() as u32)?; // property length
-                props_with_len.extend(&props);
-
-                let mut flags = 0;
-
-                if p.retain {
-                    flags |= 0x01;
+                if let Some(s) = &p.content_type {
+                    // content type
+                    dest.write_all(&[0x03])?;
+                    write_string(dest, s)?;
                 }
 
-                flags |= (p.qos & 0x03) << 1;
-
-                if p.dup {
-                    flags |= 0x08;
+                if let Some(s) = &p.response_topic {
+                    // response topic
+                    dest.write_all(&[0x08])?;
+                    write_string(dest, s)?;
                 }
 
-                out.push(0x30 | flags); // type=3
-
-                let len = (2 + p.topic.len() + props_with_len.len() + p.message.len()) as u32;
-                write_int(&mut out, len)?; // remaining length
-
-                out.extend(&(p.topic.len() as u16).to_be_bytes());
-                out.extend(p.topic.as_bytes());
+                if let Some(data) = &p.correlation_data {
+                    // correlation data
+                    dest.write_all(&[0x09])?;
+                    write_binary(dest, data)?;
+                }
 
-                out.extend(&props_with_len);
+                for (name, value) in &p.user_properties {
+                    // user property
+                    dest.write_all(&[0x26])?;
+                    write_string(dest, name)?;
+                    write_string(dest, value)?;
+                }
 
-                out.extend(p.message.as_ref());
+                dest.write_all(p.message.as_ref())?;
             }
             Self::Disconnect(Disconnect { reason }) => {
-                out.push(0xe0); // type 14
-
-                write_int(&mut out, 1)?;
-                out.push(*reason as u8);
+                dest.write_all(&[*reason as u8])?;
             }
-            _ => panic!("cannot serialize type"),
+            Self::Unsupported(_) => {} // rejected above
         }
 
-        dest.write_all(&out)?;
-
         Ok(())
     }
 }
@@ -733,6 +1070,10 @@ mod tests {
             qos: 0,
             retain: false,
             message_expiry_interval: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
         });
 
         let mut data = Vec::new();
@@ -763,6 +1104,10 @@ mod tests {
             qos: 1,
             retain: true,
             message_expiry_interval: Some(30),
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
         });
 
         let mut data = Vec::new();
@@ -785,5 +1130,159 @@ mod tests {
         assert_eq!(publish.qos, 1);
         assert!(publish.retain);
         assert_eq!(publish.message_expiry_interval, Some(30));
+
+        let p = Packet::Publish(Publish {
+            topic: Cow::from(topic),
+            message: Cow::from(message),
+            dup: false,
+            qos: 0,
+            retain: false,
+            message_expiry_interval: None,
+            content_type: Some(Cow::from("text/plain")),
+            response_topic: Some(Cow::from("replies")),
+            correlation_data: Some(Cow::from(&b"\x01\x02"[..])),
+            user_properties: vec![(Cow::from("a"), Cow::from("b"))],
+        });
+
+        let mut data = Vec::new();
+        p.serialize(&mut data).unwrap();
+
+        let (p, _) = Packet::parse(&data).unwrap().unwrap();
+
+        let publish = match p {
+            Packet::Publish(p) => p,
+            _ => panic!("unexpected packet type"),
+        };
+
+        assert_eq!(publish.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(publish.response_topic.as_deref(), Some("replies"));
+        assert_eq!(publish.correlation_data.as_deref(), Some(&b"\x01\x02"[..]));
+        assert_eq!(
+            publish.user_properties,
+            vec![(Cow::from("a"), Cow::from("b"))]
+        );
+        assert_eq!(publish.message.as_ref(), b"apple");
+    }
+
+    #[test]
+    fn serialized_len() {
+        let packets = [
+            Packet::ConnAck(ConnAck {
+                reason: Reason::Success,
+                maximum_packet_size: None,
+            }),
+            Packet::ConnAck(ConnAck {
+                reason: Reason::Success,
+                maximum_packet_size: Some(1000),
+            }),
+            Packet::ConnAckV4(ConnAckV4 { ret: 0 }),
+            Packet::PingResp(PingResp),
+            Packet::SubAck(SubAck {
+                id: 1,
+                reason: Reason::Success,
+            }),
+            Packet::UnsubAck(UnsubAck {
+                id: 1,
+                reason: Reason::NoSubscriptionExisted,
+            }),
+            Packet::Disconnect(Disconnect {
+                reason: Reason::UnspecifiedError,
+            }),
+            Packet::Publish(Publish {
+                topic: Cow::from("fruit"),
+                message: Cow::from(vec![0; 200]),
+                dup: false,
+                qos: 0,
+                retain: true,
+                message_expiry_interval: Some(30),
+                content_type: Some(Cow::from("text/plain")),
+                response_topic: None,
+                correlation_data: None,
+                user_properties: vec![(Cow::from("a"), Cow::from("b"))],
+            }),
+        ];
+
+        for p in &packets {
+            let mut data = Vec::new();
+            p.serialize(&mut data).unwrap();
+
+            assert_eq!(data.len(), p.serialized_len().unwrap());
+        }
+
+        let mut data = Vec::new();
+        assert!(Packet::Unsupported(15).serialize(&mut data).is_err());
+        assert!(Packet::Unsupported(15).serialized_len().is_none());
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let packets = [
+            Packet::Connect(Connect {
+                version: 5,
+                keep_alive: 60,
+                client_id: "client",
+                password: Some("secret"),
+            }),
+            Packet::Subscribe(Subscribe {
+                id: 2,
+                topic: "fruit/#",
+                maximum_qos: 1,
+                no_local: true,
+                retain_as_published: false,
+                retain_handling: 2,
+            }),
+            Packet::Unsubscribe(Unsubscribe {
+                id: 3,
+                topic: "fruit/#",
+            }),
+            Packet::PingReq(PingReq),
+            Packet::Disconnect(Disconnect {
+                reason: Reason::ProtocolError,
+            }),
+            Packet::ConnAck(ConnAck {
+                reason: Reason::NotAuthorized,
+                maximum_packet_size: Some(32_768),
+            }),
+            Packet::ConnAckV4(ConnAckV4 { ret: 0x01 }),
+            Packet::SubAck(SubAck {
+                id: 2,
+                reason: Reason::QoSNotSupported,
+            }),
+            Packet::UnsubAck(UnsubAck {
+                id: 3,
+                reason: Reason::NoSubscriptionExisted,
+            }),
+            Packet::PingResp(PingResp),
+        ];
+
+        for p in &packets {
+            let mut data = Vec::new();
+            p.serialize(&mut data).unwrap();
+
+            assert_eq!(data.len(), p.serialized_len().unwrap());
+
+            let (parsed, read) = Packet::parse(&data).unwrap().unwrap();
+            assert_eq!(read, data.len());
+
+            // compare via debug output, since packets don't implement Eq
+            assert_eq!(format!("{parsed:?}"), format!("{p:?}"));
+        }
+
+        // a limited packet, for versions before 5
+        let p = Packet::Connect(Connect {
+            version: 4,
+            keep_alive: 0,
+            client_id: "",
+            password: None,
+        });
+
+        let mut data = Vec::new();
+        p.serialize(&mut data).unwrap();
+
+        match Packet::parse(&data).unwrap().unwrap() {
+            (Packet::Connect(c), _) => assert_eq!(c.version, 4),
+            _ => panic!("unexpected packet type"),
+        }
     }
 }
@@ -1,42 +1,96 @@
 use crate::auth::Authorization;
 use crate::config::Config;
-use crate::grip::ControlMessage;
+use crate::deadline::Deadline;
+use crate::geo;
+use crate::grip::{parse_grip_last, ControlMessage};
 use crate::mqtthandler;
 use crate::mqttpacket::Packet;
+use crate::presence;
+use crate::problem;
+use crate::publish::{read_body, Batch, CONNECTION_CHANNEL_PREFIX, TRANSPORT_REQUEST_SIZE_MAX};
+use crate::receipt::Transport;
 use crate::storage::Storage;
-use crate::websocket::{parse_websocket_event, WsEvent};
+use crate::webhook;
+use crate::websocket::{
+    read_websocket_events, write_websocket_event, write_websocket_event_footer,
+    write_websocket_event_header, ReadEventsError, WsEvent,
+};
+use crate::{log_debug, log_error};
 use fastly::http::{HeaderValue, StatusCode};
 use fastly::{Body, Request, Response};
 use std::collections::HashSet;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::mem;
 use std::str;
 
+const SYNC_CONTINUE_INTERVAL: usize = 1;
+
+// most bytes of websocket events accepted in one request
+const EVENTS_SIZE_MAX: usize = 1024 * 1024;
+
 struct Context<'a> {
     handler_ctx: mqtthandler::Context<'a>,
     cid: String,
     in_buf: Vec<u8>,
     opening: bool,
+    closing: bool,
     content_accepted: usize,
 }
 
-fn handle_websocket_event<H>(ctx: &mut Context, e: WsEvent, mut handler: H) -> Vec<WsEvent>
+// websocket-over-http messages must be prefixed
+const MESSAGE_PREFIX: &[u8] = b"m:";
+
+// writes a packet as a BINARY event, serializing it directly into the body
+fn write_packet_event(body: &mut Vec<u8>, p: &Packet) {
+    let Some(len) = p.serialized_len() else {
+        log_error!("cannot serialize packet: {p:?}");
+        return;
+    };
+
+    write_websocket_event_header(body, "BINARY", MESSAGE_PREFIX.len() + len).unwrap();
+    body.write_all(MESSAGE_PREFIX).unwrap();
+    p.serialize(body).unwrap();
+    write_websocket_event_footer(body).unwrap();
+}
+
+// the size of a BINARY event containing a packet, used to size the body
+fn packet_event_len(p: &Packet) -> usize {
+    let Some(len) = p.serialized_len() else {
+        return 0;
+    };
+
+    let content_len = MESSAGE_PREFIX.len() + len;
+
+    // header, with content length in hex, plus footer
+    let hex_len = (usize::BITS - content_len.leading_zeros()).div_ceil(4) as usize;
+
+    "BINARY ".len() + hex_len + 2 + content_len + 2
+}
+
+// appends any response events to body
+fn handle_websocket_event<H>(ctx: &mut Context, e: WsEvent, body: &mut Vec<u8>, mut handler: H)
 where
     H: for<'a> FnMut(&mut mqtthandler::Context, Packet<'a>) -> Vec<Packet<'a>>,
 {
-    let mut out_events = Vec::new();
     let mut content_accepted = e.content.len();
 
-    println!("{} event {} size={}", ctx.cid, e.etype, e.content.len());
+    log_debug!(
+        {cid = ctx.cid, event = e.etype, size = e.content.len()},
+        "websocket event"
+    );
 
     match e.etype.as_str() {
         "OPEN" => {
             ctx.opening = true;
 
             // ack
-            out_events.push(e.clone())
+            write_websocket_event(body, &e.etype, &e.content).unwrap();
+        }
+        "CLOSE" => {
+            ctx.closing = true;
+
+            write_websocket_event(body, &e.etype, &e.content).unwrap(); // ack
         }
-        "CLOSE" => out_events.push(e.clone()), // ack
         "TEXT" | "BINARY" => {
             content_accepted = 0;
 
@@ -53,22 +107,12 @@ where
                     }
                 };
 
-                println!("{} IN {:?}", ctx.cid, p);
+                log_debug!({cid = ctx.cid, packet = format!("{p:?}")}, "packet in");
 
                 for p in handler(&mut ctx.handler_ctx, p) {
-                    println!("{} OUT {:?}", ctx.cid, p);
+                    log_debug!({cid = ctx.cid, packet = format!("{p:?}")}, "packet out");
 
-                    let mut buf = Vec::new();
-
-                    // websocket-over-http messages must be prefixed
-                    write!(&mut buf, "m:").unwrap();
-
-                    p.serialize(&mut buf).unwrap();
-
-                    out_events.push(WsEvent {
-                        etype: "BINARY".to_string(),
-                        content: buf,
-                    });
+                    write_packet_event(body, &p);
                 }
 
                 in_buf = in_buf.split_off(read);
@@ -81,24 +125,25 @@ where
     }
 
     ctx.content_accepted += content_accepted;
-
-    out_events
 }
 
 fn bad_request<T: AsRef<str>>(message: T) -> Response {
     Response::from_status(400).with_body_text_plain(&format!("{}\n", message.as_ref()))
 }
 
-fn handle_websocket_events<P, S>(
+#[allow(clippy::too_many_arguments)]
+fn handle_websocket_events<R, P, S>(
     config: &Config,
     auth: &Authorization,
     storage: &dyn Storage,
+    deadline: Deadline,
     req: Request,
-    body: Vec<u8>,
+    body: R,
     mut packet_handler: P,
     mut sync_handler: S,
 ) -> Response
 where
+    R: Read,
     P: for<'a> FnMut(&mut mqtthandler::Context, Packet<'a>) -> Vec<Packet<'a>>,
     S: FnMut(&mut mqtthandler::Context) -> Vec<Packet<'static>>,
 {
@@ -107,6 +152,7 @@ where
     let mut cid = String::new();
     let mut state = mqtthandler::State::default();
     let mut client_id = String::new();
+    let mut namespace = None;
     let mut connected_subs = HashSet::new();
 
     if let Some(v) = req.get_header("Sec-WebSocket-Extensions") {
@@ -142,15 +188,35 @@ where
         match serde_json::from_slice(v.as_bytes()) {
             Ok(v) => state = v,
             Err(e) => {
-                println!("failed to parse state: {e}");
+                log_error!("failed to parse state: {e}");
                 return bad_request("Invalid header");
             }
         }
 
         client_id = state.client_id.clone();
+        namespace = state.namespace.clone();
         connected_subs = state.subs.keys().map(|s| s.to_string()).collect();
     }
 
+    // fanout sends Grip-Last when it detects a gap in a durable channel.
+    // the sync below will catch the subscriber up, after which we resend
+    // the subscription with the new cursor
+    let mut resync_subs = HashSet::new();
+
+    match parse_grip_last(&req) {
+        Ok(v) => {
+            for (channel, _) in v {
+                if let Some(topic) = channel.strip_prefix("d:") {
+                    resync_subs.insert(topic.to_string());
+                }
+            }
+        }
+        Err(e) => {
+            log_error!("failed to parse Grip-Last: {e}");
+            return bad_request("Invalid header");
+        }
+    }
+
     let mut replayed = 0;
 
     if let Some(v) = req.get_header("Content-Bytes-Replayed") {
@@ -163,61 +229,75 @@ where
         }
     }
 
-    println!("{cid} receiving {replayed} replayed bytes");
-
-    let mut events = Vec::new();
-    let mut pos = 0;
+    log_debug!({ cid = cid }, "receiving {replayed} replayed bytes");
 
-    while pos < body.len() {
-        match parse_websocket_event(&body[pos..]) {
-            Ok((e, size)) => {
-                events.push(e);
-                pos += size;
-            }
-            Err(_) => return bad_request("Failed to parse WebSocket events"),
+    let events = match read_websocket_events(body, EVENTS_SIZE_MAX) {
+        Ok(events) => events,
+        Err(ReadEventsError::Parse) => return bad_request("Failed to parse WebSocket events"),
+        Err(ReadEventsError::TooLarge) => {
+            return bad_request(format!(
+                "WebSocket events exceed {EVENTS_SIZE_MAX} bytes maximum"
+            ))
         }
-    }
+        Err(ReadEventsError::Io(e)) => {
+            log_error!("failed to read body: {e}");
+            return bad_request("Failed to read body");
+        }
+    };
 
     let mut ctx = Context {
         handler_ctx: mqtthandler::Context {
             config,
             auth,
             storage,
+            deadline,
+            location: geo::location(&req),
             disconnect: false,
+            sync_incomplete: false,
             state,
+            publishes: Batch::default(),
         },
         cid,
         in_buf: Vec::new(),
         opening: false,
+        closing: false,
         content_accepted: 0,
     };
 
-    let mut out_events = Vec::new();
-
-    for p in sync_handler(&mut ctx.handler_ctx) {
-        println!("{} OUT {:?}", ctx.cid, p);
-
-        let mut buf = Vec::new();
+    let sync_packets = sync_handler(&mut ctx.handler_ctx);
 
-        // websocket-over-http messages must be prefixed
-        write!(&mut buf, "m:").unwrap();
+    // syncs may replay many messages, so size the body for them up front
+    let mut body = Vec::with_capacity(sync_packets.iter().map(packet_event_len).sum());
 
-        p.serialize(&mut buf).unwrap();
+    for p in sync_packets {
+        log_debug!({cid = ctx.cid, packet = format!("{p:?}")}, "packet out");
 
-        out_events.push(WsEvent {
-            etype: "BINARY".to_string(),
-            content: buf,
-        });
+        write_packet_event(&mut body, &p);
     }
 
     for e in events {
-        out_events.extend(handle_websocket_event(&mut ctx, e, |ctx, p| {
-            packet_handler(ctx, p)
-        }));
+        handle_websocket_event(&mut ctx, e, &mut body, |ctx, p| packet_handler(ctx, p));
+    }
+
+    let publishes = std::mem::take(&mut ctx.handler_ctx.publishes);
+
+    if !publishes.is_empty() {
+        if let Err(e) = publishes.send(config, deadline) {
+            // no error response. only log
+            log_error!("failed to publish: {e:?}");
+        }
     }
 
     let mut cmsgs = Vec::new();
 
+    if ctx.opening && !ctx.cid.is_empty() {
+        cmsgs.push(ControlMessage {
+            ctype: "subscribe".to_string(),
+            channel: Some(format!("{CONNECTION_CHANNEL_PREFIX}{}", ctx.cid)),
+            ..Default::default()
+        });
+    }
+
     if ctx.handler_ctx.state.client_id != client_id {
         cmsgs.push(ControlMessage {
             ctype: "set-meta".to_string(),
@@ -228,7 +308,9 @@ where
     }
 
     for (topic, sub) in &ctx.handler_ctx.state.subs {
-        if !connected_subs.contains(topic) {
+        let is_new = !connected_subs.contains(topic);
+
+        if is_new {
             let mut filters = Vec::new();
 
             if sub.no_local {
@@ -241,10 +323,13 @@ where
                 filters,
                 ..Default::default()
             });
+        }
 
+        if is_new || resync_subs.contains(topic) {
             cmsgs.push(ControlMessage {
                 ctype: "subscribe".to_string(),
                 channel: Some(format!("d:{topic}")),
+                prev_id: Some(sub.cursor_id()),
                 ..Default::default()
             });
         }
@@ -266,32 +351,66 @@ where
         }
     }
 
-    for cmsg in cmsgs {
-        out_events.push(WsEvent {
-            etype: "TEXT".to_string(),
-            content: format!("c:{}", serde_json::to_string(&cmsg).unwrap()).into_bytes(),
-        });
-    }
+    // presence follows the subscriptions. a disconnect clears the state, so
+    // the client is identified as it was at the start of the request
+    let state = &ctx.handler_ctx.state;
 
-    if ctx.handler_ctx.disconnect {
-        let code: u16 = 1000;
+    let (member, namespace) = if state.client_id.is_empty() {
+        (client_id.as_str(), namespace.as_deref())
+    } else {
+        (state.client_id.as_str(), state.namespace.as_deref())
+    };
 
-        out_events.push(WsEvent {
-            etype: "CLOSE".to_string(),
-            content: Vec::from(code.to_be_bytes()),
-        });
-    }
+    if !member.is_empty() {
+        let mut joined = Vec::new();
+        let mut left = Vec::new();
 
-    let mut body = Vec::new();
+        if ctx.closing {
+            let topics: HashSet<&String> = connected_subs.iter().chain(state.subs.keys()).collect();
 
-    for e in out_events {
-        if !e.content.is_empty() {
-            write!(&mut body, "{} {:x}\r\n", e.etype, e.content.len()).unwrap();
-            body.write_all(&e.content).unwrap();
-            body.write_all(b"\r\n").unwrap();
+            left.extend(topics);
         } else {
-            write!(&mut body, "{}\r\n", e.etype).unwrap();
+            joined.extend(state.subs.keys().filter(|t| !connected_subs.contains(*t)));
+            left.extend(
+                connected_subs
+                    .iter()
+                    .filter(|t| !state.subs.contains_key(t.as_str())),
+            );
         }
+
+        for topic in joined {
+            presence::joined(config, namespace, topic, member, presence::MQTT_MEMBER_TTL);
+            webhook::changed(
+                config,
+                webhook::Action::Subscribe,
+                topic,
+                member,
+                Transport::Mqtt,
+            );
+        }
+
+        for topic in left {
+            presence::left(config, namespace, topic, member);
+            webhook::changed(
+                config,
+                webhook::Action::Unsubscribe,
+                topic,
+                member,
+                Transport::Mqtt,
+            );
+        }
+    }
+
+    for cmsg in cmsgs {
+        let content = format!("c:{}", serde_json::to_string(&cmsg).unwrap());
+
+        write_websocket_event(&mut body, "TEXT", content.as_bytes()).unwrap();
+    }
+
+    if ctx.handler_ctx.disconnect {
+        let code: u16 = 1000;
+
+        write_websocket_event(&mut body, "CLOSE", &code.to_be_bytes()).unwrap();
     }
 
     let mut resp = Response::from_status(StatusCode::OK)
@@ -308,15 +427,24 @@ where
         }
     }
 
-    println!("{} accepting {} bytes", ctx.cid, ctx.content_accepted);
+    log_debug!(
+        { cid = ctx.cid },
+        "accepting {} bytes",
+        ctx.content_accepted
+    );
 
     resp.append_header("Content-Bytes-Accepted", ctx.content_accepted.to_string());
 
     let state = serde_json::to_string(&ctx.handler_ctx.state).unwrap();
-    println!("saving state: {state}");
+    log_debug!({ state = state }, "saving state");
     resp.append_header("Set-Meta-State", state);
 
-    resp.append_header("Keep-Alive-Interval", "120");
+    if ctx.handler_ctx.sync_incomplete {
+        // ask for another request soon, so the sync can continue
+        resp.append_header("Keep-Alive-Interval", SYNC_CONTINUE_INTERVAL.to_string());
+    } else {
+        resp.append_header("Keep-Alive-Interval", "120");
+    }
 
     resp
 }
@@ -325,9 +453,13 @@ pub fn post(
     config: &Config,
     auth: &Authorization,
     storage: &dyn Storage,
+    deadline: Deadline,
     mut req: Request,
 ) -> Response {
-    let body = req.take_body().into_bytes();
+    let body = match read_body(&mut req, TRANSPORT_REQUEST_SIZE_MAX) {
+        Ok(body) => body,
+        Err(e) => return problem::body_error(&e, TRANSPORT_REQUEST_SIZE_MAX),
+    };
 
     if req.get_header("Content-Type")
         == Some(&HeaderValue::from_static("application/websocket-events"))
@@ -336,8 +468,9 @@ pub fn post(
             config,
             auth,
             storage,
+            deadline,
             req,
-            body,
+            body.as_slice(),
             mqtthandler::handle_packet,
             mqtthandler::handle_sync,
         )
@@ -351,33 +484,38 @@ mod tests {
     use super::*;
     use crate::auth::{Authorization, TestAppTokenAuthorizor, TestGripAuthorizor};
     use crate::config::Config;
+    use crate::memorykv::MemoryKv;
     use crate::mqttpacket::Publish;
-    use crate::storage::{RetainedSlot, RetainedVersion, StorageError};
+    use crate::storage::KvStorage;
+    use crate::websocket::parse_websocket_event;
     use std::borrow::Cow;
     use std::io::Write;
-    use std::time::Duration;
-
-    struct TestStorage;
-
-    impl Storage for TestStorage {
-        fn write_retained(
-            &self,
-            _topic: &str,
-            _message: &[u8],
-            _ttl: Option<Duration>,
-        ) -> Result<RetainedVersion, StorageError> {
-            Ok(RetainedVersion {
-                generation: 1,
-                seq: 1,
-            })
-        }
 
-        fn read_retained(
-            &self,
-            _topic: &str,
-            _after: Option<RetainedVersion>,
-        ) -> Result<Option<RetainedSlot>, StorageError> {
-            Ok(None)
+    #[test]
+    fn packet_events() {
+        for size in [0, 5, 200, 5000] {
+            let p = Packet::Publish(Publish {
+                topic: Cow::from("fruit"),
+                message: Cow::from(vec![0; size]),
+                dup: false,
+                qos: 0,
+                retain: false,
+                message_expiry_interval: None,
+                content_type: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: Vec::new(),
+            });
+
+            let mut body = Vec::new();
+            write_packet_event(&mut body, &p);
+
+            assert_eq!(body.len(), packet_event_len(&p));
+
+            let (e, read) = parse_websocket_event(&body).ok().unwrap();
+            assert_eq!(read, body.len());
+            assert_eq!(e.etype, "BINARY");
+            assert!(e.content.starts_with(b"m:"));
         }
     }
 
@@ -388,8 +526,11 @@ mod tests {
             grip: Box::new(TestGripAuthorizor),
             fastly: false,
             app_token: Box::new(TestAppTokenAuthorizor),
+            token_validation: Default::default(),
+            client_cert: None,
+            namespace: None,
         };
-        let storage = TestStorage;
+        let storage = KvStorage::new(Box::new(MemoryKv::new()));
 
         let p = Publish {
             topic: Cow::from("fruit"),
@@ -398,6 +539,10 @@ mod tests {
             qos: 0,
             retain: false,
             message_expiry_interval: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
         };
 
         let mut packet_bytes = Vec::new();
@@ -419,8 +564,9 @@ mod tests {
                 &config,
                 &auth,
                 &storage,
+                Deadline::none(),
                 req,
-                body.clone(),
+                body.as_slice(),
                 |_, p| {
                     if let Packet::Publish(p) = &p {
                         out = Some(Publish {
@@ -430,6 +576,10 @@ mod tests {
                             qos: 0,
                             retain: false,
                             message_expiry_interval: None,
+                            content_type: None,
+                            response_topic: None,
+                            correlation_data: None,
+                            user_properties: Vec::new(),
                         });
                     }
 
@@ -455,8 +605,9 @@ mod tests {
                 &config,
                 &auth,
                 &storage,
+                Deadline::none(),
                 req,
-                body,
+                body.as_slice(),
                 |_, p| {
                     if let Packet::Publish(p) = &p {
                         out = Some(Publish {
@@ -466,6 +617,10 @@ mod tests {
                             qos: 0,
                             retain: false,
                             message_expiry_interval: None,
+                            content_type: None,
+                            response_topic: None,
+                            correlation_data: None,
+                            user_properties: Vec::new(),
                         });
                     }
 
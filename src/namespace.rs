@@ -0,0 +1,65 @@
+#[cfg(feature = "fastly")]
+use crate::config::Config;
+use crate::topic;
+#[cfg(feature = "fastly")]
+use fastly::http::header;
+#[cfg(feature = "fastly")]
+use fastly::Request;
+
+// clients in a namespace use topic names relative to it. internally, and
+// to platform admins, the topics are beneath the namespace, e.g. "orders"
+// in namespace "acme" is "acme/orders". storage and Fanout channels are
+// keyed by the full name, so namespaces are isolated from each other
+
+// the full name of a topic given by a client
+pub fn resolve(namespace: Option<&str>, topic: &str) -> String {
+    match namespace {
+        Some(ns) => format!("{ns}{}{topic}", topic::SEPARATOR),
+        None => topic.to_string(),
+    }
+}
+
+// the name of a topic as seen by clients in the namespace. topics outside
+// of it keep their full names
+pub fn strip<'a>(namespace: Option<&str>, topic: &'a str) -> &'a str {
+    let Some(ns) = namespace else {
+        return topic;
+    };
+
+    match topic.strip_prefix(ns) {
+        Some(rest) => rest.strip_prefix(topic::SEPARATOR).unwrap_or(topic),
+        None => topic,
+    }
+}
+
+pub fn is_valid(namespace: &str) -> bool {
+    topic::parse(namespace).is_ok()
+}
+
+// the namespace configured for the host the request was made to, if any
+#[cfg(feature = "fastly")]
+pub fn from_host(config: &Config, req: &Request) -> Option<String> {
+    let host = req.get_header_str(header::HOST)?;
+
+    config.namespace_hosts.get(host).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(resolve(None, "orders"), "orders");
+        assert_eq!(resolve(Some("acme"), "orders/eu"), "acme/orders/eu");
+
+        assert_eq!(strip(Some("acme"), "acme/orders/eu"), "orders/eu");
+        assert_eq!(strip(Some("acme"), "acmeco/orders"), "acmeco/orders");
+        assert_eq!(strip(Some("acme"), "acme"), "acme");
+        assert_eq!(strip(None, "acme/orders"), "acme/orders");
+
+        assert!(is_valid("acme/eu"));
+        assert!(!is_valid("acme/"));
+        assert!(!is_valid(""));
+    }
+}
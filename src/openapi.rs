@@ -0,0 +1,484 @@
+use crate::config::Config;
+use fastly::http::{header, StatusCode};
+use fastly::Response;
+use serde_json::{json, Map, Value};
+
+fn query(name: &str, schema: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "schema": {"type": schema},
+        "description": description,
+    })
+}
+
+fn required_query(name: &str, schema: &str, description: &str) -> Value {
+    let mut p = query(name, schema, description);
+    p["required"] = json!(true);
+
+    p
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+        "description": description,
+    })
+}
+
+fn operation(summary: &str, params: Vec<Value>, security: &[&str]) -> Value {
+    let mut op = json!({
+        "summary": summary,
+        "responses": {
+            "200": {"description": "Success"},
+            "default": {
+                "description": "Error",
+                "content": {
+                    "application/problem+json": {
+                        "schema": {"$ref": "#/components/schemas/Problem"},
+                    },
+                },
+            },
+        },
+    });
+
+    if !params.is_empty() {
+        op["parameters"] = Value::Array(params);
+    }
+
+    // any one of the schemes is accepted
+    if !security.is_empty() {
+        op["security"] = security.iter().map(|s| json!({ *s: [] })).collect();
+    }
+
+    op
+}
+
+fn with_json_body(mut op: Value, properties: Value) -> Value {
+    op["requestBody"] = json!({
+        "content": {
+            "application/json": {
+                "schema": {"type": "object", "properties": properties},
+            },
+        },
+    });
+
+    op
+}
+
+// messages are published as-is, with their content type as metadata
+fn message_body() -> Value {
+    json!({
+        "required": true,
+        "content": {"*/*": {"schema": {"type": "string", "format": "binary"}}},
+    })
+}
+
+// ways of authorizing publishing requests
+const PUBLISHER: &[&str] = &["token", "apiKey", "fastlyKey"];
+
+const ADMIN: &[&str] = &["fastlyKey", "token"];
+
+// describes the HTTP routes, leaving out those disabled in config. MQTT is
+// left out, being a WebSocket protocol
+pub fn document(config: &Config) -> Value {
+    let mut paths = Map::new();
+
+    paths.insert(
+        "/healthz".to_string(),
+        json!({"get": operation("Check readiness", vec![], &[])}),
+    );
+
+    paths.insert(
+        "/topics/{topic}/public-keys".to_string(),
+        json!({"get": operation(
+            "Get a topic's public keys",
+            vec![path_param("topic", "Topic name")],
+            &[],
+        )}),
+    );
+
+    let mut events = Map::new();
+
+    if config.sse_enabled {
+        let mut op = operation(
+            "Subscribe to topics",
+            vec![
+                required_query("topic", "string", "Topic to subscribe to. May be repeated"),
+                query(
+                    "format",
+                    "string",
+                    "Event format: plain, json, ndjson or cloudevents",
+                ),
+                query("events", "string", "Event names: generic or topic"),
+                query(
+                    "meta",
+                    "boolean",
+                    "Include message metadata, as format=json",
+                ),
+                query("durable", "boolean", "Deliver retained messages reliably"),
+                query("large", "boolean", "Accept messages too large to publish"),
+                query(
+                    "compact",
+                    "boolean",
+                    "Name topics by hash in durable event IDs",
+                ),
+                query(
+                    "dynamic",
+                    "boolean",
+                    "Allow changing topics while connected",
+                ),
+                query("retry", "integer", "Reconnect delay in milliseconds"),
+                query("subscription", "string", "Name of a durable subscription"),
+                query("lastEventId", "string", "ID of the last event received"),
+                query(
+                    "auth",
+                    "string",
+                    "Token, as an alternative to the Authorization header",
+                ),
+                query("sig", "string", "Signature of a pre-signed URL"),
+                query("kid", "string", "Key ID of a pre-signed URL"),
+                query("expires", "integer", "Expiration of a pre-signed URL"),
+            ],
+            &["token"],
+        );
+
+        op["responses"]["200"] = json!({
+            "description": "Event stream",
+            "content": {
+                "text/event-stream": {"schema": {"type": "string"}},
+                "application/x-ndjson": {"schema": {"type": "string"}},
+            },
+        });
+
+        events.insert("get".to_string(), op);
+
+        paths.insert(
+            "/events/subscriptions".to_string(),
+            json!({
+                "get": operation(
+                    "Get a durable subscription's cursors",
+                    vec![required_query("subscription", "string", "Subscription name")],
+                    &["token"],
+                ),
+                "post": operation(
+                    "Change the topics of a dynamic stream",
+                    vec![
+                        required_query("cid", "string", "Connection ID of the stream"),
+                        query("subscribe", "string", "Topic to add. May be repeated"),
+                        query("unsubscribe", "string", "Topic to remove. May be repeated"),
+                    ],
+                    &["token"],
+                ),
+            }),
+        );
+    }
+
+    if config.http_publish_enabled {
+        let mut op = operation(
+            "Publish a message",
+            vec![
+                required_query("topic", "string", "Topic to publish to"),
+                query("retain", "boolean", "Retain the message in storage"),
+                query("ttl", "integer", "Seconds to retain the message for"),
+                query(
+                    "expiry",
+                    "integer",
+                    "Seconds the message may be delivered for",
+                ),
+                query("delay", "integer", "Seconds to delay delivery by"),
+                query("receipt", "string", "URL to send delivery receipts to"),
+            ],
+            PUBLISHER,
+        );
+
+        op["requestBody"] = message_body();
+
+        events.insert("post".to_string(), op);
+
+        events.insert(
+            "delete".to_string(),
+            operation(
+                "Delete a retained message",
+                vec![required_query("topic", "string", "Topic of the message")],
+                PUBLISHER,
+            ),
+        );
+
+        paths.insert(
+            "/events/transaction".to_string(),
+            json!({"post": with_json_body(
+                operation("Publish messages to several topics as a unit", vec![], PUBLISHER),
+                json!({
+                    "messages": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "topic": {"type": "string"},
+                                "content": {"type": "string"},
+                                "content-bin": {"type": "string", "format": "byte"},
+                                "ttl": {"type": "integer"},
+                            },
+                            "required": ["topic"],
+                        },
+                    },
+                }),
+            )}),
+        );
+    }
+
+    if !events.is_empty() {
+        paths.insert("/events".to_string(), Value::Object(events));
+    }
+
+    if config.presence_enabled {
+        paths.insert(
+            "/presence".to_string(),
+            json!({"get": operation(
+                "List a topic's subscribers",
+                vec![required_query("topic", "string", "Topic name")],
+                &["token"],
+            )}),
+        );
+    }
+
+    // websocket clients use the same path
+    if config.bayeux_enabled {
+        paths.insert(
+            "/bayeux".to_string(),
+            json!({"post": with_json_body(
+                operation("Send Bayeux messages by long-polling", vec![], &["token"]),
+                json!({
+                    "channel": {"type": "string"},
+                    "clientId": {"type": "string"},
+                    "subscription": {"type": "string"},
+                    "data": {},
+                    "ext": {"type": "object"},
+                }),
+            )}),
+        );
+    }
+
+    if config.admin_enabled {
+        let grants = json!({
+            "read": {"type": "array", "items": {"type": "string"}},
+            "write": {"type": "array", "items": {"type": "string"}},
+            "subtree": {"type": "boolean"},
+            "sub": {"type": "string"},
+            "ttl": {"type": "integer"},
+        });
+
+        let mut admin_grants = grants.clone();
+        admin_grants["key"] = json!({"type": "string"});
+        admin_grants["expires_at"] = json!({"type": "integer"});
+
+        let paging = || {
+            vec![
+                query("cursor", "string", "Cursor returned by the previous page"),
+                query("limit", "integer", "Maximum number of items"),
+            ]
+        };
+
+        let mut retained_params = paging();
+        retained_params.push(query("prefix", "string", "Topic prefix"));
+        retained_params.push(query("details", "boolean", "Include message details"));
+
+        let mut broadcast = operation(
+            "Publish a message to all retained topics under a prefix",
+            vec![
+                query("prefix", "string", "Topic prefix"),
+                query(
+                    "cursor",
+                    "string",
+                    "Cursor returned by the previous request",
+                ),
+            ],
+            ADMIN,
+        );
+
+        broadcast["requestBody"] = message_body();
+
+        let admin_paths = [
+            (
+                "/admin/keys",
+                json!({
+                    "get": operation("List signing keys", paging(), ADMIN),
+                    "post": with_json_body(
+                        operation("Create a signing key", vec![], ADMIN),
+                        json!({"label": {"type": "string"}}),
+                    ),
+                }),
+            ),
+            (
+                "/admin/keys/{id}",
+                json!({"delete": operation(
+                    "Delete a signing key",
+                    vec![path_param("id", "Key ID")],
+                    ADMIN,
+                )}),
+            ),
+            (
+                "/admin/keys/{id}/rotate",
+                json!({"post": operation(
+                    "Replace a signing key's value",
+                    vec![
+                        path_param("id", "Key ID"),
+                        query("grace", "integer", "Seconds to accept the previous value for"),
+                    ],
+                    ADMIN,
+                )}),
+            ),
+            (
+                "/tokens",
+                json!({"post": with_json_body(
+                    operation("Mint a token", vec![], ADMIN),
+                    grants,
+                )}),
+            ),
+            (
+                "/admin/tokens",
+                json!({"post": with_json_body(
+                    operation("Mint a long-lived service token", vec![], ADMIN),
+                    admin_grants,
+                )}),
+            ),
+            (
+                "/admin/selftest",
+                json!({"post": operation("Publish a probe message", vec![], ADMIN)}),
+            ),
+            (
+                "/admin/config",
+                json!({"get": operation("Get the effective config", vec![], ADMIN)}),
+            ),
+            (
+                "/admin/stats",
+                json!({"get": operation(
+                    "Get usage stats",
+                    vec![query("minutes", "integer", "Number of minutes to report")],
+                    ADMIN,
+                )}),
+            ),
+            (
+                "/admin/retained",
+                json!({"get": operation("List retained topics", retained_params, ADMIN)}),
+            ),
+            (
+                "/admin/retained/{topic}",
+                json!({"delete": operation(
+                    "Purge a retained topic",
+                    vec![
+                        path_param("topic", "Topic name, percent-encoded"),
+                        query("tombstone", "boolean", "Publish a deletion to subscribers"),
+                    ],
+                    ADMIN,
+                )}),
+            ),
+            ("/admin/broadcast", json!({"post": broadcast})),
+            (
+                "/admin/scheduled",
+                json!({"post": operation("Deliver scheduled messages that are due", vec![], ADMIN)}),
+            ),
+            (
+                "/admin/connections/{cid}/close",
+                json!({"post": operation(
+                    "Close a client connection",
+                    vec![path_param("cid", "Connection ID")],
+                    ADMIN,
+                )}),
+            ),
+        ];
+
+        for (path, item) in admin_paths {
+            paths.insert(path.to_string(), item);
+        }
+    }
+
+    paths.insert(
+        "/version".to_string(),
+        json!({"get": operation("Get the deployed version", vec![], &[])}),
+    );
+
+    paths.insert(
+        "/openapi.json".to_string(),
+        json!({"get": operation("Get this document", vec![], &[])}),
+    );
+
+    // relative to the host the document was fetched from
+    let server = if config.route_prefix.is_empty() {
+        "/"
+    } else {
+        config.route_prefix.as_str()
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Fastly Pub/Sub",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"url": server}],
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "Problem": {
+                    "type": "object",
+                    "properties": {
+                        "type": {"type": "string"},
+                        "title": {"type": "string"},
+                        "status": {"type": "integer"},
+                        "detail": {"type": "string"},
+                        "code": {"type": "string"},
+                    },
+                },
+            },
+            "securitySchemes": {
+                "token": {"type": "http", "scheme": "bearer", "bearerFormat": "JWT"},
+                "apiKey": {"type": "apiKey", "in": "header", "name": "X-Api-Key"},
+                "fastlyKey": {"type": "apiKey", "in": "header", "name": "Fastly-Key"},
+            },
+        },
+    })
+}
+
+pub fn get(config: &Config) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_body(serde_json::to_string(&document(config)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_paths() {
+        let config = Config {
+            route_prefix: "/pubsub".to_string(),
+            ..Default::default()
+        };
+
+        let doc = document(&config);
+        assert_eq!(doc["servers"][0]["url"], "/pubsub");
+        assert!(doc["paths"]["/events"]["get"].is_object());
+        assert!(doc["paths"]["/events"]["post"].is_object());
+        assert!(doc["paths"]["/admin/keys/{id}"]["delete"].is_object());
+
+        let config = Config {
+            http_publish_enabled: false,
+            admin_enabled: false,
+            ..Default::default()
+        };
+
+        let doc = document(&config);
+        assert_eq!(doc["servers"][0]["url"], "/");
+        assert!(doc["paths"]["/events"]["get"].is_object());
+        assert!(doc["paths"]["/events"].get("post").is_none());
+        assert!(doc["paths"].get("/events/transaction").is_none());
+        assert!(doc["paths"].get("/admin/keys").is_none());
+        assert!(doc["paths"]["/healthz"].is_object());
+    }
+}
@@ -0,0 +1,260 @@
+use crate::auth::{Authorization, AuthorizationError};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::events::get_token;
+use crate::log_error;
+use crate::meta::MessageMeta;
+use crate::namespace;
+use crate::problem;
+use crate::publish::Batch;
+use crate::storage::{unix_now, Storage, StorageError};
+use crate::topic;
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// presence events for a topic are published to the topic with this level
+// in front of it, e.g. "$presence/orders" for "orders"
+pub const EVENTS_LEVEL: &str = "$presence";
+
+// SSE streams give no notice when they close, so their subscribers are
+// only counted for this long after they open or follow a next link
+pub const SSE_MEMBER_TTL: Duration = Duration::from_secs(60 * 60);
+
+// MQTT subscribers are removed when they unsubscribe or disconnect. this
+// covers connections that go away without either
+pub const MQTT_MEMBER_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+// changes beyond this are dropped, so that a large subscribe can't hold up
+// the request for long
+const CHANGES_PER_REQUEST_MAX: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    topic: String,
+
+    // the topic as named in the subscriber's namespace
+    name: String,
+
+    // where to publish an event about the change, in the subscriber's
+    // namespace
+    events_topic: String,
+
+    member: String,
+
+    // None if the member left
+    ttl: Option<Duration>,
+}
+
+#[derive(Debug, Serialize)]
+struct Event<'a> {
+    action: &'a str,
+    topic: &'a str,
+    member: &'a str,
+    count: usize,
+}
+
+// changes made while handling the current request, not yet applied
+static PENDING: Mutex<Vec<Change>> = Mutex::new(Vec::new());
+
+// presence isn't tracked for the topics that presence events are published
+// to, so that watching presence doesn't itself cause events
+fn is_events_topic(topic: &str) -> bool {
+    topic
+        .split(topic::SEPARATOR)
+        .any(|level| level == EVENTS_LEVEL)
+}
+
+fn note(
+    config: &Config,
+    namespace: Option<&str>,
+    topic: &str,
+    member: &str,
+    ttl: Option<Duration>,
+) {
+    if !config.presence_enabled || is_events_topic(topic) {
+        return;
+    }
+
+    let name = namespace::strip(namespace, topic);
+
+    let c = Change {
+        topic: topic.to_string(),
+        name: name.to_string(),
+        events_topic: namespace::resolve(namespace, &format!("{EVENTS_LEVEL}/{name}")),
+        member: member.to_string(),
+        ttl,
+    };
+
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push(c);
+}
+
+// notes that a member subscribed to a topic, or is still subscribed
+pub fn joined(config: &Config, namespace: Option<&str>, topic: &str, member: &str, ttl: Duration) {
+    note(config, namespace, topic, member, Some(ttl));
+}
+
+pub fn left(config: &Config, namespace: Option<&str>, topic: &str, member: &str) {
+    note(config, namespace, topic, member, None);
+}
+
+// returns the pending changes and clears them
+pub fn take() -> Vec<Change> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+// applies changes to storage, and publishes events for members that joined
+// or left. failures are logged
+pub fn apply(config: &Config, storage: &dyn Storage, changes: Vec<Change>, deadline: Deadline) {
+    let mut batch = Batch::default();
+
+    let meta = MessageMeta {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+
+    for c in changes.iter().take(CHANGES_PER_REQUEST_MAX) {
+        let expires_at = c.ttl.map(|ttl| unix_now() + ttl.as_secs());
+
+        let change = match storage.update_presence(&c.topic, &c.member, expires_at, deadline) {
+            Ok(change) => change,
+            Err(e) => {
+                log_error!("failed to update presence of topic {}: {e:?}", c.topic);
+                continue;
+            }
+        };
+
+        if change.changed && !config.publish_token.is_empty() {
+            let event = Event {
+                action: if c.ttl.is_some() { "join" } else { "leave" },
+                topic: &c.name,
+                member: &c.member,
+                count: change.count,
+            };
+
+            let data = serde_json::to_vec(&event).expect("event should always be serializable");
+
+            if let Err(e) = batch.add(config, &c.events_topic, None, &data, &meta, None, None) {
+                log_error!("failed to publish presence event: {e:?}");
+            }
+        }
+
+        if deadline.expired() {
+            break;
+        }
+    }
+
+    if !batch.is_empty() {
+        if let Err(e) = batch.send(config, deadline) {
+            log_error!("failed to publish presence events: {e:?}");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PresenceResponse<'a> {
+    topic: &'a str,
+    count: usize,
+    members: Vec<String>,
+}
+
+// lists a topic's subscribers, for those allowed to subscribe to it
+pub fn get(auth: &Authorization, storage: &dyn Storage, req: Request) -> Response {
+    let Some(topic) = req.get_query_parameter("topic") else {
+        return problem::response(StatusCode::BAD_REQUEST, "Missing 'topic' param");
+    };
+
+    let token = match get_token(&req, true) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return problem::response(
+                StatusCode::BAD_REQUEST,
+                "Missing 'Authorization' header or 'auth' parameter",
+            )
+        }
+        Err(e) => return problem::response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let caps = match auth.validate_token(token) {
+        Ok(caps) => caps,
+        Err(AuthorizationError::Token(_)) => {
+            return problem::response(StatusCode::FORBIDDEN, "Invalid token");
+        }
+        Err(e) => {
+            log_error!("auth failed: {e:?}");
+
+            return problem::response(StatusCode::INTERNAL_SERVER_ERROR, "Auth process failed");
+        }
+    };
+
+    let full_topic = caps.resolve_topic(topic);
+
+    if !caps.can_subscribe(&full_topic) {
+        return problem::response(
+            StatusCode::FORBIDDEN,
+            &format!("Cannot subscribe to topic: {topic}"),
+        );
+    }
+
+    let members = match storage.read_presence(&full_topic) {
+        Ok(members) => members,
+        Err(StorageError::StoreNotFound) => Vec::new(),
+        Err(e) => {
+            log_error!("failed to read presence from storage: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read presence from storage",
+            );
+        }
+    };
+
+    let body = PresenceResponse {
+        topic,
+        count: members.len(),
+        members,
+    };
+
+    Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_body(serde_json::to_string(&body).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes() {
+        take();
+
+        joined(&Config::default(), None, "orders", "alice", SSE_MEMBER_TTL);
+        assert!(take().is_empty());
+
+        let config = Config {
+            presence_enabled: true,
+            ..Default::default()
+        };
+
+        joined(
+            &config,
+            Some("acme"),
+            "acme/orders",
+            "alice",
+            SSE_MEMBER_TTL,
+        );
+        left(&config, None, "orders", "bob");
+        joined(&config, None, "$presence/orders", "carol", SSE_MEMBER_TTL);
+
+        let changes = take();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].topic, "acme/orders");
+        assert_eq!(changes[0].name, "orders");
+        assert_eq!(changes[0].events_topic, "acme/$presence/orders");
+        assert_eq!(changes[1].events_topic, "$presence/orders");
+        assert!(changes[1].ttl.is_none());
+        assert!(take().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::log_error;
+use crate::publish::BodyError;
+use fastly::http::{header, StatusCode};
+use fastly::Response;
+use serde::Serialize;
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+// an error response body, per RFC 9457. the code is a stable identifier of
+// the kind of error, for clients to match on instead of the detail, whose
+// wording may change. helpers that fail with an error response return
+// this rather than a Response, which is large
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub code: &'static str,
+}
+
+impl Problem {
+    // the code defaults to one for the status
+    pub fn new(status: StatusCode, detail: &str) -> Self {
+        Self {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: detail.to_string(),
+            code: status_code(status),
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = code;
+
+        self
+    }
+
+    pub fn response(&self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        Response::from_status(status)
+            .with_header(header::CONTENT_TYPE, CONTENT_TYPE)
+            .with_body(format!("{}\n", serde_json::to_string(self).unwrap()))
+    }
+}
+
+impl From<Problem> for Response {
+    fn from(p: Problem) -> Self {
+        p.response()
+    }
+}
+
+fn status_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad-request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not-found",
+        StatusCode::METHOD_NOT_ALLOWED => "method-not-allowed",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PAYLOAD_TOO_LARGE => "too-large",
+        StatusCode::TOO_MANY_REQUESTS => "rate-limited",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        s if s.is_server_error() => "internal-error",
+        _ => "error",
+    }
+}
+
+// an error response with the default code for the status
+pub fn response(status: StatusCode, detail: &str) -> Response {
+    Problem::new(status, detail).response()
+}
+
+// for a request body that couldn't be read, such as one over the limit of
+// a transport
+pub fn body_error(e: &BodyError, size_max: usize) -> Response {
+    match e {
+        BodyError::TooLarge => response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Request body exceeds {size_max} bytes maximum"),
+        ),
+        BodyError::Read(e) => {
+            log_error!("failed to read body: {e}");
+
+            response(StatusCode::BAD_REQUEST, "Failed to read body")
+        }
+    }
+}
+
+pub fn method_not_allowed(allow: &str) -> Response {
+    response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed").with_header(header::ALLOW, allow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body() {
+        let p = Problem::new(StatusCode::FORBIDDEN, "Request is stale").with_code("stale-request");
+
+        let v = serde_json::to_value(&p).unwrap();
+        assert_eq!(
+            v,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Forbidden",
+                "status": 403,
+                "detail": "Request is stale",
+                "code": "stale-request",
+            })
+        );
+
+        let p = Problem::new(StatusCode::GATEWAY_TIMEOUT, "Timed out");
+        assert_eq!(p.code, "internal-error");
+
+        let p = Problem::new(StatusCode::NOT_FOUND, "Key not found");
+        assert_eq!(p.code, "not-found");
+        assert_eq!(p.title, "Not Found");
+    }
+}
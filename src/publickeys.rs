@@ -0,0 +1,109 @@
+use crate::config::Config;
+use crate::log_error;
+use crate::problem;
+use crate::storage::{Storage, StorageError};
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+use sha1::{Digest, Sha1};
+
+const PATH_PREFIX: &str = "/topics/";
+const PATH_SUFFIX: &str = "/public-keys";
+
+fn from_hex(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let src = s.as_bytes();
+    let mut out = Vec::with_capacity(src.len());
+
+    let mut i = 0;
+    while i < src.len() {
+        if src[i] == b'%' {
+            let hi = from_hex(*src.get(i + 1)?)?;
+            let lo = from_hex(*src.get(i + 2)?)?;
+            out.push((hi << 4) | lo);
+            i += 3;
+        } else {
+            out.push(src[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+// returns the topic of a path of the form /topics/{topic}/public-keys. the
+// topic may contain slashes
+pub fn parse_path(path: &str) -> Option<String> {
+    let topic = path.strip_prefix(PATH_PREFIX)?.strip_suffix(PATH_SUFFIX)?;
+
+    if topic.is_empty() {
+        return None;
+    }
+
+    percent_decode(topic)
+}
+
+// the keys document is served as-is. it is expected to be JSON, e.g. a JWK
+// set, but this is up to the operator
+pub fn get(config: &Config, storage: &dyn Storage, topic: &str, req: Request) -> Response {
+    let keys = match storage.read_public_keys(topic) {
+        Ok(Some(v)) => v,
+        Ok(None) | Err(StorageError::StoreNotFound) => {
+            return problem::response(StatusCode::NOT_FOUND, "No public keys for topic");
+        }
+        Err(e) => {
+            log_error!("failed to read public keys from storage: {e:?}");
+
+            return problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read public keys from storage",
+            );
+        }
+    };
+
+    let etag = format!("\"{}\"", hex::encode(Sha1::digest(&keys)));
+
+    let cache_control = format!("public, max-age={}", config.public_keys_max_age);
+
+    let not_modified = match req.get_header_str(header::IF_NONE_MATCH) {
+        Some(v) => v.split(',').any(|t| {
+            let t = t.trim();
+            t == "*" || t.strip_prefix("W/").unwrap_or(t) == etag
+        }),
+        None => false,
+    };
+
+    let resp = if not_modified {
+        Response::from_status(StatusCode::NOT_MODIFIED)
+    } else {
+        Response::from_status(StatusCode::OK)
+            .with_header(header::CONTENT_TYPE, "application/json")
+            .with_body(keys)
+    };
+
+    resp.with_header(header::ETAG, etag)
+        .with_header(header::CACHE_CONTROL, cache_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(parse_path("/topics/fruit/public-keys").unwrap(), "fruit");
+        assert_eq!(parse_path("/topics/a/b/public-keys").unwrap(), "a/b");
+        assert_eq!(parse_path("/topics/a%20b/public-keys").unwrap(), "a b");
+        assert_eq!(parse_path("/topics//public-keys"), None);
+        assert_eq!(parse_path("/topics/a%2/public-keys"), None);
+        assert_eq!(parse_path("/topics/fruit"), None);
+        assert_eq!(parse_path("/events"), None);
+    }
+}
@@ -1,60 +1,275 @@
+use crate::config::{Config, LongLines};
+use crate::log;
+use crate::log_info;
+use crate::meta::MessageMeta;
 use crate::mqttpacket::{Packet, Publish};
+use crate::namespace;
+use crate::sse;
+use crate::storage::unix_now;
+use crate::trace::TRACEPARENT;
+use anyhow::Error;
 use base64::Engine;
-use fastly::error::anyhow;
+use std::borrow::Cow;
+use std::io::{self, Read};
+
+// publishing to Fanout, and the transports that have items of their own
+#[cfg(feature = "fastly")]
+use crate::deadline::{Deadline, DeadlineExceeded};
+#[cfg(feature = "fastly")]
+use crate::stats::{self, Counter};
+#[cfg(feature = "fastly")]
+use crate::{bayeux, mirror, socketio};
+#[cfg(feature = "fastly")]
+use anyhow::anyhow;
+#[cfg(feature = "fastly")]
 use fastly::http::{header, StatusCode};
-use fastly::{Error, Request};
+#[cfg(feature = "fastly")]
+use fastly::Request;
+#[cfg(feature = "fastly")]
 use std::env;
-use std::fmt::Write;
-use std::str;
+#[cfg(feature = "fastly")]
+use std::time::Duration;
+
+#[cfg(feature = "fastly")]
+const PUBLISH_TRIES_MAX: u32 = 2;
+
+// delay before the first retry of a publish
+#[cfg(feature = "fastly")]
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// backend for the Fastly API, used to publish to Fanout
+pub const API_BACKEND: &str = "api";
 
 // allow 256 bytes of protocol overhead
 pub const MESSAGE_SIZE_MAX: usize = 32_768 - 256;
 
+// retained messages can be larger, since they are read from storage rather
+// than published. only durable SSE subscribers that opt in receive them
+pub const LARGE_MESSAGE_SIZE_MAX: usize = 8 * 1024 * 1024;
+
+// most body to accept in a request made by Fanout for a WebSocket
+// connection, or in a long-polling request. such requests may carry several
+// messages
+pub const TRANSPORT_REQUEST_SIZE_MAX: usize = 1024 * 1024;
+
+// reads a message body, returning None as soon as it's found to exceed
+// size_max, without reading the rest
+pub fn read_message<R: Read>(src: R, size_max: usize) -> Result<Option<Vec<u8>>, io::Error> {
+    let mut message = Vec::new();
+
+    src.take(size_max as u64 + 1).read_to_end(&mut message)?;
+
+    if message.len() > size_max {
+        return Ok(None);
+    }
+
+    Ok(Some(message))
+}
+
+#[cfg(feature = "fastly")]
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    Read(io::Error),
+}
+
+// reads a request body of at most size_max bytes. a request declaring a
+// larger length is rejected before any of its body is read
+#[cfg(feature = "fastly")]
+pub fn read_body(req: &mut Request, size_max: usize) -> Result<Vec<u8>, BodyError> {
+    let declared = req
+        .get_header_str(header::CONTENT_LENGTH)
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if declared.is_some_and(|len| len > size_max as u64) {
+        return Err(BodyError::TooLarge);
+    }
+
+    match read_message(req.take_body(), size_max) {
+        Ok(Some(body)) => Ok(body),
+        Ok(None) => Err(BodyError::TooLarge),
+        Err(e) => Err(BodyError::Read(e)),
+    }
+}
+
+// returns the line length at which SSE data lines for a topic should be
+// split, if any
+pub fn sse_line_max(config: &Config, topic: &str) -> Option<usize> {
+    match config.topic_config(topic).long_lines {
+        LongLines::Split => Some(config.sse_line_length_max),
+        LongLines::Allow | LongLines::Reject => None,
+    }
+}
+
+// returns false if a message must be refused because of its line lengths
+pub fn check_line_lengths(config: &Config, topic: &str, message: &[u8]) -> bool {
+    config.topic_config(topic).long_lines != LongLines::Reject
+        || sse::longest_line(message) <= config.sse_line_length_max
+}
+
 pub struct Sequencing {
     pub id: String,
     pub prev_id: String,
+
+    // whether the message is a tombstone, marking a deletion
+    pub deleted: bool,
 }
 
+// most items to send to Fanout in one request. a message has an item per
+// format, so a batch may need several requests
+#[cfg(feature = "fastly")]
+const ITEMS_PER_REQUEST_MAX: usize = 64;
+
+// messages are rendered with topic names relative to the publisher's
+// namespace, if any, since the subscribers share it
+#[cfg(feature = "fastly")]
+#[allow(clippy::too_many_arguments)]
 pub fn publish(
-    api_token: &str,
+    config: &Config,
     topic: &str,
+    namespace: Option<&str>,
     message: &[u8],
+    meta: &MessageMeta,
     sequencing: Option<Sequencing>,
     sender: Option<&str>,
+    deadline: Deadline,
 ) -> Result<(), Error> {
-    let service_id = env::var("FASTLY_SERVICE_ID").unwrap();
+    let mut batch = Batch::default();
+
+    batch.add(config, topic, namespace, message, meta, sequencing, sender)?;
 
-    let sse_content = match str::from_utf8(message) {
-        Ok(s) => {
-            let mut content = String::new();
-            content.push_str("event: message\n");
+    batch.send(config, deadline)
+}
+
+struct PendingMessage {
+    topic: String,
+    message: Vec<u8>,
+    meta: MessageMeta,
 
-            for line in s.split('\n') {
-                content.write_fmt(format_args!("data: {line}\n")).unwrap();
+    // a tombstone, which isn't counted or mirrored as a message
+    deleted: bool,
+
+    // batches are only sent on Compute
+    #[cfg_attr(not(feature = "fastly"), allow(dead_code))]
+    items: Vec<serde_json::Value>,
+}
+
+// accumulates the items of several messages, such as those of a
+// transaction or of the packets in a WebSocket request, to publish them to
+// Fanout together
+#[derive(Default)]
+pub struct Batch {
+    messages: Vec<PendingMessage>,
+}
+
+impl Batch {
+    #[allow(clippy::too_many_arguments)]
+    pub fn add(
+        &mut self,
+        config: &Config,
+        topic: &str,
+        namespace: Option<&str>,
+        message: &[u8],
+        meta: &MessageMeta,
+        sequencing: Option<Sequencing>,
+        sender: Option<&str>,
+    ) -> Result<(), Error> {
+        let deleted = sequencing.as_ref().is_some_and(|seq| seq.deleted);
+
+        let items = message_items(config, topic, namespace, message, meta, sequencing, sender)?;
+
+        self.messages.push(PendingMessage {
+            topic: topic.to_string(),
+            message: message.to_vec(),
+            meta: meta.clone(),
+            deleted,
+            items,
+        });
+
+        Ok(())
+    }
+
+    // the number of messages
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // the topic, content and metadata of each message, for delivering them
+    // some other way than through Fanout. tombstones are left out
+    pub fn messages(&self) -> impl Iterator<Item = (&str, &[u8], &MessageMeta)> {
+        self.messages
+            .iter()
+            .filter(|m| !m.deleted)
+            .map(|m| (m.topic.as_str(), m.message.as_slice(), &m.meta))
+    }
+
+    // sends the items in as few requests as possible, keeping each
+    // message's items together. messages in requests made before a failure
+    // are still published
+    #[cfg(feature = "fastly")]
+    pub fn send(self, config: &Config, deadline: Deadline) -> Result<(), Error> {
+        let mut messages = self.messages.into_iter().peekable();
+
+        while messages.peek().is_some() {
+            let mut sent = Vec::new();
+            let mut items = Vec::new();
+
+            while let Some(m) = messages.next_if(|m| {
+                items.is_empty() || items.len() + m.items.len() <= ITEMS_PER_REQUEST_MAX
+            }) {
+                items.extend(m.items.iter().cloned());
+                sent.push(m);
             }
 
-            content.push('\n');
+            // a request carries a single trace context
+            let traceparent = sent.iter().find_map(|m| m.meta.traceparent.as_deref());
 
-            content
+            send_items(&config.publish_token, items, traceparent, deadline)?;
+
+            for m in sent.into_iter().filter(|m| !m.deleted) {
+                stats::incr(Counter::Publishes, 1);
+                stats::incr(Counter::PayloadBytes, m.message.len() as u64);
+
+                mirror::published(config, &m.topic, &m.message, &m.meta);
+            }
         }
-        Err(_) => {
-            let encoded = base64::prelude::BASE64_STANDARD.encode(message);
 
-            let mut content = String::new();
-            content.push_str("event: message-base64\ndata: ");
-            content.push_str(&encoded);
-            content.push_str("\n\n");
+        Ok(())
+    }
+}
+
+// the items that deliver a message to each kind of subscriber
+fn message_items(
+    config: &Config,
+    topic: &str,
+    namespace: Option<&str>,
+    message: &[u8],
+    meta: &MessageMeta,
+    sequencing: Option<Sequencing>,
+    sender: Option<&str>,
+) -> Result<Vec<serde_json::Value>, Error> {
+    let line_max = sse_line_max(config, topic);
+
+    let name = namespace::strip(namespace, topic);
 
-            content
+    if let Some(max) = line_max {
+        if sse::longest_line(message) > max {
+            log_info!("message to topic {topic} has lines exceeding {max} bytes, splitting");
         }
-    };
+    }
 
-    let mut item = if sequencing.is_some() {
+    let item = if let Some(seq) = &sequencing {
         serde_json::json!({
             "channel": format!("d:{topic}"),
+            "id": seq.id,
+            "prev-id": seq.prev_id,
             "formats": {
                 "http-stream": {
-                    "action": "hint", // TODO: send content instead
+                    "action": "hint", // for streams without durable content
                 },
                 "ws-message": {
                     "action": "refresh", // currently the only way to reliably deliver over websockets
@@ -65,12 +280,25 @@ pub fn publish(
         let mqtt_content = {
             let mut v = Vec::new();
             Packet::Publish(Publish {
-                topic: topic.into(),
+                topic: name.into(),
                 message: message.into(),
                 dup: false,
                 qos: 0,
-                retain: false,                 // always false for non-durable
-                message_expiry_interval: None, // always none for non-durable
+                retain: false, // always false for non-durable
+                message_expiry_interval: meta.expiry_interval(unix_now()),
+                content_type: meta.content_type.as_deref().map(Cow::from),
+                response_topic: meta.response_topic.as_deref().map(Cow::from),
+                correlation_data: meta.correlation_bytes().map(Cow::from),
+                user_properties: meta
+                    .user
+                    .iter()
+                    .map(|(k, v)| (Cow::from(k.as_str()), Cow::from(v.as_str())))
+                    .chain(
+                        meta.traceparent
+                            .iter()
+                            .map(|t| (Cow::from(TRACEPARENT), Cow::from(t.as_str()))),
+                    )
+                    .collect(),
             })
             .serialize(&mut v)?;
 
@@ -81,7 +309,7 @@ pub fn publish(
             "channel": format!("s:{topic}"),
             "formats": {
                 "http-stream": {
-                    "content": sse_content
+                    "content": sse::message_event(name, None, message, meta, sse::Options::default(), line_max),
                 },
                 "ws-message": {
                     "content-bin": mqtt_content,
@@ -90,34 +318,239 @@ pub fn publish(
         })
     };
 
+    let mut items = vec![item];
+
+    if let Some(seq) = &sequencing {
+        if config.sse_durable_content {
+            items.extend(durable_sse_items(
+                config, topic, name, message, meta, seq, line_max,
+            ));
+        }
+    } else {
+        for opts in config.sse_renderings() {
+            if opts == sse::Options::default() {
+                // already included above
+                continue;
+            }
+
+            items.push(serde_json::json!({
+                "channel": format!("{}{topic}", opts.channel_prefix()),
+                "formats": {
+                    "http-stream": {
+                        "content": sse::message_event(name, None, message, meta, opts, line_max),
+                    },
+                }
+            }));
+        }
+    }
+
+    // a retained message that was deleted
+    #[cfg(feature = "fastly")]
+    let deleted = sequencing.as_ref().is_some_and(|seq| seq.deleted);
+
+    // bayeux clients receive live messages only, including retained ones,
+    // but not deletions
+    #[cfg(feature = "fastly")]
+    if config.bayeux_enabled && !deleted {
+        if let Some(item) = bayeux::publish_item(topic, name, message, meta) {
+            items.push(item);
+        }
+    }
+
+    // socket.io has no way to tell of a deletion, so tombstones aren't
+    // delivered
+    #[cfg(feature = "fastly")]
+    if config.socketio_enabled && !deleted {
+        if let Some(item) = socketio::publish_item(topic, name, message, meta) {
+            items.push(item);
+        }
+    }
+
+    // the request ID lets a publish be correlated with the request that
+    // made it
+    let mut item_meta = serde_json::Map::new();
+
     if let Some(sender) = sender {
-        item["meta"] = serde_json::json!({
-            "sender": sender,
-        });
+        item_meta.insert("sender".to_string(), sender.into());
+    }
+
+    if let Some(id) = log::request_id() {
+        item_meta.insert("request-id".to_string(), id.into());
     }
 
+    if !item_meta.is_empty() {
+        for item in &mut items {
+            item["meta"] = item_meta.clone().into();
+        }
+    }
+
+    Ok(items)
+}
+
+// the items that deliver a durable message to SSE subscribers, one per
+// enabled rendering. the event ID is built by Fanout from the last ID of each of the
+// subscriber's channels. messages that are too large for some subscribers,
+// or whose deliveries need receipts, are still fetched
+fn durable_sse_items(
+    config: &Config,
+    topic: &str,
+    name: &str,
+    message: &[u8],
+    meta: &MessageMeta,
+    seq: &Sequencing,
+    line_max: Option<usize>,
+) -> Vec<serde_json::Value> {
+    let fetch = message.len() > MESSAGE_SIZE_MAX || meta.receipt.is_some();
+
+    config
+        .sse_renderings()
+        .map(|opts| {
+            let http_stream = if fetch {
+                serde_json::json!({ "action": "hint" })
+            } else {
+                let content = sse::id_template(|id| {
+                    if seq.deleted {
+                        sse::deleted_event(name, id, opts.format)
+                    } else {
+                        sse::message_event(name, Some(id), message, meta, opts, line_max)
+                    }
+                });
+
+                serde_json::json!({ "content": content })
+            };
+
+            serde_json::json!({
+                "channel": format!("{}{topic}", opts.durable_channel_prefix()),
+                "id": seq.id,
+                "prev-id": seq.prev_id,
+                "formats": {
+                    "http-stream": http_stream,
+                }
+            })
+        })
+        .collect()
+}
+
+// each SSE dynamic stream and MQTT connection is subscribed to a channel of
+// its own, so that it can be closed by ID
+pub const CONNECTION_CHANNEL_PREFIX: &str = "cn:";
+
+// tells streams subscribed to a channel to re-request their next link, and
+// held responses to be requested again, so that they pick up changes made
+// on the server side
+#[cfg(feature = "fastly")]
+pub fn publish_hint(config: &Config, channel: &str, deadline: Deadline) -> Result<(), Error> {
+    let item = serde_json::json!({
+        "channel": channel,
+        "formats": {
+            "http-stream": {
+                "action": "hint",
+            },
+            "http-response": {
+                "action": "hint",
+            },
+        }
+    });
+
+    send_items(&config.publish_token, vec![item], None, deadline)
+}
+
+// closes the streams and WebSocket connections subscribed to a channel
+#[cfg(feature = "fastly")]
+pub fn publish_close(config: &Config, channel: &str, deadline: Deadline) -> Result<(), Error> {
+    let item = serde_json::json!({
+        "channel": channel,
+        "formats": {
+            "http-stream": {
+                "action": "close",
+            },
+            "ws-message": {
+                "action": "close",
+                "code": 1008,
+            },
+        }
+    });
+
+    send_items(&config.publish_token, vec![item], None, deadline)
+}
+
+// completes requests held on a channel with the given response body
+#[cfg(feature = "fastly")]
+pub fn publish_response(
+    config: &Config,
+    channel: &str,
+    body: &str,
+    deadline: Deadline,
+) -> Result<(), Error> {
+    let item = serde_json::json!({
+        "channel": channel,
+        "formats": {
+            "http-response": {
+                "headers": {"Content-Type": "application/json"},
+                "body": body,
+            },
+        }
+    });
+
+    send_items(&config.publish_token, vec![item], None, deadline)
+}
+
+// if traceparent is set, it is passed on to Fanout
+#[cfg(feature = "fastly")]
+fn send_items(
+    api_token: &str,
+    items: Vec<serde_json::Value>,
+    traceparent: Option<&str>,
+    deadline: Deadline,
+) -> Result<(), Error> {
+    let service_id = env::var("FASTLY_SERVICE_ID").unwrap();
+
     let body = serde_json::json!({
-        "items": [item],
+        "items": items,
     });
 
     let body = body.to_string();
 
-    let req = Request::post(format!(
-        "https://api.fastly.com/service/{service_id}/publish/"
-    ))
-    .with_header(header::AUTHORIZATION, format!("Bearer {api_token}"))
-    .with_body(body)
-    .with_pass(true);
+    let mut tries = 0;
 
-    let resp = req.send("api")?;
+    loop {
+        let mut req = Request::post(format!(
+            "https://api.fastly.com/service/{service_id}/publish/"
+        ))
+        .with_header(header::AUTHORIZATION, format!("Bearer {api_token}"))
+        .with_body(body.clone())
+        .with_pass(true);
 
-    if resp.get_status() != StatusCode::OK {
-        let body = resp.into_body().into_bytes();
-        return Err(anyhow!(
-            "publish error: {:?}",
-            String::from_utf8_lossy(&body)
-        ));
-    }
+        if let Some(traceparent) = traceparent {
+            req.set_header(TRACEPARENT, traceparent);
+        }
+
+        tries += 1;
+
+        // retry on transport errors and server errors
+        let e = match req.send(API_BACKEND) {
+            Ok(resp) if resp.get_status() == StatusCode::OK => return Ok(()),
+            Ok(resp) => {
+                let status = resp.get_status();
+                let body = resp.into_body().into_bytes();
+
+                let e = anyhow!("publish error: {:?}", String::from_utf8_lossy(&body));
+
+                if !status.is_server_error() {
+                    return Err(e);
+                }
+
+                e
+            }
+            Err(e) => e.into(),
+        };
 
-    Ok(())
+        if tries >= PUBLISH_TRIES_MAX {
+            return Err(e);
+        }
+
+        if !deadline.backoff(tries, PUBLISH_RETRY_DELAY) {
+            return Err(DeadlineExceeded.into());
+        }
+    }
 }
@@ -0,0 +1,220 @@
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::log_error;
+use crate::storage::{unix_now, Storage, StorageError};
+use fastly::Response;
+use std::time::Duration;
+use time::{Date, Month, UtcDateTime};
+
+pub const LIMIT_HEADER: &str = "X-RateLimit-Limit";
+pub const REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+pub const RESET_HEADER: &str = "X-RateLimit-Reset";
+
+// counts are kept a while past the end of their period, so that the last
+// publishes of a period can't land in a counter that has just expired
+const COUNT_LINGER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    fn date(now: u64) -> Date {
+        UtcDateTime::from_unix_timestamp(now as i64)
+            .expect("now should always be a valid time")
+            .date()
+    }
+
+    // identifies the period containing the time, e.g. "d20261016" or
+    // "m202610"
+    pub fn id(self, now: u64) -> String {
+        let date = Self::date(now);
+
+        match self {
+            Self::Day => format!(
+                "d{:04}{:02}{:02}",
+                date.year(),
+                date.month() as u8,
+                date.day()
+            ),
+            Self::Month => format!("m{:04}{:02}", date.year(), date.month() as u8),
+        }
+    }
+
+    // when the period containing the time ends, in unix seconds
+    pub fn end(self, now: u64) -> u64 {
+        match self {
+            Self::Day => (now / 86400 + 1) * 86400,
+            Self::Month => {
+                let date = Self::date(now);
+
+                let (year, month) = match date.month() {
+                    Month::December => (date.year() + 1, Month::January),
+                    m => (date.year(), m.next()),
+                };
+
+                let start = Date::from_calendar_date(year, month, 1)
+                    .expect("first of month should always be a valid date");
+
+                start.midnight().assume_utc().unix_timestamp() as u64
+            }
+        }
+    }
+}
+
+// a publisher's use of one of its quotas, after the current request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub limit: u32,
+    pub used: u64,
+
+    // seconds until the count starts over
+    pub reset: u64,
+}
+
+impl Usage {
+    pub fn remaining(&self) -> u64 {
+        u64::from(self.limit).saturating_sub(self.used)
+    }
+
+    pub fn add_headers(&self, resp: &mut Response) {
+        resp.set_header(LIMIT_HEADER, self.limit.to_string());
+        resp.set_header(REMAINING_HEADER, self.remaining().to_string());
+        resp.set_header(RESET_HEADER, self.reset.to_string());
+    }
+}
+
+fn quotas(config: &Config) -> impl Iterator<Item = (Period, u32)> {
+    [
+        (Period::Day, config.publish_quota_daily),
+        (Period::Month, config.publish_quota_monthly),
+    ]
+    .into_iter()
+    .filter_map(|(period, limit)| Some((period, limit?)))
+}
+
+// checks publishes against the configured quotas of a publisher, without
+// counting them. if a quota would be exceeded, its usage is returned as an
+// error. otherwise, the usage of the quota with the least remaining, once
+// the publishes are counted, is returned, if any. this is best effort, so
+// quotas that can't be read are skipped. since reading and counting are
+// separate, concurrent publishes may exceed a quota slightly
+pub fn check(
+    config: &Config,
+    storage: &dyn Storage,
+    key: &str,
+    count: u32,
+) -> Result<Option<Usage>, Usage> {
+    let now = unix_now();
+
+    let mut usages = Vec::new();
+
+    for (period, limit) in quotas(config) {
+        let id = period.id(now);
+
+        let used = match storage.read_quota_usage(key, &id) {
+            Ok(used) => used,
+            Err(StorageError::StoreNotFound) => continue,
+            Err(e) => {
+                log_error!("failed to read publish quota usage: {e:?}");
+                continue;
+            }
+        };
+
+        let usage = Usage {
+            limit,
+            used: used + u64::from(count),
+            reset: period.end(now) - now,
+        };
+
+        if usage.used > u64::from(limit) {
+            return Err(Usage { used, ..usage });
+        }
+
+        usages.push(usage);
+    }
+
+    Ok(usages.into_iter().min_by_key(|usage| usage.remaining()))
+}
+
+// counts publishes against the configured quotas of a publisher. this is
+// done only once they have been published, so that failed publishes don't
+// use up a quota
+pub fn count(config: &Config, storage: &dyn Storage, key: &str, count: u32, deadline: Deadline) {
+    let now = unix_now();
+
+    for (period, _) in quotas(config) {
+        let id = period.id(now);
+        let ttl = Duration::from_secs(period.end(now) - now) + COUNT_LINGER;
+
+        match storage.add_quota_usage(key, &id, count, ttl, deadline) {
+            Ok(()) | Err(StorageError::StoreNotFound) => {}
+            Err(e) => log_error!("failed to count publish quota usage: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memorykv::MemoryKv;
+    use crate::storage::KvStorage;
+
+    #[test]
+    fn periods() {
+        // 2026-10-16T12:00:00Z
+        let now = 1_792_152_000;
+
+        assert_eq!(Period::Day.id(now), "d20261016");
+        assert_eq!(Period::Month.id(now), "m202610");
+
+        // 2026-10-17T00:00:00Z
+        assert_eq!(Period::Day.end(now), 1_792_195_200);
+
+        // 2026-11-01T00:00:00Z
+        assert_eq!(Period::Month.end(now), 1_793_491_200);
+
+        // 2026-12-31T23:59:59Z to 2027-01-01T00:00:00Z
+        assert_eq!(Period::Month.end(1_798_761_599), 1_798_761_600);
+        assert_eq!(Period::Month.id(1_798_761_600), "m202701");
+
+        let usage = Usage {
+            limit: 10,
+            used: 12,
+            reset: 60,
+        };
+        assert_eq!(usage.remaining(), 0);
+    }
+
+    #[test]
+    fn check_and_count() {
+        let storage = KvStorage::new(Box::new(MemoryKv::new()));
+
+        let config = Config {
+            publish_quota_daily: Some(3),
+            ..Default::default()
+        };
+
+        // checking doesn't count
+        let usage = check(&config, &storage, "key", 2).unwrap().unwrap();
+        assert_eq!(usage.used, 2);
+        assert_eq!(usage.remaining(), 1);
+
+        let usage = check(&config, &storage, "key", 2).unwrap().unwrap();
+        assert_eq!(usage.used, 2);
+
+        count(&config, &storage, "key", 2, Deadline::none());
+
+        let usage = check(&config, &storage, "key", 1).unwrap().unwrap();
+        assert_eq!(usage.used, 3);
+        assert_eq!(usage.remaining(), 0);
+
+        let usage = check(&config, &storage, "key", 2).unwrap_err();
+        assert_eq!(usage.used, 2);
+
+        // other keys are counted separately
+        assert!(check(&config, &storage, "other", 3).is_ok());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::events;
+use crate::log_error;
+use crate::remotekv::{https_host, url_backend};
+use crate::storage::{unix_now_ms, RetainedMessage, RetainedVersion};
+use fastly::http::header;
+use fastly::Request;
+use serde::Serialize;
+use std::sync::Mutex;
+
+// receipt URLs are stored with retained messages, in KV item metadata
+pub const RECEIPT_URL_LEN_MAX: usize = 512;
+
+// receipts beyond this are dropped, so that a large sync can't hold up the
+// request for long
+const RECEIPTS_PER_REQUEST_MAX: usize = 20;
+
+pub const SIGNATURE_HEADER: &str = "Pubsub-Signature";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Sse,
+    Mqtt,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Receipt {
+    #[serde(skip)]
+    pub url: String,
+
+    pub topic: String,
+
+    // ID of the delivered version, as listed by /admin/retained
+    pub id: String,
+
+    pub transport: Transport,
+
+    // unix milliseconds
+    pub delivered_at: u64,
+}
+
+// receipts for deliveries made while handling the current request, not
+// yet sent
+static PENDING: Mutex<Vec<Receipt>> = Mutex::new(Vec::new());
+
+// checks a receipt URL given by a publisher. receipts can only be sent
+// over https to hosts the operator allows
+pub fn check_url(config: &Config, url: &str) -> Result<(), String> {
+    let (Some(hosts), Some(_)) = (&config.receipt_hosts, &config.receipt_key) else {
+        return Err("Receipts not enabled".to_string());
+    };
+
+    if url.len() > RECEIPT_URL_LEN_MAX {
+        return Err(format!("URL exceeds {RECEIPT_URL_LEN_MAX} bytes maximum"));
+    }
+
+    let Some(host) = https_host(url) else {
+        return Err("URL must use https".to_string());
+    };
+
+    if !hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+        return Err(format!("Host not allowed: {host}"));
+    }
+
+    Ok(())
+}
+
+// notes the delivery of a retained message, if its publisher asked for a
+// receipt
+pub fn delivered(
+    topic: &str,
+    version: &RetainedVersion,
+    message: &RetainedMessage,
+    transport: Transport,
+) {
+    let Some(url) = &message.meta.receipt else {
+        return;
+    };
+
+    let receipt = Receipt {
+        url: url.clone(),
+        topic: topic.to_string(),
+        id: events::version_id(version),
+        transport,
+        delivered_at: unix_now_ms(),
+    };
+
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(receipt);
+}
+
+// returns the pending receipts and clears them
+pub fn take() -> Vec<Receipt> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+// hex of the HMAC-SHA256 of the body
+pub fn sign(key: &[u8], body: &[u8]) -> String {
+    hex::encode(hmac_sha256::HMAC::mac(body, key))
+}
+
+// requests are sent together and then waited on. failures are logged, and
+// receipts aren't retried
+pub fn send(config: &Config, receipts: Vec<Receipt>, deadline: Deadline) {
+    let Some(key) = &config.receipt_key else {
+        return;
+    };
+
+    if receipts.len() > RECEIPTS_PER_REQUEST_MAX {
+        log_error!(
+            "dropping {} delivery receipts",
+            receipts.len() - RECEIPTS_PER_REQUEST_MAX
+        );
+    }
+
+    let mut pending = Vec::new();
+
+    for r in receipts.into_iter().take(RECEIPTS_PER_REQUEST_MAX) {
+        // hosts are checked when publishing, but may have been disallowed
+        // since
+        if let Err(e) = check_url(config, &r.url) {
+            log_error!("not sending delivery receipt to {}: {e}", r.url);
+            continue;
+        }
+
+        // each host needs its own backend
+        let host = https_host(&r.url).unwrap_or_default();
+
+        let backend = match url_backend(None, &format!("receipt-{host}"), &r.url) {
+            Ok(b) => b,
+            Err(e) => {
+                log_error!("failed to send delivery receipt to {}: {e}", r.url);
+                continue;
+            }
+        };
+
+        let body = serde_json::to_vec(&r).expect("receipt should always be serializable");
+
+        let req = Request::post(&r.url)
+            .with_header(header::CONTENT_TYPE, "application/json")
+            .with_header(SIGNATURE_HEADER, format!("sha256={}", sign(key, &body)))
+            .with_body(body);
+
+        match req.send_async(backend) {
+            Ok(p) => pending.push((r.url, p)),
+            Err(e) => log_error!("failed to send delivery receipt to {}: {e}", r.url),
+        }
+
+        if deadline.expired() {
+            break;
+        }
+    }
+
+    for (url, p) in pending {
+        match p.wait() {
+            Ok(resp) if resp.get_status().is_success() => {}
+            Ok(resp) => log_error!(
+                "delivery receipt to {url} failed with status {}",
+                resp.get_status().as_u16()
+            ),
+            Err(e) => log_error!("failed to send delivery receipt to {url}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::MessageMeta;
+
+    #[test]
+    fn receipts() {
+        let mut config = Config::default();
+        assert!(check_url(&config, "https://example.com/r").is_err());
+
+        config.receipt_hosts = Some(vec!["example.com".to_string()]);
+        config.receipt_key = Some(b"notasecret".to_vec());
+        assert!(check_url(&config, "https://example.com/r").is_ok());
+        assert!(check_url(&config, "http://example.com/r").is_err());
+        assert!(check_url(&config, "https://example.org/r").is_err());
+
+        let long = format!("https://example.com/{}", "x".repeat(RECEIPT_URL_LEN_MAX));
+        assert!(check_url(&config, &long).is_err());
+
+        let version = RetainedVersion {
+            epoch: 1,
+            generation: 2,
+            seq: 3,
+            started: 0,
+        };
+
+        let mut message = RetainedMessage {
+            ttl: None,
+            data: b"hello".to_vec(),
+            meta: MessageMeta::default(),
+        };
+
+        take();
+
+        delivered("a", &version, &message, Transport::Sse);
+        assert!(take().is_empty());
+
+        message.meta.receipt = Some("https://example.com/r".to_string());
+        delivered("a", &version, &message, Transport::Mqtt);
+
+        let receipts = take();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].id, events::version_id(&version));
+
+        let v = serde_json::to_value(&receipts[0]).unwrap();
+        assert_eq!(v["transport"], "mqtt");
+        assert!(v.get("url").is_none());
+
+        assert_eq!(sign(b"k", b"body"), sign(b"k", b"body"));
+        assert_ne!(sign(b"k", b"body"), sign(b"j", b"body"));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::config::RemoteStorage;
+use crate::kv::{Condition, Insert, Item, Kv, KvError, ListPage};
+use base64::Engine;
+use fastly::backend::{Backend, BackendCreationError};
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::cell::OnceCell;
+use std::fmt::Write;
+use std::time::Duration;
+
+// name of the backend created for the storage URL, if no backend is named
+const DYNAMIC_BACKEND: &str = "remote-storage";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const METADATA_HEADER: &str = "Kv-Metadata";
+const TTL_HEADER: &str = "Kv-Ttl";
+
+#[derive(Deserialize)]
+struct ListResponse {
+    keys: Vec<String>,
+
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+// escapes everything but unreserved characters, so that keys can contain
+// slashes and other characters used in topics
+fn percent_encode(s: &str) -> String {
+    let mut out = String::new();
+
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.write_fmt(format_args!("%{b:02X}")).unwrap();
+        }
+    }
+
+    out
+}
+
+// generations are sent as quoted entity tags
+fn parse_etag(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("W/").unwrap_or(s);
+
+    s.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+}
+
+// returns the host of an https URL
+pub(crate) fn https_host(url: &str) -> Option<&str> {
+    let rest = url.strip_prefix("https://")?;
+
+    let host = match rest.find('/') {
+        Some(pos) => &rest[..pos],
+        None => rest,
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some(host)
+}
+
+// returns the named backend, or else a dynamic backend for the host of an
+// https URL, registered under dynamic_name
+pub(crate) fn url_backend(
+    name: Option<&str>,
+    dynamic_name: &str,
+    url: &str,
+) -> Result<Backend, String> {
+    if let Some(name) = name {
+        return Backend::from_name(name).map_err(|e| format!("invalid backend: {e}"));
+    }
+
+    let Some(host) = https_host(url) else {
+        return Err("URL must use https".to_string());
+    };
+
+    let result = Backend::builder(dynamic_name, host)
+        .override_host(host)
+        .enable_ssl()
+        .sni_hostname(host)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .first_byte_timeout(FIRST_BYTE_TIMEOUT)
+        .finish();
+
+    match result {
+        Ok(b) => Ok(b),
+
+        // already registered earlier in the request
+        Err(BackendCreationError::NameInUse) => {
+            Backend::from_name(dynamic_name).map_err(|e| format!("invalid backend: {e}"))
+        }
+
+        Err(e) => Err(format!("failed to create backend: {e}")),
+    }
+}
+
+fn remote_error(resp: &Response) -> KvError {
+    match resp.get_status() {
+        StatusCode::PRECONDITION_FAILED => KvError::PreconditionFailed,
+        StatusCode::TOO_MANY_REQUESTS => KvError::TooManyRequests,
+        status => KvError::Remote(format!("unexpected status {}", status.as_u16())),
+    }
+}
+
+fn to_item(mut resp: Response) -> Result<Option<Item>, KvError> {
+    match resp.get_status() {
+        StatusCode::OK => {}
+        StatusCode::NOT_FOUND => return Ok(None),
+        _ => return Err(remote_error(&resp)),
+    }
+
+    let Some(generation) = resp.get_header_str(header::ETAG).and_then(parse_etag) else {
+        return Err(KvError::Remote("missing or invalid etag".to_string()));
+    };
+
+    let metadata = match resp.get_header_str(METADATA_HEADER) {
+        Some(s) => match base64::prelude::BASE64_STANDARD.decode(s) {
+            Ok(v) => Some(v),
+            Err(_) => return Err(KvError::Remote("invalid metadata".to_string())),
+        },
+        None => None,
+    };
+
+    Ok(Some(Item {
+        value: resp.take_body_bytes(),
+        metadata,
+        generation,
+    }))
+}
+
+// a key-value service reached over HTTP. see the README for the protocol
+pub struct RemoteKv {
+    url: String,
+    backend_name: Option<String>,
+    token: Option<String>,
+    backend: OnceCell<Backend>,
+}
+
+impl RemoteKv {
+    pub fn new(config: &RemoteStorage) -> Self {
+        Self {
+            url: config.url.trim_end_matches('/').to_string(),
+            backend_name: config.backend.clone(),
+            token: config.token.clone(),
+            backend: OnceCell::new(),
+        }
+    }
+
+    fn backend(&self) -> Result<&Backend, KvError> {
+        if let Some(b) = self.backend.get() {
+            return Ok(b);
+        }
+
+        let b = url_backend(self.backend_name.as_deref(), DYNAMIC_BACKEND, &self.url)
+            .map_err(KvError::Remote)?;
+
+        Ok(self.backend.get_or_init(|| b))
+    }
+
+    fn key_url(&self, key: &str) -> String {
+        format!("{}/keys/{}", self.url, percent_encode(key))
+    }
+
+    fn authorize(&self, req: Request) -> Request {
+        match &self.token {
+            Some(token) => req.with_header(header::AUTHORIZATION, format!("Bearer {token}")),
+            None => req,
+        }
+    }
+
+    fn send(&self, req: Request) -> Result<Response, KvError> {
+        let backend = self.backend()?;
+
+        self.authorize(req)
+            .send(backend)
+            .map_err(|e| KvError::Remote(format!("request failed: {e}")))
+    }
+}
+
+impl Kv for RemoteKv {
+    fn lookup(&self, key: &str) -> Result<Option<Item>, KvError> {
+        to_item(self.send(Request::get(self.key_url(key)))?)
+    }
+
+    // requests are sent together and then waited on
+    fn lookup_many(&self, keys: &[String]) -> Result<Vec<Option<Item>>, KvError> {
+        let backend = self.backend()?;
+
+        let mut pending = Vec::new();
+
+        for key in keys {
+            let req = self.authorize(Request::get(self.key_url(key)));
+
+            match req.send_async(backend) {
+                Ok(p) => pending.push(p),
+                Err(e) => return Err(KvError::Remote(format!("request failed: {e}"))),
+            }
+        }
+
+        pending
+            .into_iter()
+            .map(|p| match p.wait() {
+                Ok(resp) => to_item(resp),
+                Err(e) => Err(KvError::Remote(format!("request failed: {e}"))),
+            })
+            .collect()
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>, insert: &Insert) -> Result<(), KvError> {
+        let mut req = Request::put(self.key_url(key)).with_body(value);
+
+        match insert.condition {
+            Condition::Always => {}
+            Condition::Absent => req.set_header(header::IF_NONE_MATCH, "*"),
+            Condition::Generation(generation) => {
+                req.set_header(header::IF_MATCH, format!("\"{generation}\""))
+            }
+        }
+
+        if let Some(metadata) = insert.metadata {
+            req.set_header(
+                METADATA_HEADER,
+                base64::prelude::BASE64_STANDARD.encode(metadata),
+            );
+        }
+
+        if let Some(ttl) = insert.ttl {
+            req.set_header(TTL_HEADER, ttl.as_secs().to_string());
+        }
+
+        let resp = self.send(req)?;
+
+        if !resp.get_status().is_success() {
+            return Err(remote_error(&resp));
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), KvError> {
+        let resp = self.send(Request::delete(self.key_url(key)))?;
+
+        if !resp.get_status().is_success() && resp.get_status() != StatusCode::NOT_FOUND {
+            return Err(remote_error(&resp));
+        }
+
+        Ok(())
+    }
+
+    fn list(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<ListPage, KvError> {
+        let mut url = format!("{}/keys?prefix={}", self.url, percent_encode(prefix));
+
+        if let Some(cursor) = cursor {
+            url.write_fmt(format_args!("&cursor={}", percent_encode(cursor)))
+                .unwrap();
+        }
+
+        if let Some(limit) = limit {
+            url.write_fmt(format_args!("&limit={limit}")).unwrap();
+        }
+
+        let resp = self.send(Request::get(url))?;
+
+        if resp.get_status() != StatusCode::OK {
+            return Err(remote_error(&resp));
+        }
+
+        let list: ListResponse = match serde_json::from_slice(&resp.into_body_bytes()) {
+            Ok(v) => v,
+            Err(_) => return Err(KvError::Remote("invalid list response".to_string())),
+        };
+
+        Ok(ListPage {
+            keys: list.keys,
+            cursor: list.cursor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        assert_eq!(percent_encode("r:a/b c"), "r%3Aa%2Fb%20c");
+        assert_eq!(percent_encode("x-1_2.3~"), "x-1_2.3~");
+
+        assert_eq!(parse_etag("\"42\""), Some(42));
+        assert_eq!(parse_etag("W/\"42\""), Some(42));
+        assert_eq!(parse_etag("42"), None);
+        assert_eq!(parse_etag("\"abc\""), None);
+
+        assert_eq!(
+            https_host("https://kv.example.com/v1"),
+            Some("kv.example.com")
+        );
+        assert_eq!(https_host("https://kv.example.com"), Some("kv.example.com"));
+        assert_eq!(https_host("http://kv.example.com"), None);
+        assert_eq!(https_host("https:///v1"), None);
+    }
+}
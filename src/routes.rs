@@ -1,39 +1,202 @@
-use crate::{admin, auth, config, events, mqtttransport, storage};
+use crate::deadline::Deadline;
+use crate::kv::Kv;
+use crate::problem::Problem;
+use crate::{
+    admin, auth, bayeux, cache, cidr, compress, config, encryption, events, geo, health, jwks, log,
+    log_error, metrics, mirror, mqtttransport, namespace, openapi, presence, problem, publickeys,
+    receipt, remotekv, socketio, stats, storage, version, webhook, wiring,
+};
 use fastly::http::{header, Method, StatusCode};
 use fastly::{Error, Request, Response};
+use std::time::{Duration, Instant};
+
+// backend pointing at the service itself, for Fanout to proxy requests to
+pub const SELF_BACKEND: &str = "self";
+
+// included in every response, and in log lines and publishes made while
+// handling the request
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const REQUEST_ID_LEN_MAX: usize = 64;
+
+const STATS_TIME_BUDGET: Duration = Duration::from_millis(500);
+
+const RECEIPTS_TIME_BUDGET: Duration = Duration::from_millis(2_000);
+const MIRROR_TIME_BUDGET: Duration = Duration::from_millis(2_000);
+const PRESENCE_TIME_BUDGET: Duration = Duration::from_millis(1_000);
+
+struct Cors {
+    allow_origin: Option<String>,
+    vary: bool,
+}
+
+impl Cors {
+    fn new(allowed_origins: Option<&[String]>, origin: Option<&str>) -> Self {
+        let Some(allowed_origins) = allowed_origins else {
+            return Self {
+                allow_origin: Some("*".to_string()),
+                vary: false,
+            };
+        };
+
+        // echo the origin if allowed. the response varies by origin either way
+        let allow_origin = match origin {
+            Some(origin) if allowed_origins.iter().any(|o| o == origin) => Some(origin.to_string()),
+            _ => None,
+        };
+
+        Self {
+            allow_origin,
+            vary: true,
+        }
+    }
+}
 
 trait WithCors {
-    fn with_cors(self) -> Self;
+    fn with_cors(self, cors: &Cors) -> Self;
 }
 
 impl WithCors for Response {
-    fn with_cors(self) -> Self {
-        self.with_header("Access-Control-Allow-Origin", "*")
+    fn with_cors(mut self, cors: &Cors) -> Self {
+        if cors.vary {
+            self.append_header(header::VARY, "Origin");
+        }
+
+        let Some(allow_origin) = &cors.allow_origin else {
+            return self;
+        };
+
+        self.with_header("Access-Control-Allow-Origin", allow_origin)
             .with_header(
                 "Access-Control-Allow-Methods",
                 "OPTIONS, HEAD, GET, POST, PUT, DELETE",
             )
             .with_header(
                 "Access-Control-Allow-Headers",
-                "Authorization, Content-Type",
+                "Authorization, Content-Type, X-Api-Key",
             )
             .with_header("Access-Control-Allow-Credentials", "true")
             .with_header("Access-Control-Max-Age", "3600")
+            .with_header("Access-Control-Expose-Headers", REQUEST_ID_HEADER)
+    }
+}
+
+// requests that Fanout makes on behalf of a WebSocket connection carry the
+// connection's ID, which is reused so that the session's requests can be
+// correlated. otherwise, a new ID is generated
+fn request_id(req: &Request) -> String {
+    if let Some(cid) = req.get_header_str("Connection-Id") {
+        let valid = cid
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+
+        if !cid.is_empty() && cid.len() <= REQUEST_ID_LEN_MAX && valid {
+            return cid.to_string();
+        }
+    }
+
+    hex::encode(rand::random::<[u8; 8]>())
+}
+
+// the networks that the client must be in for the route, if it is limited.
+// subscribing is never limited, and neither are CORS preflight requests
+fn allowed_ips<'a>(
+    config: &'a config::Config,
+    path: &str,
+    method: &Method,
+) -> Option<&'a [cidr::Cidr]> {
+    if *method == Method::OPTIONS {
+        return None;
+    }
+
+    if path == "/tokens" || path.starts_with("/admin/") {
+        return config.admin_allowed_ips.as_deref();
+    }
+
+    let publish = match path {
+        "/events" => *method == Method::POST || *method == Method::DELETE,
+        "/events/transaction" => true,
+        _ => false,
+    };
+
+    if publish {
+        config.publish_allowed_ips.as_deref()
+    } else {
+        None
+    }
+}
+
+// logs the error lines held while handling a request, when dropped. each
+// request runs in a new instance, so they are limited with state kept in
+// storage
+struct HeldErrors<'a> {
+    storage: &'a dyn storage::Storage,
+}
+
+impl Drop for HeldErrors<'_> {
+    fn drop(&mut self) {
+        let held = log::take_held_errors();
+
+        if held.is_empty() {
+            return;
+        }
+
+        match self.storage.read_log_limiter() {
+            Ok(limiter) => {
+                let mut limiter = limiter.unwrap_or_default();
+
+                log::release_errors(held, Some(&mut limiter));
+
+                if let Err(e) = self.storage.write_log_limiter(&limiter) {
+                    log_error!("failed to write log limiter to storage: {e:?}");
+                }
+            }
+            Err(e) => {
+                log::release_errors(held, None);
+
+                if !matches!(e, storage::StorageError::StoreNotFound) {
+                    log_error!("failed to read log limiter from storage: {e:?}");
+                }
+            }
+        }
     }
 }
 
 pub fn handle_request(
     config_source: &dyn config::Source,
-    auth: &auth::Authorization,
-    storage: &dyn storage::Storage,
-    req: Request,
+    auth: auth::Authorization,
+    kv: Box<dyn Kv>,
+    resources: &wiring::Resources,
+    mut req: Request,
 ) -> Result<(), Error> {
+    let start = Instant::now();
+
+    let request_id = request_id(&req);
+    log::set_request_id(&request_id);
+
+    let origin = req.get_header_str(header::ORIGIN).map(|s| s.to_string());
+
     let config = match config_source.config() {
         Ok(config) => config,
-        Err(_) => {
-            let resp = Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
-                .with_body_text_plain("Configuration process failed.\n")
-                .with_cors();
+        Err(e) => {
+            // the allowlist is unknown, so don't allow any origin
+            let cors = Cors::new(Some(&[]), origin.as_deref());
+
+            // so is the route prefix, so the health route is matched by its
+            // last segment
+            let resp =
+                if req.get_method() == Method::GET && req.get_url().path().ends_with("/healthz") {
+                    health::get(Err(&e), resources)
+                } else {
+                    problem::response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Configuration process failed",
+                    )
+                };
+
+            let resp = resp
+                .with_cors(&cors)
+                .with_header(REQUEST_ID_HEADER, &request_id);
 
             resp.send_to_client();
 
@@ -41,35 +204,180 @@ pub fn handle_request(
         }
     };
 
-    let path = req.get_url().path();
+    log::configure(
+        config.log_level,
+        config.log_endpoint.as_deref(),
+        config.log_error_interval,
+    );
+
+    let deadline = Deadline::new(Duration::from_millis(config.request_time_budget_ms.into()));
+
+    let cors = Cors::new(config.cors_allowed_origins.as_deref(), origin.as_deref());
+
+    // tokens are checked against the key set first, if configured
+    let mut auth = match &config.jwks {
+        Some(jwks) => auth::Authorization {
+            app_token: Box::new(jwks::JwksAppTokenAuthorizor::new(jwks, auth.app_token)),
+            ..auth
+        },
+        None => auth,
+    };
+
+    auth.token_validation = config.token_validation.clone();
+    auth.namespace = namespace::from_host(&config, &req);
+
+    if !config.client_cert_auth {
+        auth.client_cert = None;
+    }
+
+    let auth = &auth;
+
+    // the client's location is looked up before any handoff, and carried
+    // along in a header. requests that aren't from Fanout can't set it
+    if config.has_geo_policies() {
+        let from_fanout = req
+            .get_header_str("Grip-Sig")
+            .is_some_and(|sig| auth.grip.validate_sig(sig).is_ok());
+
+        if !from_fanout {
+            geo::tag(&mut req);
+        }
+    }
+
+    let kv: Box<dyn Kv> = match &config.remote_storage {
+        Some(remote) => Box::new(remotekv::RemoteKv::new(remote)),
+        None => kv,
+    };
+
+    let keys = encryption::Keys::new(
+        config.retained_key.as_deref(),
+        config.retained_previous_key.as_deref(),
+    );
+
+    let storage = storage::KvStorage::new(kv).with_keys(keys.clone());
+
+    let storage: &dyn storage::Storage = &storage;
+
+    let cached_storage;
+
+    let storage: &dyn storage::Storage = if config.retained_cache_ms > 0 {
+        let ttl = Duration::from_millis(config.retained_cache_ms.into());
+
+        cached_storage = cache::CachedStorage::new(storage, ttl).with_keys(keys);
+
+        &cached_storage
+    } else {
+        storage
+    };
+
+    // covers every return from here on
+    log::hold_errors();
+    let _held_errors = HeldErrors { storage };
+
+    // paths outside the route prefix aren't ours
+    let path = config.route_path(req.get_url().path()).unwrap_or("");
+
+    if config.validate_wiring {
+        let missing = wiring::check(&config, resources);
+
+        if !missing.is_empty() {
+            log_error!(
+                "missing resources: {}",
+                serde_json::to_string(&missing).unwrap()
+            );
+        }
+
+        let required: Vec<String> = missing
+            .iter()
+            .filter(|m| m.required)
+            .map(|m| m.name.clone())
+            .collect();
+
+        // the health route reports missing resources itself
+        if !required.is_empty() && path != "/healthz" {
+            let resp = Problem::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &format!(
+                    "Service is missing required resources: {}",
+                    required.join(", ")
+                ),
+            )
+            .with_code("missing-resources")
+            .response()
+            .with_cors(&cors)
+            .with_header(REQUEST_ID_HEADER, &request_id);
+
+            resp.send_to_client();
 
-    let resp = if path == "/" {
+            return Ok(());
+        }
+    }
+
+    let route = metrics::route_name(path);
+    let method = req.get_method_str().to_string();
+    let gzip = compress::accepts_gzip(&req);
+
+    let client_allowed = match allowed_ips(&config, path, req.get_method()) {
+        Some(allowed) => req
+            .get_client_ip_addr()
+            .is_some_and(|addr| cidr::any_contains(allowed, addr)),
+        None => true,
+    };
+
+    let resp = if !client_allowed {
+        Problem::new(StatusCode::FORBIDDEN, "Client address not allowed")
+            .with_code("address-not-allowed")
+            .response()
+    } else if path == "/" {
         Response::from_status(StatusCode::OK).with_body_text_plain("Hello from Fastly Pub/Sub!\n")
+    } else if path == "/healthz" {
+        if req.get_method() == Method::GET {
+            health::get(Ok(&config), resources)
+        } else {
+            problem::method_not_allowed("GET")
+        }
+    } else if path == "/version" {
+        if req.get_method() == Method::GET {
+            version::get(&config)
+        } else {
+            problem::method_not_allowed("GET")
+        }
+    } else if path == "/openapi.json" {
+        if req.get_method() == Method::GET {
+            openapi::get(&config)
+        } else {
+            problem::method_not_allowed("GET")
+        }
     } else if path == "/events" && (config.sse_enabled || config.http_publish_enabled) {
         if req.get_method() == Method::OPTIONS {
             Response::from_status(StatusCode::OK)
         } else if req.get_method() == Method::GET && config.sse_enabled {
             let Some(sig) = req.get_header_str("Grip-Sig") else {
                 // handoff if necessary
-                req.handoff_fanout("self")?;
+                req.handoff_fanout(SELF_BACKEND)?;
                 return Ok(());
             };
 
             if let Err(e) = auth.grip.validate_sig(sig) {
-                println!("failed to validate Grip-Sig: {e}");
+                log_error!("failed to validate Grip-Sig: {e}");
 
-                let resp = Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .with_body_text_plain("Failed to authorize Fanout proxy.\n")
-                    .with_cors();
+                let resp = problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to authorize Fanout proxy",
+                )
+                .with_cors(&cors)
+                .with_header(REQUEST_ID_HEADER, &request_id);
 
                 resp.send_to_client();
 
                 return Ok(());
             }
 
-            events::get(auth, storage, req)
+            events::get(&config, auth, storage, req)
         } else if req.get_method() == Method::POST && config.http_publish_enabled {
-            events::post(&config, auth, storage, req)
+            events::post(&config, auth, storage, deadline, req)
+        } else if req.get_method() == Method::DELETE && config.http_publish_enabled {
+            events::delete(&config, auth, storage, deadline, req)
         } else {
             let mut allow = "OPTIONS".to_string();
 
@@ -78,26 +386,47 @@ pub fn handle_request(
             }
 
             if config.http_publish_enabled {
-                allow.push_str(", POST");
+                allow.push_str(", POST, DELETE");
             }
 
-            Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
-                .with_header(header::ALLOW, allow)
-                .with_body_text_plain("Method Not Allowed\n")
+            problem::method_not_allowed(&allow)
+        }
+    } else if path == "/events/transaction" && config.http_publish_enabled {
+        if req.get_method() == Method::POST {
+            events::post_transaction(&config, auth, storage, deadline, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if path == "/events/subscriptions" && config.sse_enabled {
+        if req.get_method() == Method::GET {
+            events::get_subscriptions(auth, storage, req)
+        } else if req.get_method() == Method::POST {
+            events::post_subscriptions(&config, auth, storage, deadline, req)
+        } else {
+            problem::method_not_allowed("GET, POST")
+        }
+    } else if path == "/presence" && config.presence_enabled {
+        if req.get_method() == Method::GET {
+            presence::get(auth, storage, req)
+        } else {
+            problem::method_not_allowed("GET")
         }
     } else if path == "/mqtt" && config.mqtt_enabled {
         let Some(sig) = req.get_header_str("Grip-Sig") else {
             // handoff if necessary
-            req.handoff_fanout("self")?;
+            req.handoff_fanout(SELF_BACKEND)?;
             return Ok(());
         };
 
         if let Err(e) = auth.grip.validate_sig(sig) {
-            println!("failed to validate Grip-Sig: {e}");
+            log_error!("failed to validate Grip-Sig: {e}");
 
-            let resp = Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
-                .with_body_text_plain("Failed to authorize Fanout proxy.\n")
-                .with_cors();
+            let resp = problem::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to authorize Fanout proxy",
+            )
+            .with_cors(&cors)
+            .with_header(REQUEST_ID_HEADER, &request_id);
 
             resp.send_to_client();
 
@@ -105,25 +434,243 @@ pub fn handle_request(
         }
 
         if req.get_method() == Method::POST {
-            mqtttransport::post(&config, auth, storage, req)
+            mqtttransport::post(&config, auth, storage, deadline, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if path == "/bayeux" && config.bayeux_enabled {
+        // long-polling clients in browsers may be cross-origin
+        if req.get_method() == Method::OPTIONS {
+            Response::from_status(StatusCode::OK)
+        } else {
+            let Some(sig) = req.get_header_str("Grip-Sig") else {
+                // handoff if necessary
+                req.handoff_fanout(SELF_BACKEND)?;
+                return Ok(());
+            };
+
+            if let Err(e) = auth.grip.validate_sig(sig) {
+                log_error!("failed to validate Grip-Sig: {e}");
+
+                let resp = problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to authorize Fanout proxy",
+                )
+                .with_cors(&cors)
+                .with_header(REQUEST_ID_HEADER, &request_id);
+
+                resp.send_to_client();
+
+                return Ok(());
+            }
+
+            if req.get_method() == Method::POST {
+                bayeux::post(&config, auth, storage, deadline, req)
+            } else {
+                problem::method_not_allowed("OPTIONS, POST")
+            }
+        }
+    } else if (path == "/socket.io" || path == "/socket.io/") && config.socketio_enabled {
+        // polling clients in browsers may be cross-origin
+        if req.get_method() == Method::OPTIONS {
+            Response::from_status(StatusCode::OK)
         } else {
-            Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
-                .with_header(header::ALLOW, "POST")
-                .with_body_text_plain("Method Not Allowed\n")
+            let Some(sig) = req.get_header_str("Grip-Sig") else {
+                // handoff if necessary
+                req.handoff_fanout(SELF_BACKEND)?;
+                return Ok(());
+            };
+
+            if let Err(e) = auth.grip.validate_sig(sig) {
+                log_error!("failed to validate Grip-Sig: {e}");
+
+                let resp = problem::response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to authorize Fanout proxy",
+                )
+                .with_cors(&cors)
+                .with_header(REQUEST_ID_HEADER, &request_id);
+
+                resp.send_to_client();
+
+                return Ok(());
+            }
+
+            if req.get_method() == Method::GET {
+                socketio::get(storage, req)
+            } else if req.get_method() == Method::POST {
+                socketio::post(&config, auth, storage, deadline, req)
+            } else {
+                problem::method_not_allowed("OPTIONS, GET, POST")
+            }
         }
     } else if path == "/admin/keys" && config.admin_enabled {
+        if req.get_method() == "GET" {
+            admin::get_keys(auth, req)
+        } else if req.get_method() == "POST" {
+            admin::post_keys(&config, auth, storage, req)
+        } else {
+            problem::method_not_allowed("GET, POST")
+        }
+    } else if let Some(key_path) = admin::parse_key_path(path).filter(|_| config.admin_enabled) {
+        match key_path {
+            admin::KeyPath::Key(id) => {
+                if req.get_method() == "DELETE" {
+                    admin::delete_key(&config, auth, storage, &id, req)
+                } else {
+                    problem::method_not_allowed("DELETE")
+                }
+            }
+            admin::KeyPath::Rotate(id) => {
+                if req.get_method() == "POST" {
+                    admin::post_key_rotate(&config, auth, storage, &id, req)
+                } else {
+                    problem::method_not_allowed("POST")
+                }
+            }
+        }
+    } else if path == "/tokens" && config.admin_enabled {
         if req.get_method() == "POST" {
-            admin::post_keys(auth, req)
+            admin::post_tokens(&config, auth, storage, req)
         } else {
-            Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
-                .with_header(header::ALLOW, "POST")
-                .with_body_text_plain("Method Not Allowed\n")
+            problem::method_not_allowed("POST")
+        }
+    } else if path == "/admin/tokens" && config.admin_enabled {
+        if req.get_method() == "POST" {
+            admin::post_admin_tokens(&config, auth, storage, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if path == "/admin/selftest" && config.admin_enabled {
+        if req.get_method() == "POST" {
+            admin::post_selftest(&config, auth, deadline, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if path == "/admin/config" && config.admin_enabled {
+        if req.get_method() == "GET" {
+            admin::get_config(&config, auth, req)
+        } else {
+            problem::method_not_allowed("GET")
+        }
+    } else if path == "/admin/stats" && config.admin_enabled {
+        if req.get_method() == "GET" {
+            admin::get_stats(&config, auth, storage, req)
+        } else {
+            problem::method_not_allowed("GET")
+        }
+    } else if path == "/admin/retained" && config.admin_enabled {
+        if req.get_method() == "GET" {
+            admin::get_retained(auth, storage, req)
+        } else {
+            problem::method_not_allowed("GET")
+        }
+    } else if let Some(topic) = admin::parse_retained_path(path).filter(|_| config.admin_enabled) {
+        if req.get_method() == "DELETE" {
+            admin::delete_retained(&config, auth, storage, &topic, deadline, req)
+        } else {
+            problem::method_not_allowed("DELETE")
+        }
+    } else if path == "/admin/broadcast" && config.admin_enabled {
+        if req.get_method() == "POST" {
+            admin::post_broadcast(&config, auth, storage, deadline, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if let Some(cid) =
+        admin::parse_connection_close_path(path).filter(|_| config.admin_enabled)
+    {
+        if req.get_method() == "POST" {
+            admin::post_connection_close(&config, auth, storage, &cid, deadline, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if path == "/admin/scheduled" && config.admin_enabled {
+        if req.get_method() == "POST" {
+            admin::post_scheduled(&config, auth, storage, deadline, req)
+        } else {
+            problem::method_not_allowed("POST")
+        }
+    } else if let Some(topic) = publickeys::parse_path(path) {
+        if req.get_method() == Method::GET {
+            publickeys::get(&config, storage, &topic, req)
+        } else {
+            problem::method_not_allowed("GET")
         }
     } else {
-        Response::from_status(StatusCode::NOT_FOUND).with_body_text_plain("Not Found\n")
+        problem::response(StatusCode::NOT_FOUND, "Not found")
     };
 
-    resp.with_cors().send_to_client();
+    // held responses are skipped, but long-polls answered right away, such
+    // as with queued Socket.IO packets, are compressed
+    let resp = if gzip { compress::gzip(resp) } else { resp };
+
+    let status = resp.get_status();
+
+    resp.with_cors(&cors)
+        .with_header(REQUEST_ID_HEADER, &request_id)
+        .send_to_client();
+
+    let latency = start.elapsed();
+
+    // counts are added after responding, so that clients don't wait on them
+    let counts = stats::take();
+
+    if let Some(endpoint) = &config.metrics_log_endpoint {
+        let record = metrics::Record {
+            time: storage::unix_now_ms(),
+            request_id: &request_id,
+            route,
+            method: &method,
+            status: status.as_u16(),
+            outcome: metrics::Outcome::from_status(status),
+            latency_ms: latency.as_millis() as u64,
+            counts,
+        };
+
+        metrics::emit(endpoint, &record);
+    }
+
+    let receipts = receipt::take();
+
+    if !receipts.is_empty() {
+        receipt::send(&config, receipts, Deadline::new(RECEIPTS_TIME_BUDGET));
+    }
+
+    let mirrored = mirror::take();
+
+    if let Some(m) = config.mirror.as_ref().filter(|_| !mirrored.is_empty()) {
+        mirror::send(m, mirrored, Deadline::new(MIRROR_TIME_BUDGET));
+    }
+
+    let events = webhook::take();
+
+    if let Some(w) = config
+        .subscription_webhook
+        .as_ref()
+        .filter(|_| !events.is_empty())
+    {
+        webhook::send(w, events);
+    }
+
+    let changes = presence::take();
+
+    if !changes.is_empty() {
+        presence::apply(
+            &config,
+            storage,
+            changes,
+            Deadline::new(PRESENCE_TIME_BUDGET),
+        );
+    }
+
+    if config.stats_enabled && !counts.is_empty() {
+        let deadline = Deadline::new(STATS_TIME_BUDGET);
+
+        if let Err(e) = storage.add_stats(&counts, deadline) {
+            log_error!("failed to add stats: {e:?}");
+        }
+    }
 
     Ok(())
 }
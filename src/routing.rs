@@ -0,0 +1,228 @@
+use crate::config::{Config, RouteAction, RouteRule};
+use crate::log_debug;
+use crate::namespace;
+use crate::topic;
+
+// returns the value of a field in a JSON document, by dotted path
+fn field<'a>(doc: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(doc, |v, name| v.get(name))
+}
+
+// returns the topic a rule routes to, if the rule applies. derived topics
+// are held to the same rules as topics published to directly
+fn apply(rules: &topic::Rules, rule: &RouteRule, doc: &serde_json::Value) -> Option<String> {
+    let v = field(doc, &rule.field)?;
+
+    if let Some(equals) = &rule.equals {
+        if v != equals {
+            return None;
+        }
+    }
+
+    let value = match v {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Number(x) => x.to_string(),
+        serde_json::Value::Bool(b) => b.to_string(),
+        _ => return None,
+    };
+
+    // don't let payloads choose arbitrary places in the hierarchy
+    if value.is_empty() || value.contains(topic::SEPARATOR) {
+        return None;
+    }
+
+    let target = rule.topic.replace("{value}", &value);
+
+    if let Err(e) = topic::validate_publish(rules, &target) {
+        log_debug!("not routing to invalid topic {target}: {e}");
+
+        return None;
+    }
+
+    Some(target)
+}
+
+// returns the topics a message published to a topic should be delivered to,
+// according to the topic's routing rules. derived topics are not routed
+// further. if no rules apply, the result is the topic itself. derived
+// topics are named relative to the publisher's namespace, and a rule is
+// skipped if the publisher couldn't publish to its topic directly
+pub fn route(
+    config: &Config,
+    namespace: Option<&str>,
+    topic: &str,
+    message: &[u8],
+    can_publish: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let rules = config.topic_config(topic).routes;
+
+    if rules.is_empty() {
+        return vec![topic.to_string()];
+    }
+
+    let Ok(doc) = serde_json::from_slice::<serde_json::Value>(message) else {
+        return vec![topic.to_string()];
+    };
+
+    let mut keep_original = true;
+    let mut targets = Vec::new();
+
+    for rule in &rules {
+        let Some(target) = apply(&config.topic_rules, rule, &doc) else {
+            continue;
+        };
+
+        let target = namespace::resolve(namespace, &target);
+
+        if !can_publish(&target) {
+            log_debug!("not routing to topic {target}: not allowed");
+
+            continue;
+        }
+
+        if rule.action == RouteAction::Move {
+            keep_original = false;
+        }
+
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+
+    if keep_original && !targets.iter().any(|t| t == topic) {
+        targets.insert(0, topic.to_string());
+    }
+
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopicConfig;
+
+    fn config_with_routes(topic: &str, routes: serde_json::Value) -> Config {
+        let mut config = Config::default();
+
+        let tc: TopicConfig = serde_json::from_value(serde_json::json!({
+            "routes": routes,
+        }))
+        .unwrap();
+
+        config.topics.insert(topic.to_string(), tc);
+
+        config
+    }
+
+    #[test]
+    fn copy_and_move() {
+        let config = config_with_routes(
+            "orders",
+            serde_json::json!([{"field": "region", "topic": "orders/{value}"}]),
+        );
+
+        let t = route(&config, None, "orders", br#"{"region":"eu"}"#, |_| true);
+        assert_eq!(t, vec!["orders", "orders/eu"]);
+
+        // not json, or field missing
+        assert_eq!(
+            route(&config, None, "orders", b"hello", |_| true),
+            vec!["orders"]
+        );
+        assert_eq!(
+            route(&config, None, "orders", b"{}", |_| true),
+            vec!["orders"]
+        );
+
+        // values can't add levels
+        let t = route(&config, None, "orders", br#"{"region":"eu/x"}"#, |_| true);
+        assert_eq!(t, vec!["orders"]);
+
+        let config = config_with_routes(
+            "orders",
+            serde_json::json!([{
+                "field": "meta.priority",
+                "equals": 1,
+                "topic": "urgent",
+                "action": "move",
+            }]),
+        );
+
+        let t = route(
+            &config,
+            None,
+            "orders",
+            br#"{"meta":{"priority":1}}"#,
+            |_| true,
+        );
+        assert_eq!(t, vec!["urgent"]);
+
+        let t = route(
+            &config,
+            None,
+            "orders",
+            br#"{"meta":{"priority":2}}"#,
+            |_| true,
+        );
+        assert_eq!(t, vec!["orders"]);
+    }
+
+    #[test]
+    fn hostile_values() {
+        let config = config_with_routes(
+            "orders",
+            serde_json::json!([{"field": "to", "topic": "{value}"}]),
+        );
+
+        let t = route(&config, None, "orders", br#"{"to":"invoices"}"#, |_| true);
+        assert_eq!(t, vec!["orders", "invoices"]);
+
+        // reserved prefixes, characters that would split channel lists, and
+        // control characters
+        for to in ["$SYS", "a,b", "a;b", "a\\nb", "a\\u0000b"] {
+            let doc = format!(r#"{{"to":"{to}"}}"#);
+
+            let t = route(&config, None, "orders", doc.as_bytes(), |_| true);
+            assert_eq!(t, vec!["orders"], "{to}");
+        }
+
+        // too long
+        let doc = format!(
+            r#"{{"to":"{}"}}"#,
+            "a".repeat(config.topic_rules.length_max + 1)
+        );
+        assert_eq!(
+            route(&config, None, "orders", doc.as_bytes(), |_| true),
+            vec!["orders"]
+        );
+    }
+
+    #[test]
+    fn namespaced_and_allowed() {
+        let config = config_with_routes(
+            "acme/orders",
+            serde_json::json!([{"field": "region", "topic": "orders/{value}", "action": "move"}]),
+        );
+
+        // derived topics are relative to the publisher's namespace
+        let t = route(
+            &config,
+            Some("acme"),
+            "acme/orders",
+            br#"{"region":"eu"}"#,
+            |_| true,
+        );
+        assert_eq!(t, vec!["acme/orders/eu"]);
+
+        // topics the publisher can't publish to aren't routed to, and don't
+        // move the message away
+        let t = route(
+            &config,
+            Some("acme"),
+            "acme/orders",
+            br#"{"region":"eu"}"#,
+            |t| t == "acme/orders",
+        );
+        assert_eq!(t, vec!["acme/orders"]);
+    }
+}
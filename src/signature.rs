@@ -0,0 +1,157 @@
+use crate::config::Config;
+use crate::meta::MessageMeta;
+use crate::storage::{Storage, StorageError};
+use base64::Engine;
+use serde::Deserialize;
+use thiserror::Error;
+
+// publishers sign messages with this metadata entry, sent as the
+// Pubsub-Meta-Signature header or a "signature" MQTT user property. it is
+// passed on to subscribers like any other metadata, so that they can verify
+// the message too. the value is "{KEY_ID}:{SIGNATURE}", where the signature
+// is the base64url-encoded Ed25519 signature of the message
+pub const META_NAME: &str = "signature";
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("message signature required")]
+    Missing,
+
+    #[error("invalid signature format")]
+    Malformed,
+
+    #[error("no public keys for topic")]
+    NoKeys,
+
+    #[error("unknown signing key: {0}")]
+    UnknownKey(String),
+
+    #[error("signature does not match message")]
+    Mismatch,
+
+    #[error("failed to read public keys: {0:?}")]
+    Storage(StorageError),
+}
+
+// the parts of a JWK set that are needed. keys of other types are ignored
+#[derive(Deserialize)]
+struct KeySet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+
+    #[serde(default)]
+    crv: Option<String>,
+
+    #[serde(default)]
+    kid: Option<String>,
+
+    #[serde(default)]
+    x: Option<String>,
+}
+
+fn decode(s: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .ok()
+}
+
+// the ed25519 public key with the given ID, from a topic's keys document
+fn find_key(doc: &[u8], key_id: &str) -> Option<Vec<u8>> {
+    let set: KeySet = serde_json::from_slice(doc).ok()?;
+
+    let jwk = set.keys.into_iter().find(|k| {
+        k.kty == "OKP" && k.crv.as_deref() == Some("Ed25519") && k.kid.as_deref() == Some(key_id)
+    })?;
+
+    decode(jwk.x.as_deref()?)
+}
+
+// verifies a message against the public keys document of its topic, as
+// served by /topics/{topic}/public-keys
+pub fn verify_with(doc: &[u8], message: &[u8], value: &str) -> Result<(), SignatureError> {
+    let Some((key_id, sig)) = value.rsplit_once(':') else {
+        return Err(SignatureError::Malformed);
+    };
+
+    let sig = decode(sig)
+        .and_then(|s| ed25519_compact::Signature::from_slice(&s).ok())
+        .ok_or(SignatureError::Malformed)?;
+
+    let key = find_key(doc, key_id)
+        .and_then(|k| ed25519_compact::PublicKey::from_slice(&k).ok())
+        .ok_or_else(|| SignatureError::UnknownKey(key_id.to_string()))?;
+
+    key.verify(message, &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+// checks the signature of a message published by a client, if the topic's
+// settings require one
+pub fn check(
+    config: &Config,
+    storage: &dyn Storage,
+    topic: &str,
+    message: &[u8],
+    meta: &MessageMeta,
+) -> Result<(), SignatureError> {
+    if !config.topic_config(topic).require_signature {
+        return Ok(());
+    }
+
+    let value = meta
+        .user
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(META_NAME))
+        .map(|(_, v)| v)
+        .ok_or(SignatureError::Missing)?;
+
+    let doc = match storage.read_public_keys(topic) {
+        Ok(Some(doc)) => doc,
+        Ok(None) | Err(StorageError::StoreNotFound) => return Err(SignatureError::NoKeys),
+        Err(e) => return Err(SignatureError::Storage(e)),
+    };
+
+    verify_with(&doc, message, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    #[test]
+    fn verify() {
+        let kp = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([7; 32]));
+
+        let doc = serde_json::json!({"keys": [
+            {"kty": "RSA", "kid": "k1", "n": "AQAB", "e": "AQAB"},
+            {"kty": "OKP", "crv": "Ed25519", "kid": "k1", "x": URL_SAFE_NO_PAD.encode(*kp.pk)},
+        ]});
+        let doc = serde_json::to_vec(&doc).unwrap();
+
+        let sig = URL_SAFE_NO_PAD.encode(*kp.sk.sign(b"open valve", None));
+
+        assert!(verify_with(&doc, b"open valve", &format!("k1:{sig}")).is_ok());
+
+        assert!(matches!(
+            verify_with(&doc, b"close valve", &format!("k1:{sig}")),
+            Err(SignatureError::Mismatch)
+        ));
+        assert!(matches!(
+            verify_with(&doc, b"open valve", &format!("k2:{sig}")),
+            Err(SignatureError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            verify_with(&doc, b"open valve", &sig),
+            Err(SignatureError::Malformed)
+        ));
+        assert!(matches!(
+            verify_with(&doc, b"open valve", "k1:AAAA"),
+            Err(SignatureError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,1091 @@
+use crate::auth::{Authorization, AuthorizationError, Capabilities};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::events::get_token;
+use crate::geo::{self, Location};
+use crate::grip::ControlMessage;
+use crate::meta::MessageMeta;
+use crate::problem;
+use crate::publish::{
+    check_line_lengths, publish_hint, read_body, Batch, MESSAGE_SIZE_MAX,
+    TRANSPORT_REQUEST_SIZE_MAX,
+};
+use crate::routing;
+use crate::storage::{Storage, StorageError};
+use crate::topic;
+use crate::websocket::{parse_websocket_event, write_websocket_event, WsEvent};
+use crate::{log_debug, log_error, log_warn};
+use fastly::http::{header, HeaderValue, StatusCode};
+use fastly::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+// rooms are delivered to socket.io clients on fanout channels with this
+// prefix. polling requests also hold on a channel of their session
+pub const CHANNEL_PREFIX: &str = "io:";
+const SESSION_CHANNEL_PREFIX: &str = "ios:";
+
+// engine.io protocol version
+const EIO_VERSION: &str = "4";
+
+// the server pings clients this often. polls are held for the same time,
+// and answered with a ping if nothing else comes up
+const PING_INTERVAL: u32 = 25;
+const PING_TIMEOUT: u32 = 20;
+
+// packets in a polling payload are separated by this
+const RECORD_SEPARATOR: char = '\x1e';
+
+// websocket-over-http messages must be prefixed
+const MESSAGE_PREFIX: &str = "m:";
+
+// event a room's messages are emitted as, with the room and data as args
+const MESSAGE_EVENT: &str = "message";
+
+// websocket sessions keep this in fanout's meta state, and polling sessions
+// in storage
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    sid: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+
+    // whether the client has connected to the default namespace
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    connected: bool,
+
+    // full topic names
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rooms: Vec<String>,
+
+    // packets waiting for the next poll
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    outbox: Vec<String>,
+
+    // set once a polling session moves to websocket
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    upgraded: bool,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    closed: bool,
+}
+
+struct Context<'a> {
+    config: &'a Config,
+    auth: &'a Authorization,
+    storage: &'a dyn Storage,
+    deadline: Deadline,
+
+    // from the request's Authorization header or auth parameter, if any
+    header_token: Option<&'a str>,
+
+    // where the client is, if known. see geo.rs
+    location: Option<Location>,
+
+    state: State,
+    changed: bool,
+    out: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct SocketPacket<'a> {
+    ptype: u8,
+    namespace: &'a str,
+    ack_id: Option<u64>,
+    data: Option<Value>,
+}
+
+// packets are of the form {type}[{namespace},][{ack id}][{json}]
+fn parse_socket_packet(s: &str) -> Option<SocketPacket<'_>> {
+    let ptype = s.bytes().next().filter(u8::is_ascii_digit)? - b'0';
+    let mut rest = &s[1..];
+
+    let mut namespace = "/";
+
+    if rest.starts_with('/') {
+        let end = rest.find(',').unwrap_or(rest.len());
+        namespace = &rest[..end];
+        rest = rest.get((end + 1)..).unwrap_or("");
+    }
+
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+
+    let ack_id = if digits > 0 {
+        Some(rest[..digits].parse().ok()?)
+    } else {
+        None
+    };
+
+    rest = &rest[digits..];
+
+    let data = if rest.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_str(rest).ok()?)
+    };
+
+    Some(SocketPacket {
+        ptype,
+        namespace,
+        ack_id,
+        data,
+    })
+}
+
+fn open_packet(sid: &str, upgrades: &[&str]) -> String {
+    let open = json!({
+        "sid": sid,
+        "upgrades": upgrades,
+        "pingInterval": PING_INTERVAL * 1000,
+        "pingTimeout": PING_TIMEOUT * 1000,
+        "maxPayload": MESSAGE_SIZE_MAX,
+    });
+
+    format!("0{open}")
+}
+
+// the token may be given when connecting, or with each request
+fn caps(ctx: &Context) -> Result<Capabilities, String> {
+    let Some(token) = ctx.state.token.as_deref().or(ctx.header_token) else {
+        return Err("Missing token".to_string());
+    };
+
+    match ctx.auth.validate_token(token) {
+        Ok(caps) => Ok(caps),
+        Err(AuthorizationError::Token(_)) => Err("Invalid token".to_string()),
+        Err(e) => {
+            log_error!("auth failed: {e:?}");
+
+            Err("Auth process failed".to_string())
+        }
+    }
+}
+
+// rooms are topics. wildcards aren't supported
+fn room_topic(rules: &topic::Rules, caps: &Capabilities, room: &Value) -> Result<String, String> {
+    let Some(room) = room.as_str() else {
+        return Err("Room must be a string".to_string());
+    };
+
+    let Ok(levels) = topic::parse(room) else {
+        return Err(format!("Invalid room: {room}"));
+    };
+
+    if levels.contains(&topic::WILDCARD) {
+        return Err("Wildcard rooms not supported".to_string());
+    }
+
+    if let Err(e) = topic::validate(rules, room) {
+        return Err(format!("Invalid room {room}: {e}"));
+    }
+
+    Ok(caps.resolve_topic(room))
+}
+
+// a room or an array of rooms
+fn room_list(v: Option<&Value>) -> Vec<&Value> {
+    match v {
+        Some(Value::Array(rooms)) => rooms.iter().collect(),
+        Some(v) => vec![v],
+        None => vec![],
+    }
+}
+
+fn subscribe(ctx: &mut Context, args: &[Value]) -> Result<(), String> {
+    let caps = caps(ctx)?;
+
+    let mut topics = Vec::new();
+
+    for room in room_list(args.first()) {
+        let topic = room_topic(&ctx.config.topic_rules, &caps, room)?;
+
+        if !caps.can_subscribe(&topic)
+            || !geo::can_subscribe(ctx.config, &topic, ctx.location.as_ref())
+        {
+            return Err(format!(
+                "Not allowed to join room: {}",
+                room.as_str().unwrap_or("")
+            ));
+        }
+
+        topics.push(topic);
+    }
+
+    if topics.is_empty() {
+        return Err("Missing room".to_string());
+    }
+
+    let count = topics
+        .iter()
+        .filter(|t| !ctx.state.rooms.contains(t))
+        .count();
+
+    if let Some(max) = ctx.config.subscriptions_max(caps.max_subs()) {
+        if ctx.state.rooms.len() + count > max {
+            return Err("Too many rooms".to_string());
+        }
+    }
+
+    for topic in topics {
+        if !ctx.state.rooms.contains(&topic) {
+            ctx.state.rooms.push(topic);
+            ctx.changed = true;
+        }
+    }
+
+    Ok(())
+}
+
+fn unsubscribe(ctx: &mut Context, args: &[Value]) -> Result<(), String> {
+    // the namespace is needed to know the full names
+    let caps = caps(ctx)?;
+
+    for room in room_list(args.first()) {
+        let topic = room_topic(&ctx.config.topic_rules, &caps, room)?;
+
+        let before = ctx.state.rooms.len();
+        ctx.state.rooms.retain(|t| *t != topic);
+
+        if ctx.state.rooms.len() != before {
+            ctx.changed = true;
+        }
+    }
+
+    Ok(())
+}
+
+fn publish_message(ctx: &mut Context, args: &[Value]) -> Result<(), String> {
+    let caps = caps(ctx)?;
+
+    let (Some(room), Some(data)) = (args.first(), args.get(1)) else {
+        return Err("Expected a room and data".to_string());
+    };
+
+    let topic = room_topic(&ctx.config.topic_rules, &caps, room)?;
+
+    if let Err(e) = topic::validate_publish(&ctx.config.topic_rules, room.as_str().unwrap_or("")) {
+        return Err(format!("Not allowed to publish to room: {e}"));
+    }
+
+    if !caps.can_publish(&topic) || !geo::can_publish(ctx.config, &topic, ctx.location.as_ref()) {
+        return Err(format!(
+            "Not allowed to publish to room: {}",
+            room.as_str().unwrap_or("")
+        ));
+    }
+
+    // socket.io messages can't carry a signature
+    if ctx.config.topic_config(&topic).require_signature {
+        return Err("Room requires signed messages".to_string());
+    }
+
+    // strings are published as they are, and anything else as JSON
+    let (message, content_type) = match data {
+        Value::String(s) => (s.clone().into_bytes(), "text/plain"),
+        v => (v.to_string().into_bytes(), "application/json"),
+    };
+
+    if message.len() > MESSAGE_SIZE_MAX {
+        return Err(format!("Message exceeds {MESSAGE_SIZE_MAX} bytes maximum"));
+    }
+
+    if let Some((key, limit)) = caps.publish_limit() {
+        match ctx.storage.count_publishes(key, 1, limit, ctx.deadline) {
+            Ok(()) | Err(StorageError::StoreNotFound) => {}
+            Err(StorageError::LimitReached) => {
+                return Err(format!("Token is limited to {limit} publishes per minute"))
+            }
+            Err(e) => log_error!("failed to count publishes: {e:?}"),
+        }
+    }
+
+    let targets = routing::route(ctx.config, caps.namespace(), &topic, &message, |t| {
+        caps.can_publish(t) && geo::can_publish(ctx.config, t, ctx.location.as_ref())
+    });
+
+    for target in &targets {
+        if !check_line_lengths(ctx.config, target, &message) {
+            return Err("Message has lines that are too long".to_string());
+        }
+    }
+
+    if ctx.config.publish_token.is_empty() {
+        log_warn!("publishing not configured, dropping socket.io message");
+
+        return Err("Publishing not configured".to_string());
+    }
+
+    let mut meta = MessageMeta {
+        content_type: Some(content_type.to_string()),
+        ..Default::default()
+    };
+
+    meta.start_span(&topic);
+
+    let mut batch = Batch::default();
+
+    for target in targets {
+        if let Err(e) = batch.add(
+            ctx.config,
+            &target,
+            caps.namespace(),
+            &message,
+            &meta,
+            None,
+            Some(&ctx.state.sid),
+        ) {
+            log_error!("failed to publish: {e:?}");
+
+            return Err("Failed to publish".to_string());
+        }
+    }
+
+    if let Err(e) = batch.send(ctx.config, ctx.deadline) {
+        log_error!("failed to publish: {e:?}");
+
+        return Err("Failed to publish".to_string());
+    }
+
+    Ok(())
+}
+
+// handles a socket.io packet, carried in an engine.io message packet
+fn handle_socket_packet(ctx: &mut Context, s: &str) {
+    let Some(p) = parse_socket_packet(s) else {
+        log_debug!("invalid socket.io packet");
+        return;
+    };
+
+    // only the default namespace is supported
+    if p.namespace != "/" {
+        if p.ptype == 0 {
+            let error = json!({"message": "Invalid namespace"});
+            ctx.out.push(format!("44{},{error}", p.namespace));
+        }
+
+        return;
+    }
+
+    match p.ptype {
+        // connect
+        0 => {
+            let token = p
+                .data
+                .as_ref()
+                .and_then(|v| v.get("token"))
+                .and_then(|v| v.as_str());
+
+            if let Some(token) = token {
+                ctx.state.token = Some(token.to_string());
+            }
+
+            if let Err(e) = caps(ctx) {
+                ctx.state.token = None;
+                ctx.out.push(format!("44{}", json!({ "message": e })));
+
+                return;
+            }
+
+            ctx.state.connected = true;
+            ctx.changed = true;
+
+            ctx.out
+                .push(format!("40{}", json!({ "sid": ctx.state.sid })));
+        }
+        // disconnect
+        1 => {
+            ctx.state.connected = false;
+            ctx.state.token = None;
+            ctx.state.rooms.clear();
+            ctx.changed = true;
+        }
+        // event
+        2 if ctx.state.connected => {
+            let args = match p.data {
+                Some(Value::Array(args)) => args,
+                _ => return,
+            };
+
+            let Some(name) = args.first().and_then(|v| v.as_str()) else {
+                return;
+            };
+
+            let ret = match name {
+                "subscribe" => subscribe(ctx, &args[1..]),
+                "unsubscribe" => unsubscribe(ctx, &args[1..]),
+                "publish" => publish_message(ctx, &args[1..]),
+                name => Err(format!("Unknown event: {name}")),
+            };
+
+            // acks are error-first, as with node callbacks
+            if let Some(id) = p.ack_id {
+                let args = match ret {
+                    Ok(()) => json!([null]),
+                    Err(e) => json!([e]),
+                };
+
+                ctx.out.push(format!("43{id}{args}"));
+            } else if let Err(e) = ret {
+                log_debug!("socket.io event failed: {e}");
+            }
+        }
+        // acks from the client, and binary packets, aren't supported
+        _ => {}
+    }
+}
+
+// handles an engine.io packet. returns false if the session should close
+fn handle_packet(ctx: &mut Context, s: &str) -> bool {
+    let Some(ptype) = s.chars().next() else {
+        return true;
+    };
+
+    let data = &s[1..];
+
+    match ptype {
+        // close
+        '1' => {
+            ctx.state.closed = true;
+            ctx.state.rooms.clear();
+            ctx.changed = true;
+
+            return false;
+        }
+        // ping, which clients only send to probe an upgrade
+        '2' => ctx.out.push(format!("3{data}")),
+        // message
+        '4' => handle_socket_packet(ctx, data),
+        // upgrade
+        '5' => {
+            ctx.state.upgraded = true;
+            ctx.changed = true;
+        }
+        // pong and noop
+        '3' | '6' => {}
+        _ => return false,
+    }
+
+    true
+}
+
+// socket.io messages carry JSON, which may be a string. binary messages
+// would need attachments, and aren't delivered
+fn message_data(message: &[u8], meta: &MessageMeta) -> Option<Value> {
+    let json = match &meta.content_type {
+        Some(t) => {
+            let t = t.split(';').next().unwrap_or("").trim();
+
+            t == "application/json" || t.ends_with("+json")
+        }
+        None => false,
+    };
+
+    if json {
+        if let Ok(v) = serde_json::from_slice(message) {
+            return Some(v);
+        }
+    }
+
+    std::str::from_utf8(message).ok().map(Value::from)
+}
+
+// the item that delivers a message to the room's clients, as an event
+// packet. name is the topic relative to the publisher's namespace
+pub fn publish_item(topic: &str, name: &str, message: &[u8], meta: &MessageMeta) -> Option<Value> {
+    let data = message_data(message, meta)?;
+
+    let packet = format!("42{}", json!([MESSAGE_EVENT, name, data]));
+
+    Some(json!({
+        "channel": format!("{CHANNEL_PREFIX}{topic}"),
+        "formats": {
+            "http-response": {
+                "headers": {"Content-Type": "text/plain; charset=UTF-8"},
+                "body": packet,
+            },
+            "ws-message": {
+                "content": packet,
+            },
+        },
+    }))
+}
+
+fn bad_request(message: &str) -> Response {
+    // engine.io errors are JSON objects with a code
+    let code = match message {
+        "Transport unknown" => 0,
+        "Session ID unknown" => 1,
+        "Unsupported protocol version" => 5,
+        _ => 3,
+    };
+
+    Response::from_status(StatusCode::BAD_REQUEST)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_body(json!({"code": code, "message": message}).to_string())
+}
+
+fn text_response(body: String) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "text/plain; charset=UTF-8")
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body(body)
+}
+
+fn storage_error() -> Response {
+    Response::from_status(StatusCode::INTERNAL_SERVER_ERROR).with_body_text_plain("Storage error\n")
+}
+
+// storage errors are logged here, for callers to respond with
+// storage_error()
+fn read_session(storage: &dyn Storage, sid: &str) -> Result<Option<State>, StorageError> {
+    match storage.read_session(sid) {
+        Ok(Some(v)) => match serde_json::from_slice::<State>(&v) {
+            Ok(state) if !state.closed => Ok(Some(state)),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                log_error!("failed to parse session: {e}");
+
+                Ok(None)
+            }
+        },
+        Ok(None) => Ok(None),
+        Err(e) => {
+            log_error!("failed to read session: {e:?}");
+
+            Err(e)
+        }
+    }
+}
+
+fn write_session(storage: &dyn Storage, state: &State) -> Result<(), StorageError> {
+    if let Err(e) = storage.write_session(&state.sid, &serde_json::to_vec(state).unwrap()) {
+        log_error!("failed to write session: {e:?}");
+
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// polls are answered right away if packets are waiting, and held otherwise
+fn poll(storage: &dyn Storage, sid: Option<&str>) -> Response {
+    let Some(sid) = sid else {
+        let state = State {
+            sid: hex::encode(rand::random::<[u8; 16]>()),
+            ..Default::default()
+        };
+
+        if write_session(storage, &state).is_err() {
+            return storage_error();
+        }
+
+        return text_response(open_packet(&state.sid, &["websocket"]));
+    };
+
+    let mut state = match read_session(storage, sid) {
+        Ok(Some(state)) => state,
+        Ok(None) => return bad_request("Session ID unknown"),
+        Err(_) => return storage_error(),
+    };
+
+    // the client stops polling once upgraded
+    if state.upgraded {
+        return text_response("6".to_string());
+    }
+
+    if !state.outbox.is_empty() {
+        let body = state.outbox.join(&RECORD_SEPARATOR.to_string());
+
+        state.outbox.clear();
+
+        if write_session(storage, &state).is_err() {
+            return storage_error();
+        }
+
+        return text_response(body);
+    }
+
+    log_debug!({ sid = sid }, "holding poll");
+
+    let mut resp = text_response("2".to_string())
+        .with_header("Grip-Hold", "response")
+        .with_header("Grip-Timeout", PING_INTERVAL.to_string());
+
+    resp.append_header("Grip-Channel", format!("{SESSION_CHANNEL_PREFIX}{sid}"));
+
+    if state.connected {
+        for topic in &state.rooms {
+            resp.append_header("Grip-Channel", format!("{CHANNEL_PREFIX}{topic}"));
+        }
+    }
+
+    resp
+}
+
+// packets sent by polling clients are answered on the next poll
+fn send(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: &Request,
+    sid: &str,
+    body: Vec<u8>,
+) -> Response {
+    let state = match read_session(storage, sid) {
+        Ok(Some(state)) => state,
+        Ok(None) => return bad_request("Session ID unknown"),
+        Err(_) => return storage_error(),
+    };
+
+    let Ok(body) = String::from_utf8(body) else {
+        return bad_request("Bad request");
+    };
+
+    let header_token = match get_token(req, true) {
+        Ok(v) => v,
+        Err(e) => return bad_request(&e),
+    };
+
+    let mut ctx = Context {
+        config,
+        auth,
+        storage,
+        deadline,
+        header_token,
+        location: geo::location(req),
+        state,
+        changed: false,
+        out: Vec::new(),
+    };
+
+    for packet in body.split(RECORD_SEPARATOR) {
+        if !handle_packet(&mut ctx, packet) {
+            break;
+        }
+    }
+
+    if !ctx.out.is_empty() {
+        ctx.state.outbox.append(&mut ctx.out);
+        ctx.changed = true;
+    }
+
+    if ctx.changed {
+        if write_session(storage, &ctx.state).is_err() {
+            return storage_error();
+        }
+
+        // have the held poll come back for the packets or the new rooms
+        if !config.publish_token.is_empty() {
+            let channel = format!("{SESSION_CHANNEL_PREFIX}{sid}");
+
+            if let Err(e) = publish_hint(config, &channel, deadline) {
+                log_error!("failed to publish hint: {e:?}");
+            }
+        }
+    }
+
+    text_response("ok".to_string())
+}
+
+fn handle_websocket_events(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    req: Request,
+    body: Vec<u8>,
+) -> Response {
+    let grip_offered = req
+        .get_header_str("Sec-WebSocket-Extensions")
+        .is_some_and(|s| s.contains("grip"));
+
+    let Some(cid) = req.get_header_str("Connection-Id") else {
+        return bad_request("Missing Connection-Id header");
+    };
+
+    let mut state: Option<State> = None;
+
+    if let Some(v) = req.get_header("Meta-State") {
+        match serde_json::from_slice::<State>(v.as_bytes()) {
+            Ok(v) => state = Some(v),
+            Err(e) => {
+                log_error!("failed to parse state: {e}");
+                return bad_request("Invalid header");
+            }
+        }
+    }
+
+    let mut events = Vec::new();
+    let mut pos = 0;
+
+    while pos < body.len() {
+        match parse_websocket_event(&body[pos..]) {
+            Ok((e, size)) => {
+                events.push(e);
+                pos += size;
+            }
+            Err(_) => return bad_request("Failed to parse WebSocket events"),
+        }
+    }
+
+    let header_token = match get_token(&req, true) {
+        Ok(v) => v,
+        Err(e) => return bad_request(&e),
+    };
+
+    // the rooms fanout has subscribed the connection to
+    let connected_rooms: HashSet<String> = match &state {
+        Some(state) if state.connected => state.rooms.iter().cloned().collect(),
+        _ => HashSet::new(),
+    };
+
+    let mut out = Vec::new();
+    let mut opening = false;
+    let mut probing = false;
+
+    // a new connection either continues a polling session, to which it is
+    // probing an upgrade, or starts a session of its own
+    let state = match state {
+        Some(state) => state,
+        None => match req.get_query_parameter("sid") {
+            Some(sid) => match read_session(storage, sid) {
+                Ok(Some(state)) => {
+                    probing = true;
+
+                    state
+                }
+                Ok(None) => return bad_request("Session ID unknown"),
+                Err(_) => return storage_error(),
+            },
+            None => State {
+                sid: cid.to_string(),
+                ..Default::default()
+            },
+        },
+    };
+
+    let mut ctx = Context {
+        config,
+        auth,
+        storage,
+        deadline,
+        header_token,
+        location: geo::location(&req),
+        state,
+        changed: false,
+        out: Vec::new(),
+    };
+
+    let mut close = false;
+
+    for WsEvent { etype, content } in events {
+        log_debug!({cid = cid, event = etype, size = content.len()}, "websocket event");
+
+        match etype.as_str() {
+            "OPEN" => {
+                opening = true;
+
+                // ack
+                write_websocket_event(&mut out, &etype, &content).unwrap();
+
+                if !probing {
+                    ctx.out.push(open_packet(&ctx.state.sid, &[]));
+                }
+            }
+            "CLOSE" => write_websocket_event(&mut out, &etype, &content).unwrap(), // ack
+            "TEXT" => {
+                let Ok(packet) = String::from_utf8(content) else {
+                    close = true;
+                    break;
+                };
+
+                let was_upgraded = ctx.state.upgraded;
+
+                if !handle_packet(&mut ctx, &packet) {
+                    close = true;
+                }
+
+                // packets waiting for the polling transport are sent here
+                // instead, and the pending poll is told to finish
+                if probing && ctx.state.upgraded && !was_upgraded {
+                    let mut outbox = std::mem::take(&mut ctx.state.outbox);
+                    outbox.append(&mut ctx.out);
+                    ctx.out = outbox;
+
+                    if write_session(storage, &ctx.state).is_err() {
+                        return storage_error();
+                    }
+
+                    if !config.publish_token.is_empty() {
+                        let channel = format!("{SESSION_CHANNEL_PREFIX}{}", ctx.state.sid);
+
+                        if let Err(e) = publish_hint(config, &channel, deadline) {
+                            log_error!("failed to publish hint: {e:?}");
+                        }
+                    }
+                }
+
+                if close {
+                    break;
+                }
+            }
+            _ => {} // unsupported event type, ignore
+        }
+    }
+
+    for packet in &ctx.out {
+        let content = format!("{MESSAGE_PREFIX}{packet}");
+
+        write_websocket_event(&mut out, "TEXT", content.as_bytes()).unwrap();
+    }
+
+    let mut cmsgs = Vec::new();
+
+    if opening {
+        // engine.io clients expect pings from the server
+        cmsgs.push(ControlMessage {
+            ctype: "keep-alive".to_string(),
+            content: Some("2".to_string()),
+            timeout: Some(PING_INTERVAL),
+            mode: Some("interval".to_string()),
+            ..Default::default()
+        });
+    }
+
+    let rooms: HashSet<String> = if ctx.state.connected {
+        ctx.state.rooms.iter().cloned().collect()
+    } else {
+        HashSet::new()
+    };
+
+    for topic in rooms.difference(&connected_rooms) {
+        cmsgs.push(ControlMessage {
+            ctype: "subscribe".to_string(),
+            channel: Some(format!("{CHANNEL_PREFIX}{topic}")),
+            ..Default::default()
+        });
+    }
+
+    for topic in connected_rooms.difference(&rooms) {
+        cmsgs.push(ControlMessage {
+            ctype: "unsubscribe".to_string(),
+            channel: Some(format!("{CHANNEL_PREFIX}{topic}")),
+            ..Default::default()
+        });
+    }
+
+    for cmsg in cmsgs {
+        let content = format!("c:{}", serde_json::to_string(&cmsg).unwrap());
+
+        write_websocket_event(&mut out, "TEXT", content.as_bytes()).unwrap();
+    }
+
+    if close {
+        let code: u16 = 1000;
+
+        write_websocket_event(&mut out, "CLOSE", &code.to_be_bytes()).unwrap();
+    }
+
+    let mut resp = Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/websocket-events")
+        .with_body(Body::from(out));
+
+    if opening && grip_offered {
+        resp.append_header("Sec-WebSocket-Extensions", "grip");
+    }
+
+    resp.append_header("Set-Meta-State", serde_json::to_string(&ctx.state).unwrap());
+    resp.append_header("Keep-Alive-Interval", "120");
+
+    resp
+}
+
+pub fn get(storage: &dyn Storage, req: Request) -> Response {
+    if req.get_query_parameter("EIO") != Some(EIO_VERSION) {
+        return bad_request("Unsupported protocol version");
+    }
+
+    match req.get_query_parameter("transport") {
+        Some("polling") => poll(storage, req.get_query_parameter("sid")),
+        _ => bad_request("Transport unknown"),
+    }
+}
+
+// polling clients send packets, and websocket clients arrive as
+// websocket-over-http events
+pub fn post(
+    config: &Config,
+    auth: &Authorization,
+    storage: &dyn Storage,
+    deadline: Deadline,
+    mut req: Request,
+) -> Response {
+    let body = match read_body(&mut req, TRANSPORT_REQUEST_SIZE_MAX) {
+        Ok(body) => body,
+        Err(e) => return problem::body_error(&e, TRANSPORT_REQUEST_SIZE_MAX),
+    };
+
+    if req.get_header(header::CONTENT_TYPE)
+        == Some(&HeaderValue::from_static("application/websocket-events"))
+    {
+        return handle_websocket_events(config, auth, storage, deadline, req, body);
+    }
+
+    if req.get_query_parameter("EIO") != Some(EIO_VERSION) {
+        return bad_request("Unsupported protocol version");
+    }
+
+    match (
+        req.get_query_parameter("transport"),
+        req.get_query_parameter("sid"),
+    ) {
+        (Some("polling"), Some(sid)) => send(config, auth, storage, deadline, &req, sid, body),
+        (Some("polling"), None) => bad_request("Session ID unknown"),
+        _ => bad_request("Transport unknown"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_token, TestAppTokenAuthorizor, TestGripAuthorizor, TokenGrants};
+    use crate::compress;
+    use crate::kv::FastlyKv;
+    use crate::memorykv::MemoryKv;
+    use crate::storage::KvStorage;
+    use jwt_simple::prelude::Duration;
+
+    #[test]
+    fn socket_packets() {
+        assert_eq!(
+            parse_socket_packet("0"),
+            Some(SocketPacket {
+                ptype: 0,
+                namespace: "/",
+                ack_id: None,
+                data: None,
+            })
+        );
+
+        let p = parse_socket_packet(r#"212["subscribe","chat"]"#).unwrap();
+        assert_eq!(p.ptype, 2);
+        assert_eq!(p.ack_id, Some(12));
+        assert_eq!(p.data, Some(json!(["subscribe", "chat"])));
+
+        let p = parse_socket_packet(r#"0/admin,{"token":"t"}"#).unwrap();
+        assert_eq!(p.namespace, "/admin");
+        assert_eq!(p.data, Some(json!({"token": "t"})));
+
+        assert!(parse_socket_packet(r#"2["x""#).is_none());
+        assert!(parse_socket_packet("x").is_none());
+
+        let config = Config::default();
+        let auth = Authorization {
+            grip: Box::new(TestGripAuthorizor),
+            fastly: false,
+            app_token: Box::new(TestAppTokenAuthorizor),
+            token_validation: Default::default(),
+            client_cert: None,
+            namespace: None,
+        };
+
+        // not used unless publishing
+        let storage = KvStorage::new(Box::new(FastlyKv::new("messages")));
+
+        let grants = TokenGrants {
+            subject: None,
+            read: vec!["chat".to_string()],
+            write: Vec::new(),
+            subtree: false,
+        };
+
+        let token = create_token("k1", b"notasecret", &grants, Duration::from_secs(60)).unwrap();
+
+        let mut ctx = Context {
+            config: &config,
+            auth: &auth,
+            storage: &storage,
+            deadline: Deadline::none(),
+            header_token: None,
+            location: None,
+            state: State {
+                sid: "s1".to_string(),
+                ..Default::default()
+            },
+            changed: false,
+            out: Vec::new(),
+        };
+
+        // events before connecting are ignored
+        assert!(handle_packet(&mut ctx, r#"421["subscribe","chat"]"#));
+        assert!(ctx.out.is_empty());
+
+        assert!(handle_packet(&mut ctx, "40"));
+        assert_eq!(ctx.out, [r#"44{"message":"Missing token"}"#]);
+        ctx.out.clear();
+
+        assert!(handle_packet(
+            &mut ctx,
+            &format!(r#"40{{"token":"{token}"}}"#)
+        ));
+        assert_eq!(ctx.out, [r#"40{"sid":"s1"}"#]);
+        ctx.out.clear();
+
+        assert!(handle_packet(&mut ctx, r#"421["subscribe","chat"]"#));
+        assert!(handle_packet(&mut ctx, r#"422["subscribe","other"]"#));
+        assert!(handle_packet(&mut ctx, r#"423["publish","chat","hi"]"#));
+        assert_eq!(
+            ctx.out,
+            [
+                "431[null]",
+                r#"432["Not allowed to join room: other"]"#,
+                r#"433["Not allowed to publish to room: chat"]"#,
+            ]
+        );
+        assert_eq!(ctx.state.rooms, ["chat"]);
+
+        assert!(handle_packet(&mut ctx, "2probe"));
+        assert_eq!(ctx.out.last().unwrap(), "3probe");
+
+        assert!(!handle_packet(&mut ctx, "1"));
+        assert!(ctx.state.closed);
+
+        let item = publish_item("chat", "chat", b"hi", &MessageMeta::default()).unwrap();
+        assert_eq!(item["channel"], "io:chat");
+        assert_eq!(
+            item["formats"]["ws-message"]["content"],
+            r#"42["message","chat","hi"]"#
+        );
+    }
+
+    #[test]
+    fn poll_compressed() {
+        let storage = KvStorage::new(Box::new(MemoryKv::new()));
+
+        let packet = r#"42["message","chat","hello world"]"#;
+
+        let state = State {
+            sid: "s1".to_string(),
+            connected: true,
+            outbox: vec![packet.to_string(); 100],
+            ..Default::default()
+        };
+        write_session(&storage, &state).unwrap();
+
+        // queued packets are answered right away, and compressed
+        let resp = compress::gzip(poll(&storage, Some("s1")));
+        assert_eq!(resp.get_header_str(header::CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(
+            compress::gunzip_data(&resp.into_body_bytes()).unwrap(),
+            state.outbox.join(&RECORD_SEPARATOR.to_string()).as_bytes()
+        );
+
+        // with nothing queued, the poll is held and left as-is
+        let resp = compress::gzip(poll(&storage, Some("s1")));
+        assert_eq!(resp.get_header_str("Grip-Hold"), Some("response"));
+        assert!(!resp.contains_header(header::CONTENT_ENCODING));
+    }
+}
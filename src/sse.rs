@@ -0,0 +1,751 @@
+use crate::cloudevents;
+use crate::meta::MessageMeta;
+use base64::Engine;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    Json,
+    Ndjson,
+
+    // each event's data is a CloudEvent in structured mode
+    CloudEvents,
+}
+
+pub struct FormatParseError;
+
+impl Format {
+    pub const ALL: [Self; 4] = [Self::Plain, Self::Json, Self::Ndjson, Self::CloudEvents];
+
+    pub fn parse(s: &str) -> Result<Self, FormatParseError> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "cloudevents" => Ok(Self::CloudEvents),
+            _ => Err(FormatParseError),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Plain => "plain",
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::CloudEvents => "cloudevents",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Plain | Self::Json | Self::CloudEvents => "text/event-stream",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum EventNames {
+    // "message" or "message-base64"
+    #[default]
+    Generic,
+
+    // the topic name, with a "-base64" suffix for binary content
+    Topic,
+}
+
+pub struct EventNamesParseError;
+
+impl EventNames {
+    pub fn parse(s: &str) -> Result<Self, EventNamesParseError> {
+        match s {
+            "message" => Ok(Self::Generic),
+            "topic-name" => Ok(Self::Topic),
+            _ => Err(EventNamesParseError),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Generic => "message",
+            Self::Topic => "topic-name",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub format: Format,
+    pub event_names: EventNames,
+}
+
+impl Options {
+    pub fn new(format: Format, event_names: EventNames) -> Self {
+        // ndjson has no event names
+        let event_names = if format == Format::Ndjson {
+            EventNames::Generic
+        } else {
+            event_names
+        };
+
+        Self {
+            format,
+            event_names,
+        }
+    }
+
+    // all option combinations that render messages differently
+    pub fn all() -> impl Iterator<Item = Self> {
+        Format::ALL.into_iter().flat_map(|format| {
+            let names: &[EventNames] = if format == Format::Ndjson {
+                &[EventNames::Generic]
+            } else {
+                &[EventNames::Generic, EventNames::Topic]
+            };
+
+            names.iter().map(move |&n| Self::new(format, n))
+        })
+    }
+
+    // live (non-durable) messages are published to a separate channel per
+    // rendering, since content can't vary per subscriber. the plain channel
+    // is shared with MQTT subscribers
+    pub fn channel_prefix(&self) -> String {
+        let base = match self.format {
+            Format::Plain => "s",
+            Format::Json => "j",
+            Format::Ndjson => "n",
+            Format::CloudEvents => "c",
+        };
+
+        match self.event_names {
+            EventNames::Generic => format!("{base}:"),
+            EventNames::Topic => format!("{base}t:"),
+        }
+    }
+
+    // durable messages are also published to a channel per rendering, so
+    // that their content can be delivered without a fetch. the "d:"
+    // channel only carries hints
+    pub fn durable_channel_prefix(&self) -> String {
+        format!("d{}", self.channel_prefix())
+    }
+}
+
+// the content of a durable event whose ID is filled in by Fanout for each
+// subscriber, with the build-id filter. in the template, "%I" stands for
+// the ID and "%%" for '%'. the event is rendered with two IDs of the same
+// length, to find where the ID goes
+pub fn id_template(render: impl Fn(&str) -> String) -> String {
+    let a = render("0");
+    let b = render("1");
+
+    let mut out = String::new();
+
+    for (x, y) in a.chars().zip(b.chars()) {
+        if x != y {
+            out.push_str("%I");
+        } else if x == '%' {
+            out.push_str("%%");
+        } else {
+            out.push(x);
+        }
+    }
+
+    out
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    // only included for ndjson, which has no event field
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    etype: Option<&'a str>,
+
+    topic: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+
+    data: &'a str,
+
+    content_type: &'a str,
+
+    // user metadata provided by the publisher
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    meta: BTreeMap<&'a str, &'a str>,
+
+    // unix timestamp, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    traceparent: Option<&'a str>,
+
+    // for replying to requests published over MQTT. the correlation data
+    // is base64-encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_topic: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_data: Option<&'a str>,
+}
+
+// event names can't contain line breaks
+fn is_valid_event_name(s: &str) -> bool {
+    !s.is_empty() && !s.contains(['\r', '\n'])
+}
+
+// the length of the longest data line a message would be rendered as, in
+// plain format
+pub fn longest_line(message: &[u8]) -> usize {
+    match str::from_utf8(message) {
+        Ok(s) => s.split('\n').map(|l| l.len()).max().unwrap_or(0),
+        Err(_) => base64::encoded_len(message.len(), true).unwrap_or(usize::MAX),
+    }
+}
+
+fn trailing_backslashes(s: &str) -> usize {
+    s.bytes().rev().take_while(|&b| b == b'\\').count()
+}
+
+// writes a line as one or more data fields. if line_max is given, the
+// line is split where it exceeds it, and each field but the last ends with
+// a backslash to indicate that the line continues. to tell these apart
+// from backslashes in the content, those at the end of a field are
+// doubled, so a field ending in an odd number of them continues
+fn write_data_line(content: &mut String, line: &str, line_max: Option<usize>) {
+    let Some(max) = line_max else {
+        content.write_fmt(format_args!("data: {line}\n")).unwrap();
+
+        return;
+    };
+
+    let mut line = line;
+
+    // leave room for the marker
+    let max = max.max(2) - 1;
+
+    while line.len() + trailing_backslashes(line) > max {
+        let mut pos = max.min(line.len());
+
+        loop {
+            while !line.is_char_boundary(pos) {
+                pos -= 1;
+            }
+
+            if pos == 0 || pos + trailing_backslashes(&line[..pos]) <= max {
+                break;
+            }
+
+            pos -= 1;
+        }
+
+        // always make progress, even if a character exceeds the limit
+        if pos == 0 {
+            pos = line.chars().next().map_or(line.len(), char::len_utf8);
+        }
+
+        // the rest is written as the last field
+        if pos == line.len() {
+            break;
+        }
+
+        let part = &line[..pos];
+
+        content
+            .write_fmt(format_args!(
+                "data: {part}{}\\\n",
+                "\\".repeat(trailing_backslashes(part))
+            ))
+            .unwrap();
+
+        line = &line[pos..];
+    }
+
+    content
+        .write_fmt(format_args!(
+            "data: {line}{}\n",
+            "\\".repeat(trailing_backslashes(line))
+        ))
+        .unwrap();
+}
+
+// line_max only applies to the plain format. metadata is only included
+// in the json formats
+pub fn message_event(
+    topic: &str,
+    id: Option<&str>,
+    message: &[u8],
+    meta: &MessageMeta,
+    opts: Options,
+    line_max: Option<usize>,
+) -> String {
+    let format = opts.format;
+
+    let base_etype = if opts.event_names == EventNames::Topic && is_valid_event_name(topic) {
+        topic
+    } else {
+        "message"
+    };
+
+    let (etype, data, content_type) = match str::from_utf8(message) {
+        Ok(s) => (base_etype.to_string(), s.to_string(), "text/plain"),
+        Err(_) => (
+            format!("{base_etype}-base64"),
+            base64::prelude::BASE64_STANDARD.encode(message),
+            "application/octet-stream",
+        ),
+    };
+
+    // the event type indicates whether the data is base64-encoded, so the
+    // publisher's content type can be passed through as-is
+    let content_type = meta.content_type.as_deref().unwrap_or(content_type);
+
+    let user_meta = || {
+        meta.user
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    };
+
+    if format == Format::Ndjson {
+        let envelope = Envelope {
+            etype: Some(&etype),
+            topic,
+            id,
+            data: &data,
+            content_type,
+            meta: user_meta(),
+            expires_at: meta.expires_at,
+            traceparent: meta.traceparent.as_deref(),
+            response_topic: meta.response_topic.as_deref(),
+            correlation_data: meta.correlation_data.as_deref(),
+        };
+
+        return format!("{}\n", serde_json::to_string(&envelope).unwrap());
+    }
+
+    let mut content = String::new();
+
+    // the event's data says whether it's base64-encoded
+    if format == Format::CloudEvents {
+        content
+            .write_fmt(format_args!("event: {base_etype}\n"))
+            .unwrap();
+    } else {
+        content.write_fmt(format_args!("event: {etype}\n")).unwrap();
+    }
+
+    if let Some(id) = id {
+        content.write_fmt(format_args!("id: {id}\n")).unwrap();
+    }
+
+    if format == Format::CloudEvents {
+        let event = cloudevents::event(topic, id, message, meta);
+
+        // serialized json never contains raw newlines
+        content.write_fmt(format_args!("data: {event}\n")).unwrap();
+    } else if format == Format::Json {
+        let envelope = Envelope {
+            etype: None,
+            topic,
+            id,
+            data: &data,
+            content_type,
+            meta: user_meta(),
+            expires_at: meta.expires_at,
+            traceparent: meta.traceparent.as_deref(),
+            response_topic: meta.response_topic.as_deref(),
+            correlation_data: meta.correlation_data.as_deref(),
+        };
+
+        // serialized json never contains raw newlines
+        let data = serde_json::to_string(&envelope).unwrap();
+
+        content.write_fmt(format_args!("data: {data}\n")).unwrap();
+    } else {
+        for line in data.split('\n') {
+            write_data_line(&mut content, line, line_max);
+        }
+    }
+
+    content.push('\n');
+
+    content
+}
+
+// an event without data, such as stream-open or keep-alive
+pub fn signal_event(etype: &str, format: Format) -> String {
+    match format {
+        Format::Ndjson => format!("{}\n", serde_json::json!({ "type": etype })),
+        Format::Plain | Format::Json | Format::CloudEvents => {
+            format!("event: {etype}\ndata: \n\n")
+        }
+    }
+}
+
+// the retry field tells SSE clients how long to wait before reconnecting.
+// dynamic streams include their connection ID
+pub fn stream_open_event(retry_ms: Option<u32>, cid: Option<&str>, format: Format) -> String {
+    if format == Format::Ndjson {
+        let Some(cid) = cid else {
+            return signal_event("stream-open", format);
+        };
+
+        let data = serde_json::json!({
+            "type": "stream-open",
+            "cid": cid,
+        });
+
+        return format!("{data}\n");
+    }
+
+    let mut content = "event: stream-open\n".to_string();
+
+    if let Some(x) = retry_ms {
+        content.write_fmt(format_args!("retry: {x}\n")).unwrap();
+    }
+
+    match cid {
+        Some(cid) => {
+            let data = serde_json::json!({ "cid": cid });
+            content.write_fmt(format_args!("data: {data}\n\n")).unwrap();
+        }
+        None => content.push_str("data: \n\n"),
+    }
+
+    content
+}
+
+// tells durable subscribers that a topic's retained message was deleted
+pub fn deleted_event(topic: &str, id: &str, format: Format) -> String {
+    if format == Format::Ndjson {
+        let data = serde_json::json!({
+            "type": "message-deleted",
+            "topic": topic,
+            "id": id,
+        });
+
+        return format!("{data}\n");
+    }
+
+    let data = serde_json::json!({ "topic": topic });
+
+    format!("event: message-deleted\nid: {id}\ndata: {data}\n\n")
+}
+
+// tells durable subscribers that a topic has a message too large for them
+// to receive, so that they can fetch it another way
+pub fn too_large_event(topic: &str, id: &str, size: usize, format: Format) -> String {
+    if format == Format::Ndjson {
+        let data = serde_json::json!({
+            "type": "message-too-large",
+            "topic": topic,
+            "id": id,
+            "size": size,
+        });
+
+        return format!("{data}\n");
+    }
+
+    let data = serde_json::json!({ "topic": topic, "size": size });
+
+    format!("event: message-too-large\nid: {id}\ndata: {data}\n\n")
+}
+
+pub fn error_event(condition: &str, text: &str, format: Format) -> String {
+    match format {
+        Format::Ndjson => {
+            let data = serde_json::json!({
+                "type": "stream-error",
+                "condition": condition,
+                "text": text,
+            });
+
+            format!("{data}\n")
+        }
+        Format::Plain | Format::Json | Format::CloudEvents => {
+            let data = serde_json::json!({
+                "condition": condition,
+                "text": text,
+            });
+
+            format!("event: stream-error\ndata: {data}\n\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain() {
+        let e = message_event(
+            "fruit",
+            None,
+            b"apple\nbanana",
+            &MessageMeta::default(),
+            Options::default(),
+            None,
+        );
+        assert_eq!(e, "event: message\ndata: apple\ndata: banana\n\n");
+
+        let e = message_event(
+            "fruit",
+            Some("a-1"),
+            &[0xff, 0x00],
+            &MessageMeta::default(),
+            Options::default(),
+            None,
+        );
+        assert_eq!(e, "event: message-base64\nid: a-1\ndata: /wA=\n\n");
+
+        let e = stream_open_event(Some(1000), None, Format::Plain);
+        assert_eq!(e, "event: stream-open\nretry: 1000\ndata: \n\n");
+
+        let e = stream_open_event(None, Some("abc"), Format::Plain);
+        assert_eq!(e, "event: stream-open\ndata: {\"cid\":\"abc\"}\n\n");
+    }
+
+    #[test]
+    fn json() {
+        let e = message_event(
+            "fruit",
+            Some("a-1"),
+            b"apple\nbanana",
+            &MessageMeta::default(),
+            Options::new(Format::Json, EventNames::Generic),
+            None,
+        );
+        assert_eq!(
+            e,
+            concat!(
+                "event: message\n",
+                "id: a-1\n",
+                "data: {\"topic\":\"fruit\",\"id\":\"a-1\",\"data\":\"apple\\nbanana\",",
+                "\"content_type\":\"text/plain\"}\n\n",
+            )
+        );
+    }
+
+    #[test]
+    fn json_meta() {
+        let meta = MessageMeta {
+            content_type: Some("application/json".to_string()),
+            user: vec![("schema".to_string(), "v2".to_string())],
+            expires_at: Some(1000),
+            receipt: None,
+            traceparent: Some(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+            ),
+            response_topic: Some("replies/1".to_string()),
+            correlation_data: Some("cmVxLTE=".to_string()),
+        };
+
+        let e = message_event(
+            "fruit",
+            None,
+            b"{}",
+            &meta,
+            Options::new(Format::Ndjson, EventNames::Generic),
+            None,
+        );
+        assert_eq!(
+            e,
+            concat!(
+                "{\"type\":\"message\",\"topic\":\"fruit\",\"data\":\"{}\",",
+                "\"content_type\":\"application/json\",\"meta\":{\"schema\":\"v2\"},",
+                "\"expires_at\":1000,",
+                "\"traceparent\":\"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\",",
+                "\"response_topic\":\"replies/1\",\"correlation_data\":\"cmVxLTE=\"}\n",
+            )
+        );
+
+        // plain format has nowhere to put it
+        let e = message_event("fruit", None, b"{}", &meta, Options::default(), None);
+        assert_eq!(e, "event: message\ndata: {}\n\n");
+    }
+
+    #[test]
+    fn ndjson() {
+        let e = message_event(
+            "fruit",
+            None,
+            b"apple",
+            &MessageMeta::default(),
+            Options::new(Format::Ndjson, EventNames::Generic),
+            None,
+        );
+        assert_eq!(
+            e,
+            concat!(
+                "{\"type\":\"message\",\"topic\":\"fruit\",\"data\":\"apple\",",
+                "\"content_type\":\"text/plain\"}\n",
+            )
+        );
+
+        let e = signal_event("keep-alive", Format::Ndjson);
+        assert_eq!(e, "{\"type\":\"keep-alive\"}\n");
+
+        let e = stream_open_event(None, Some("abc"), Format::Ndjson);
+        assert_eq!(e, "{\"cid\":\"abc\",\"type\":\"stream-open\"}\n");
+    }
+
+    #[test]
+    fn topic_names() {
+        let opts = Options::new(Format::Plain, EventNames::Topic);
+
+        let e = message_event("fruit", None, b"apple", &MessageMeta::default(), opts, None);
+        assert_eq!(e, "event: fruit\ndata: apple\n\n");
+
+        let e = message_event(
+            "fruit",
+            None,
+            &[0xff, 0x00],
+            &MessageMeta::default(),
+            opts,
+            None,
+        );
+        assert_eq!(e, "event: fruit-base64\ndata: /wA=\n\n");
+
+        let e = message_event("a\nb", None, b"apple", &MessageMeta::default(), opts, None);
+        assert_eq!(e, "event: message\ndata: apple\n\n");
+    }
+
+    #[test]
+    fn deleted() {
+        let e = deleted_event("fruit", "fruit:a-2", Format::Plain);
+        assert_eq!(
+            e,
+            "event: message-deleted\nid: fruit:a-2\ndata: {\"topic\":\"fruit\"}\n\n"
+        );
+
+        let e = deleted_event("fruit", "fruit:a-2", Format::Ndjson);
+        assert_eq!(
+            e,
+            "{\"id\":\"fruit:a-2\",\"topic\":\"fruit\",\"type\":\"message-deleted\"}\n"
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let e = too_large_event("fruit", "fruit:a-2", 40000, Format::Plain);
+        assert_eq!(
+            e,
+            "event: message-too-large\nid: fruit:a-2\ndata: {\"size\":40000,\"topic\":\"fruit\"}\n\n"
+        );
+
+        let e = too_large_event("fruit", "fruit:a-2", 40000, Format::Ndjson);
+        assert_eq!(
+            e,
+            "{\"id\":\"fruit:a-2\",\"size\":40000,\"topic\":\"fruit\",\"type\":\"message-too-large\"}\n"
+        );
+    }
+
+    #[test]
+    fn channel_prefixes() {
+        let prefixes: Vec<String> = Options::all().map(|o| o.channel_prefix()).collect();
+        assert_eq!(prefixes, vec!["s:", "st:", "j:", "jt:", "n:", "c:", "ct:"]);
+
+        let prefixes: Vec<String> = Options::all().map(|o| o.durable_channel_prefix()).collect();
+        assert_eq!(
+            prefixes,
+            vec!["ds:", "dst:", "dj:", "djt:", "dn:", "dc:", "dct:"]
+        );
+    }
+
+    #[test]
+    fn id_templates() {
+        let render = |id: &str| {
+            message_event(
+                "fruit",
+                Some(id),
+                b"100% apple",
+                &MessageMeta::default(),
+                Options::new(Format::Json, EventNames::Generic),
+                None,
+            )
+        };
+
+        let t = id_template(render);
+        assert_eq!(
+            t,
+            "event: message\nid: %I\ndata: {\"topic\":\"fruit\",\"id\":\"%I\",\"data\":\"100%% apple\",\"content_type\":\"text/plain\"}\n\n"
+        );
+
+        let t = id_template(|id| deleted_event("fruit", id, Format::Ndjson));
+        assert_eq!(
+            t,
+            "{\"id\":\"%I\",\"topic\":\"fruit\",\"type\":\"message-deleted\"}\n"
+        );
+    }
+
+    #[test]
+    fn long_lines() {
+        let opts = Options::default();
+
+        let e = message_event(
+            "fruit",
+            None,
+            b"apple\nbanana",
+            &MessageMeta::default(),
+            opts,
+            Some(4),
+        );
+        assert_eq!(
+            e,
+            "event: message\ndata: app\\\ndata: le\ndata: ban\\\ndata: ana\n\n"
+        );
+
+        // never split within a character
+        let e = message_event(
+            "fruit",
+            None,
+            "aé".as_bytes(),
+            &MessageMeta::default(),
+            opts,
+            Some(3),
+        );
+        assert_eq!(e, "event: message\ndata: a\\\ndata: é\n\n");
+
+        // a character longer than the limit is still written whole
+        let e = message_event(
+            "fruit",
+            None,
+            "éé".as_bytes(),
+            &MessageMeta::default(),
+            opts,
+            Some(1),
+        );
+        assert_eq!(e, "event: message\ndata: é\\\ndata: é\n\n");
+
+        // backslashes at the end of a field are doubled, so that they
+        // aren't taken for the marker
+        let e = message_event(
+            "fruit",
+            None,
+            b"a\\\nabc\\",
+            &MessageMeta::default(),
+            opts,
+            Some(4),
+        );
+        assert_eq!(
+            e,
+            "event: message\ndata: a\\\\\ndata: abc\\\ndata: \\\\\n\n"
+        );
+
+        // lines aren't escaped unless splitting
+        let e = message_event("fruit", None, b"a\\", &MessageMeta::default(), opts, None);
+        assert_eq!(e, "event: message\ndata: a\\\n\n");
+
+        assert_eq!(longest_line(b"apple\nbanana"), 6);
+        assert_eq!(longest_line(&[0xff, 0x00]), 4);
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Counter {
+    // messages published to Fanout
+    Publishes,
+
+    // retained messages sent to subscribers from storage
+    Deliveries,
+
+    // requests with credentials that were rejected
+    AuthFailures,
+
+    // failed storage operations, not counting write conflicts
+    StorageErrors,
+
+    // storage writes retried after a conflict or rate limiting
+    StorageRetries,
+
+    // bytes of messages published or delivered
+    PayloadBytes,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    #[serde(default)]
+    pub publishes: u64,
+
+    #[serde(default)]
+    pub deliveries: u64,
+
+    #[serde(default)]
+    pub auth_failures: u64,
+
+    #[serde(default)]
+    pub storage_errors: u64,
+
+    #[serde(default)]
+    pub storage_retries: u64,
+
+    #[serde(default)]
+    pub payload_bytes: u64,
+}
+
+impl Counts {
+    pub fn incr(&mut self, counter: Counter, n: u64) {
+        let v = match counter {
+            Counter::Publishes => &mut self.publishes,
+            Counter::Deliveries => &mut self.deliveries,
+            Counter::AuthFailures => &mut self.auth_failures,
+            Counter::StorageErrors => &mut self.storage_errors,
+            Counter::StorageRetries => &mut self.storage_retries,
+            Counter::PayloadBytes => &mut self.payload_bytes,
+        };
+
+        *v = v.saturating_add(n);
+    }
+
+    pub fn add(&mut self, other: &Counts) {
+        self.incr(Counter::Publishes, other.publishes);
+        self.incr(Counter::Deliveries, other.deliveries);
+        self.incr(Counter::AuthFailures, other.auth_failures);
+        self.incr(Counter::StorageErrors, other.storage_errors);
+        self.incr(Counter::StorageRetries, other.storage_retries);
+        self.incr(Counter::PayloadBytes, other.payload_bytes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// counts made while handling the current request, not yet added to storage
+static PENDING: Mutex<Counts> = Mutex::new(Counts {
+    publishes: 0,
+    deliveries: 0,
+    auth_failures: 0,
+    storage_errors: 0,
+    storage_retries: 0,
+    payload_bytes: 0,
+});
+
+pub fn incr(counter: Counter, n: u64) {
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .incr(counter, n);
+}
+
+// returns the pending counts and resets them
+pub fn take() -> Counts {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let mut a = Counts::default();
+        assert!(a.is_empty());
+
+        a.incr(Counter::Publishes, 2);
+        a.incr(Counter::StorageErrors, 1);
+
+        let mut b = Counts::default();
+        b.incr(Counter::Publishes, 1);
+        b.incr(Counter::AuthFailures, 3);
+        b.add(&a);
+
+        assert_eq!(
+            b,
+            Counts {
+                publishes: 3,
+                deliveries: 0,
+                auth_failures: 3,
+                storage_errors: 1,
+                storage_retries: 0,
+                payload_bytes: 0,
+            }
+        );
+
+        // missing counters read as zero, so more can be added later
+        let c: Counts = serde_json::from_str(r#"{"publishes":5}"#).unwrap();
+        assert_eq!(c.publishes, 5);
+        assert_eq!(c.deliveries, 0);
+    }
+}
@@ -1,236 +1,1969 @@
-use fastly::kv_store::{InsertMode, KVStoreError, LookupResponse};
-use fastly::KVStore;
+use crate::compress;
+use crate::deadline::Deadline;
+use crate::encryption::{self, Keys};
+use crate::kv::{Condition, Insert, Item, Kv, KvError};
+use crate::log::Limiter;
+use crate::meta::MessageMeta;
+use crate::stats::{self, Counter, Counts};
+use crate::{log_error, log_info};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::env;
 use std::time::Duration;
 
-// the amount of time to wait before deleting an item after its expiration
-// is reached. keeping expired items around allows their sequencing
-// information to be reused if they are later updated prior to deletion.
-// this helps reduce the chance of sequences restarting, which can be
-// disruptive to message delivery.
-const LINGER: Duration = Duration::from_secs(60 * 60 * 24);
+// subscription cursors are informational only, so there's no need to keep
+// them for long after a subscriber goes away
+const CURSORS_TTL: Duration = Duration::from_secs(60 * 60 * 24);
 
-const WRITE_TRIES_MAX: usize = 5;
+// long enough to cover producer retries
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(60 * 10);
+
+// how long an idempotency key is reserved for a request in progress, in
+// case the request never completes
+const IDEMPOTENCY_PENDING_TTL: Duration = Duration::from_secs(60);
+
+// scheduled messages are kept for this long after they are due, in case
+// delivery is held up
+const SCHEDULED_LINGER: Duration = Duration::from_secs(60 * 60 * 24);
+
+// lists that have been emptied are kept briefly, since they can't be
+// conditionally deleted
+const SCHEDULED_EMPTY_TTL: Duration = Duration::from_secs(60);
+
+// per topic
+pub const SCHEDULED_MAX: usize = 100;
+
+// retained messages at least this large are stored compressed, if that
+// makes them smaller
+const RETAINED_COMPRESS_MIN: usize = 1024;
+
+// retained values larger than this are split across several items, to stay
+// within the value size limits of remote stores
+const RETAINED_CHUNK_SIZE: usize = 1024 * 1024;
+
+// chunks of replaced values are emptied and kept briefly, since they can't
+// be deleted
+const CHUNKS_RELEASED_TTL: Duration = Duration::from_secs(60);
+
+// most messages a topic can keep
+pub const RETAINED_DEPTH_MAX: u32 = 32;
+
+// topics of dynamic streams. streams are closed once this expires, and
+// clients are expected to reconnect
+const STREAM_TOPICS_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+// long-polling sessions are expected to poll much more often than this
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+// presence lists are dropped this long after their last member expires or
+// leaves
+const PRESENCE_LINGER: Duration = Duration::from_secs(60);
+
+// most members a topic's presence list keeps. beyond this, subscribers
+// aren't tracked until others leave
+pub const PRESENCE_MEMBERS_MAX: usize = 1000;
+
+// presence lists are best effort, so contended writes aren't retried for
+// long
+const PRESENCE_TRIES_MAX: u32 = 3;
+
+// publish counters cover a minute each, and are kept until the next one is
+// done with
+const PUBLISH_COUNT_TTL: Duration = Duration::from_secs(60 * 2);
+
+// counters are best effort, so contended writes aren't retried for long
+const PUBLISH_COUNT_TRIES_MAX: u32 = 3;
+
+// stats are kept per minute, spread across several items to reduce
+// contention, for as long as the stats endpoint can look back
+const STATS_SHARDS: u32 = 4;
+const STATS_TTL: Duration = Duration::from_secs(60 * 60 * 2);
+
+// quota counts are spread across several items, since busy publishers
+// would otherwise contend on a single one for the whole period
+const QUOTA_SHARDS: u32 = 4;
+
+const AUDIT_ENTRY_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 90);
+
+// error log limiting only covers recent lines
+const LOG_LIMITER_TTL: Duration = Duration::from_secs(60 * 60 * 24);
 
 #[derive(Debug)]
 pub enum StorageError {
     StoreNotFound,
     TooManyRequests,
     InvalidMetadata,
-    KVStore(KVStoreError),
+    InvalidValue,
+    DeadlineExceeded,
+    LimitReached,
+    Kv(KvError),
+}
+
+impl From<KvError> for StorageError {
+    fn from(e: KvError) -> Self {
+        match e {
+            KvError::StoreNotFound => Self::StoreNotFound,
+            KvError::TooManyRequests => {
+                stats::incr(Counter::StorageErrors, 1);
+
+                Self::TooManyRequests
+            }
+            // write conflicts are expected
+            KvError::PreconditionFailed => Self::Kv(KvError::PreconditionFailed),
+            e => {
+                stats::incr(Counter::StorageErrors, 1);
+
+                Self::Kv(e)
+            }
+        }
+    }
+}
+
+// how retained slots are written. see Config::retained_settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetainedSettings {
+    // the amount of time to wait before deleting an item after its
+    // expiration is reached. keeping expired items around allows their
+    // sequencing information to be reused if they are later updated prior
+    // to deletion. this helps reduce the chance of sequences restarting,
+    // which can be disruptive to message delivery.
+    pub linger: Duration,
+
+    // how many times to try a write that conflicts with other writes or is
+    // rate limited
+    pub write_tries_max: usize,
+
+    // how many of the latest messages to keep. messages before the latest
+    // are kept in a ring of items, and returned to readers along with it
+    pub depth: u32,
+}
+
+impl Default for RetainedSettings {
+    fn default() -> Self {
+        Self {
+            linger: Duration::from_secs(60 * 60 * 24),
+            write_tries_max: 5,
+            depth: 1,
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct RetainedVersion {
+    // the epoch in which the generation was created, or 0 if unknown
+    pub epoch: u32,
+
     pub generation: u64,
     pub seq: u64,
+
+    // when the generation was created, in unix milliseconds, or 0 if
+    // unknown
+    #[serde(default)]
+    pub started: u64,
+}
+
+impl RetainedVersion {
+    // versions of the same generation are ordered by sequence number, and
+    // generations by when they were created. returns None if that isn't
+    // known
+    pub fn order(&self, other: &RetainedVersion) -> Option<Ordering> {
+        if self.generation == other.generation {
+            Some(self.seq.cmp(&other.seq))
+        } else if self.started > 0 && other.started > 0 && self.started != other.started {
+            Some(self.started.cmp(&other.started))
+        } else {
+            None
+        }
+    }
+}
+
+// the epoch is the service version. it is recorded with each new generation
+// so that sequence resets can be correlated with deployments
+pub fn current_epoch() -> u32 {
+    match env::var("FASTLY_SERVICE_VERSION") {
+        Ok(s) => s.parse().unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
+fn is_zero_u64(x: &u64) -> bool {
+    *x == 0
+}
+
+fn is_false(x: &bool) -> bool {
+    !*x
 }
 
 pub struct RetainedMessage {
     pub ttl: Option<Duration>,
     pub data: Vec<u8>,
+    pub meta: MessageMeta,
+}
+
+// a page of topics that have retained slots
+pub struct RetainedList {
+    pub topics: Vec<String>,
+
+    // for requesting the next page, if there may be more
+    pub cursor: Option<String>,
+}
+
+pub struct RetainedEntry {
+    pub version: RetainedVersion,
+    pub message: RetainedMessage,
 }
 
 pub struct RetainedSlot {
     pub version: RetainedVersion,
     pub message: Option<RetainedMessage>,
+
+    // whether the message was explicitly deleted, as opposed to expired
+    pub deleted: bool,
+
+    // the depth the slot was written with
+    pub depth: u32,
+
+    // messages before the latest that are still kept and are newer than
+    // the version read after, oldest first
+    pub earlier: Vec<RetainedEntry>,
+}
+
+// the response to a request, kept so that it can be returned again if the
+// request is retried
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct IdempotentResult {
+    pub status: u16,
+    pub body: String,
+
+    // text/plain if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+// what is kept for an idempotency key. the key is bound to the content of
+// the request that reserved it, so that it can't be reused for another
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct IdempotencyRecord {
+    pub hash: String,
+
+    // absent while the request is in progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<IdempotentResult>,
+}
+
+// the outcome of a change to a topic's presence list
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PresenceChange {
+    // whether the member joined or left, rather than refreshing its
+    // presence or leaving a list it wasn't in
+    pub changed: bool,
+
+    // members after the change
+    pub count: usize,
+}
+
+pub(crate) mod base64_data {
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::prelude::BASE64_STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+
+        base64::prelude::BASE64_STANDARD
+            .decode(s)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// a message to be retained as part of a transaction
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct TransactionMessage {
+    pub topic: String,
+
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+
+    #[serde(default, skip_serializing_if = "MessageMeta::is_empty")]
+    pub meta: MessageMeta,
+
+    // expiration of the retained message, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+
+    // the topic's retain depth, if more than 1
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub depth: u32,
+}
+
+// a message to be retained as part of a batch of independent writes
+pub struct RetainedWrite<'a> {
+    pub topic: &'a str,
+    pub message: &'a [u8],
+    pub meta: &'a MessageMeta,
+    pub ttl: Option<Duration>,
+    pub settings: RetainedSettings,
+}
+
+// the record of a transaction. it is written before any of the slots, so
+// that readers encountering a slot of an incomplete transaction can
+// complete it
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Transaction {
+    #[serde(default, skip_serializing_if = "is_false")]
+    committed: bool,
+
+    // seconds, from the settings of the writer
+    linger: u64,
+
+    messages: Vec<TransactionMessage>,
+}
+
+// a message to be published at a later time
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ScheduledMessage {
+    // unix timestamp, in seconds
+    pub due: u64,
+
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+
+    #[serde(default, skip_serializing_if = "MessageMeta::is_empty")]
+    pub meta: MessageMeta,
+
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub retain: bool,
+
+    // expiration of the retained message, in seconds after delivery
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+
+    // namespace of the publisher, for naming the topic to its subscribers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+
+    // topics to deliver to, routed with the publisher's capabilities when
+    // the message was scheduled. if empty, the message's own topic
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+}
+
+pub fn unix_now() -> u64 {
+    time::UtcDateTime::now().unix_timestamp() as u64
+}
+
+pub fn unix_now_ms() -> u64 {
+    (time::UtcDateTime::now().unix_timestamp_nanos() / 1_000_000) as u64
 }
 
 #[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
 struct Metadata {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    epoch: u32,
+
     generation: u64,
     seq: u64,
 
+    // when the generation was created, in unix milliseconds
+    #[serde(rename = "started-at", default, skip_serializing_if = "is_zero_u64")]
+    started_at: u64,
+
     #[serde(rename = "expires-at", skip_serializing_if = "Option::is_none")]
     expires_at: Option<time::UtcDateTime>,
+
+    #[serde(default, skip_serializing_if = "is_false")]
+    deleted: bool,
+
+    #[serde(
+        rename = "message-meta",
+        default,
+        skip_serializing_if = "MessageMeta::is_empty"
+    )]
+    message_meta: MessageMeta,
+
+    // the transaction that wrote the slot, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    txn: Option<String>,
+
+    // how the value is encoded, if not stored as-is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+
+    // the key the value is encrypted with, after any encoding
+    #[serde(rename = "key-id", default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+
+    // the number of items the value is split across, if it is chunked. the
+    // slot's own value is empty in that case
+    #[serde(default, skip_serializing_if = "is_zero")]
+    chunks: u32,
+
+    // the retain depth, if more than 1
+    #[serde(default, skip_serializing_if = "is_zero")]
+    depth: u32,
+}
+
+impl Metadata {
+    // time left until expiration, as of now
+    fn ttl(&self) -> Option<Duration> {
+        self.expires_at.map(|expires_at| {
+            let now = time::UtcDateTime::now();
+
+            if now < expires_at {
+                (expires_at - now).unsigned_abs()
+            } else {
+                Duration::from_millis(0)
+            }
+        })
+    }
+
+    fn version(&self) -> RetainedVersion {
+        RetainedVersion {
+            epoch: self.epoch,
+            generation: self.generation,
+            seq: self.seq,
+            started: self.started_at,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    Gzip,
+}
+
+// returns the value to store for a message, and its encoding
+fn encode_value(data: &[u8]) -> (Cow<'_, [u8]>, Option<Encoding>) {
+    if data.len() >= RETAINED_COMPRESS_MIN {
+        if let Ok(v) = compress::gzip_data(data) {
+            if v.len() < data.len() {
+                return (Cow::Owned(v), Some(Encoding::Gzip));
+            }
+        }
+    }
+
+    (Cow::Borrowed(data), None)
+}
+
+// encrypts an encoded value with the current key, if one is set. returns the
+// ID of the key used
+fn encrypt_value<'a>(keys: &Keys, value: Cow<'a, [u8]>) -> (Cow<'a, [u8]>, Option<String>) {
+    match keys.current() {
+        Some(key) => (
+            Cow::Owned(encryption::encrypt(key, &value)),
+            Some(key.id.clone()),
+        ),
+        None => (value, None),
+    }
 }
 
-fn lookup(
-    store: &KVStore,
-    key_name: &str,
-) -> Result<Option<(LookupResponse, Metadata)>, StorageError> {
-    let lookup = match store.lookup(key_name) {
-        Ok(l) => l,
-        Err(KVStoreError::ItemNotFound) => return Ok(None),
-        Err(e) => return Err(StorageError::KVStore(e)),
+fn decode_value(
+    keys: &Keys,
+    value: Vec<u8>,
+    encoding: Option<Encoding>,
+    key_id: Option<&str>,
+) -> Result<Vec<u8>, StorageError> {
+    let value = match key_id {
+        Some(id) => {
+            let Some(key) = keys.find(id) else {
+                log_error!("retained value encrypted with unknown key {id}");
+
+                return Err(StorageError::InvalidValue);
+            };
+
+            encryption::decrypt(key, &value).ok_or(StorageError::InvalidValue)?
+        }
+        None => value,
     };
 
-    let meta = match lookup.metadata() {
-        Some(data) => match serde_json::from_slice(&data) {
-            Ok(v) => v,
-            Err(_) => return Err(StorageError::InvalidMetadata),
+    match encoding {
+        Some(Encoding::Gzip) => {
+            compress::gunzip_data(&value).map_err(|_| StorageError::InvalidValue)
+        }
+        None => Ok(value),
+    }
+}
+
+// chunks are keyed by slot version, so that writing a new version doesn't
+// disturb readers of the previous one
+fn chunk_key(topic: &str, generation: u64, seq: u64, index: u32) -> String {
+    format!("k:{generation:016x}-{seq}-{index}:{topic}")
+}
+
+// replaced messages of topics with a retain depth are copied into a ring of
+// depth - 1 items, by sequence number
+fn ring_key(topic: &str, seq: u64, depth: u32) -> String {
+    let pos = seq % u64::from(depth - 1);
+
+    format!("h:{pos}:{topic}")
+}
+
+fn lookup_metadata(item: &Item) -> Result<Metadata, StorageError> {
+    match &item.metadata {
+        Some(data) => match serde_json::from_slice(data) {
+            Ok(v) => Ok(v),
+            Err(_) => Err(StorageError::InvalidMetadata),
         },
-        None => return Err(StorageError::InvalidMetadata),
-    };
+        None => Err(StorageError::InvalidMetadata),
+    }
+}
+
+pub trait Storage {
+    fn write_retained(
+        &self,
+        topic: &str,
+        message: &[u8],
+        meta: &MessageMeta,
+        ttl: Option<Duration>,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<RetainedVersion, StorageError>;
+
+    // retains messages for several topics at once, returning results in the
+    // same order. unlike a transaction, each write succeeds or fails on its
+    // own
+    fn write_retained_many(
+        &self,
+        writes: &[RetainedWrite],
+        deadline: Deadline,
+    ) -> Result<Vec<Result<RetainedVersion, StorageError>>, StorageError> {
+        Ok(writes
+            .iter()
+            .map(|w| self.write_retained(w.topic, w.message, w.meta, w.ttl, w.settings, deadline))
+            .collect())
+    }
+
+    // replaces the retained message with a tombstone, keeping the sequence
+    // going so that durable subscribers learn of the deletion. returns None
+    // if there was nothing retained
+    fn delete_retained(
+        &self,
+        topic: &str,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Option<RetainedVersion>, StorageError>;
+
+    // removes the retained slot along with any history, so that the topic
+    // starts over as if nothing had been retained. returns false if there
+    // was nothing retained
+    fn purge_retained(&self, topic: &str) -> Result<bool, StorageError>;
+
+    fn read_retained(
+        &self,
+        topic: &str,
+        after: Option<RetainedVersion>,
+    ) -> Result<Option<RetainedSlot>, StorageError>;
+
+    // reads several retained slots, returning results in the same order
+    fn read_retained_many(
+        &self,
+        reads: &[(&str, Option<RetainedVersion>)],
+    ) -> Result<Vec<Option<RetainedSlot>>, StorageError> {
+        reads
+            .iter()
+            .map(|(topic, after)| self.read_retained(topic, *after))
+            .collect()
+    }
+
+    // lists topics beginning with prefix that have retained slots, including
+    // slots whose messages have expired or been deleted but that are kept
+    // for sequencing
+    fn list_retained(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<RetainedList, StorageError>;
+
+    // retains messages for several topics as a unit. readers that observe
+    // any of the messages are guaranteed to be able to observe all of them.
+    // returns the versions in the same order as the messages
+    fn write_transaction(
+        &self,
+        messages: &[TransactionMessage],
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<RetainedVersion>, StorageError>;
+
+    fn write_cursors(
+        &self,
+        key: &str,
+        cursors: &HashMap<String, String>,
+    ) -> Result<(), StorageError>;
+
+    fn read_cursors(&self, key: &str) -> Result<Option<HashMap<String, String>>, StorageError>;
+
+    fn write_stream_topics(&self, cid: &str, topics: &[String]) -> Result<(), StorageError>;
+
+    fn read_stream_topics(&self, cid: &str) -> Result<Option<Vec<String>>, StorageError>;
+
+    fn delete_stream_topics(&self, cid: &str) -> Result<(), StorageError>;
+
+    // state of a long-polling session, which is opaque to storage
+    fn write_session(&self, id: &str, state: &[u8]) -> Result<(), StorageError>;
+
+    fn read_session(&self, id: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    // adds a member to a topic's presence list until expires_at, or removes
+    // it if expires_at is None. expired members are dropped along the way
+    fn update_presence(
+        &self,
+        topic: &str,
+        member: &str,
+        expires_at: Option<u64>,
+        deadline: Deadline,
+    ) -> Result<PresenceChange, StorageError>;
+
+    // returns the unexpired members of a topic's presence list, sorted
+    fn read_presence(&self, topic: &str) -> Result<Vec<String>, StorageError>;
+
+    fn read_public_keys(&self, topic: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    // records a request nonce for ttl. returns false if it was already
+    // recorded
+    fn record_nonce(&self, nonce: &str, ttl: Duration) -> Result<bool, StorageError>;
+
+    // reserves an idempotency key for a request with the given content
+    // hash. if the key is already reserved, its record is returned instead
+    fn reserve_idempotency_key(
+        &self,
+        key: &str,
+        hash: &str,
+    ) -> Result<Option<IdempotencyRecord>, StorageError>;
+
+    fn write_idempotent_result(
+        &self,
+        key: &str,
+        hash: &str,
+        result: &IdempotentResult,
+    ) -> Result<(), StorageError>;
+
+    // frees a reserved idempotency key, so that a failed request can be
+    // retried
+    fn release_idempotency_key(&self, key: &str) -> Result<(), StorageError>;
+
+    fn write_scheduled(
+        &self,
+        topic: &str,
+        message: &ScheduledMessage,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<(), StorageError>;
+
+    // removes and returns a topic's scheduled messages that are due by the
+    // given time, in order of due time
+    fn take_scheduled(
+        &self,
+        topic: &str,
+        due_by: u64,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<ScheduledMessage>, StorageError>;
+
+    // topics that may have scheduled messages
+    fn list_scheduled_topics(&self) -> Result<Vec<String>, StorageError>;
+
+    // adds count publishes to the current minute's counter for key, failing
+    // with LimitReached if that would exceed limit
+    fn count_publishes(
+        &self,
+        key: &str,
+        count: u32,
+        limit: u32,
+        deadline: Deadline,
+    ) -> Result<(), StorageError>;
+
+    // the publishes counted for a quota key in a period, such as
+    // "d20261016". see quota.rs
+    fn read_quota_usage(&self, key: &str, period: &str) -> Result<u64, StorageError>;
+
+    // adds to a quota key's count for a period. the count is kept for ttl
+    fn add_quota_usage(
+        &self,
+        key: &str,
+        period: &str,
+        count: u32,
+        ttl: Duration,
+        deadline: Deadline,
+    ) -> Result<(), StorageError>;
+
+    // adds to the current minute's stats
+    fn add_stats(&self, counts: &Counts, deadline: Deadline) -> Result<(), StorageError>;
+
+    // the stats of each of the given unix minutes, in the same order
+    fn read_stats(&self, minutes: &[u64]) -> Result<Vec<Counts>, StorageError>;
+
+    // adds an entry to the audit log. time is in unix milliseconds
+    fn append_audit_entry(&self, time: u64, data: Vec<u8>) -> Result<(), StorageError>;
+
+    // error log limiting state shared by instances. concurrent writers may
+    // overwrite each other, which only makes the limiting approximate
+    fn write_log_limiter(&self, limiter: &Limiter) -> Result<(), StorageError>;
+
+    fn read_log_limiter(&self) -> Result<Option<Limiter>, StorageError>;
+}
+
+pub struct KvStorage {
+    kv: Box<dyn Kv>,
+
+    // for retained messages
+    keys: Keys,
+}
+
+impl KvStorage {
+    pub fn new(kv: Box<dyn Kv>) -> Self {
+        Self {
+            kv,
+            keys: Keys::default(),
+        }
+    }
+
+    // retained messages are encrypted with the current key, if any
+    pub fn with_keys(mut self, keys: Keys) -> Self {
+        self.keys = keys;
+
+        self
+    }
+
+    fn lookup(&self, key_name: &str) -> Result<Option<(Item, Metadata)>, StorageError> {
+        let Some(item) = self.kv.lookup(key_name)? else {
+            return Ok(None);
+        };
+
+        let meta = lookup_metadata(&item)?;
+
+        Ok(Some((item, meta)))
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, key_name: &str) -> Result<Option<T>, StorageError> {
+        let Some(item) = self.kv.lookup(key_name)? else {
+            return Ok(None);
+        };
+
+        match serde_json::from_slice(&item.value) {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(StorageError::InvalidValue),
+        }
+    }
+
+    fn write_json<T: serde::Serialize + ?Sized>(
+        &self,
+        key_name: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(value).expect("value should always be serializable");
+
+        let insert = Insert {
+            ttl: Some(ttl),
+            ..Default::default()
+        };
+
+        Ok(self.kv.insert(key_name, value, &insert)?)
+    }
+
+    // applies a change to a topic's list of scheduled messages, retrying on
+    // conflicts. the list is only written if the change altered its length
+    fn update_scheduled<T, F>(
+        &self,
+        topic: &str,
+        settings: RetainedSettings,
+        deadline: Deadline,
+        mut f: F,
+    ) -> Result<T, StorageError>
+    where
+        F: FnMut(&mut Vec<ScheduledMessage>) -> Result<T, StorageError>,
+    {
+        let key_name = format!("q:{topic}");
+
+        let mut tries = 0;
+
+        loop {
+            let (mut messages, condition) = match self.kv.lookup(&key_name)? {
+                Some(item) => match serde_json::from_slice(&item.value) {
+                    Ok(v) => (v, Condition::Generation(item.generation)),
+                    Err(_) => return Err(StorageError::InvalidValue),
+                },
+                None => (Vec::new(), Condition::Absent),
+            };
+
+            let len = messages.len();
+
+            let ret = f(&mut messages)?;
+
+            if messages.len() == len {
+                return Ok(ret);
+            }
+
+            let ttl = match messages.iter().map(|m| m.due).max() {
+                Some(due) => Duration::from_secs(due.saturating_sub(unix_now())) + SCHEDULED_LINGER,
+                None => SCHEDULED_EMPTY_TTL,
+            };
+
+            let value =
+                serde_json::to_vec(&messages).expect("messages should always be serializable");
+
+            let insert = Insert {
+                ttl: Some(ttl),
+                condition,
+                ..Default::default()
+            };
+
+            match self.kv.insert(&key_name, value, &insert) {
+                Ok(()) => return Ok(ret),
+                Err(KvError::PreconditionFailed) => {}
+                Err(KvError::TooManyRequests) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            tries += 1;
+
+            if tries >= settings.write_tries_max {
+                return Err(StorageError::TooManyRequests);
+            }
+
+            if deadline.expired() {
+                return Err(StorageError::DeadlineExceeded);
+            }
+
+            stats::incr(Counter::StorageRetries, 1);
+        }
+    }
+
+    // writes a message to a retained slot, or a tombstone if message is None.
+    // if txn is set and the slot was already written by that transaction, the
+    // existing version is returned instead. if the slot has already been
+    // looked up, it is passed in prefetched and used for the first try. a
+    // prefetched slot may be stale, so a conflict on the first try doesn't
+    // count against the tries
+    #[allow(clippy::too_many_arguments)]
+    fn write_slot(
+        &self,
+        topic: &str,
+        message: Option<(&[u8], &MessageMeta)>,
+        ttl: Option<Duration>,
+        txn: Option<&str>,
+        settings: RetainedSettings,
+        mut prefetched: Option<Option<(Item, Metadata)>>,
+        deadline: Deadline,
+    ) -> Result<Option<RetainedVersion>, StorageError> {
+        let key_name = format!("r:{topic}");
+
+        let expires_at = ttl.map(|ttl| time::UtcDateTime::now() + ttl);
+
+        let (value, encoding, key_id) = match message {
+            Some((data, _)) => {
+                let (value, encoding) = encode_value(data);
+                let (value, key_id) = encrypt_value(&self.keys, value);
+
+                (value, encoding, key_id)
+            }
+            None => (Cow::Borrowed(&[][..]), None, None),
+        };
+
+        let chunks: Vec<&[u8]> = if value.len() > RETAINED_CHUNK_SIZE {
+            value.chunks(RETAINED_CHUNK_SIZE).collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut tries = 0;
+
+        let version = loop {
+            let (slot, was_prefetched) = match prefetched.take() {
+                Some(slot) => (slot, true),
+                None => (self.lookup(&key_name)?, false),
+            };
+
+            let (mut meta, prev_item) = match slot {
+                Some((item, meta)) => (meta, Some(item)),
+                None if message.is_none() => return Ok(None),
+                None => (Metadata::default(), None),
+            };
+
+            if txn.is_some() && meta.txn.as_deref() == txn {
+                break meta.version();
+            }
+
+            let generation = prev_item.as_ref().map(|item| item.generation);
+
+            // keep the message being replaced, if the topic keeps several.
+            // tombstones end the history, so there's no need then
+            if let (Some(item), Some(_)) = (prev_item, message) {
+                if settings.depth > 1 && !meta.deleted && meta.chunks == 0 {
+                    self.keep_replaced(topic, item, &meta, settings)?;
+                }
+            }
+
+            let prev = (meta.generation, meta.seq, meta.chunks);
+
+            let condition = if let Some(generation) = generation {
+                meta.seq += 1;
+
+                Condition::Generation(generation)
+            } else {
+                meta.epoch = current_epoch();
+                meta.generation = rand::random();
+                meta.started_at = unix_now_ms();
+                meta.seq = 1;
+
+                Condition::Absent
+            };
+
+            meta.expires_at = expires_at;
+            meta.deleted = message.is_none();
+            meta.message_meta = message.map(|(_, m)| m.clone()).unwrap_or_default();
+            meta.txn = txn.map(|s| s.to_string());
+
+            meta.encoding = encoding;
+            meta.key_id = key_id.clone();
+            meta.chunks = chunks.len() as u32;
+            meta.depth = if settings.depth > 1 {
+                settings.depth
+            } else {
+                0
+            };
+
+            let meta_json =
+                serde_json::to_string(&meta).expect("metadata should always be serializable");
+
+            let ttl = if let Some(ttl) = ttl {
+                // we set a TTL longer than the item's expiration time, to
+                // allow the opportunity to reuse the item after expiration
+                Some(ttl + settings.linger)
+            } else if message.is_none() {
+                // tombstones only need to stick around for sequencing
+                Some(settings.linger)
+            } else {
+                None
+            };
+
+            // chunks are written first, so they are in place by the time
+            // the slot refers to them
+            for (i, chunk) in chunks.iter().enumerate() {
+                let insert = Insert {
+                    ttl,
+                    ..Default::default()
+                };
+
+                let chunk_key = chunk_key(topic, meta.generation, meta.seq, i as u32);
+
+                self.kv.insert(&chunk_key, chunk.to_vec(), &insert)?;
+            }
+
+            let insert = Insert {
+                metadata: Some(&meta_json),
+                ttl,
+                condition,
+            };
+
+            let slot_value = if chunks.is_empty() {
+                value.to_vec()
+            } else {
+                Vec::new()
+            };
+
+            let conflict = match self.kv.insert(&key_name, slot_value, &insert) {
+                Ok(()) => {
+                    let (generation, seq, count) = prev;
+
+                    self.release_chunks(topic, generation, seq, count);
+
+                    break meta.version();
+                }
+                Err(KvError::PreconditionFailed) => true,
+                Err(KvError::TooManyRequests) => false,
+                Err(e) => return Err(e.into()),
+            };
+
+            self.release_chunks(topic, meta.generation, meta.seq, meta.chunks);
+
+            if conflict && was_prefetched {
+                continue;
+            }
+
+            tries += 1;
+
+            if tries >= settings.write_tries_max {
+                // getting conflicts or rate limit errors after several tries
+                return Err(StorageError::TooManyRequests);
+            }
+
+            if deadline.expired() {
+                return Err(StorageError::DeadlineExceeded);
+            }
+
+            stats::incr(Counter::StorageRetries, 1);
+        };
+
+        Ok(Some(version))
+    }
+
+    // copies a replaced slot into the ring, as-is
+    fn keep_replaced(
+        &self,
+        topic: &str,
+        item: Item,
+        meta: &Metadata,
+        settings: RetainedSettings,
+    ) -> Result<(), StorageError> {
+        let metadata = match &item.metadata {
+            Some(data) => String::from_utf8_lossy(data).into_owned(),
+            None => return Err(StorageError::InvalidMetadata),
+        };
+
+        let insert = Insert {
+            metadata: Some(&metadata),
+            ttl: meta.ttl().map(|ttl| ttl + settings.linger),
+            ..Default::default()
+        };
+
+        let ring_key = ring_key(topic, meta.seq, settings.depth);
+
+        Ok(self.kv.insert(&ring_key, item.value, &insert)?)
+    }
+
+    // returns the messages kept in the ring that came before a slot and
+    // after a version, oldest first. entries that have been overwritten,
+    // expired or can't be read are skipped
+    fn read_ring(
+        &self,
+        topic: &str,
+        meta: &Metadata,
+        after: Option<RetainedVersion>,
+    ) -> Result<Vec<RetainedEntry>, StorageError> {
+        let oldest = meta.seq.saturating_sub(u64::from(meta.depth - 1)).max(1);
+
+        let first = match after {
+            Some(after) if after.generation == meta.generation => oldest.max(after.seq + 1),
+            _ => oldest,
+        };
+
+        let seqs: Vec<u64> = (first..meta.seq).collect();
+
+        let keys: Vec<String> = seqs
+            .iter()
+            .map(|seq| ring_key(topic, *seq, meta.depth))
+            .collect();
+
+        let mut entries = Vec::new();
+
+        for (seq, item) in seqs.into_iter().zip(self.kv.lookup_many(&keys)?) {
+            let Some(item) = item else {
+                continue;
+            };
+
+            let Ok(m) = lookup_metadata(&item) else {
+                continue;
+            };
+
+            if m.generation != meta.generation || m.seq != seq || m.deleted {
+                continue;
+            }
+
+            let ttl = m.ttl();
+
+            if ttl == Some(Duration::from_millis(0)) || m.message_meta.is_expired(unix_now()) {
+                continue;
+            }
+
+            let Ok(data) = decode_value(&self.keys, item.value, m.encoding, m.key_id.as_deref())
+            else {
+                continue;
+            };
+
+            entries.push(RetainedEntry {
+                version: m.version(),
+                message: RetainedMessage {
+                    ttl,
+                    data,
+                    meta: m.message_meta,
+                },
+            });
+        }
+
+        Ok(entries)
+    }
+
+    // empties the chunks of a slot version that is no longer current. best
+    // effort, since chunks left behind are only wasted space
+    fn release_chunks(&self, topic: &str, generation: u64, seq: u64, count: u32) {
+        for i in 0..count {
+            let insert = Insert {
+                ttl: Some(CHUNKS_RELEASED_TTL),
+                ..Default::default()
+            };
+
+            let chunk_key = chunk_key(topic, generation, seq, i);
+
+            if self.kv.insert(&chunk_key, Vec::new(), &insert).is_err() {
+                break;
+            }
+        }
+    }
+
+    // returns None if any chunk is missing or released, which happens if the
+    // slot was replaced while being read
+    fn read_chunks(&self, topic: &str, meta: &Metadata) -> Result<Option<Vec<u8>>, StorageError> {
+        let keys: Vec<String> = (0..meta.chunks)
+            .map(|i| chunk_key(topic, meta.generation, meta.seq, i))
+            .collect();
+
+        let mut value = Vec::new();
+
+        for item in self.kv.lookup_many(&keys)? {
+            match item {
+                Some(item) if !item.value.is_empty() => value.extend(item.value),
+                _ => return Ok(None),
+            }
+        }
+
+        Ok(Some(value))
+    }
+
+    // writes the slots of a transaction that haven't been written yet, and
+    // marks it committed. safe to call concurrently with the writer, since
+    // slots already written by the transaction are skipped
+    fn complete_transaction(
+        &self,
+        id: &str,
+        txn: &mut Transaction,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<RetainedVersion>, StorageError> {
+        let mut versions = Vec::new();
+
+        for m in &txn.messages {
+            let ttl = m.ttl.map(|x| Duration::from_secs(x.into()));
+
+            let settings = RetainedSettings {
+                depth: m.depth.max(1),
+                ..settings
+            };
+
+            let version = self.write_slot(
+                &m.topic,
+                Some((&m.data, &m.meta)),
+                ttl,
+                Some(id),
+                settings,
+                None,
+                deadline,
+            )?;
+
+            versions.push(version.expect("writing a message should always produce a version"));
+        }
+
+        txn.committed = true;
+
+        self.write_transaction_record(id, txn, Condition::Always)?;
+
+        Ok(versions)
+    }
+
+    fn write_transaction_record(
+        &self,
+        id: &str,
+        txn: &Transaction,
+        condition: Condition,
+    ) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(txn).expect("transaction should always be serializable");
+
+        // slots of the transaction refer to the record. once it is gone, they
+        // are assumed to be committed
+        let insert = Insert {
+            ttl: Some(Duration::from_secs(txn.linger)),
+            condition,
+            ..Default::default()
+        };
+
+        Ok(self.kv.insert(&format!("x:{id}"), value, &insert)?)
+    }
+
+    // interprets a looked up retained slot. returns None if the slot isn't
+    // newer than after. a slot of another generation is assumed to be newer
+    // unless known to have been created earlier
+    fn retained_slot(
+        &self,
+        topic: &str,
+        item: Item,
+        meta: Metadata,
+        after: Option<RetainedVersion>,
+    ) -> Result<Option<RetainedSlot>, StorageError> {
+        if let Some(after) = after {
+            if let Some(Ordering::Less | Ordering::Equal) = meta.version().order(&after) {
+                return Ok(None);
+            }
+        }
+
+        // the message must not be returned until the rest of its
+        // transaction is in place
+        if let Some(id) = &meta.txn {
+            self.recover_transaction(id)?;
+        }
+
+        let version = meta.version();
+
+        let ttl = meta.ttl();
+
+        let expired =
+            ttl == Some(Duration::from_millis(0)) || meta.message_meta.is_expired(unix_now());
+
+        let value = if meta.deleted || expired {
+            None
+        } else if meta.chunks > 0 {
+            self.read_chunks(topic, &meta)?
+        } else {
+            Some(item.value)
+        };
+
+        let message = match value {
+            Some(value) => Some(RetainedMessage {
+                ttl,
+                data: decode_value(&self.keys, value, meta.encoding, meta.key_id.as_deref())?,
+                meta: meta.message_meta.clone(),
+            }),
+            None => None,
+        };
+
+        // history is only of interest while there is a current message
+        let earlier = if meta.depth > 1 && message.is_some() {
+            self.read_ring(topic, &meta, after)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Some(RetainedSlot {
+            version,
+            message,
+            deleted: meta.deleted,
+            depth: meta.depth.max(1),
+            earlier,
+        }))
+    }
+
+    // completes a transaction if it isn't committed
+    fn recover_transaction(&self, id: &str) -> Result<(), StorageError> {
+        let Some(mut txn) = self.read_json::<Transaction>(&format!("x:{id}"))? else {
+            return Ok(());
+        };
+
+        if txn.committed {
+            return Ok(());
+        }
+
+        log_info!("completing transaction {id}");
+
+        let settings = RetainedSettings {
+            linger: Duration::from_secs(txn.linger),
+            ..Default::default()
+        };
+
+        self.complete_transaction(id, &mut txn, settings, Deadline::none())?;
+
+        Ok(())
+    }
+}
+
+impl Storage for KvStorage {
+    fn write_retained(
+        &self,
+        topic: &str,
+        message: &[u8],
+        meta: &MessageMeta,
+        ttl: Option<Duration>,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<RetainedVersion, StorageError> {
+        // the slot is assumed to be absent, so that retaining to a new topic
+        // takes a single insert. if it exists, the insert fails and the slot
+        // is looked up for the next try
+        let version = self.write_slot(
+            topic,
+            Some((message, meta)),
+            ttl,
+            None,
+            settings,
+            Some(None),
+            deadline,
+        )?;
+
+        Ok(version.expect("writing a message should always produce a version"))
+    }
+
+    // the slots are looked up all at once. writes are then made one by one
+    fn write_retained_many(
+        &self,
+        writes: &[RetainedWrite],
+        deadline: Deadline,
+    ) -> Result<Vec<Result<RetainedVersion, StorageError>>, StorageError> {
+        let keys: Vec<String> = writes.iter().map(|w| format!("r:{}", w.topic)).collect();
+
+        let items = self.kv.lookup_many(&keys)?;
+
+        let mut out = Vec::new();
+
+        for (w, item) in writes.iter().zip(items) {
+            let slot = match item {
+                Some(item) => match lookup_metadata(&item) {
+                    Ok(meta) => Some((item, meta)),
+                    Err(e) => {
+                        out.push(Err(e));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let result = self.write_slot(
+                w.topic,
+                Some((w.message, w.meta)),
+                w.ttl,
+                None,
+                w.settings,
+                Some(slot),
+                deadline,
+            );
+
+            out.push(result.map(|v| v.expect("writing a message should always produce a version")));
+        }
+
+        Ok(out)
+    }
+
+    fn delete_retained(
+        &self,
+        topic: &str,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Option<RetainedVersion>, StorageError> {
+        self.write_slot(topic, None, None, None, settings, None, deadline)
+    }
+
+    // the slot is removed last, so that a failed purge can be retried
+    fn purge_retained(&self, topic: &str) -> Result<bool, StorageError> {
+        let key_name = format!("r:{topic}");
+
+        let Some((_, meta)) = self.lookup(&key_name)? else {
+            return Ok(false);
+        };
+
+        if meta.depth > 1 {
+            for seq in 0..u64::from(meta.depth - 1) {
+                self.kv.delete(&ring_key(topic, seq, meta.depth))?;
+            }
+        }
+
+        for i in 0..meta.chunks {
+            self.kv
+                .delete(&chunk_key(topic, meta.generation, meta.seq, i))?;
+        }
+
+        self.kv.delete(&key_name)?;
+
+        Ok(true)
+    }
+
+    fn read_retained(
+        &self,
+        topic: &str,
+        after: Option<RetainedVersion>,
+    ) -> Result<Option<RetainedSlot>, StorageError> {
+        let key_name = format!("r:{topic}");
+
+        match self.lookup(&key_name)? {
+            Some((item, meta)) => self.retained_slot(topic, item, meta, after),
+            None => Ok(None),
+        }
+    }
+
+    fn read_retained_many(
+        &self,
+        reads: &[(&str, Option<RetainedVersion>)],
+    ) -> Result<Vec<Option<RetainedSlot>>, StorageError> {
+        let keys: Vec<String> = reads
+            .iter()
+            .map(|(topic, _)| format!("r:{topic}"))
+            .collect();
+
+        let items = self.kv.lookup_many(&keys)?;
+
+        let mut out = Vec::new();
+
+        for ((topic, after), item) in reads.iter().zip(items) {
+            let Some(item) = item else {
+                out.push(None);
+                continue;
+            };
+
+            let meta = lookup_metadata(&item)?;
+
+            out.push(self.retained_slot(topic, item, meta, *after)?);
+        }
+
+        Ok(out)
+    }
+
+    fn list_retained(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<RetainedList, StorageError> {
+        let page = self.kv.list(&format!("r:{prefix}"), cursor, Some(limit))?;
+
+        let topics = page
+            .keys
+            .into_iter()
+            .filter_map(|key| key.strip_prefix("r:").map(|s| s.to_string()))
+            .collect();
+
+        Ok(RetainedList {
+            topics,
+            cursor: page.cursor,
+        })
+    }
+
+    fn write_transaction(
+        &self,
+        messages: &[TransactionMessage],
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<RetainedVersion>, StorageError> {
+        let id = format!("{:016x}", rand::random::<u64>());
+
+        let mut txn = Transaction {
+            committed: false,
+            linger: settings.linger.as_secs(),
+            messages: messages.to_vec(),
+        };
+
+        self.write_transaction_record(&id, &txn, Condition::Absent)?;
+
+        self.complete_transaction(&id, &mut txn, settings, deadline)
+    }
+
+    fn write_cursors(
+        &self,
+        key: &str,
+        cursors: &HashMap<String, String>,
+    ) -> Result<(), StorageError> {
+        self.write_json(&format!("c:{key}"), cursors, CURSORS_TTL)
+    }
+
+    fn read_cursors(&self, key: &str) -> Result<Option<HashMap<String, String>>, StorageError> {
+        self.read_json(&format!("c:{key}"))
+    }
+
+    fn write_stream_topics(&self, cid: &str, topics: &[String]) -> Result<(), StorageError> {
+        self.write_json(&format!("t:{cid}"), topics, STREAM_TOPICS_TTL)
+    }
+
+    fn read_stream_topics(&self, cid: &str) -> Result<Option<Vec<String>>, StorageError> {
+        self.read_json(&format!("t:{cid}"))
+    }
+
+    fn delete_stream_topics(&self, cid: &str) -> Result<(), StorageError> {
+        Ok(self.kv.delete(&format!("t:{cid}"))?)
+    }
+
+    fn write_session(&self, id: &str, state: &[u8]) -> Result<(), StorageError> {
+        let insert = Insert {
+            ttl: Some(SESSION_TTL),
+            ..Default::default()
+        };
+
+        Ok(self
+            .kv
+            .insert(&format!("e:{id}"), state.to_vec(), &insert)?)
+    }
+
+    fn read_session(&self, id: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.kv.lookup(&format!("e:{id}"))?.map(|item| item.value))
+    }
+
+    // members are kept in a single item per topic, mapped to the unix time
+    // they expire at
+    fn update_presence(
+        &self,
+        topic: &str,
+        member: &str,
+        expires_at: Option<u64>,
+        deadline: Deadline,
+    ) -> Result<PresenceChange, StorageError> {
+        let key_name = format!("m:{topic}");
+
+        let mut tries = 0;
+
+        loop {
+            let (mut members, condition): (HashMap<String, u64>, _) =
+                match self.kv.lookup(&key_name)? {
+                    Some(item) => match serde_json::from_slice(&item.value) {
+                        Ok(v) => (v, Condition::Generation(item.generation)),
+                        Err(_) => return Err(StorageError::InvalidValue),
+                    },
+                    None => (HashMap::new(), Condition::Absent),
+                };
+
+            let now = unix_now();
+
+            members.retain(|_, t| *t > now);
+
+            let changed = match expires_at {
+                Some(t) => {
+                    if members.len() >= PRESENCE_MEMBERS_MAX && !members.contains_key(member) {
+                        return Ok(PresenceChange {
+                            changed: false,
+                            count: members.len(),
+                        });
+                    }
+
+                    members.insert(member.to_string(), t).is_none()
+                }
+                None => {
+                    if members.remove(member).is_none() {
+                        return Ok(PresenceChange {
+                            changed: false,
+                            count: members.len(),
+                        });
+                    }
+
+                    true
+                }
+            };
+
+            let ttl = members.values().max().map_or(Duration::ZERO, |t| {
+                Duration::from_secs(t.saturating_sub(now))
+            }) + PRESENCE_LINGER;
+
+            let value =
+                serde_json::to_vec(&members).expect("members should always be serializable");
+
+            let insert = Insert {
+                ttl: Some(ttl),
+                condition,
+                ..Default::default()
+            };
+
+            match self.kv.insert(&key_name, value, &insert) {
+                Ok(()) => {
+                    return Ok(PresenceChange {
+                        changed,
+                        count: members.len(),
+                    })
+                }
+                Err(KvError::PreconditionFailed) => {}
+                Err(KvError::TooManyRequests) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            tries += 1;
+
+            if tries >= PRESENCE_TRIES_MAX {
+                return Err(StorageError::TooManyRequests);
+            }
+
+            if deadline.expired() {
+                return Err(StorageError::DeadlineExceeded);
+            }
+
+            stats::incr(Counter::StorageRetries, 1);
+        }
+    }
+
+    fn read_presence(&self, topic: &str) -> Result<Vec<String>, StorageError> {
+        let members: HashMap<String, u64> = match self.read_json(&format!("m:{topic}"))? {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+
+        let now = unix_now();
+
+        let mut out: Vec<String> = members
+            .into_iter()
+            .filter(|(_, t)| *t > now)
+            .map(|(m, _)| m)
+            .collect();
+
+        out.sort();
+
+        Ok(out)
+    }
+
+    // public keys are uploaded by the operator, directly to the store
+    fn read_public_keys(&self, topic: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let item = self.kv.lookup(&format!("p:{topic}"))?;
+
+        Ok(item.map(|item| item.value))
+    }
+
+    fn record_nonce(&self, nonce: &str, ttl: Duration) -> Result<bool, StorageError> {
+        let insert = Insert {
+            ttl: Some(ttl),
+            condition: Condition::Absent,
+            ..Default::default()
+        };
+
+        match self.kv.insert(&format!("o:{nonce}"), Vec::new(), &insert) {
+            Ok(()) => Ok(true),
+            Err(KvError::PreconditionFailed) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn reserve_idempotency_key(
+        &self,
+        key: &str,
+        hash: &str,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let key_name = format!("i:{key}");
+
+        let record = IdempotencyRecord {
+            hash: hash.to_string(),
+            result: None,
+        };
+
+        let insert = Insert {
+            ttl: Some(IDEMPOTENCY_PENDING_TTL),
+            condition: Condition::Absent,
+            ..Default::default()
+        };
+
+        match self
+            .kv
+            .insert(&key_name, serde_json::to_vec(&record).unwrap(), &insert)
+        {
+            Ok(()) => Ok(None),
+            // if the record expired since, the request goes ahead
+            // unreserved
+            Err(KvError::PreconditionFailed) => self.read_json(&key_name),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_idempotent_result(
+        &self,
+        key: &str,
+        hash: &str,
+        result: &IdempotentResult,
+    ) -> Result<(), StorageError> {
+        let record = IdempotencyRecord {
+            hash: hash.to_string(),
+            result: Some(result.clone()),
+        };
 
-    Ok(Some((lookup, meta)))
-}
+        self.write_json(&format!("i:{key}"), &record, IDEMPOTENCY_TTL)
+    }
 
-pub trait Storage {
-    fn write_retained(
-        &self,
-        topic: &str,
-        message: &[u8],
-        ttl: Option<Duration>,
-    ) -> Result<RetainedVersion, StorageError>;
+    fn release_idempotency_key(&self, key: &str) -> Result<(), StorageError> {
+        Ok(self.kv.delete(&format!("i:{key}"))?)
+    }
 
-    fn read_retained(
+    fn write_scheduled(
         &self,
         topic: &str,
-        after: Option<RetainedVersion>,
-    ) -> Result<Option<RetainedSlot>, StorageError>;
-}
+        message: &ScheduledMessage,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<(), StorageError> {
+        self.update_scheduled(topic, settings, deadline, |messages| {
+            if messages.len() >= SCHEDULED_MAX {
+                return Err(StorageError::LimitReached);
+            }
 
-pub struct KVStoreStorage {
-    store_name: String,
-}
+            messages.push(message.clone());
 
-impl KVStoreStorage {
-    pub fn new(store_name: &str) -> Self {
-        Self {
-            store_name: store_name.to_string(),
-        }
+            Ok(())
+        })
     }
-}
 
-impl Storage for KVStoreStorage {
-    fn write_retained(
+    fn take_scheduled(
         &self,
         topic: &str,
-        message: &[u8],
-        ttl: Option<Duration>,
-    ) -> Result<RetainedVersion, StorageError> {
-        let store = match KVStore::open(&self.store_name) {
-            Ok(Some(store)) => store,
-            Ok(None) | Err(KVStoreError::StoreNotFound(_)) => {
-                return Err(StorageError::StoreNotFound)
+        due_by: u64,
+        settings: RetainedSettings,
+        deadline: Deadline,
+    ) -> Result<Vec<ScheduledMessage>, StorageError> {
+        self.update_scheduled(topic, settings, deadline, |messages| {
+            let (mut due, pending): (Vec<_>, Vec<_>) =
+                messages.drain(..).partition(|m| m.due <= due_by);
+
+            *messages = pending;
+
+            // stable, so messages due at the same time keep their order
+            due.sort_by_key(|m| m.due);
+
+            Ok(due)
+        })
+    }
+
+    fn list_scheduled_topics(&self) -> Result<Vec<String>, StorageError> {
+        let mut topics = Vec::new();
+
+        let mut cursor = None;
+
+        loop {
+            let page = self.kv.list("q:", cursor.as_deref(), None)?;
+
+            for key in page.keys {
+                if let Some(topic) = key.strip_prefix("q:") {
+                    topics.push(topic.to_string());
+                }
             }
-            Err(e) => return Err(StorageError::KVStore(e)),
-        };
 
-        let key_name = format!("r:{topic}");
+            cursor = page.cursor;
 
-        let expires_at = ttl.map(|ttl| time::UtcDateTime::now() + ttl);
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(topics)
+    }
+
+    fn count_publishes(
+        &self,
+        key: &str,
+        count: u32,
+        limit: u32,
+        deadline: Deadline,
+    ) -> Result<(), StorageError> {
+        let key_name = format!("n:{key}:{}", unix_now() / 60);
 
         let mut tries = 0;
 
-        let version = loop {
-            let (mut meta, generation) = match lookup(&store, &key_name)? {
-                Some((lookup, meta)) => (meta, Some(lookup.current_generation())),
-                None => (Metadata::default(), None),
+        loop {
+            let (current, condition) = match self.kv.lookup(&key_name)? {
+                Some(item) => match serde_json::from_slice::<u32>(&item.value) {
+                    Ok(v) => (v, Condition::Generation(item.generation)),
+                    Err(_) => return Err(StorageError::InvalidValue),
+                },
+                None => (0, Condition::Absent),
             };
 
-            let insert = store.build_insert();
-
-            let insert = if let Some(generation) = generation {
-                meta.seq += 1;
+            let total = current.saturating_add(count);
 
-                insert.if_generation_match(generation)
-            } else {
-                meta.generation = rand::random();
-                meta.seq = 1;
+            if total > limit {
+                return Err(StorageError::LimitReached);
+            }
 
-                insert.mode(InsertMode::Add)
+            let insert = Insert {
+                ttl: Some(PUBLISH_COUNT_TTL),
+                condition,
+                ..Default::default()
             };
 
-            meta.expires_at = expires_at;
+            match self
+                .kv
+                .insert(&key_name, total.to_string().into_bytes(), &insert)
+            {
+                Ok(()) => return Ok(()),
+                Err(KvError::PreconditionFailed) => {}
+                Err(KvError::TooManyRequests) => {}
+                Err(e) => return Err(e.into()),
+            }
 
-            let meta_json =
-                serde_json::to_string(&meta).expect("metadata should always be serializable");
+            tries += 1;
+
+            if tries >= PUBLISH_COUNT_TRIES_MAX {
+                return Err(StorageError::TooManyRequests);
+            }
 
-            let insert = insert.metadata(&meta_json);
+            if deadline.expired() {
+                return Err(StorageError::DeadlineExceeded);
+            }
 
-            let insert = if let Some(ttl) = ttl {
-                // we set a TTL longer than the item's expiration time, to
-                // allow the opportunity to reuse the item after expiration
-                insert.time_to_live(ttl + LINGER)
-            } else {
-                insert
+            stats::incr(Counter::StorageRetries, 1);
+        }
+    }
+
+    // unreadable shards are skipped
+    fn read_quota_usage(&self, key: &str, period: &str) -> Result<u64, StorageError> {
+        let keys: Vec<String> = (0..QUOTA_SHARDS)
+            .map(|shard| format!("u:{key}:{period}:{shard}"))
+            .collect();
+
+        let items = self.kv.lookup_many(&keys)?;
+
+        let total = items
+            .iter()
+            .flatten()
+            .filter_map(|item| serde_json::from_slice::<u64>(&item.value).ok())
+            .sum();
+
+        Ok(total)
+    }
+
+    fn add_quota_usage(
+        &self,
+        key: &str,
+        period: &str,
+        count: u32,
+        ttl: Duration,
+        deadline: Deadline,
+    ) -> Result<(), StorageError> {
+        let shard = rand::random::<u32>() % QUOTA_SHARDS;
+        let key_name = format!("u:{key}:{period}:{shard}");
+
+        let mut tries = 0;
+
+        loop {
+            let (current, condition) = match self.kv.lookup(&key_name)? {
+                Some(item) => match serde_json::from_slice::<u64>(&item.value) {
+                    Ok(v) => (v, Condition::Generation(item.generation)),
+                    Err(_) => return Err(StorageError::InvalidValue),
+                },
+                None => (0, Condition::Absent),
             };
 
-            match insert.execute(&key_name, message.to_vec()) {
-                Ok(()) => {
-                    break RetainedVersion {
-                        generation: meta.generation,
-                        seq: meta.seq,
-                    }
-                }
-                Err(KVStoreError::ItemPreconditionFailed) => {}
-                Err(KVStoreError::TooManyRequests) => {}
-                Err(e) => return Err(StorageError::KVStore(e)),
+            let total = current.saturating_add(count.into());
+
+            let insert = Insert {
+                ttl: Some(ttl),
+                condition,
+                ..Default::default()
+            };
+
+            match self
+                .kv
+                .insert(&key_name, total.to_string().into_bytes(), &insert)
+            {
+                Ok(()) => return Ok(()),
+                Err(KvError::PreconditionFailed) => {}
+                Err(KvError::TooManyRequests) => {}
+                Err(e) => return Err(e.into()),
             }
 
             tries += 1;
 
-            if tries >= WRITE_TRIES_MAX {
-                // getting conflicts or rate limit errors after several tries
+            if tries >= PUBLISH_COUNT_TRIES_MAX {
                 return Err(StorageError::TooManyRequests);
             }
-        };
 
-        Ok(version)
+            if deadline.expired() {
+                return Err(StorageError::DeadlineExceeded);
+            }
+
+            stats::incr(Counter::StorageRetries, 1);
+        }
     }
 
-    fn read_retained(
-        &self,
-        topic: &str,
-        after: Option<RetainedVersion>,
-    ) -> Result<Option<RetainedSlot>, StorageError> {
-        let store = match KVStore::open(&self.store_name) {
-            Ok(Some(store)) => store,
-            Ok(None) | Err(KVStoreError::StoreNotFound(_)) => {
-                return Err(StorageError::StoreNotFound)
+    fn add_stats(&self, counts: &Counts, deadline: Deadline) -> Result<(), StorageError> {
+        let shard = rand::random::<u32>() % STATS_SHARDS;
+        let key_name = format!("s:{}:{shard}", unix_now() / 60);
+
+        let mut tries = 0;
+
+        loop {
+            let (mut total, condition) = match self.kv.lookup(&key_name)? {
+                Some(item) => match serde_json::from_slice::<Counts>(&item.value) {
+                    Ok(v) => (v, Condition::Generation(item.generation)),
+                    Err(_) => return Err(StorageError::InvalidValue),
+                },
+                None => (Counts::default(), Condition::Absent),
+            };
+
+            total.add(counts);
+
+            let value = serde_json::to_vec(&total).expect("stats should always be serializable");
+
+            let insert = Insert {
+                ttl: Some(STATS_TTL),
+                condition,
+                ..Default::default()
+            };
+
+            match self.kv.insert(&key_name, value, &insert) {
+                Ok(()) => return Ok(()),
+                Err(KvError::PreconditionFailed) => {}
+                Err(KvError::TooManyRequests) => {}
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(StorageError::KVStore(e)),
-        };
 
-        let key_name = format!("r:{topic}");
+            tries += 1;
 
-        let (mut lookup, meta) = match lookup(&store, &key_name)? {
-            Some(ret) => ret,
-            None => return Ok(None),
-        };
+            if tries >= PUBLISH_COUNT_TRIES_MAX {
+                return Err(StorageError::TooManyRequests);
+            }
 
-        if let Some(after) = after {
-            if meta.generation == after.generation && meta.seq <= after.seq {
-                return Ok(None);
+            if deadline.expired() {
+                return Err(StorageError::DeadlineExceeded);
             }
         }
+    }
 
-        let version = RetainedVersion {
-            generation: meta.generation,
-            seq: meta.seq,
-        };
+    // unreadable shards are skipped
+    fn read_stats(&self, minutes: &[u64]) -> Result<Vec<Counts>, StorageError> {
+        let keys: Vec<String> = minutes
+            .iter()
+            .flat_map(|minute| (0..STATS_SHARDS).map(move |shard| format!("s:{minute}:{shard}")))
+            .collect();
 
-        let ttl = meta.expires_at.map(|expires_at| {
-            let now = time::UtcDateTime::now();
+        let items = self.kv.lookup_many(&keys)?;
 
-            if now < expires_at {
-                (expires_at - now).unsigned_abs()
-            } else {
-                Duration::from_millis(0)
-            }
-        });
+        let out = items
+            .chunks(STATS_SHARDS as usize)
+            .map(|shards| {
+                let mut counts = Counts::default();
+
+                for item in shards.iter().flatten() {
+                    if let Ok(v) = serde_json::from_slice::<Counts>(&item.value) {
+                        counts.add(&v);
+                    }
+                }
 
-        let message = if ttl != Some(Duration::from_millis(0)) {
-            let value = lookup.take_body_bytes();
+                counts
+            })
+            .collect();
 
-            Some(RetainedMessage { ttl, data: value })
-        } else {
-            None
+        Ok(out)
+    }
+
+    // entries are never overwritten. keys sort by time, so that the log can
+    // be read in order by listing them
+    fn append_audit_entry(&self, time: u64, data: Vec<u8>) -> Result<(), StorageError> {
+        let key_name = format!("a:{time:016x}-{:08x}", rand::random::<u32>());
+
+        let insert = Insert {
+            ttl: Some(AUDIT_ENTRY_TTL),
+            condition: Condition::Absent,
+            ..Default::default()
         };
 
-        Ok(Some(RetainedSlot { version, message }))
+        Ok(self.kv.insert(&key_name, data, &insert)?)
+    }
+
+    fn write_log_limiter(&self, limiter: &Limiter) -> Result<(), StorageError> {
+        self.write_json("l:errors", limiter, LOG_LIMITER_TTL)
+    }
+
+    fn read_log_limiter(&self) -> Result<Option<Limiter>, StorageError> {
+        self.read_json("l:errors")
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "fastly")]
+    use crate::kv::FastlyKv;
+    use crate::memorykv::MemoryKv;
     use std::str;
 
+    #[cfg(feature = "fastly")]
     #[test]
     fn retained() {
-        let storage = KVStoreStorage::new("messages");
+        let storage = KvStorage::new(Box::new(FastlyKv::new("messages")));
 
         assert!(storage
             .read_retained("storage-test", None)
@@ -238,7 +1971,14 @@ mod tests {
             .is_none());
 
         let v1 = storage
-            .write_retained("storage-test", "hello".as_bytes(), None)
+            .write_retained(
+                "storage-test",
+                "hello".as_bytes(),
+                &MessageMeta::default(),
+                None,
+                RetainedSettings::default(),
+                Deadline::none(),
+            )
             .unwrap();
         assert_eq!(v1.seq, 1);
 
@@ -256,7 +1996,10 @@ mod tests {
             .write_retained(
                 "storage-test",
                 "world".as_bytes(),
+                &MessageMeta::default(),
                 Some(Duration::from_secs(60)),
+                RetainedSettings::default(),
+                Deadline::none(),
             )
             .unwrap();
         assert_eq!(v2.generation, v1.generation);
@@ -280,16 +2023,335 @@ mod tests {
             .is_none());
 
         // delete item so next write gets a new generation
-        KVStore::open(&storage.store_name)
+        fastly::KVStore::open("messages")
             .unwrap()
             .unwrap()
             .delete("r:storage-test")
             .unwrap();
 
         let new_v1 = storage
-            .write_retained("storage-test", "hello".as_bytes(), None)
+            .write_retained(
+                "storage-test",
+                "hello".as_bytes(),
+                &MessageMeta::default(),
+                None,
+                RetainedSettings::default(),
+                Deadline::none(),
+            )
             .unwrap();
         assert!(new_v1.generation != v1.generation);
         assert_eq!(new_v1.seq, 1);
     }
+
+    #[test]
+    fn retained_in_memory() {
+        let storage = KvStorage::new(Box::new(MemoryKv::new()));
+
+        let write = |data: &str, ttl| {
+            storage
+                .write_retained(
+                    "storage-test",
+                    data.as_bytes(),
+                    &MessageMeta::default(),
+                    ttl,
+                    RetainedSettings::default(),
+                    Deadline::none(),
+                )
+                .unwrap()
+        };
+
+        assert!(storage
+            .read_retained("storage-test", None)
+            .unwrap()
+            .is_none());
+
+        let v1 = write("hello", None);
+        assert_eq!(v1.seq, 1);
+
+        let v2 = write("world", Some(Duration::from_secs(60)));
+        assert_eq!(v2.generation, v1.generation);
+        assert_eq!(v2.seq, 2);
+
+        let s = storage
+            .read_retained("storage-test", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(s.version.seq, 2);
+        assert_eq!(s.message.unwrap().data, b"world");
+
+        assert!(storage
+            .read_retained("storage-test", Some(s.version))
+            .unwrap()
+            .is_none());
+
+        storage.kv.delete("r:storage-test").unwrap();
+
+        let new_v1 = write("hello", None);
+        assert_ne!(new_v1.generation, v1.generation);
+        assert_eq!(new_v1.seq, 1);
+    }
+
+    #[test]
+    fn encrypted_in_memory() {
+        let keys = Keys::new(Some(b"notasecret"), None);
+
+        let storage = KvStorage::new(Box::new(MemoryKv::new())).with_keys(keys);
+
+        storage
+            .write_retained(
+                "storage-test",
+                b"hello",
+                &MessageMeta::default(),
+                None,
+                RetainedSettings::default(),
+                Deadline::none(),
+            )
+            .unwrap();
+
+        let item = storage.kv.lookup("r:storage-test").unwrap().unwrap();
+        assert!(!item.value.windows(5).any(|w| w == b"hello"));
+
+        let s = storage
+            .read_retained("storage-test", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(s.message.unwrap().data, b"hello");
+
+        // the same data can't be read without the key
+        let other = KvStorage::new(Box::new(MemoryKv::new()));
+        other
+            .kv
+            .insert(
+                "r:storage-test",
+                item.value,
+                &Insert {
+                    metadata: item
+                        .metadata
+                        .as_deref()
+                        .map(|m| std::str::from_utf8(m).unwrap()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(other.read_retained("storage-test", None).is_err());
+    }
+
+    #[test]
+    fn memory_ttl_and_seq() {
+        let storage = KvStorage::new(Box::new(MemoryKv::new()));
+
+        let settings = RetainedSettings {
+            linger: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        let write = |topic: &str, data: &[u8], ttl: Option<Duration>| {
+            storage
+                .write_retained(
+                    topic,
+                    data,
+                    &MessageMeta::default(),
+                    ttl,
+                    settings,
+                    Deadline::none(),
+                )
+                .unwrap()
+        };
+
+        let v1 = write("storage-test", b"a", None);
+        assert_eq!(v1.seq, 1);
+
+        let v2 = write("storage-test", b"b", None);
+        assert_eq!(v2.seq, 2);
+        assert_eq!(v2.generation, v1.generation);
+
+        // an expired message lingers, so that its sequence continues
+        let v3 = write("storage-test", b"c", Some(Duration::from_millis(1)));
+        assert_eq!(v3.seq, 3);
+
+        std::thread::sleep(Duration::from_millis(5));
+
+        let s = storage
+            .read_retained("storage-test", None)
+            .unwrap()
+            .unwrap();
+        assert!(s.message.is_none());
+        assert_eq!(s.version.seq, 3);
+
+        let v4 = write("storage-test", b"d", None);
+        assert_eq!(v4.seq, 4);
+        assert_eq!(v4.generation, v1.generation);
+
+        // without lingering, the slot is removed, and the sequence restarts
+        let settings = RetainedSettings {
+            linger: Duration::ZERO,
+            ..settings
+        };
+
+        storage
+            .write_retained(
+                "storage-test2",
+                b"a",
+                &MessageMeta::default(),
+                Some(Duration::ZERO),
+                settings,
+                Deadline::none(),
+            )
+            .unwrap();
+        assert!(storage
+            .read_retained("storage-test2", None)
+            .unwrap()
+            .is_none());
+
+        let v = write("storage-test2", b"b", None);
+        assert_eq!(v.seq, 1);
+    }
+
+    #[test]
+    fn idempotency() {
+        let storage = KvStorage::new(Box::new(MemoryKv::new()));
+
+        assert!(storage
+            .reserve_idempotency_key("k", "h1")
+            .unwrap()
+            .is_none());
+
+        // reserved, with the request in progress
+        let r = storage.reserve_idempotency_key("k", "h1").unwrap().unwrap();
+        assert_eq!(r.hash, "h1");
+        assert!(r.result.is_none());
+
+        let result = IdempotentResult {
+            status: 200,
+            body: "Published\n".to_string(),
+            content_type: None,
+        };
+
+        storage.write_idempotent_result("k", "h1", &result).unwrap();
+
+        // retried with the same or a different message
+        for hash in ["h1", "h2"] {
+            let r = storage.reserve_idempotency_key("k", hash).unwrap().unwrap();
+            assert_eq!(r.hash, "h1");
+            assert_eq!(r.result.unwrap().body, "Published\n");
+        }
+
+        // a failed request frees the key
+        assert!(storage
+            .reserve_idempotency_key("k2", "h1")
+            .unwrap()
+            .is_none());
+        storage.release_idempotency_key("k2").unwrap();
+        assert!(storage
+            .reserve_idempotency_key("k2", "h2")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn version_order() {
+        let v = RetainedVersion {
+            epoch: 1,
+            generation: 7,
+            seq: 3,
+            started: 1000,
+        };
+
+        assert_eq!(
+            v.order(&RetainedVersion { seq: 2, ..v }),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(v.order(&v), Some(Ordering::Equal));
+
+        let other = RetainedVersion {
+            generation: 8,
+            seq: 9,
+            started: 2000,
+            ..v
+        };
+        assert_eq!(v.order(&other), Some(Ordering::Less));
+        assert_eq!(other.order(&v), Some(Ordering::Greater));
+
+        // unknown start times
+        assert_eq!(
+            v.order(&RetainedVersion {
+                started: 0,
+                ..other
+            }),
+            None
+        );
+        assert_eq!(
+            v.order(&RetainedVersion {
+                started: 1000,
+                ..other
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn scheduled_serialization() {
+        let m = ScheduledMessage {
+            due: 1000,
+            data: vec![0xff, 0x00],
+            meta: MessageMeta::default(),
+            retain: false,
+            ttl: None,
+            namespace: None,
+            targets: Vec::new(),
+        };
+
+        let s = serde_json::to_string(&m).unwrap();
+        assert_eq!(s, r#"{"due":1000,"data":"/wA="}"#);
+
+        let m: ScheduledMessage = serde_json::from_str(&s).unwrap();
+        assert_eq!(m.data, vec![0xff, 0x00]);
+    }
+
+    #[test]
+    fn value_encoding() {
+        let small = b"hello";
+        let (v, encoding) = encode_value(small);
+        assert_eq!(encoding, None);
+        assert_eq!(&*v, small);
+
+        let large = "hello world. ".repeat(100);
+        let (v, encoding) = encode_value(large.as_bytes());
+        assert_eq!(encoding, Some(Encoding::Gzip));
+        assert!(v.len() < large.len());
+        assert_eq!(
+            decode_value(&Keys::default(), v.into_owned(), encoding, None).unwrap(),
+            large.as_bytes()
+        );
+
+        // incompressible
+        let random: Vec<u8> = (0..2000).map(|_| rand::random()).collect();
+        let (_, encoding) = encode_value(&random);
+        assert_eq!(encoding, None);
+    }
+
+    #[test]
+    fn transaction_serialization() {
+        let txn = Transaction {
+            committed: false,
+            linger: 60,
+            messages: vec![TransactionMessage {
+                topic: "a".to_string(),
+                data: b"hello".to_vec(),
+                meta: MessageMeta::default(),
+                ttl: Some(30),
+                depth: 0,
+            }],
+        };
+
+        let s = serde_json::to_string(&txn).unwrap();
+        assert_eq!(
+            s,
+            r#"{"linger":60,"messages":[{"topic":"a","data":"aGVsbG8=","ttl":30}]}"#
+        );
+
+        let txn: Transaction = serde_json::from_str(&s).unwrap();
+        assert!(!txn.committed);
+        assert_eq!(txn.messages[0].data, b"hello");
+    }
 }
@@ -0,0 +1,280 @@
+use serde::Serialize;
+use thiserror::Error;
+
+// topic levels are separated by slashes, as in MQTT
+pub const SEPARATOR: char = '/';
+
+// in patterns, matches any single level
+pub const WILDCARD: &str = "*";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TopicError {
+    #[error("topic is empty")]
+    Empty,
+
+    #[error("topic has an empty level")]
+    EmptyLevel,
+
+    #[error("topic exceeds {0} bytes")]
+    TooLong(usize),
+
+    #[error("topic exceeds {0} levels")]
+    TooDeep(usize),
+
+    #[error("topic contains character not allowed: {0:?}")]
+    InvalidChar(char),
+
+    #[error("topic is reserved")]
+    Reserved,
+}
+
+// limits on the topics that clients name, applied the same way by every
+// transport
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rules {
+    // in bytes
+    pub length_max: usize,
+
+    pub depth_max: usize,
+
+    // characters allowed besides ASCII letters and digits and the separator.
+    // any characters are allowed if None
+    pub allowed_chars: Option<String>,
+
+    // topics beginning with these can be subscribed to but not published to
+    // by clients, as with "$" in MQTT
+    pub reserved_prefixes: Vec<String>,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            length_max: 512,
+            depth_max: 32,
+            allowed_chars: None,
+            reserved_prefixes: vec!["$".to_string()],
+        }
+    }
+}
+
+// control characters can't be carried in headers, and commas and
+// semicolons would split the channel lists that fanout is given
+fn is_always_invalid(c: char) -> bool {
+    c.is_control() || c == ',' || c == ';'
+}
+
+// checks a topic for subscribing
+pub fn validate(rules: &Rules, topic: &str) -> Result<(), TopicError> {
+    let levels = parse(topic)?;
+
+    if topic.len() > rules.length_max {
+        return Err(TopicError::TooLong(rules.length_max));
+    }
+
+    if levels.len() > rules.depth_max {
+        return Err(TopicError::TooDeep(rules.depth_max));
+    }
+
+    for c in topic.chars() {
+        let allowed = match &rules.allowed_chars {
+            _ if is_always_invalid(c) => false,
+            Some(chars) => c.is_ascii_alphanumeric() || c == SEPARATOR || chars.contains(c),
+            None => true,
+        };
+
+        if !allowed {
+            return Err(TopicError::InvalidChar(c));
+        }
+    }
+
+    Ok(())
+}
+
+// checks a topic for publishing
+pub fn validate_publish(rules: &Rules, topic: &str) -> Result<(), TopicError> {
+    validate(rules, topic)?;
+
+    if rules
+        .reserved_prefixes
+        .iter()
+        .any(|p| topic.starts_with(p.as_str()))
+    {
+        return Err(TopicError::Reserved);
+    }
+
+    Ok(())
+}
+
+// splits a topic into its levels, rejecting topics that are empty or that
+// contain empty levels (e.g. leading, trailing or repeated separators)
+pub fn parse(topic: &str) -> Result<Vec<&str>, TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    let levels: Vec<&str> = topic.split(SEPARATOR).collect();
+
+    if levels.iter().any(|l| l.is_empty()) {
+        return Err(TopicError::EmptyLevel);
+    }
+
+    Ok(levels)
+}
+
+// removes empty levels, e.g. "/a//b/" becomes "a/b"
+pub fn normalize(topic: &str) -> String {
+    let levels: Vec<&str> = topic.split(SEPARATOR).filter(|l| !l.is_empty()).collect();
+
+    levels.join(&SEPARATOR.to_string())
+}
+
+pub fn depth(topic: &str) -> usize {
+    topic.split(SEPARATOR).count()
+}
+
+pub fn parent(topic: &str) -> Option<&str> {
+    topic.rfind(SEPARATOR).map(|pos| &topic[..pos])
+}
+
+pub struct Ancestors<'a> {
+    cur: &'a str,
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let p = parent(self.cur)?;
+        self.cur = p;
+
+        Some(p)
+    }
+}
+
+// iterates over the ancestors of a topic, nearest first. the topic itself
+// is not included
+pub fn ancestors(topic: &str) -> Ancestors<'_> {
+    Ancestors { cur: topic }
+}
+
+// returns true if topic is root or is a descendant of root
+pub fn is_within(topic: &str, root: &str) -> bool {
+    match topic.strip_prefix(root) {
+        Some(rest) => rest.is_empty() || rest.starts_with(SEPARATOR),
+        None => false,
+    }
+}
+
+// returns true if topic matches pattern, level by level. a wildcard level
+// matches any one level
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut p = pattern.split(SEPARATOR);
+    let mut t = topic.split(SEPARATOR);
+
+    loop {
+        match (p.next(), t.next()) {
+            (Some(pl), Some(tl)) => {
+                if pl != WILDCARD && pl != tl {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_levels() {
+        assert_eq!(parse("a").unwrap(), vec!["a"]);
+        assert_eq!(parse("a/b/c").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(parse(""), Err(TopicError::Empty));
+        assert_eq!(parse("/a"), Err(TopicError::EmptyLevel));
+        assert_eq!(parse("a/"), Err(TopicError::EmptyLevel));
+        assert_eq!(parse("a//b"), Err(TopicError::EmptyLevel));
+        assert_eq!(parse("/"), Err(TopicError::EmptyLevel));
+    }
+
+    #[test]
+    fn rules() {
+        let r = Rules::default();
+        assert_eq!(validate(&r, "a/b c/d-e"), Ok(()));
+        assert_eq!(validate(&r, "a//b"), Err(TopicError::EmptyLevel));
+        assert_eq!(validate(&r, "a,b"), Err(TopicError::InvalidChar(',')));
+        assert_eq!(validate(&r, "a\nb"), Err(TopicError::InvalidChar('\n')));
+        assert_eq!(
+            validate(&r, &"a".repeat(513)),
+            Err(TopicError::TooLong(512))
+        );
+        assert_eq!(
+            validate(&r, &["a"; 33].join("/")),
+            Err(TopicError::TooDeep(32))
+        );
+        assert_eq!(validate(&r, "$presence/a"), Ok(()));
+        assert_eq!(
+            validate_publish(&r, "$presence/a"),
+            Err(TopicError::Reserved)
+        );
+        assert_eq!(validate_publish(&r, "a/$b"), Ok(()));
+
+        let r = Rules {
+            allowed_chars: Some("-_".to_string()),
+            reserved_prefixes: vec![],
+            ..Default::default()
+        };
+        assert_eq!(validate(&r, "room-1/user_2"), Ok(()));
+        assert_eq!(validate(&r, "a b"), Err(TopicError::InvalidChar(' ')));
+        assert_eq!(
+            validate_publish(&r, "$a"),
+            Err(TopicError::InvalidChar('$'))
+        );
+    }
+
+    #[test]
+    fn normalize_levels() {
+        assert_eq!(normalize("a/b"), "a/b");
+        assert_eq!(normalize("/a//b/"), "a/b");
+        assert_eq!(normalize("//"), "");
+        assert_eq!(normalize(""), "");
+    }
+
+    #[test]
+    fn hierarchy() {
+        assert_eq!(depth("a"), 1);
+        assert_eq!(depth("a/b/c"), 3);
+
+        assert_eq!(parent("a/b/c"), Some("a/b"));
+        assert_eq!(parent("a"), None);
+
+        let a: Vec<&str> = ancestors("building1/floor2/room3").collect();
+        assert_eq!(a, vec!["building1/floor2", "building1"]);
+
+        assert_eq!(ancestors("a").count(), 0);
+    }
+
+    #[test]
+    fn within() {
+        assert!(is_within("a/b", "a/b"));
+        assert!(is_within("a/b/c", "a/b"));
+        assert!(is_within("a/b/c/d", "a"));
+        assert!(!is_within("a/bc", "a/b"));
+        assert!(!is_within("a", "a/b"));
+        assert!(!is_within("b/a", "a"));
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("a/b", "a/b"));
+        assert!(matches("chat/*", "chat/room1"));
+        assert!(matches("*/inbox", "alice/inbox"));
+        assert!(matches("*", "a"));
+        assert!(!matches("chat/*", "chat"));
+        assert!(!matches("chat/*", "chat/room1/x"));
+        assert!(!matches("chat/*", "news/room1"));
+        assert!(!matches("a/b", "a/bc"));
+    }
+}
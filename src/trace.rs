@@ -0,0 +1,113 @@
+use std::fmt;
+
+// name of the W3C trace context header, also used for MQTT user properties
+pub const TRACEPARENT: &str = "traceparent";
+
+// a W3C trace context, identifying a trace and the span within it that
+// made a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub flags: String,
+}
+
+fn is_lower_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl TraceParent {
+    // values from later versions are read as version 00, as the spec
+    // recommends. invalid values are ignored rather than rejected
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().split('-');
+
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if !is_lower_hex(version, 2) || version == "ff" {
+            return None;
+        }
+
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+
+        if !is_lower_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+
+        if !is_lower_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+
+        if !is_lower_hex(flags, 2) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            flags: flags.to_string(),
+        })
+    }
+
+    // a new span in the same trace, whose parent is this one
+    pub fn child(&self) -> Self {
+        let mut id = [0; 8];
+
+        while id == [0; 8] {
+            id = rand::random();
+        }
+
+        Self {
+            trace_id: self.trace_id.clone(),
+            parent_id: hex::encode(id),
+            flags: self.flags.clone(),
+        }
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "00-{}-{}-{}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent() {
+        let s = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+        let t = TraceParent::parse(s).unwrap();
+        assert_eq!(t.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(t.parent_id, "00f067aa0ba902b7");
+        assert_eq!(t.to_string(), s);
+
+        let c = t.child();
+        assert_eq!(c.trace_id, t.trace_id);
+        assert_eq!(c.flags, t.flags);
+        assert_ne!(c.parent_id, t.parent_id);
+        assert_eq!(TraceParent::parse(&c.to_string()), Some(c));
+
+        // later versions may add fields
+        let t = TraceParent::parse(&format!("01{}-extra", &s[2..])).unwrap();
+        assert_eq!(t.to_string(), s);
+
+        assert!(TraceParent::parse(&format!("{s}-extra")).is_none());
+        assert!(TraceParent::parse(&s.to_uppercase()).is_none());
+        assert!(TraceParent::parse(&format!("ff{}", &s[2..])).is_none());
+        assert!(
+            TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01").is_none()
+        );
+        assert!(TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736").is_none());
+    }
+}
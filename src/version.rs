@@ -0,0 +1,72 @@
+use crate::config::Config;
+use crate::storage::current_epoch;
+use fastly::http::{header, StatusCode};
+use fastly::Response;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Features {
+    pub sse: bool,
+    pub mqtt: bool,
+    pub bayeux: bool,
+    pub socketio: bool,
+    pub http_publish: bool,
+    pub admin: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+
+    // commit the app was built from, if known. see build.rs
+    pub git_sha: Option<&'static str>,
+
+    // version of the service configuration, if deployed
+    pub service_version: Option<u32>,
+
+    pub features: Features,
+}
+
+pub fn info(config: &Config) -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: option_env!("PUBSUB_GIT_SHA"),
+        service_version: Some(current_epoch()).filter(|&v| v > 0),
+        features: Features {
+            sse: config.sse_enabled,
+            mqtt: config.mqtt_enabled,
+            bayeux: config.bayeux_enabled,
+            socketio: config.socketio_enabled,
+            http_publish: config.http_publish_enabled,
+            admin: config.admin_enabled,
+        },
+    }
+}
+
+// no auth is required. nothing here is secret, and it helps confirm what is
+// deployed
+pub fn get(config: &Config) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_json(&info(config))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_info() {
+        let config = Config {
+            mqtt_enabled: false,
+            ..Default::default()
+        };
+
+        let v = serde_json::to_value(info(&config)).unwrap();
+        assert_eq!(v["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(v["features"]["sse"], true);
+        assert_eq!(v["features"]["mqtt"], false);
+        assert_eq!(v["features"]["http_publish"], true);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config::{Config, SubscriptionWebhook};
+use crate::log_error;
+use crate::receipt::{sign, Transport, SIGNATURE_HEADER};
+use crate::remotekv::url_backend;
+use crate::storage::unix_now_ms;
+use crate::topic;
+use fastly::http::header;
+use fastly::Request;
+use serde::Serialize;
+use std::sync::Mutex;
+
+// events beyond this are dropped, so that a large subscribe can't make for
+// a large request
+const EVENTS_PER_REQUEST_MAX: usize = 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Subscribe,
+    Unsubscribe,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub action: Action,
+
+    // full name, including any namespace
+    pub topic: String,
+
+    // MQTT client ID, SSE stream connection ID or token subject, as
+    // tracked for presence
+    pub subscriber: String,
+
+    pub transport: Transport,
+
+    // unix milliseconds
+    pub time: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Body<'a> {
+    events: &'a [Event],
+}
+
+// events for subscriptions changed while handling the current request, not
+// yet sent
+static PENDING: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+fn is_selected(webhook: &SubscriptionWebhook, topic: &str) -> bool {
+    webhook.topics.is_empty() || webhook.topics.iter().any(|p| topic::matches(p, topic))
+}
+
+// notes a subscription change, if the webhook is configured and selects
+// the topic
+pub fn changed(
+    config: &Config,
+    action: Action,
+    topic: &str,
+    subscriber: &str,
+    transport: Transport,
+) {
+    let Some(webhook) = &config.subscription_webhook else {
+        return;
+    };
+
+    if !is_selected(webhook, topic) {
+        return;
+    }
+
+    let e = Event {
+        action,
+        topic: topic.to_string(),
+        subscriber: subscriber.to_string(),
+        transport,
+        time: unix_now_ms(),
+    };
+
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push(e);
+}
+
+// returns the pending events and clears them
+pub fn take() -> Vec<Event> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+// the events are sent together in one request. failures are logged, and
+// requests aren't retried
+pub fn send(webhook: &SubscriptionWebhook, events: Vec<Event>) {
+    if events.len() > EVENTS_PER_REQUEST_MAX {
+        log_error!(
+            "dropping {} subscription webhook events",
+            events.len() - EVENTS_PER_REQUEST_MAX
+        );
+    }
+
+    let events = &events[..events.len().min(EVENTS_PER_REQUEST_MAX)];
+
+    let backend = match url_backend(webhook.backend.as_deref(), "webhook", &webhook.url) {
+        Ok(b) => b,
+        Err(e) => {
+            log_error!("failed to send subscription webhook: {e}");
+            return;
+        }
+    };
+
+    let body = serde_json::to_vec(&Body { events }).expect("events should always be serializable");
+
+    let mut req = Request::post(&webhook.url).with_header(header::CONTENT_TYPE, "application/json");
+
+    if let Some(key) = &webhook.key {
+        req.set_header(SIGNATURE_HEADER, format!("sha256={}", sign(key, &body)));
+    }
+
+    match req.with_body(body).send(backend) {
+        Ok(resp) if resp.get_status().is_success() => {}
+        Ok(resp) => log_error!(
+            "subscription webhook failed with status {}",
+            resp.get_status().as_u16()
+        ),
+        Err(e) => log_error!("failed to send subscription webhook: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events() {
+        take();
+
+        changed(
+            &Config::default(),
+            Action::Subscribe,
+            "orders",
+            "alice",
+            Transport::Sse,
+        );
+        assert!(take().is_empty());
+
+        let config = Config {
+            subscription_webhook: Some(SubscriptionWebhook {
+                url: "https://example.com/hooks".to_string(),
+                backend: None,
+                topics: vec!["orders/*".to_string()],
+                key: None,
+            }),
+            ..Default::default()
+        };
+
+        changed(
+            &config,
+            Action::Subscribe,
+            "orders/eu",
+            "alice",
+            Transport::Mqtt,
+        );
+        changed(
+            &config,
+            Action::Subscribe,
+            "payments",
+            "alice",
+            Transport::Mqtt,
+        );
+        changed(
+            &config,
+            Action::Unsubscribe,
+            "orders/us",
+            "bob",
+            Transport::Sse,
+        );
+
+        let events = take();
+        assert_eq!(events.len(), 2);
+        assert!(take().is_empty());
+
+        let v: serde_json::Value = serde_json::to_value(Body { events: &events }).unwrap();
+        assert_eq!(v["events"][0]["action"], "subscribe");
+        assert_eq!(v["events"][0]["topic"], "orders/eu");
+        assert_eq!(v["events"][0]["transport"], "mqtt");
+        assert_eq!(v["events"][1]["action"], "unsubscribe");
+        assert_eq!(v["events"][1]["subscriber"], "bob");
+    }
+}
@@ -1,3 +1,4 @@
+use std::io::{self, Read, Write};
 use std::str;
 
 #[derive(Clone)]
@@ -77,3 +78,139 @@ pub fn parse_websocket_event(src: &[u8]) -> Result<(WsEvent, usize), ParseEventE
         size_so_far + 2,
     ))
 }
+
+pub enum ReadEventsError {
+    Parse,
+    TooLarge,
+    Io(io::Error),
+}
+
+// bodies are read this much at a time
+const READ_CHUNK_SIZE: usize = 16_384;
+
+// reads and parses events as the body arrives, so that only the events and
+// any partial event are held rather than the whole body. bodies larger than
+// size_max are rejected as soon as that much has been read
+pub fn read_websocket_events<R: Read>(
+    mut src: R,
+    size_max: usize,
+) -> Result<Vec<WsEvent>, ReadEventsError> {
+    let mut events = Vec::new();
+    let mut buf = Vec::new();
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    let mut total = 0;
+
+    loop {
+        let size = match src.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ReadEventsError::Io(e)),
+        };
+
+        total += size;
+
+        if total > size_max {
+            return Err(ReadEventsError::TooLarge);
+        }
+
+        buf.extend_from_slice(&chunk[..size]);
+
+        // parse whatever is complete. an error may just mean the rest of
+        // the event hasn't been read yet
+        let mut pos = 0;
+
+        while let Ok((e, size)) = parse_websocket_event(&buf[pos..]) {
+            events.push(e);
+            pos += size;
+        }
+
+        buf.drain(..pos);
+    }
+
+    // anything left over is an incomplete or invalid event
+    if !buf.is_empty() {
+        return Err(ReadEventsError::Parse);
+    }
+
+    Ok(events)
+}
+
+// writes the start of an event. if content_len is non-zero, the caller must
+// follow with that much content and then the footer
+pub fn write_websocket_event_header<W: Write>(
+    dest: &mut W,
+    etype: &str,
+    content_len: usize,
+) -> Result<(), io::Error> {
+    if content_len > 0 {
+        write!(dest, "{etype} {content_len:x}\r\n")
+    } else {
+        write!(dest, "{etype}\r\n")
+    }
+}
+
+pub fn write_websocket_event_footer<W: Write>(dest: &mut W) -> Result<(), io::Error> {
+    dest.write_all(b"\r\n")
+}
+
+pub fn write_websocket_event<W: Write>(
+    dest: &mut W,
+    etype: &str,
+    content: &[u8],
+) -> Result<(), io::Error> {
+    write_websocket_event_header(dest, etype, content.len())?;
+
+    if !content.is_empty() {
+        dest.write_all(content)?;
+        write_websocket_event_footer(dest)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // returns a few bytes per read, to split events across reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.0.len().min(buf.len()).min(3);
+
+            buf[..size].copy_from_slice(&self.0[..size]);
+            self.0 = &self.0[size..];
+
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn read_events() {
+        let mut body = Vec::new();
+        write_websocket_event(&mut body, "OPEN", b"").unwrap();
+        write_websocket_event(&mut body, "TEXT", b"hello").unwrap();
+        write_websocket_event(&mut body, "CLOSE", b"").unwrap();
+
+        let events = match read_websocket_events(Trickle(&body), 1024) {
+            Ok(events) => events,
+            Err(_) => panic!("failed to read events"),
+        };
+
+        let etypes: Vec<&str> = events.iter().map(|e| e.etype.as_str()).collect();
+        assert_eq!(etypes, ["OPEN", "TEXT", "CLOSE"]);
+        assert_eq!(events[1].content, b"hello");
+
+        assert!(matches!(
+            read_websocket_events(Trickle(&body), body.len() - 1),
+            Err(ReadEventsError::TooLarge)
+        ));
+
+        assert!(matches!(
+            read_websocket_events(Trickle(&body[..body.len() - 1]), 1024),
+            Err(ReadEventsError::Parse)
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::Config;
+use crate::publish::API_BACKEND;
+use crate::routes::SELF_BACKEND;
+use fastly::backend::Backend;
+use fastly::{secret_store, KVStore};
+use serde::Serialize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResourceKind {
+    Backend,
+    KvStore,
+    SecretStore,
+}
+
+// names of the resources the app expects to be linked to the service
+pub struct Resources<'a> {
+    pub config_store: &'a str,
+    pub keys_store: &'a str,
+    pub messages_store: &'a str,
+    pub secret_store: &'a str,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Missing {
+    pub kind: ResourceKind,
+    pub name: String,
+
+    // whether enabled features can't work without the resource. optional
+    // resources only disable some functionality, such as durability
+    pub required: bool,
+}
+
+fn find_missing<F>(config: &Config, r: &Resources, exists: F) -> Vec<Missing>
+where
+    F: Fn(ResourceKind, &str) -> bool,
+{
+    let publishing = config.http_publish_enabled
+        || config.mqtt_enabled
+        || config.bayeux_enabled
+        || config.socketio_enabled;
+    let streaming = config.sse_enabled
+        || config.mqtt_enabled
+        || config.bayeux_enabled
+        || config.socketio_enabled;
+    let tokens = publishing || config.sse_enabled;
+
+    let mut checks = vec![
+        (ResourceKind::Backend, SELF_BACKEND, streaming),
+        (ResourceKind::Backend, API_BACKEND, publishing),
+        (ResourceKind::KvStore, r.keys_store, tokens),
+    ];
+
+    // the messages store isn't used if storage is remote
+    match &config.remote_storage {
+        Some(remote) => {
+            if let Some(name) = &remote.backend {
+                checks.push((ResourceKind::Backend, name, true));
+            }
+        }
+        None => checks.push((ResourceKind::KvStore, r.messages_store, false)),
+    }
+
+    // without it, messages just aren't mirrored
+    if let Some(name) = config.mirror.as_ref().and_then(|m| m.backend.as_deref()) {
+        checks.push((ResourceKind::Backend, name, false));
+    }
+
+    // without it, webhooks just aren't sent
+    if let Some(name) = config
+        .subscription_webhook
+        .as_ref()
+        .and_then(|w| w.backend.as_deref())
+    {
+        checks.push((ResourceKind::Backend, name, false));
+    }
+
+    if let Some(name) = config.jwks.as_ref().and_then(|j| j.backend.as_deref()) {
+        checks.push((ResourceKind::Backend, name, tokens));
+    }
+
+    checks.push((ResourceKind::SecretStore, r.secret_store, publishing));
+
+    checks
+        .into_iter()
+        .filter(|(kind, name, _)| !exists(*kind, name))
+        .map(|(kind, name, required)| Missing {
+            kind,
+            name: name.to_string(),
+            required,
+        })
+        .collect()
+}
+
+// checks that each resource exists. errors other than the resource not
+// existing are reported as missing too, since the app can't use it either
+pub fn check(config: &Config, r: &Resources) -> Vec<Missing> {
+    find_missing(config, r, |kind, name| match kind {
+        ResourceKind::Backend => matches!(Backend::from_name(name), Ok(b) if b.exists()),
+        ResourceKind::KvStore => matches!(KVStore::open(name), Ok(Some(_))),
+        ResourceKind::SecretStore => secret_store::SecretStore::open(name).is_ok(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing() {
+        let r = Resources {
+            config_store: "config",
+            keys_store: "keys",
+            messages_store: "messages",
+            secret_store: "secrets",
+        };
+
+        let config = Config::default();
+
+        assert!(find_missing(&config, &r, |_, _| true).is_empty());
+
+        let m = find_missing(&config, &r, |kind, _| kind != ResourceKind::KvStore);
+        assert_eq!(
+            m,
+            vec![
+                Missing {
+                    kind: ResourceKind::KvStore,
+                    name: "keys".to_string(),
+                    required: true,
+                },
+                Missing {
+                    kind: ResourceKind::KvStore,
+                    name: "messages".to_string(),
+                    required: false,
+                },
+            ]
+        );
+
+        // with publishing disabled, only subscribing needs to work
+        let config = Config {
+            http_publish_enabled: false,
+            mqtt_enabled: false,
+            ..Default::default()
+        };
+
+        let m = find_missing(&config, &r, |_, _| false);
+        let required: Vec<&str> = m
+            .iter()
+            .filter(|m| m.required)
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(required, ["self", "keys"]);
+    }
+}